@@ -0,0 +1 @@
+ALTER TABLE model ADD COLUMN supports_streaming BOOLEAN NOT NULL DEFAULT 1;
@@ -0,0 +1,7 @@
+INSERT INTO provider (name, base_url)
+VALUES ('grok', 'https://api.x.ai/v1');
+
+INSERT INTO model (provider_id, name, supports_json, supports_tools)
+SELECT id, 'grok-2-latest', 1, 1
+FROM provider
+WHERE name = 'grok';
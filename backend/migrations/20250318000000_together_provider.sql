@@ -0,0 +1,7 @@
+INSERT INTO provider (name, base_url)
+VALUES ('together', 'https://api.together.xyz/v1');
+
+INSERT INTO model (provider_id, name, supports_json, supports_tools)
+SELECT id, 'meta-llama/Llama-3.3-70B-Instruct-Turbo', 1, 1
+FROM provider
+WHERE name = 'together';
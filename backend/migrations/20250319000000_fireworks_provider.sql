@@ -0,0 +1,7 @@
+INSERT INTO provider (name, base_url)
+VALUES ('fireworks', 'https://api.fireworks.ai/inference/v1');
+
+INSERT INTO model (provider_id, name, supports_json, supports_tools)
+SELECT id, 'accounts/fireworks/models/llama-v3p1-70b-instruct', 1, 1
+FROM provider
+WHERE name = 'fireworks';
@@ -0,0 +1,7 @@
+INSERT INTO provider (name, base_url)
+VALUES ('replicate', 'https://api.replicate.com/v1');
+
+INSERT INTO model (provider_id, name, supports_json, supports_tools)
+SELECT id, 'meta/meta-llama-3-70b-instruct', 0, 0
+FROM provider
+WHERE name = 'replicate';
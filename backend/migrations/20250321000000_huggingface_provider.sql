@@ -0,0 +1,7 @@
+INSERT INTO provider (name, base_url)
+VALUES ('huggingface', 'https://router.huggingface.co/v1');
+
+INSERT INTO model (provider_id, name, supports_json, supports_tools)
+SELECT id, 'meta-llama/Llama-3.1-8B-Instruct', 1, 1
+FROM provider
+WHERE name = 'huggingface';
@@ -0,0 +1,7 @@
+INSERT INTO provider (name, base_url)
+VALUES ('groq', 'https://api.groq.com/openai/v1');
+
+INSERT INTO model (provider_id, name, supports_json, supports_tools)
+SELECT id, 'llama-3.3-70b-versatile', 1, 1
+FROM provider
+WHERE name = 'groq';
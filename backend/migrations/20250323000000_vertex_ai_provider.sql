@@ -0,0 +1,8 @@
+-- An empty base url is the endpoint of GOOGLE_CLOUD_LOCATION
+INSERT INTO provider (name, base_url)
+VALUES ('vertex_ai', '');
+
+INSERT INTO model (provider_id, name, supports_json, supports_tools)
+SELECT id, 'gemini-2.0-flash', 1, 1
+FROM provider
+WHERE name = 'vertex_ai';
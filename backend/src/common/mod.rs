@@ -1,2 +1 @@
-pub mod mapping;
 pub mod types;
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+use crate::services::types::llm_error::LlmError;
+
+use super::chat_request::{
+    ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionRequestResponseFormat,
+    ChatCompletionRequestTool,
+};
+use super::chat_response::{
+    LlmServiceChatCompletionResponse, LlmServiceChatCompletionResponseToolCall,
+    LlmServiceChatCompletionResponseUsage, SafetyRating, Annotation,
+};
+
+/// Provider agnostic chat request: the conversation, how to sample and the tools on offer.
+/// Which model answers it and how it's reached is added by
+/// [`LlmServiceRequest::from_chat_request`](crate::services::types::llm_service::LlmServiceRequest::from_chat_request),
+/// each provider then turns it into its own wire format.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    #[serde(default)]
+    pub params: ChatParams,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<ChatCompletionRequestTool>>,
+}
+
+/// Sampling and output parameters, `None` leaves the provider default.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ChatParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ChatCompletionRequestResponseFormat>,
+}
+
+impl ChatRequest {
+    pub fn new(messages: Vec<ChatCompletionRequestMessage>) -> Self {
+        ChatRequest {
+            messages,
+            ..Default::default()
+        }
+    }
+
+    /// The OpenAi shaped request for `model` the providers are built on.
+    pub fn into_completion_request(self, model: &str) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            model: model.to_string(),
+            messages: self.messages,
+            stream: None,
+            response_format: self.params.response_format,
+            tools: self.tools,
+            provider: None,
+            models: None,
+            transforms: None,
+            max_tokens: self.params.max_tokens,
+            temperature: self.params.temperature,
+            frequency_penalty: self.params.frequency_penalty,
+            presence_penalty: self.params.presence_penalty,
+        }
+    }
+}
+
+impl From<&ChatCompletionRequest> for ChatRequest {
+    fn from(request: &ChatCompletionRequest) -> Self {
+        ChatRequest {
+            messages: request.messages.clone(),
+            params: ChatParams {
+                max_tokens: request.max_tokens,
+                temperature: request.temperature,
+                frequency_penalty: request.frequency_penalty,
+                presence_penalty: request.presence_penalty,
+                response_format: request.response_format.clone(),
+            },
+            tools: request.tools.clone(),
+        }
+    }
+}
+
+/// Provider agnostic answer to a [`ChatRequest`], taken from the first choice.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatResponse {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<LlmServiceChatCompletionResponseToolCall>,
+    pub usage: Option<LlmServiceChatCompletionResponseUsage>,
+    /// Normalized to the OpenAi values (`stop`, `length`, `tool_calls`, ...).
+    pub finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub safety_ratings: Vec<SafetyRating>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+    /// Exact model version that answered, when the provider reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_version: Option<String>,
+    /// The model's thought summary, for providers asked to include one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+}
+
+impl TryFrom<LlmServiceChatCompletionResponse> for ChatResponse {
+    type Error = LlmError;
+
+    fn try_from(response: LlmServiceChatCompletionResponse) -> Result<Self, Self::Error> {
+        let choice = response.choices.into_iter().next().ok_or(LlmError::EmptyResponse)?;
+
+        Ok(ChatResponse {
+            reasoning: choice.message.reasoning,
+            text: choice.message.content,
+            tool_calls: choice.message.tool_calls.unwrap_or_default(),
+            usage: response.usage,
+            finish_reason: choice.finish_reason,
+            safety_ratings: choice.safety_ratings,
+            annotations: choice.annotations,
+            model_version: response.model_version,
+        })
+    }
+}
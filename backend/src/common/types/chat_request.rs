@@ -1,4 +1,10 @@
+use std::borrow::Cow;
+
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::chat_response::LlmServiceChatCompletionResponseToolCall;
+use crate::services::types::llm_error::LlmError;
 
 
 /// Chat completion request matching the OpenAi API schema.
@@ -32,6 +38,13 @@ pub struct ChatCompletionRequest {
     /// What sampling temperature to use, between 0 and 2
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
+    /// Penalizes tokens by how often they already appeared. Clamped to the provider's
+    /// range and left out for providers that don't support it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    /// Penalizes tokens that already appeared at all. Handled like `frequency_penalty`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -70,6 +83,15 @@ pub enum ChatCompletionRequestMessage{
         #[serde(skip_serializing_if = "Option::is_none")]
         name: Option<String>,
     },
+    /// The result of a tool call, answering the assistant's call with the same id.
+    /// Structured results keep their JSON; a plain string result is `Value::String`
+    /// (`content: result.into()` works for both).
+    Tool {
+        content: Value,
+        tool_call_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -92,6 +114,20 @@ pub struct ChatCompletionRequestToolCall {
     pub function_call: ChatCompletionRequestFunctionCall,
 }
 
+/// A call the model made, sent back as part of the assistant turn in the history.
+impl From<LlmServiceChatCompletionResponseToolCall> for ChatCompletionRequestToolCall {
+    fn from(call: LlmServiceChatCompletionResponseToolCall) -> Self {
+        ChatCompletionRequestToolCall {
+            id: call.id,
+            kind: call.kind,
+            function_call: ChatCompletionRequestFunctionCall {
+                name: call.function_call.name,
+                arguments: call.function_call.arguments,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ChatCompletionRequestTool {
@@ -116,12 +152,15 @@ pub struct ChatCompletionRequestFunctionDescription {
 
 // Helper Methods for easy extraction
 impl ChatCompletionRequestMessage {
-    /// Returns the content of the message regardless of its role
-    pub fn content(&self) -> &str {
+    /// Returns the content of the message regardless of its role. Structured tool
+    /// results are returned as their JSON text.
+    pub fn content(&self) -> Cow<'_, str> {
         match self {
-            ChatCompletionRequestMessage::System { content, .. } => content,
-            ChatCompletionRequestMessage::User { content, .. } => content,
-            ChatCompletionRequestMessage::Assistant { content, .. } => content,
+            ChatCompletionRequestMessage::System { content, .. } => Cow::Borrowed(content),
+            ChatCompletionRequestMessage::User { content, .. } => Cow::Borrowed(content),
+            ChatCompletionRequestMessage::Assistant { content, .. } => Cow::Borrowed(content),
+            ChatCompletionRequestMessage::Tool { content: Value::String(content), .. } => Cow::Borrowed(content),
+            ChatCompletionRequestMessage::Tool { content, .. } => Cow::Owned(content.to_string()),
         }
     }
 
@@ -131,6 +170,7 @@ impl ChatCompletionRequestMessage {
             ChatCompletionRequestMessage::System { name, .. } => name.as_deref(),
             ChatCompletionRequestMessage::User { name, .. } => name.as_deref(),
             ChatCompletionRequestMessage::Assistant { name, .. } => name.as_deref(),
+            ChatCompletionRequestMessage::Tool { name, .. } => name.as_deref(),
         }
     }
 
@@ -140,6 +180,7 @@ impl ChatCompletionRequestMessage {
             ChatCompletionRequestMessage::System { .. } => "system",
             ChatCompletionRequestMessage::User { .. } => "user",
             ChatCompletionRequestMessage::Assistant { .. } => "assistant",
+            ChatCompletionRequestMessage::Tool { .. } => "tool",
         }
     }
 
@@ -165,5 +206,300 @@ impl ChatCompletionRequestMessage {
     pub fn is_assistant(&self) -> bool {
         matches!(self, ChatCompletionRequestMessage::Assistant { .. })
     }
+
+    /// Check if this message is a tool result
+    pub fn is_tool(&self) -> bool {
+        matches!(self, ChatCompletionRequestMessage::Tool { .. })
+    }
+
+    /// The content of a trailing assistant message (without tool calls). Such a message
+    /// seeds the reply: the model is expected to continue it rather than start fresh.
+    pub fn prefill(messages: &[Self]) -> Option<&str> {
+        match messages.last()? {
+            ChatCompletionRequestMessage::Assistant { content, tool_calls: None, .. } if !content.is_empty() => {
+                Some(content.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// Converts an OpenAI-style JSON array of `{role, content, ...}` objects into messages.
+    /// A `null` content (assistant messages that only carry tool calls) becomes an empty string,
+    /// an array of content parts its text parts joined. Other parts, e.g. images, can't be
+    /// represented and fail with [`LlmError::Validation`].
+    pub fn from_openai_json(value: Value) -> Result<Vec<Self>, LlmError> {
+        let items = match value {
+            Value::Array(items) => items,
+            _ => {
+                return Err(LlmError::Validation {
+                    field: "messages".to_string(),
+                    message: "expected a JSON array of messages".to_string(),
+                })
+            }
+        };
+
+        items.into_iter().map(Self::from_openai_message).collect()
+    }
+
+    fn from_openai_message(value: Value) -> Result<Self, LlmError> {
+        let role = value.get("role")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LlmError::MissingField("role".to_string()))?;
+        let text = || openai_text_content(value.get("content"));
+        let name = value.get("name")
+            .and_then(Value::as_str)
+            .map(|n| n.to_string());
+
+        match role {
+            "system" => Ok(ChatCompletionRequestMessage::System { content: text()?, name }),
+            "user" => Ok(ChatCompletionRequestMessage::User { content: text()?, name }),
+            "assistant" => {
+                let tool_calls = match value.get("tool_calls") {
+                    Some(Value::Null) | None => None,
+                    Some(tool_calls) => Some(serde_json::from_value(tool_calls.clone())?),
+                };
+                Ok(ChatCompletionRequestMessage::Assistant { content: text()?, tool_calls, name })
+            }
+            "tool" => {
+                let tool_call_id = value.get("tool_call_id")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| LlmError::MissingField("tool_call_id".to_string()))?
+                    .to_string();
+                // keep structured results as they are
+                let content = match value.get("content") {
+                    Some(Value::String(_)) | Some(Value::Null) | None => Value::String(text()?),
+                    Some(structured) => structured.clone(),
+                };
+                Ok(ChatCompletionRequestMessage::Tool { content, tool_call_id, name })
+            }
+            other => Err(LlmError::InvalidRole(other.to_string())),
+        }
+    }
+
+    /// Converts messages into the OpenAI JSON message format, the reverse of [`Self::from_openai_json`].
+    pub fn to_openai_json(messages: &[Self]) -> Value {
+        Value::Array(messages.iter().map(Self::to_openai_message).collect())
+    }
+
+    fn to_openai_message(&self) -> Value {
+        let mut message = json!({
+            "role": self.role(),
+            "content": self.content(),
+        });
+
+        if let Some(name) = self.name() {
+            message["name"] = json!(name);
+        }
+        if let Some(tool_calls) = self.tool_calls() {
+            message["tool_calls"] = json!(tool_calls);
+        }
+        if let ChatCompletionRequestMessage::Tool { tool_call_id, .. } = self {
+            message["tool_call_id"] = json!(tool_call_id);
+        }
+
+        message
+    }
+}
+
+/// The text of an OpenAI message `content`: a string, `null`, or an array of content parts
+/// of which only `text` parts can be represented.
+fn openai_text_content(content: Option<&Value>) -> Result<String, LlmError> {
+    let invalid = |message: String| LlmError::Validation { field: "content".to_string(), message };
+
+    match content {
+        None | Some(Value::Null) => Ok(String::new()),
+        Some(Value::String(text)) => Ok(text.clone()),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .map(|part| match part.get("type").and_then(Value::as_str) {
+                Some("text") => part
+                    .get("text")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid("text part without text".to_string())),
+                Some(other) => Err(invalid(format!("unsupported content part type {}", other))),
+                None => Err(invalid("content part without a type".to_string())),
+            })
+            .collect(),
+        Some(_) => Err(invalid("expected a string or an array of content parts".to_string())),
+    }
+}
+
+/// Characters of content across `messages`, what rough token estimates are based on.
+/// Structured tool results count as their JSON text.
+pub fn message_chars(messages: &[ChatCompletionRequestMessage]) -> usize {
+    messages.iter().map(|msg| msg.content().chars().count()).sum()
+}
+
+/// The conversation turns, everything but the system messages.
+pub fn non_system_messages(
+    messages: &[ChatCompletionRequestMessage],
+) -> impl Iterator<Item = &ChatCompletionRequestMessage> {
+    messages.iter().filter(|msg| !msg.is_system())
+}
+
+/// The most recent user message, the one the model is about to answer.
+pub fn last_user_message(messages: &[ChatCompletionRequestMessage]) -> Option<&ChatCompletionRequestMessage> {
+    messages.iter().rev().find(|msg| msg.is_user())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mixed_conversation() -> Vec<ChatCompletionRequestMessage> {
+        ChatCompletionRequestMessage::from_openai_json(json!([
+            { "role": "system", "content": "Be brief" },
+            { "role": "user", "content": "Weather in Paris?" },
+            {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "get_weather", "arguments": "{}" }
+                }]
+            },
+            { "role": "tool", "content": { "temp": 18 }, "tool_call_id": "call_1" },
+            { "role": "user", "content": "And in Zürich?" },
+            { "role": "assistant", "content": "12 degrees." }
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_message_chars() {
+        let messages = mixed_conversation();
+        // "Zürich" is counted in characters, not bytes
+        assert_eq!(message_chars(&messages), 8 + 17 + 0 + "{\"temp\":18}".len() + 14 + 11);
+        assert_eq!(message_chars(&[]), 0);
+    }
+
+    #[test]
+    fn test_non_system_messages() {
+        let messages = mixed_conversation();
+        let roles: Vec<&str> = non_system_messages(&messages).map(|msg| msg.role()).collect();
+        assert_eq!(roles, ["user", "assistant", "tool", "user", "assistant"]);
+    }
+
+    #[test]
+    fn test_last_user_message() {
+        let messages = mixed_conversation();
+        assert_eq!(last_user_message(&messages).unwrap().content(), "And in Zürich?");
+        assert!(last_user_message(&messages[..1]).is_none());
+    }
+
+    #[test]
+    fn test_openai_json_round_trip() {
+        let value = json!([
+            { "role": "system", "content": "You are a weather bot" },
+            { "role": "user", "content": "Weather in Paris?", "name": "alice" },
+            {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" }
+                }]
+            },
+            { "role": "tool", "content": "{\"temp\":18}", "tool_call_id": "call_1" },
+            { "role": "assistant", "content": "It is 18 degrees." }
+        ]);
+
+        let messages = ChatCompletionRequestMessage::from_openai_json(value.clone()).unwrap();
+        assert_eq!(messages.len(), 5);
+        assert!(messages[0].is_system());
+        assert_eq!(messages[1].name(), Some("alice"));
+
+        let tool_calls = messages[2].tool_calls().unwrap();
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(tool_calls[0].function_call.name, "get_weather");
+
+        match &messages[3] {
+            ChatCompletionRequestMessage::Tool { tool_call_id, content, .. } => {
+                assert_eq!(tool_call_id, "call_1");
+                assert_eq!(content, "{\"temp\":18}");
+            }
+            other => panic!("Expected tool message, got {:?}", other),
+        }
+
+        assert_eq!(ChatCompletionRequestMessage::to_openai_json(&messages), value);
+    }
+
+    #[test]
+    fn test_openai_json_null_content() {
+        let value = json!([{
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "get_time", "arguments": "{}" }
+            }]
+        }]);
+
+        let messages = ChatCompletionRequestMessage::from_openai_json(value).unwrap();
+        assert_eq!(messages[0].content(), "");
+        assert_eq!(messages[0].tool_calls().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_prefill_only_for_trailing_assistant() {
+        let mut messages = vec![
+            ChatCompletionRequestMessage::User { content: "List three colors".to_string(), name: None },
+            ChatCompletionRequestMessage::Assistant { content: "1.".to_string(), tool_calls: None, name: None },
+        ];
+        assert_eq!(ChatCompletionRequestMessage::prefill(&messages), Some("1."));
+
+        messages.push(ChatCompletionRequestMessage::User { content: "Go on".to_string(), name: None });
+        assert_eq!(ChatCompletionRequestMessage::prefill(&messages), None);
+    }
+
+    #[test]
+    fn test_openai_json_unknown_role() {
+        let value = json!([{ "role": "developer", "content": "hi" }]);
+
+        let err = ChatCompletionRequestMessage::from_openai_json(value).unwrap_err();
+        assert!(matches!(err, LlmError::InvalidRole(role) if role == "developer"));
+    }
+
+    #[test]
+    fn test_openai_json_content_parts() {
+        let value = json!([{
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "What is in " },
+                { "type": "text", "text": "this picture?" }
+            ]
+        }]);
+
+        let messages = ChatCompletionRequestMessage::from_openai_json(value).unwrap();
+        assert_eq!(messages[0].content(), "What is in this picture?");
+
+        // Images have no representation here
+        let value = json!([{
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "Describe it" },
+                { "type": "image_url", "image_url": { "url": "https://example.com/cat.png" } }
+            ]
+        }]);
+        let err = ChatCompletionRequestMessage::from_openai_json(value).unwrap_err();
+        assert!(matches!(err, LlmError::Validation { field, message } if field == "content" && message.contains("image_url")));
+    }
+
+    #[test]
+    fn test_openai_json_not_an_array() {
+        let err = ChatCompletionRequestMessage::from_openai_json(json!({ "role": "user", "content": "hi" })).unwrap_err();
+        assert!(matches!(err, LlmError::Validation { field, .. } if field == "messages"));
+    }
+
+    #[test]
+    fn test_openai_json_tool_without_call_id() {
+        let value = json!([{ "role": "tool", "content": "{}" }]);
+
+        let err = ChatCompletionRequestMessage::from_openai_json(value).unwrap_err();
+        assert!(matches!(err, LlmError::MissingField(field) if field == "tool_call_id"));
+    }
 }
 
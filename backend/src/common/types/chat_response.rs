@@ -1,5 +1,4 @@
 use serde::{Deserialize, Serialize};
-use openrouter_api::{types::chat::ChatCompletionResponse, ChatCompletionChunk};
 
 /// Chat completion response.
 #[derive(Debug, Deserialize, Serialize)]
@@ -8,7 +7,32 @@ pub struct LlmServiceChatCompletionResponse {
     pub choices: Vec<LlmServiceChatCompletionResponseChoice>,
     pub created: i64,
     pub model: String,
+    /// Upstream provider that served the request, reported by routers like OpenRouter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Exact model version that served the request, for providers that report it (Gemini's `modelVersion`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_version: Option<String>,
     pub usage: Option<LlmServiceChatCompletionResponseUsage>,
+    /// Rate limit state the provider reported with the response, regular requests only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<LlmServiceRateLimit>,
+    /// The response body as the provider sent it, for providers whose JSON is parsed here.
+    /// Never serialized, logs keep the normalized form.
+    #[serde(skip)]
+    pub raw: Option<serde_json::Value>,
+}
+
+/// The `x-ratelimit-*` headers sent by Groq and other OpenAi compatible APIs, for planning
+/// how fast to send. Resets are kept as sent, a duration like `2m59.56s`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct LlmServiceRateLimit {
+    pub limit_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset_requests: Option<String>,
+    pub reset_tokens: Option<String>,
 }
 
 /// A choice returned by the chat API.
@@ -18,6 +42,85 @@ pub struct LlmServiceChatCompletionResponseChoice {
     pub finish_reason: Option<String>,
     #[serde(rename = "native_finish_reason")]
     pub native_finish_reason: Option<String>,
+    /// How the provider rated the answer per harm category, for providers that do (Gemini).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub safety_ratings: Vec<SafetyRating>,
+    /// Sources cited for spans of `message.content`, for providers that ground answers (Gemini).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+}
+
+/// A span of the answer text backed by a source, e.g. to render as a footnote. `start`
+/// and `end` are byte offsets into the text, `end` exclusive. A span citing several
+/// sources has one annotation per source.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Annotation {
+    pub start: usize,
+    pub end: usize,
+    pub uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// A provider's rating of a response for one harm category.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SafetyRating {
+    pub category: HarmCategory,
+    pub probability: HarmProbability,
+    /// Whether this category is what stopped the response.
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+/// Serialized in snake case (`"hate_speech"`, ...). `Other` is a category we don't know,
+/// by its provider name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(into = "String", from = "String")]
+pub enum HarmCategory {
+    Harassment,
+    HateSpeech,
+    SexuallyExplicit,
+    DangerousContent,
+    CivicIntegrity,
+    Other(String),
+}
+
+impl From<String> for HarmCategory {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "harassment" => HarmCategory::Harassment,
+            "hate_speech" => HarmCategory::HateSpeech,
+            "sexually_explicit" => HarmCategory::SexuallyExplicit,
+            "dangerous_content" => HarmCategory::DangerousContent,
+            "civic_integrity" => HarmCategory::CivicIntegrity,
+            _ => HarmCategory::Other(value),
+        }
+    }
+}
+
+impl From<HarmCategory> for String {
+    fn from(value: HarmCategory) -> Self {
+        match value {
+            HarmCategory::Harassment => "harassment".to_string(),
+            HarmCategory::HateSpeech => "hate_speech".to_string(),
+            HarmCategory::SexuallyExplicit => "sexually_explicit".to_string(),
+            HarmCategory::DangerousContent => "dangerous_content".to_string(),
+            HarmCategory::CivicIntegrity => "civic_integrity".to_string(),
+            HarmCategory::Other(name) => name,
+        }
+    }
+}
+
+/// How likely the content is to be harmful in a category, as the provider judged it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HarmProbability {
+    Negligible,
+    Low,
+    Medium,
+    High,
+    #[serde(other)]
+    Unknown,
 }
 
 /// Usage data returned from the API.
@@ -37,9 +140,33 @@ pub struct LlmServiceChatCompletionResponseMessage {
     // Optionally include tool_calls when the assistant message contains a tool call.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<LlmServiceChatCompletionResponseToolCall>>,
+    /// Every part of a multimodal response in order, only set when it held more than text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parts: Option<Vec<LlmServiceContentPart>>,
+    /// The model's thought summary, kept apart from `content`. Gemini sends one when
+    /// `include_thoughts` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// One part of a multimodal response.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LlmServiceContentPart {
+    Text { text: String },
+    /// Base64 encoded image data.
+    Image { mime_type: String, data: String },
+}
+
+/// Log probability of one generated token, in the shape OpenAi style APIs return it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    /// Natural log of the token's probability, so always `<= 0`.
+    pub logprob: f64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LlmServiceChatCompletionResponseToolCall {
     /// A unique identifier for the tool call.
     pub id: String,
@@ -51,7 +178,7 @@ pub struct LlmServiceChatCompletionResponseToolCall {
     pub function_call: LlmServiceChatCompletionResponseFunctionCall,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LlmServiceChatCompletionResponseFunctionCall {
     /// The name of the function to call.
     pub name: String,
@@ -79,9 +206,13 @@ impl LlmServiceChatCompletionResponse {
                 content: message_content,
                 name: None,
                 tool_calls: None,
+                parts: None,
+                reasoning: None,
             },
             finish_reason: Some("stop".to_string()),
             native_finish_reason: None,
+            safety_ratings: Vec::new(),
+            annotations: Vec::new(),
         };
         
         // Construct usage if all token counts are provided
@@ -99,75 +230,71 @@ impl LlmServiceChatCompletionResponse {
             choices: vec![choice],
             created,
             model,
+            provider: None,
             usage,
+            model_version: None,
+            rate_limit: None,
+            raw: None,
         }
     }
 }
 
 
-impl From<ChatCompletionResponse> for LlmServiceChatCompletionResponse {
-    fn from(value: ChatCompletionResponse) -> Self {
-        LlmServiceChatCompletionResponse {
-            id: value.id,
-            choices: value.choices.into_iter().map(|choice| {
-                LlmServiceChatCompletionResponseChoice {
-                    message: LlmServiceChatCompletionResponseMessage {
-                        role: choice.message.role,
-                        content: choice.message.content,
-                        name: choice.message.name,
-                        tool_calls: choice.message.tool_calls.map(|tool_calls| {
-                            tool_calls.into_iter().map(|tool_call| {
-                                LlmServiceChatCompletionResponseToolCall {
-                                    id: tool_call.id,
-                                    kind: tool_call.kind,
-                                    function_call: LlmServiceChatCompletionResponseFunctionCall {
-                                        name: tool_call.function_call.name,
-                                        arguments: tool_call.function_call.arguments,
-                                    },
-                                }
-                            }).collect()
-                        }),
-                    },
-                    finish_reason: choice.finish_reason,
-                    native_finish_reason: choice.native_finish_reason,
-                }
-            }).collect(),
-            created: value.created,
-            model: value.model,
-            usage: value.usage.map(|usage| {
-                LlmServiceChatCompletionResponseUsage {
-                    prompt_tokens: usage.prompt_tokens,
-                    completion_tokens: usage.completion_tokens,
-                    total_tokens: usage.total_tokens,
-                }
-            }),
-        }
-    }
-}
-
-
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LlmServiceChatCompletionChunk {
     pub id: String,
     pub choices: Vec<LlmServiceChoiceStream>,
     pub usage: Option<LlmServiceUsage>,
+    /// The exact model version that served the stream, when the provider reports one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_version: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LlmServiceChoiceStream {
     pub index: u32,
     pub delta: LlmServiceStreamDelta,
     pub finish_reason: Option<String>,
     pub native_finish_reason: Option<String>,
+    /// Safety category that stopped generation, for providers that report one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safety_category: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LlmServiceStreamDelta {
     pub role: String,
     pub content: String,
+    /// Reasoning text, for providers that stream their thinking separately from the answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<String>,
+    /// Tool call fragments. Parallel tool calls are interleaved and told apart by `index`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<LlmServiceToolCallDelta>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A fragment of a streamed tool call. Only the first fragment for an index carries
+/// the id and function name, later ones append to the arguments.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LlmServiceToolCallDelta {
+    pub index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<LlmServiceFunctionCallDelta>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LlmServiceFunctionCallDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LlmServiceUsage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
@@ -184,45 +311,64 @@ impl LlmServiceChatCompletionChunk {
                 delta: LlmServiceStreamDelta {
                     role: "assistant".to_string(),
                     content: "[DONE]".to_string(),
+                    thinking: None,
+                    tool_calls: None,
                 },
                 finish_reason: Some("stop".to_string()),
                 native_finish_reason: Some("stop".to_string()),
+                safety_category: None,
             }],
             usage: None,
+            model_version: None,
         }
     }
     
-    /// Checks if this chunk is a "DONE" sentinel.
-    pub fn is_done_sentinel(&self) -> bool {
-        self.choices.iter().any(|choice| 
-            choice.delta.content == "[DONE]" && 
-            choice.finish_reason.as_deref() == Some("stop")
-        )
-    }
-}
-
-impl From<ChatCompletionChunk> for LlmServiceChatCompletionChunk {
-    fn from(chunk: ChatCompletionChunk) -> Self {
+    /// Creates a single chunk carrying the complete content of a non-streamed response.
+    pub fn from_response(response: &LlmServiceChatCompletionResponse) -> Self {
         LlmServiceChatCompletionChunk {
-            id: chunk.id,
-            choices: chunk.choices.into_iter().map(|choice| {
+            id: response.id.clone(),
+            choices: response.choices.iter().enumerate().map(|(index, choice)| {
                 LlmServiceChoiceStream {
-                    index: choice.index,
+                    index: index as u32,
                     delta: LlmServiceStreamDelta {
-                        role: choice.delta.role,
-                        content: choice.delta.content,
+                        role: choice.message.role.clone(),
+                        content: choice.message.content.clone(),
+                        thinking: None,
+                        tool_calls: choice.message.tool_calls.as_ref().map(|tool_calls| {
+                            tool_calls.iter().enumerate().map(|(index, tool_call)| {
+                                LlmServiceToolCallDelta {
+                                    index: index as u32,
+                                    id: Some(tool_call.id.clone()),
+                                    kind: Some(tool_call.kind.clone()),
+                                    function: Some(LlmServiceFunctionCallDelta {
+                                        name: Some(tool_call.function_call.name.clone()),
+                                        arguments: Some(tool_call.function_call.arguments.clone()),
+                                    }),
+                                }
+                            }).collect()
+                        }),
                     },
-                    finish_reason: choice.finish_reason,
-                    native_finish_reason: choice.native_finish_reason,
+                    finish_reason: choice.finish_reason.clone(),
+                    native_finish_reason: choice.native_finish_reason.clone(),
+                    safety_category: None,
                 }
             }).collect(),
-            usage: chunk.usage.map(|usage| {
+            usage: response.usage.as_ref().map(|usage| {
                 LlmServiceUsage {
                     prompt_tokens: usage.prompt_tokens,
                     completion_tokens: usage.completion_tokens,
                     total_tokens: usage.total_tokens,
                 }
             }),
+            model_version: response.model_version.clone(),
         }
     }
+
+    /// Checks if this chunk is a "DONE" sentinel.
+    pub fn is_done_sentinel(&self) -> bool {
+        self.choices.iter().any(|choice| 
+            choice.delta.content == "[DONE]" && 
+            choice.finish_reason.as_deref() == Some("stop")
+        )
+    }
 }
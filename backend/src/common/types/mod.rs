@@ -1,3 +1,4 @@
 pub mod models;
+pub mod chat;
 pub mod chat_request;
 pub mod chat_response;
@@ -1,22 +1,131 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// Serialized as its provider name (`"openrouter"`, `"gemini"`, ...), the same as the `provider` table.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", from = "String")]
 pub enum LlmApiProvider {
     Openrouter,
+    Gemini,
+    Grok,
+    Together,
+    Fireworks,
+    Replicate,
+    HuggingFace,
+    Groq,
+    /// Gemini models served by Vertex AI in a Google Cloud project.
+    VertexAi,
+    /// A provider registered at runtime through the provider registry.
+    Custom(String),
 
     // TODO: Will support in future with more refined SDK
     // OpenAi,
     // Anthropic,
-    // Gemini,
     // Deepseek,
     // Azure,
 }
 
+impl LlmApiProvider {
+    /// Best guess at the provider serving `model` when it isn't configured in the db.
+    /// Anything unrecognised goes to OpenRouter, which routes most model ids.
+    pub fn for_model(model: &str) -> Self {
+        if model.starts_with("gemini-") {
+            LlmApiProvider::Gemini
+        } else if model.starts_with("grok-") {
+            LlmApiProvider::Grok
+        } else if model.starts_with("accounts/fireworks/") {
+            LlmApiProvider::Fireworks
+        } else {
+            LlmApiProvider::Openrouter
+        }
+    }
+
+    /// Public API base url, matching the seeded provider rows.
+    pub fn default_base_url(&self) -> &'static str {
+        match self {
+            LlmApiProvider::Openrouter => "https://openrouter.ai/api/v1",
+            LlmApiProvider::Gemini => "https://generativelanguage.googleapis.com/v1beta",
+            LlmApiProvider::Grok => "https://api.x.ai/v1",
+            LlmApiProvider::Together => "https://api.together.xyz/v1",
+            LlmApiProvider::Fireworks => "https://api.fireworks.ai/inference/v1",
+            LlmApiProvider::Replicate => "https://api.replicate.com/v1",
+            LlmApiProvider::HuggingFace => "https://router.huggingface.co/v1",
+            LlmApiProvider::Groq => "https://api.groq.com/openai/v1",
+            // the host depends on the region of the request, filled in by the provider
+            LlmApiProvider::VertexAi => "",
+            // custom providers know their own endpoint
+            LlmApiProvider::Custom(_) => "",
+        }
+    }
+
+    /// Env var the default credential provider reads this provider's keys from.
+    /// A custom provider's is its name upper cased with `_API_KEY` appended.
+    pub fn api_key_var(&self) -> String {
+        match self {
+            LlmApiProvider::Openrouter => "OPENROUTER_API_KEY".to_string(),
+            LlmApiProvider::Gemini => "GOOGLE_API_KEY".to_string(),
+            LlmApiProvider::Grok => "XAI_API_KEY".to_string(),
+            LlmApiProvider::Together => "TOGETHER_API_KEY".to_string(),
+            LlmApiProvider::Fireworks => "FIREWORKS_API_KEY".to_string(),
+            LlmApiProvider::Replicate => "REPLICATE_API_TOKEN".to_string(),
+            LlmApiProvider::HuggingFace => "HF_TOKEN".to_string(),
+            LlmApiProvider::Groq => "GROQ_API_KEY".to_string(),
+            // only read by tests, requests authenticate with Application Default Credentials
+            LlmApiProvider::VertexAi => "GOOGLE_ACCESS_TOKEN".to_string(),
+            LlmApiProvider::Custom(name) => format!("{}_API_KEY", name.to_uppercase().replace('-', "_")),
+        }
+    }
+
+    /// What the provider's API accepts, used when a request sets `adapt_to_capabilities`.
+    /// Custom providers declare theirs when they are registered.
+    pub fn capabilities(&self) -> ProviderCapabilities {
+        match self {
+            // Replicate runs plain text-in text-out predictions
+            LlmApiProvider::Replicate => ProviderCapabilities::NONE,
+            _ => ProviderCapabilities::ALL,
+        }
+    }
+
+    /// Whether the API takes a single system prompt rather than system messages, so several
+    /// of them have to be reduced to one, see `system_message_policy` on the request.
+    pub fn single_system_message(&self) -> bool {
+        matches!(self, LlmApiProvider::Gemini | LlmApiProvider::VertexAi | LlmApiProvider::Replicate)
+    }
+
+    /// Whether the API continues a trailing assistant message (a prefill) instead of starting
+    /// a new turn after it. For the others the prefill is sent as an instruction.
+    pub fn continues_prefill(&self) -> bool {
+        matches!(self, LlmApiProvider::Openrouter | LlmApiProvider::Gemini | LlmApiProvider::VertexAi)
+    }
+}
+
+/// Request features a provider supports natively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderCapabilities {
+    /// Accepts a `response_format` asking for a JSON object.
+    pub json_mode: bool,
+    /// Also accepts a JSON schema in the `response_format`.
+    pub json_schema: bool,
+    pub tools: bool,
+}
+
+impl ProviderCapabilities {
+    pub const ALL: Self = ProviderCapabilities { json_mode: true, json_schema: true, tools: true };
+    pub const NONE: Self = ProviderCapabilities { json_mode: false, json_schema: false, tools: false };
+}
+
 impl From<String> for LlmApiProvider {
     fn from(value: String) -> Self {
         match value.as_str() {
             "openrouter" => LlmApiProvider::Openrouter,
-            _ => unreachable!("Invalid Provider"),
+            "gemini" => LlmApiProvider::Gemini,
+            "grok" => LlmApiProvider::Grok,
+            "together" => LlmApiProvider::Together,
+            "fireworks" => LlmApiProvider::Fireworks,
+            "replicate" => LlmApiProvider::Replicate,
+            "huggingface" => LlmApiProvider::HuggingFace,
+            "groq" => LlmApiProvider::Groq,
+            "vertex_ai" => LlmApiProvider::VertexAi,
+            _ => LlmApiProvider::Custom(value),
         }
     }
 }
@@ -25,6 +134,15 @@ impl From<LlmApiProvider> for String {
     fn from(value: LlmApiProvider) -> Self {
         match value {
             LlmApiProvider::Openrouter => "openrouter".to_string(),
+            LlmApiProvider::Gemini => "gemini".to_string(),
+            LlmApiProvider::Grok => "grok".to_string(),
+            LlmApiProvider::Together => "together".to_string(),
+            LlmApiProvider::Fireworks => "fireworks".to_string(),
+            LlmApiProvider::Replicate => "replicate".to_string(),
+            LlmApiProvider::HuggingFace => "huggingface".to_string(),
+            LlmApiProvider::Groq => "groq".to_string(),
+            LlmApiProvider::VertexAi => "vertex_ai".to_string(),
+            LlmApiProvider::Custom(name) => name,
         }.to_string()
     }
 }
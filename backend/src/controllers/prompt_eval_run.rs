@@ -59,6 +59,8 @@ pub async fn execute_eval_run(
             transforms: None,
             max_tokens: None,
             temperature: None,
+            frequency_penalty: None,
+            presence_penalty: None,
         };
 
         let llm_props = LlmServiceRequest::new(prompt.clone(), chat_request).map_err(|e| {
@@ -14,7 +14,7 @@ use crate::{
     }, chat_response::LlmServiceChatCompletionResponse}, 
     services::{
         llm::Llm,
-        types::llm_service::LlmServiceRequest,
+        types::{llm_service::LlmServiceRequest, stream::StreamEvent},
     }, 
     AppError, 
     AppState
@@ -363,7 +363,7 @@ pub async fn api_completions_stream(
         // Process regular stream messages
         while let Some(result) = rx.recv().await {
             match result {
-                Ok(content) => {
+                Ok(StreamEvent::Chunk(content)) => {
                     if content.is_done_sentinel() {
                         yield Ok(Event::default().data(serde_json::to_string(&content).unwrap()));
                         break;
@@ -371,6 +371,33 @@ pub async fn api_completions_stream(
 
                     yield Ok(Event::default().data(serde_json::to_string(&content).expect("Failed to turn chunk into string")));
                 }
+                Ok(StreamEvent::BlockStart { kind }) => {
+                    yield Ok(Event::default().event("block_start").data(kind.as_str()));
+                }
+                Ok(StreamEvent::BlockStop) => {
+                    yield Ok(Event::default().event("block_stop").data(""));
+                }
+                Ok(StreamEvent::ToolCall(tool_call)) => {
+                    yield Ok(Event::default().event("tool_call").data(serde_json::to_string(&tool_call).expect("Failed to turn tool call into string")));
+                }
+                Ok(StreamEvent::UsageDelta(usage)) => {
+                    yield Ok(Event::default().event("usage").data(serde_json::to_string(&usage).expect("Failed to turn usage into string")));
+                }
+                Ok(StreamEvent::FinishReason(reason)) => {
+                    yield Ok(Event::default().event("finish_reason").data(reason));
+                }
+                Ok(StreamEvent::SafetyBlock { category }) => {
+                    yield Ok(Event::default().event("safety_block").data(category));
+                }
+                Ok(StreamEvent::Completed { finish_reason, usage, model_version }) => {
+                    let completed = serde_json::json!({ "finish_reason": finish_reason, "usage": usage, "model_version": model_version });
+                    yield Ok(Event::default().event("completed").data(completed.to_string()));
+                }
+                Ok(stats @ StreamEvent::Stats { ttft_ms, tokens, elapsed_ms }) => {
+                    tracing::info!("stream stats: {:?} | tokens/sec: {:?}", stats, stats.tokens_per_sec());
+                    let stats = serde_json::json!({ "ttft_ms": ttft_ms, "tokens": tokens, "elapsed_ms": elapsed_ms });
+                    yield Ok(Event::default().event("stats").data(stats.to_string()));
+                }
                 Err(e) => {
                     tracing::error!("error in stream: {:?}", e);
                 }
@@ -124,6 +124,7 @@ impl PromptRepository {
                 m.supports_json,
                 m.supports_json_schema,
                 m.supports_tools,
+                m.supports_streaming,
                 pv.system_diff,
                 pv.user_diff,
                 pv.version_number,
@@ -165,6 +166,7 @@ impl PromptRepository {
                 m.supports_json,
                 m.supports_json_schema,
                 m.supports_tools,
+                m.supports_streaming,
                 pv.system_diff,
                 pv.user_diff,
                 pv.version_number,
@@ -313,6 +315,7 @@ impl PromptRepository {
                 m.supports_json,
                 m.supports_json_schema,
                 m.supports_tools,
+                m.supports_streaming,
                 pv.system_diff,
                 pv.user_diff,
                 pv.version_number,
@@ -354,6 +357,7 @@ impl PromptRepository {
                 m.supports_json,
                 m.supports_json_schema,
                 m.supports_tools,
+                m.supports_streaming,
                 pv.system_diff,
                 pv.user_diff,
                 pv.version_number,
@@ -35,6 +35,7 @@ pub struct PromptRowWithModel {
     pub supports_json: bool,
     pub supports_json_schema: bool,
     pub supports_tools: bool,
+    pub supports_streaming: bool,
     pub version_number: i64,
     pub version_id: i64,
     pub system_diff: Option<String>,
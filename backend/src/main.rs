@@ -247,6 +247,7 @@ impl From<LlmError> for AppError {
             LlmError::PromptTooLong(current, limit) => AppError::BadRequest(format!("Prompt exceeds token limit: {}/{}", current, limit)),
             LlmError::ContentPolicy(msg) => AppError::BadRequest(format!("Content policy violation: {}", msg)),
             LlmError::InvalidConfig(msg) => AppError::BadRequest(format!("Invalid configuration: {}", msg)),
+            LlmError::Validation { field, message } => AppError::BadRequest(format!("Invalid {}: {}", field, message)),
             
             // All network/http errors map to internal server error
             LlmError::Http(status) => {
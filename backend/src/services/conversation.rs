@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::types::chat_request::ChatCompletionRequestMessage;
+
+use super::{
+    llm::ask_with,
+    types::{llm_error::LlmError, llm_service::LlmServiceRequest},
+};
+
+/// Version of [`SessionState`], bumped when a change would break loading older states.
+const SESSION_STATE_VERSION: u32 = 1;
+
+/// A multi-turn chat: every reply is added to the history the next turn is sent with.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    props: LlmServiceRequest,
+}
+
+/// Everything needed to pick a [`Conversation`] up again, e.g. after a restart. Like any
+/// [`LlmServiceRequest`] it never contains API keys, and response transforms are not saved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionState {
+    pub version: u32,
+    /// The request config, with the history as its messages.
+    pub props: LlmServiceRequest,
+}
+
+impl Conversation {
+    /// Starts from the messages already in `props`, usually just a system prompt.
+    pub fn new(props: LlmServiceRequest) -> Self {
+        Conversation { props }
+    }
+
+    pub fn history(&self) -> &[ChatCompletionRequestMessage] {
+        &self.props.request.messages
+    }
+
+    /// Sends `text` as the next user turn and returns the reply. A failed turn leaves
+    /// the history as it was.
+    pub async fn send(&mut self, text: &str) -> Result<String, LlmError> {
+        self.props.request.messages.push(ChatCompletionRequestMessage::User {
+            content: text.to_string(),
+            name: None,
+        });
+
+        match ask_with(self.props.clone()).await {
+            Ok(reply) => {
+                self.props.request.messages.push(ChatCompletionRequestMessage::Assistant {
+                    content: reply.clone(),
+                    name: None,
+                    tool_calls: None,
+                });
+                Ok(reply)
+            }
+            Err(e) => {
+                self.props.request.messages.pop();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn to_state(&self) -> SessionState {
+        SessionState {
+            version: SESSION_STATE_VERSION,
+            props: self.props.clone(),
+        }
+    }
+
+    /// Restores a conversation saved with [`Conversation::to_state`].
+    pub fn from_state(state: SessionState) -> Result<Self, LlmError> {
+        if state.version > SESSION_STATE_VERSION {
+            return Err(LlmError::InvalidConfig(format!(
+                "Session state version {} is newer than the supported version {}",
+                state.version, SESSION_STATE_VERSION
+            )));
+        }
+
+        Ok(Conversation { props: state.props })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::models::LlmApiProvider;
+    use crate::services::utils::test_server::serve_with;
+
+    // Replies with the number of messages it was sent, so each turn shows the history it got
+    async fn counting_provider() -> String {
+        serve_with(|body| {
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            let count = body["messages"].as_array().unwrap().len();
+            let response = serde_json::json!({
+                "id": "mock-1",
+                "created": 0,
+                "model": "grok-2-latest",
+                "choices": [{ "message": { "role": "assistant", "content": format!("seen {}", count) }, "finish_reason": "stop" }]
+            });
+            (200, response.to_string())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_conversation_resumes_from_state() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "");
+        props.base_url = counting_provider().await;
+        props.request.temperature = Some(0.3);
+        props.request.messages = vec![ChatCompletionRequestMessage::System {
+            content: "Be brief".to_string(),
+            name: None,
+        }];
+
+        let mut conversation = Conversation::new(props);
+        assert_eq!(conversation.send("Hi").await.unwrap(), "seen 2");
+        assert_eq!(conversation.send("And again").await.unwrap(), "seen 4");
+
+        let saved = serde_json::to_string(&conversation.to_state()).unwrap();
+        assert!(!saved.contains("test-key"));
+        drop(conversation);
+
+        let state: SessionState = serde_json::from_str(&saved).unwrap();
+        let mut restored = Conversation::from_state(state).unwrap();
+        assert_eq!(restored.history().len(), 5);
+        assert_eq!(restored.history()[3].content(), "And again");
+        assert_eq!(restored.props.request.temperature, Some(0.3));
+
+        assert_eq!(restored.send("One more").await.unwrap(), "seen 6");
+        assert_eq!(restored.history().len(), 7);
+    }
+
+    #[test]
+    fn test_newer_state_version_rejected() {
+        let props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Hi");
+        let state = SessionState {
+            version: SESSION_STATE_VERSION + 1,
+            props,
+        };
+
+        assert!(matches!(Conversation::from_state(state), Err(LlmError::InvalidConfig(_))));
+    }
+}
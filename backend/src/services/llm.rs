@@ -1,7 +1,18 @@
-use std::time::Duration;
+use std::{
+    borrow::Cow,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use tokio::sync::mpsc::Sender;
+use futures_util::Stream;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{
+    mpsc::{self, Receiver, Sender},
+    oneshot,
+};
 use tokio_retry::{
     strategy::{jitter, ExponentialBackoff},
     Retry,
@@ -9,22 +20,75 @@ use tokio_retry::{
 use tracing;
 
 use super::{
-    providers::openrouter::OpenrouterProvider,
+    providers::{
+        gemini::GeminiProvider, huggingface::HuggingFaceProvider, openai_compatible::OpenaiCompatibleProvider,
+        openrouter::OpenrouterProvider, registry::provider_registry, replicate::ReplicateProvider,
+        vertex_gemini::VertexGeminiProvider,
+    },
     types::{
         llm_service::LlmServiceRequest,
         llm_error::{LlmError, LlmStreamingError},
+        stream::StreamEvent,
+    },
+    utils::{
+        client::{request_body_bytes, shared_client},
+        json_repair::repair_json,
+        schema::{gemini_schema, schema_for, validate_against_schema},
+        stream::forward_response,
+        transform::apply_transforms,
+    },
+};
+use crate::{
+    common::types::{
+        chat::ChatResponse,
+        chat_request::{
+            ChatCompletionRequestJsonSchema, ChatCompletionRequestMessage, ChatCompletionRequestResponseFormat,
+            ChatCompletionRequestToolCall,
+        },
+        chat_response::{
+            LlmServiceChatCompletionResponse, LlmServiceChatCompletionResponseToolCall, LlmServiceChatCompletionResponseUsage,
+        },
+        models::LlmApiProvider,
     },
+    db::logs::LogRepository,
 };
-use crate::{common::types::{chat_response::{LlmServiceChatCompletionChunk, LlmServiceChatCompletionResponse}, models::LlmApiProvider}, db::logs::LogRepository};
+
+/// One try at a request in the retry loop of [`Llm::text`] and [`Llm::json`].
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    /// Starts at 1, retries count up from there.
+    pub number: u32,
+    pub latency: Duration,
+    /// Why the attempt failed, `None` when it succeeded.
+    pub error: Option<String>,
+}
+
+/// Called after every attempt, see [`Llm::with_attempt_hook`].
+pub type AttemptHook = Arc<dyn Fn(&Attempt) + Send + Sync>;
 
 pub struct Llm {
     props: LlmServiceRequest,
     db_log: LogRepository,
+    // Only keep request bodies in the log for failed requests
+    log_requests_on_error: bool,
+    log_format: LogFormat,
+    attempt_hook: Option<AttemptHook>,
 }
 
 impl Llm {
     pub fn new(props: LlmServiceRequest, db_log: LogRepository) -> Self {
-        Llm { props, db_log }
+        let log_requests_on_error = std::env::var("LOG_REQUESTS_ON_ERROR")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+
+        Llm { props, db_log, log_requests_on_error, log_format: LogFormat::from_env(), attempt_hook: None }
+    }
+
+    /// Reports every attempt, retries included, with its number, latency and outcome.
+    /// Attempts are also logged at debug level without a hook.
+    pub fn with_attempt_hook(mut self, hook: AttemptHook) -> Self {
+        self.attempt_hook = Some(hook);
+        self
     }
 
     fn retry_strategy(&self) -> impl Iterator<Item = Duration> {
@@ -35,13 +99,15 @@ impl Llm {
     }
 
     pub async fn text(&self) -> Result<(LlmServiceChatCompletionResponse, i64), LlmError> {
+        self.props.validate()?;
         let retry_strategy = self.retry_strategy();
-        Retry::spawn(retry_strategy, || self.send_request()).await
+        retry_attempts(retry_strategy, self.attempt_hook.as_ref(), || self.send_request()).await
     }
 
     pub async fn json(&self) -> Result<(LlmServiceChatCompletionResponse, i64), LlmError> {
+        self.props.validate()?;
         let retry_strategy = self.retry_strategy();
-        Retry::spawn(retry_strategy, || async {
+        retry_attempts(retry_strategy, self.attempt_hook.as_ref(), || async {
             let res = self.send_request().await?;
 
             if let Some(c) = res.0.choices.first() {
@@ -51,10 +117,9 @@ impl Llm {
                     Some(rf) => {
                         match &rf.json_schema {
                             Some(js) => {
-                                let is_valid = &self.validate_schema(&c.message.content, &js.schema)?;
-                                if !is_valid {
-                                    tracing::error!("The schema was not valid");
-                                    return Err(LlmError::InvalidJsonSchema);
+                                if let Err(e) = validate_against_schema(&c.message.content, &js.schema) {
+                                    tracing::error!("The schema was not valid: {}", e);
+                                    return Err(e);
                                 }
                             },
                             None => {
@@ -71,21 +136,27 @@ impl Llm {
         .await
     }
 
-    fn validate_schema(&self, response: &str, schema: &serde_json::Value) -> Result<bool, LlmError> {
-        let response_json: serde_json::Value = serde_json::from_str(&response)?;
-        let is_valid = jsonschema::is_valid(&schema, &response_json);
+    /// [`Llm::stream`] as a `Stream` of events instead of a channel. The request runs in a
+    /// spawned task and ends with the `[DONE]` sentinel chunk; if it fails before streaming
+    /// starts the error is the only item. Dropping the stream aborts the request.
+    pub fn stream_events(self) -> impl Stream<Item = Result<StreamEvent, LlmStreamingError>> {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
 
-        if !is_valid {
-            return Ok(false);
-        }
+        tokio::spawn(async move {
+            if let Err(e) = self.stream(tx.clone()).await {
+                let _ = tx.send(Err(LlmStreamingError::StreamError(e.to_string()))).await;
+            }
+        });
 
-        Ok(true)
+        receiver_stream(rx)
     }
 
     pub async fn stream(
         &self,
-        tx: Sender<Result<LlmServiceChatCompletionChunk, LlmStreamingError>>,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
     ) -> Result<(LlmServiceChatCompletionResponse, i64), LlmError> {
+        self.props.validate()?;
+
         if self.props.request.response_format.is_some() {
             tracing::info!("Json mode not supported in chat mode");
             return Err(LlmError::UnsupportedMode(
@@ -106,16 +177,16 @@ impl Llm {
         let mut status = Some(500); // Default to error status
 
         // Serialize the request for logging
-        let request_body = serde_json::to_string(&self.props)
+        let request_body = self.log_format.serialize(&self.props)
             .map_err(|e| LlmError::SerializationError(e.to_string()))?;
 
         // Execute request and capture result
-        let result = match &self.props.provider {
-            LlmApiProvider::Openrouter => {
-                let provider = OpenrouterProvider::new(&self.props, false)?;
-                provider.execute_chat().await
-            }
-        };
+        let started = Instant::now();
+        let result = execute_chat(&self.props).await;
+
+        if result.is_ok() {
+            self.record_latency(started);
+        }
 
         // Process the result or prepare error
         let (exec_result, provider_response_id) = match result {
@@ -135,7 +206,7 @@ impl Llm {
                     .map(|usage| usage.completion_tokens as i64);
 
                 // Save raw response for logging
-                raw_response = serde_json::to_string(&provider_response).ok();
+                raw_response = self.log_format.serialize(&provider_response).ok();
 
                 // Extract content from the response
                 if provider_response.choices.len() > 0 {
@@ -148,7 +219,7 @@ impl Llm {
             }
             Err(e) => {
                 // For errors, prepare as much information as possible for logging
-                raw_response = Some(format!("{{\"error\": \"{}\"}}", e));
+                raw_response = self.log_format.serialize(&serde_json::json!({ "error": e.to_string() })).ok();
                 (Err(e), uuid::Uuid::new_v4().to_string())
             }
         };
@@ -168,14 +239,17 @@ impl Llm {
 
         // Return the original result but with the correct log_id
         match exec_result {
-            Ok(r) => Ok((r, log_id)),
+            Ok(mut r) => {
+                self.apply_response_transforms(&mut r);
+                Ok((r, log_id))
+            }
             Err(e) => Err(e),
         }
     }
 
     async fn send_request_stream(
         &self,
-        tx: Sender<Result<LlmServiceChatCompletionChunk, LlmStreamingError>>,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
     ) -> Result<(LlmServiceChatCompletionResponse, i64), LlmError> {
         // Initialize variables to capture data even in error cases
         let mut input_tokens = None;
@@ -185,7 +259,7 @@ impl Llm {
         let mut status = Some(500); // Default to error status
 
         // Serialize the request for logging
-        let request_body = serde_json::to_string(&self.props)
+        let request_body = self.log_format.serialize(&self.props)
             .map_err(|e| LlmError::SerializationError(e.to_string()))?;
 
         // Check json mode before making the request
@@ -194,7 +268,7 @@ impl Llm {
             let error = LlmError::UnsupportedMode("Json".to_string(), "Chat".to_string());
 
             // Log the error
-            raw_response = Some(format!("{{\"error\": \"{}\"}}", error));
+            raw_response = self.log_format.serialize(&serde_json::json!({ "error": error.to_string() })).ok();
             let provider_response_id = uuid::Uuid::new_v4().to_string();
 
             // Log the failed request
@@ -213,13 +287,23 @@ impl Llm {
         }
 
         // Execute request and capture result
-        let result = match &self.props.provider {
-            LlmApiProvider::Openrouter => {
-                let provider = OpenrouterProvider::new(&self.props, true)?;
-                provider.execute_chat_stream(tx).await
-            }
+        let started = Instant::now();
+        let result = if self.props.supports_streaming {
+            execute_chat_stream(&self.props, tx).await
+        } else if self.props.fallback_to_nonstreaming {
+            tracing::info!("Model does not support streaming, falling back to a non-streaming request");
+            self.send_request_as_stream(tx).await
+        } else {
+            Err(LlmError::UnsupportedMode(
+                "Streaming".to_string(),
+                self.props.request.model.clone(),
+            ))
         };
 
+        if result.is_ok() {
+            self.record_latency(started);
+        }
+
         // Process the result or prepare error
         let (exec_result, provider_response_id) = match result {
             Ok(response) => {
@@ -238,7 +322,7 @@ impl Llm {
                     .map(|usage| usage.completion_tokens as i64);
 
                 // Save raw response for logging
-                raw_response = serde_json::to_string(&response).ok();
+                raw_response = self.log_format.serialize(&response).ok();
 
                 // Extract content from the response
                 if response.choices.len() > 0 {
@@ -250,7 +334,7 @@ impl Llm {
             }
             Err(e) => {
                 // For errors, prepare as much information as possible for logging
-                raw_response = Some(format!("{{\"error\": \"{}\"}}", e));
+                raw_response = self.log_format.serialize(&serde_json::json!({ "error": e.to_string() })).ok();
                 (Err(e), uuid::Uuid::new_v4().to_string())
             }
         };
@@ -270,11 +354,47 @@ impl Llm {
 
         // Return the original result but with the correct log_id
         match exec_result {
-            Ok(r) => Ok((r, log_id)),
+            Ok(mut r) => {
+                self.apply_response_transforms(&mut r);
+                Ok((r, log_id))
+            }
             Err(e) => Err(e),
         }
     }
 
+    /// Records how long the completion took when latency metrics are enabled.
+    fn record_latency(&self, started: Instant) {
+        #[cfg(feature = "latency-metrics")]
+        super::utils::latency::latency_tracker().record(&self.props.provider, started.elapsed());
+
+        #[cfg(not(feature = "latency-metrics"))]
+        let _ = started;
+    }
+
+    /// Runs the configured response transforms over every choice. This happens after
+    /// logging so the log keeps the untouched provider response.
+    fn apply_response_transforms(&self, response: &mut LlmServiceChatCompletionResponse) {
+        if self.props.response_transforms.is_empty() {
+            return;
+        }
+
+        for choice in response.choices.iter_mut() {
+            choice.message.content = apply_transforms(&self.props.response_transforms, &choice.message.content);
+        }
+    }
+
+    /// Makes a regular request and delivers the response to `tx` as if it had been streamed.
+    async fn send_request_as_stream(
+        &self,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let started = Instant::now();
+        let response = execute_chat(&self.props).await?;
+
+        forward_response(&response, &tx, started, &self.props.response_transforms).await;
+        Ok(response)
+    }
+
     /// Logs the request and returns a log ID.
     async fn log_request(
         &self,
@@ -286,6 +406,9 @@ impl Llm {
         request_body: &str,
         provider_response_id: &str,
     ) -> Result<i64, LlmError> {
+        let failed = status != Some(200);
+        let request_body = logged_request_body(request_body, failed, self.log_requests_on_error);
+
         self.db_log
             .create_log(
                 Some(self.props.prompt_id),
@@ -295,7 +418,7 @@ impl Llm {
                 input_tokens,
                 output_tokens,
                 reasoning_tokens,
-                Some(request_body),
+                request_body,
                 provider_response_id,
             )
             .await
@@ -303,9 +426,1205 @@ impl Llm {
     }
 }
 
+/// Runs `attempt` until it succeeds or `strategy` runs out of delays, timing each try
+/// and reporting it to `hook`.
+async fn retry_attempts<T, F, Fut>(
+    strategy: impl IntoIterator<Item = Duration>,
+    hook: Option<&AttemptHook>,
+    mut attempt: F,
+) -> Result<T, LlmError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, LlmError>>,
+{
+    let mut number = 0;
+    Retry::spawn(strategy, || {
+        number += 1;
+        let number = number;
+        let started = Instant::now();
+        let result = attempt();
+        async move {
+            let result = result.await;
+            let attempt = Attempt {
+                number,
+                latency: started.elapsed(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            };
+            match &attempt.error {
+                Some(error) => tracing::debug!("Attempt {} failed after {:?}: {}", number, attempt.latency, error),
+                None => tracing::debug!("Attempt {} succeeded after {:?}", number, attempt.latency),
+            }
+            if let Some(hook) = hook {
+                hook(&attempt);
+            }
+            result
+        }
+    })
+    .await
+}
+
+/// Sent after a reply cut off by `max_tokens` when `auto_continue` is set.
+const CONTINUE_PROMPT: &str = "Continue exactly where you left off, without repeating anything.";
+
+/// [`execute_chat_once`], continued up to `props.auto_continue` times while the reply
+/// stops at `max_tokens`. Each continuation sends the text so far as an assistant turn
+/// followed by [`CONTINUE_PROMPT`]; the parts are concatenated into one response with
+/// the last finish reason and the usage of all calls added up.
+async fn execute_chat(props: &LlmServiceRequest) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+    let mut response = execute_chat_once(props).await?;
+    let Some(max_continuations) = props.auto_continue else {
+        return Ok(response);
+    };
+
+    let mut continued = props.clone();
+    for _ in 0..max_continuations {
+        let Some(choice) = response.choices.first() else {
+            break;
+        };
+        if choice.finish_reason.as_deref() != Some("length") {
+            break;
+        }
+
+        continued.request.messages.push(ChatCompletionRequestMessage::Assistant {
+            content: choice.message.content.clone(),
+            tool_calls: None,
+            name: None,
+        });
+        continued.request.messages.push(ChatCompletionRequestMessage::User {
+            content: CONTINUE_PROMPT.to_string(),
+            name: None,
+        });
+
+        let next = execute_chat_once(&continued).await?;
+        // The next round sends the whole text so far as a single assistant turn
+        continued.request.messages.truncate(props.request.messages.len());
+        response = merge_continuation(response, next);
+    }
+
+    Ok(response)
+}
+
+fn merge_continuation(
+    mut response: LlmServiceChatCompletionResponse,
+    next: LlmServiceChatCompletionResponse,
+) -> LlmServiceChatCompletionResponse {
+    if let (Some(choice), Some(next_choice)) = (response.choices.first_mut(), next.choices.into_iter().next()) {
+        choice.message.content += &next_choice.message.content;
+        choice.finish_reason = next_choice.finish_reason;
+        choice.native_finish_reason = next_choice.native_finish_reason;
+    }
+
+    response.usage = match (response.usage, next.usage) {
+        (Some(a), Some(b)) => Some(LlmServiceChatCompletionResponseUsage {
+            prompt_tokens: a.prompt_tokens + b.prompt_tokens,
+            completion_tokens: a.completion_tokens + b.completion_tokens,
+            total_tokens: a.total_tokens + b.total_tokens,
+        }),
+        (a, b) => a.or(b),
+    };
+    // The latest call knows best how much budget is left
+    response.rate_limit = next.rate_limit.or(response.rate_limit);
+    response
+}
+
+/// Sends a regular (non-streaming) request to the provider `props` points at.
+async fn execute_chat_once(props: &LlmServiceRequest) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+    let props = &*props.routed();
+    let result = dispatch_chat(props).await;
+    record_metrics(props, &result);
+    result
+}
+
+/// Counts the provider call when the `metrics` feature is enabled.
+fn record_metrics(props: &LlmServiceRequest, result: &Result<LlmServiceChatCompletionResponse, LlmError>) {
+    #[cfg(feature = "metrics")]
+    super::utils::metrics::record_completion(&props.provider, &props.request.model, result);
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (props, result);
+}
+
+/// `props` adapted to its provider when `adapt_to_capabilities` is set. A registered
+/// provider's capabilities take precedence over those of the built-in one.
+fn adapt_to_provider(props: &LlmServiceRequest) -> Cow<'_, LlmServiceRequest> {
+    let capabilities = provider_registry()
+        .capabilities(&String::from(props.provider.clone()))
+        .unwrap_or_else(|| props.provider.capabilities());
+    props.adapted_to(capabilities)
+}
+
+/// Providers in the registry take precedence over the built-in ones.
+async fn dispatch_chat(props: &LlmServiceRequest) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+    let props = &*adapt_to_provider(props);
+    if let Some(factory) = provider_registry().get(&String::from(props.provider.clone())) {
+        return factory(props, false).execute_chat().await;
+    }
+
+    match &props.provider {
+        LlmApiProvider::Openrouter => {
+            let provider = OpenrouterProvider::new(props, false);
+            provider.execute_chat().await
+        }
+        LlmApiProvider::Gemini => {
+            let provider = GeminiProvider::new(props, false);
+            provider.execute_chat().await
+        }
+        // Plain OpenAi compatible APIs, at the provider's base url with its key
+        LlmApiProvider::Grok | LlmApiProvider::Together | LlmApiProvider::Fireworks | LlmApiProvider::Groq => {
+            let provider = OpenaiCompatibleProvider::new(props, false, props.provider.clone());
+            provider.execute_chat().await
+        }
+        LlmApiProvider::Replicate => {
+            let provider = ReplicateProvider::new(props, false);
+            provider.execute_chat().await
+        }
+        LlmApiProvider::HuggingFace => {
+            let provider = HuggingFaceProvider::new(props, false);
+            provider.execute_chat().await
+        }
+        LlmApiProvider::VertexAi => {
+            let provider = VertexGeminiProvider::new(props, false);
+            provider.execute_chat().await
+        }
+        LlmApiProvider::Custom(name) => Err(LlmError::ProviderUnavailable(name.clone())),
+    }
+}
+
+/// Streaming counterpart of [`execute_chat`].
+async fn execute_chat_stream(
+    props: &LlmServiceRequest,
+    tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+    let props = &*props.routed();
+    let result = dispatch_chat_stream(props, tx).await;
+    record_metrics(props, &result);
+    result
+}
+
+async fn dispatch_chat_stream(
+    props: &LlmServiceRequest,
+    tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+    let props = &*adapt_to_provider(props);
+    if let Some(factory) = provider_registry().get(&String::from(props.provider.clone())) {
+        return factory(props, true).execute_chat_stream(tx).await;
+    }
+
+    match &props.provider {
+        LlmApiProvider::Openrouter => {
+            let provider = OpenrouterProvider::new(props, true);
+            provider.execute_chat_stream(tx).await
+        }
+        LlmApiProvider::Gemini => {
+            let provider = GeminiProvider::new(props, true);
+            provider.execute_chat_stream(tx).await
+        }
+        // Plain OpenAi compatible APIs, at the provider's base url with its key
+        LlmApiProvider::Grok | LlmApiProvider::Together | LlmApiProvider::Fireworks | LlmApiProvider::Groq => {
+            let provider = OpenaiCompatibleProvider::new(props, true, props.provider.clone());
+            provider.execute_chat_stream(tx).await
+        }
+        LlmApiProvider::Replicate => {
+            let provider = ReplicateProvider::new(props, true);
+            provider.execute_chat_stream(tx).await
+        }
+        LlmApiProvider::HuggingFace => {
+            let provider = HuggingFaceProvider::new(props, true);
+            provider.execute_chat_stream(tx).await
+        }
+        LlmApiProvider::VertexAi => {
+            let provider = VertexGeminiProvider::new(props, true);
+            provider.execute_chat_stream(tx).await
+        }
+        LlmApiProvider::Custom(name) => Err(LlmError::ProviderUnavailable(name.clone())),
+    }
+}
+
+/// One-shot completion for scripts: sends `prompt` as a single user message to `model`
+/// with default sampling and returns the text. The provider is picked from the model
+/// name (see [`LlmApiProvider::for_model`]). Nothing is logged.
+pub async fn ask(model: &str, prompt: &str) -> Result<String, LlmError> {
+    let provider = LlmApiProvider::for_model(model);
+    ask_with(LlmServiceRequest::from_user_prompt(provider, model, prompt)).await
+}
+
+/// Like [`ask`] for a request that was built by hand, e.g. to point it at a different base url.
+pub async fn ask_with(props: LlmServiceRequest) -> Result<String, LlmError> {
+    Ok(chat(props).await?.text)
+}
+
+/// [`ask_with`] bounded by an absolute `deadline`, e.g. one propagated from an upstream
+/// request: the time left until then is the timeout for the whole call. Fails with
+/// [`LlmError::Timeout`] with a budget of 0 without sending anything when the deadline
+/// has already passed, or with the budget it had once it passes while the request is in
+/// flight, which drops the request.
+pub async fn complete_until(props: LlmServiceRequest, deadline: Instant) -> Result<String, LlmError> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(LlmError::Timeout(0));
+    }
+
+    tokio::time::timeout(remaining, ask_with(props))
+        .await
+        .map_err(|_| LlmError::Timeout(remaining.as_millis() as u64))?
+}
+
+/// [`ask_with`] with `system_override` in place of the system messages of `props`, e.g.
+/// to answer the same conversation as another persona. `props` itself is left as it is.
+pub async fn complete_with_system(props: &LlmServiceRequest, system_override: &str) -> Result<String, LlmError> {
+    ask_with(props.with_system(system_override)).await
+}
+
+/// [`chat`] that also returns the response body as the provider sent it, e.g. to read a
+/// field this crate doesn't model. With `auto_continue` it's the body of the first call.
+/// Fails with [`LlmError::UnsupportedMode`] for providers that don't keep the body:
+/// Replicate, whose answer is assembled from polled predictions, and registered providers
+/// that don't set [`raw`](LlmServiceChatCompletionResponse::raw).
+pub async fn complete_raw(props: LlmServiceRequest) -> Result<(String, serde_json::Value), LlmError> {
+    props.validate()?;
+
+    let mut response = execute_chat(&props).await?;
+    let Some(raw) = response.raw.take() else {
+        return Err(LlmError::UnsupportedMode("Raw response".to_string(), String::from(props.provider.clone())));
+    };
+
+    let text = repair_answer(&props, ChatResponse::try_from(response)?.text);
+    check_response_schema(&props, &text)?;
+    Ok((text, raw))
+}
+
+/// Sends every request in `props_list` at once, e.g. the same prompt to two providers,
+/// and returns the first answer with the provider that gave it. The other requests are
+/// dropped as soon as one succeeds. Failures only count when all of them fail, the
+/// error is then that of the last one to fail.
+pub async fn race_complete(props_list: Vec<LlmServiceRequest>) -> Result<(LlmApiProvider, String), LlmError> {
+    if props_list.is_empty() {
+        return Err(LlmError::Validation {
+            field: "props_list".to_string(),
+            message: "must not be empty".to_string(),
+        });
+    }
+
+    let requests = props_list.into_iter().map(|props| {
+        Box::pin(async move {
+            let provider = props.provider_override.clone().unwrap_or_else(|| props.provider.clone());
+            ask_with(props).await.map(|text| (provider, text))
+        })
+    });
+    let (winner, _losers) = futures_util::future::select_ok(requests).await?;
+    Ok(winner)
+}
+
+/// Like [`ask_with`], with the whole provider agnostic [`ChatResponse`] instead of only
+/// the text. Build `props` from a [`ChatRequest`](crate::common::types::chat::ChatRequest)
+/// with [`LlmServiceRequest::from_chat_request`]. Nothing is logged.
+pub async fn chat(props: LlmServiceRequest) -> Result<ChatResponse, LlmError> {
+    props.validate()?;
+
+    let mut response = ChatResponse::try_from(execute_chat(&props).await?)?;
+    response.text = repair_answer(&props, response.text);
+    check_response_schema(&props, &response.text)?;
+    Ok(response)
+}
+
+/// Final answer of [`run_tool_loop`] and the conversation that led to it.
+#[derive(Debug, Clone)]
+pub struct ToolLoopResult {
+    pub text: String,
+    /// The request's messages followed by every assistant turn and tool result, ending
+    /// with the final answer. Ready to continue the conversation with.
+    pub messages: Vec<ChatCompletionRequestMessage>,
+}
+
+/// Agent loop: sends `props` and, while the model calls tools, runs each call through
+/// `execute_tool`, appends the calls and their results to the conversation and asks again.
+/// Ends with the first answer that calls no tools. An error from `execute_tool` ends the
+/// loop with it, return the error as a `Value` instead to let the model react to it.
+/// Makes at most `max_iters` requests, then fails with [`LlmError::ToolLoopLimit`].
+pub async fn run_tool_loop<F>(mut props: LlmServiceRequest, execute_tool: F, max_iters: u32) -> Result<ToolLoopResult, LlmError>
+where
+    F: Fn(&LlmServiceChatCompletionResponseToolCall) -> Result<serde_json::Value, LlmError>,
+{
+    for _ in 0..max_iters {
+        let response = chat(props.clone()).await?;
+        let messages = &mut props.request.messages;
+
+        if response.tool_calls.is_empty() {
+            messages.push(ChatCompletionRequestMessage::Assistant {
+                content: response.text.clone(),
+                tool_calls: None,
+                name: None,
+            });
+            return Ok(ToolLoopResult { text: response.text, messages: props.request.messages });
+        }
+
+        messages.push(ChatCompletionRequestMessage::Assistant {
+            content: response.text,
+            tool_calls: Some(response.tool_calls.iter().cloned().map(ChatCompletionRequestToolCall::from).collect()),
+            name: None,
+        });
+        for tool_call in &response.tool_calls {
+            messages.push(ChatCompletionRequestMessage::Tool {
+                content: execute_tool(tool_call)?,
+                tool_call_id: tool_call.id.clone(),
+                name: Some(tool_call.function_call.name.clone()),
+            });
+        }
+    }
+
+    Err(LlmError::ToolLoopLimit(max_iters))
+}
+
+/// `content` with malformed JSON repaired when `props.repair_json` is set and the
+/// request asked for JSON. Left as is when it parses or can't be repaired.
+fn repair_answer(props: &LlmServiceRequest, content: String) -> String {
+    if !props.repair_json || props.request.response_format.is_none() {
+        return content;
+    }
+
+    match repair_json(&content) {
+        Some(repaired) => {
+            tracing::warn!("Repaired malformed JSON in the answer of {}", props.request.model);
+            repaired
+        }
+        None => content,
+    }
+}
+
+/// Validates the answer against the requested JSON schema when `props.validate_response` is set.
+fn check_response_schema(props: &LlmServiceRequest, content: &str) -> Result<(), LlmError> {
+    match props.request.response_format.as_ref().and_then(|rf| rf.json_schema.as_ref()) {
+        Some(json_schema) if props.validate_response => validate_against_schema(content, &json_schema.schema),
+        _ => Ok(()),
+    }
+}
+
+/// Structured completion: derives `T`'s JSON schema, asks the model to answer in it and
+/// parses the answer into `T`. Gemini gets the schema as `responseSchema`, rewritten into
+/// the subset it supports (see [`gemini_schema`]); other providers get it as is. With
+/// `props.repair_json` set, an answer with small syntax mistakes is repaired first.
+pub async fn complete_schema<T: JsonSchema + DeserializeOwned>(mut props: LlmServiceRequest) -> Result<T, LlmError> {
+    request_schema::<T>(&mut props);
+
+    let content = ask_with(props).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Streaming [`complete_schema`]: the events of a single request, and a future that
+/// resolves to the parsed `T` once the stream is done. The result is only produced
+/// while the stream is being read, so keep polling it to the end.
+pub fn stream_schema<T: JsonSchema + DeserializeOwned>(
+    mut props: LlmServiceRequest,
+) -> (
+    impl Stream<Item = Result<StreamEvent, LlmStreamingError>>,
+    impl Future<Output = Result<T, LlmError>>,
+) {
+    request_schema::<T>(&mut props);
+
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+    let (done_tx, done_rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let result = match props.validate() {
+            Ok(()) => execute_chat_stream(&props, tx.clone()).await,
+            Err(e) => Err(e),
+        };
+        let content = match result {
+            Ok(response) => response
+                .choices
+                .into_iter()
+                .next()
+                .map(|c| repair_answer(&props, c.message.content))
+                .ok_or(LlmError::EmptyResponse)
+                .and_then(|content| check_response_schema(&props, &content).map(|_| content)),
+            Err(e) => {
+                let _ = tx.send(Err(LlmStreamingError::StreamError(e.to_string()))).await;
+                Err(e)
+            }
+        };
+        let _ = done_tx.send(content);
+    });
+
+    let parsed = async move {
+        let content = done_rx.await.map_err(|_| LlmError::TaskCanceled)??;
+        Ok(serde_json::from_str(&content)?)
+    };
+
+    (receiver_stream(rx), parsed)
+}
+
+/// Asks for answers in `T`'s JSON schema.
+fn request_schema<T: JsonSchema>(props: &mut LlmServiceRequest) {
+    props.request.response_format = Some(ChatCompletionRequestResponseFormat {
+        format_type: "json_schema".to_string(),
+        json_schema: Some(ChatCompletionRequestJsonSchema {
+            name: T::schema_name(),
+            strict: true,
+            schema: schema_for::<T>(),
+        }),
+    });
+}
+
+/// What [`dry_run`] would have sent.
+#[derive(Debug, Clone, Serialize)]
+pub struct PreparedRequest {
+    pub method: String,
+    pub url: String,
+    /// Credentials are never part of it, the API key shows up as `REDACTED`.
+    pub headers: Vec<(String, String)>,
+    pub body: serde_json::Value,
+}
+
+/// Stands in for the API key in dry runs, so no key is read or shown.
+const REDACTED_KEY: &str = "REDACTED";
+
+/// Validates `props` and builds the regular (non-streaming) request for it without
+/// sending anything, for debugging and cost estimates. Replicate requests take several
+/// calls and custom providers build their own, neither can be dry run.
+pub fn dry_run(props: &LlmServiceRequest) -> Result<PreparedRequest, LlmError> {
+    props.validate()?;
+    let props = &*props.routed();
+    let props = &*adapt_to_provider(props);
+
+    let provider = String::from(props.provider.clone());
+    if provider_registry().get(&provider).is_some() {
+        return Err(LlmError::UnsupportedMode("Dry run".to_string(), provider));
+    }
+
+    let request = match &props.provider {
+        LlmApiProvider::Gemini => GeminiProvider::new(props, false).build_request(REDACTED_KEY)?,
+        LlmApiProvider::Grok | LlmApiProvider::Together | LlmApiProvider::Fireworks | LlmApiProvider::Groq => {
+            OpenaiCompatibleProvider::new(props, false, props.provider.clone()).build_request(REDACTED_KEY)?
+        }
+        LlmApiProvider::HuggingFace => HuggingFaceProvider::new(props, false).build_request(REDACTED_KEY)?,
+        LlmApiProvider::VertexAi => VertexGeminiProvider::new(props, false).build_request(REDACTED_KEY)?,
+        LlmApiProvider::Openrouter => OpenrouterProvider::new(props, false).build_request(REDACTED_KEY)?,
+        LlmApiProvider::Replicate | LlmApiProvider::Custom(_) => {
+            return Err(LlmError::UnsupportedMode("Dry run".to_string(), provider))
+        }
+    }
+    .build()?;
+
+    let headers = request
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect();
+    let body = match request_body_bytes(&request) {
+        Some(bytes) => serde_json::from_slice(&bytes)?,
+        None => serde_json::Value::Null,
+    };
+
+    Ok(PreparedRequest {
+        method: request.method().to_string(),
+        url: request.url().to_string(),
+        headers,
+        body,
+    })
+}
+
+/// Connects to the provider of `props` ahead of the first request, so that request doesn't
+/// pay for the TCP and TLS handshake: a GET of the base url, afterwards the connection is
+/// in the shared client's pool for `pool_idle_timeout`. Any HTTP response counts, the API
+/// key isn't checked. Registered providers warm up through [`CustomProvider::warmup`](super::providers::registry::CustomProvider::warmup).
+pub async fn warmup(props: &LlmServiceRequest) -> Result<(), LlmError> {
+    let props = &*props.routed();
+    if let Some(factory) = provider_registry().get(&String::from(props.provider.clone())) {
+        return factory(props, false).warmup().await;
+    }
+
+    // Vertex's base url defaults to the endpoint of the region
+    let base_url = match props.provider {
+        LlmApiProvider::VertexAi => VertexGeminiProvider::new(props, false).base_url(),
+        _ => props.base_url.clone(),
+    };
+    if base_url.is_empty() {
+        return Err(LlmError::InvalidConfig(format!("No base url to warm up {}", String::from(props.provider.clone()))));
+    }
+    let response = shared_client().get(&base_url).send().await?;
+    // Read to the end, only then does the connection go back to the pool
+    response.bytes().await?;
+    Ok(())
+}
+
+/// Streaming counterpart of [`ask_with`], yielding the events as a `Stream`. Nothing is logged.
+pub fn stream_with(props: LlmServiceRequest) -> impl Stream<Item = Result<StreamEvent, LlmStreamingError>> {
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+
+    tokio::spawn(async move {
+        let result = match props.validate() {
+            Ok(()) => execute_chat_stream(&props, tx.clone()).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            let _ = tx.send(Err(LlmStreamingError::StreamError(e.to_string()))).await;
+        }
+    });
+
+    receiver_stream(rx)
+}
+
+/// Events buffered between a streaming task and a `Stream` consumer.
+const EVENT_BUFFER: usize = 100;
+
+fn receiver_stream<T>(rx: Receiver<T>) -> impl Stream<Item = T> {
+    futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) })
+}
+
+/// How request bodies and responses are serialized into the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Compact, one line per entry, for ingestion.
+    #[default]
+    Jsonl,
+    /// Indented, for reading.
+    Pretty,
+}
+
+impl LogFormat {
+    /// Read from `LOG_FORMAT` (`jsonl` or `pretty`), anything else is the default.
+    pub fn from_env() -> Self {
+        match std::env::var("LOG_FORMAT").as_deref() {
+            Ok("pretty") => LogFormat::Pretty,
+            _ => LogFormat::Jsonl,
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String, serde_json::Error> {
+        match self {
+            LogFormat::Jsonl => serde_json::to_string(value),
+            LogFormat::Pretty => serde_json::to_string_pretty(value),
+        }
+    }
+}
+
+/// Decides whether the request body goes into the log. The body never holds provider
+/// credentials, those are read from the environment by each provider.
+fn logged_request_body(request_body: &str, failed: bool, log_requests_on_error: bool) -> Option<&str> {
+    if log_requests_on_error && !failed {
+        return None;
+    }
+
+    Some(request_body)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::str::FromStr;
+    use crate::services::utils::test_server::{serve_after, serve_capture, serve_keep_alive, serve_once, serve_with};
+
+    // Serves a single canned OpenAi style completion on a local port, returns the base url
+    async fn mock_provider(content: &'static str) -> String {
+        let body = serde_json::json!({
+            "id": "mock-1",
+            "created": 0,
+            "model": "grok-2-latest",
+            "choices": [{ "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }]
+        })
+        .to_string();
+
+        serve_once(200, body).await
+    }
+
+    #[tokio::test]
+    async fn test_ask_returns_text() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "What is 6 * 7?");
+        props.base_url = mock_provider("42").await;
+
+        let answer = ask_with(props).await.unwrap();
+        assert_eq!(answer, "42");
+    }
+
+    #[tokio::test]
+    async fn test_ask_validates_response_against_schema() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let with_schema = |base_url: String, validate_response: bool| {
+            let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Count to three");
+            props.base_url = base_url;
+            props.validate_response = validate_response;
+            props.request.response_format = Some(ChatCompletionRequestResponseFormat {
+                format_type: "json_schema".to_string(),
+                json_schema: Some(ChatCompletionRequestJsonSchema {
+                    name: "Count".to_string(),
+                    strict: true,
+                    schema: serde_json::json!({
+                        "type": "object",
+                        "required": ["numbers"],
+                        "properties": { "numbers": { "type": "array", "items": { "type": "integer" } } }
+                    }),
+                }),
+            });
+            props
+        };
+
+        let props = with_schema(mock_provider(r#"{"numbers":[1,2,3]}"#).await, true);
+        assert_eq!(ask_with(props).await.unwrap(), r#"{"numbers":[1,2,3]}"#);
+
+        let props = with_schema(mock_provider(r#"{"numbers":[1,"two",3]}"#).await, true);
+        let err = ask_with(props).await.unwrap_err();
+        assert!(matches!(err, LlmError::InvalidJsonSchema(message) if message.contains("/numbers/1")));
+
+        // Without validate_response the answer is returned as is
+        let props = with_schema(mock_provider(r#"{"numbers":[1,"two",3]}"#).await, false);
+        assert!(ask_with(props).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stream_falls_back_to_regular_request() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        // A single connection, each one would get its own in-memory database
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(sqlx::sqlite::SqliteConnectOptions::from_str("sqlite::memory:").unwrap().foreign_keys(false))
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let db_log = LogRepository::in_memory(pool).await.unwrap();
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "What is 6 * 7?");
+        props.base_url = mock_provider("The answer is 42").await;
+        props.supports_streaming = false;
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let (response, _) = Llm::new(props, db_log).stream(tx).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "The answer is 42");
+
+        let mut contents = Vec::new();
+        while let Some(event) = rx.recv().await {
+            if let StreamEvent::Chunk(chunk) = event.unwrap() {
+                contents.push(chunk.choices[0].delta.content.clone());
+            }
+        }
+        // The whole response as one chunk, then the end of the stream
+        assert_eq!(contents, vec!["The answer is 42".to_string(), "[DONE]".to_string()]);
+    }
+
+    fn completion_body(content: &str, finish_reason: &str) -> String {
+        serde_json::json!({
+            "id": "mock-1",
+            "created": 0,
+            "model": "grok-2-latest",
+            "choices": [{ "message": { "role": "assistant", "content": content }, "finish_reason": finish_reason }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 }
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_warmup_connection_is_reused() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+        let (base_url, connections) = serve_keep_alive(200, completion_body("42", "stop")).await;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "What is 6 * 7?");
+        props.base_url = base_url;
+        warmup(&props).await.unwrap();
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        assert_eq!(ask_with(props).await.unwrap(), "42");
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    // Calls get_weather until it sees a tool result, then answers with it
+    fn weather_model(body: &str) -> (u16, String) {
+        let message = if body.contains(r#""role":"tool""#) {
+            serde_json::json!({ "role": "assistant", "content": "It is 18C in Paris." })
+        } else {
+            serde_json::json!({
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "get_weather", "arguments": r#"{"city":"Paris"}"# }
+                }]
+            })
+        };
+        let body = serde_json::json!({
+            "id": "mock-1",
+            "created": 0,
+            "model": "grok-2-latest",
+            "choices": [{ "message": message, "finish_reason": "stop" }]
+        });
+        (200, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_complete_until_deadline() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "What is 6 * 7?");
+        let expired = Instant::now() - Duration::from_millis(10);
+        assert!(matches!(complete_until(props.clone(), expired).await, Err(LlmError::Timeout(0))));
+
+        let mut props = props;
+        props.base_url = mock_provider("42").await;
+        let ample = Instant::now() + Duration::from_secs(30);
+        assert_eq!(complete_until(props.clone(), ample).await.unwrap(), "42");
+
+        // A budget running out in flight is reported as it was, not rounded down to seconds
+        props.base_url = serve_after(Duration::from_secs(5), 200, completion_body("late", "stop")).await;
+        let short = Instant::now() + Duration::from_millis(300);
+        let Err(LlmError::Timeout(budget_ms)) = complete_until(props, short).await else {
+            panic!("expected a timeout");
+        };
+        assert!((200..=300).contains(&budget_ms), "budget was {} ms", budget_ms);
+    }
+
+    #[tokio::test]
+    async fn test_race_complete_takes_fastest() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+        std::env::set_var("TOGETHER_API_KEY", "test-key");
+
+        let mut slow = LlmServiceRequest::from_user_prompt(LlmApiProvider::Together, "meta-llama/Llama-3.3-70B-Instruct-Turbo", "Hi");
+        slow.base_url = serve_after(Duration::from_secs(10), 200, completion_body("slow", "stop")).await;
+        let mut fast = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Hi");
+        fast.base_url = serve_after(Duration::from_millis(20), 200, completion_body("fast", "stop")).await;
+
+        let started = Instant::now();
+        let (provider, text) = race_complete(vec![slow, fast]).await.unwrap();
+        assert_eq!(provider, LlmApiProvider::Grok);
+        assert_eq!(text, "fast");
+        // The slow request was dropped, not waited for
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // A failing request loses to any answer, even a slow one
+        let mut failing = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Hi");
+        failing.base_url = serve_once(500, "{}".to_string()).await;
+        let mut answering = LlmServiceRequest::from_user_prompt(LlmApiProvider::Together, "meta-llama/Llama-3.3-70B-Instruct-Turbo", "Hi");
+        answering.base_url = serve_after(Duration::from_millis(100), 200, completion_body("late", "stop")).await;
+        let (provider, text) = race_complete(vec![failing, answering]).await.unwrap();
+        assert_eq!((provider, text.as_str()), (LlmApiProvider::Together, "late"));
+
+        assert!(matches!(race_complete(vec![]).await, Err(LlmError::Validation { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_tool_loop_runs_call_then_answers() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Weather in Paris?");
+        props.base_url = serve_with(weather_model).await;
+
+        let result = run_tool_loop(
+            props.clone(),
+            |call| {
+                assert_eq!(call.function_call.name, "get_weather");
+                Ok(serde_json::json!({ "temperature": 18 }))
+            },
+            5,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result.text, "It is 18C in Paris.");
+        let roles: Vec<_> = result.messages.iter().map(|m| m.role()).collect();
+        assert_eq!(roles, ["user", "assistant", "tool", "assistant"]);
+        assert!(matches!(
+            &result.messages[1],
+            ChatCompletionRequestMessage::Assistant { tool_calls: Some(calls), .. } if calls[0].id == "call_1"
+        ));
+        assert!(matches!(
+            &result.messages[2],
+            ChatCompletionRequestMessage::Tool { content, tool_call_id, .. }
+                if tool_call_id == "call_1" && content["temperature"] == 18
+        ));
+
+        // One request isn't enough to get past the tool call
+        let limited = run_tool_loop(props, |_| Ok(serde_json::json!({ "temperature": 18 })), 1).await;
+        assert!(matches!(limited, Err(LlmError::ToolLoopLimit(1))));
+    }
+
+    #[tokio::test]
+    async fn test_auto_continue_after_max_tokens() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let base_url = serve_with(|body| {
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            let messages = body["messages"].as_array().unwrap();
+            match messages.len() {
+                1 => (200, completion_body("Once upon a ti", "length")),
+                3 => {
+                    assert_eq!(messages[1]["role"], "assistant");
+                    assert_eq!(messages[1]["content"], "Once upon a ti");
+                    assert_eq!(messages[2]["content"], CONTINUE_PROMPT);
+                    (200, completion_body("me, the end.", "stop"))
+                }
+                n => panic!("unexpected request with {} messages", n),
+            }
+        })
+        .await;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Tell me a story");
+        props.base_url = base_url;
+        props.auto_continue = Some(3);
+
+        let response = execute_chat(&props).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "Once upon a time, the end.");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(response.usage.unwrap().total_tokens, 30);
+    }
+
+    #[tokio::test]
+    async fn test_auto_continue_stops_at_limit() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Count forever");
+        props.base_url = serve_with(|_| (200, completion_body("1 2 ", "length"))).await;
+        props.auto_continue = Some(2);
+
+        let response = execute_chat(&props).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "1 2 1 2 1 2 ");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("length"));
+    }
+
+    #[test]
+    fn test_dry_run_gemini_request() {
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Gemini, "gemini-2.0-flash", "Hello");
+        props.request.max_tokens = Some(100);
+
+        let prepared = dry_run(&props).unwrap();
+        assert_eq!(prepared.method, "POST");
+        assert_eq!(
+            prepared.url,
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:generateContent?key=REDACTED"
+        );
+        assert!(prepared.headers.contains(&("content-type".to_string(), "application/json".to_string())));
+        assert_eq!(prepared.body["contents"], serde_json::json!([{ "role": "user", "parts": [{ "text": "Hello" }] }]));
+        assert_eq!(prepared.body["generationConfig"]["maxOutputTokens"], 100);
+
+        // Validation still runs
+        props.request.messages.clear();
+        assert!(dry_run(&props).is_err());
+    }
+
+    #[test]
+    fn test_dry_run_redacts_bearer_auth() {
+        let props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Hello");
+
+        let prepared = dry_run(&props).unwrap();
+        assert_eq!(prepared.url, "https://api.x.ai/v1/chat/completions");
+        assert!(prepared.headers.contains(&("authorization".to_string(), "Bearer REDACTED".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_complete_schema_parses_nested_struct() {
+        #[derive(Debug, PartialEq, serde::Deserialize, JsonSchema)]
+        struct Ingredient {
+            name: String,
+            grams: u32,
+        }
+
+        #[derive(Debug, PartialEq, serde::Deserialize, JsonSchema)]
+        struct Recipe {
+            title: String,
+            ingredients: Vec<Ingredient>,
+            notes: Option<String>,
+        }
+
+        std::env::set_var("GOOGLE_API_KEY", "test-key");
+
+        let answer = r#"{"title":"Pancakes","ingredients":[{"name":"flour","grams":200},{"name":"milk","grams":300}]}"#;
+        let body = serde_json::json!({
+            "responseId": "gemini-1",
+            "modelVersion": "gemini-2.0-flash",
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": answer }] }, "finishReason": "STOP" }]
+        })
+        .to_string();
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Gemini, "gemini-2.0-flash", "A pancake recipe");
+        props.base_url = serve_once(200, body).await;
+
+        let recipe: Recipe = complete_schema(props).await.unwrap();
+        assert_eq!(
+            recipe,
+            Recipe {
+                title: "Pancakes".to_string(),
+                ingredients: vec![
+                    Ingredient { name: "flour".to_string(), grams: 200 },
+                    Ingredient { name: "milk".to_string(), grams: 300 },
+                ],
+                notes: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_complete_schema_repairs_trailing_comma() {
+        #[derive(Debug, PartialEq, serde::Deserialize, JsonSchema)]
+        struct Answer {
+            value: u32,
+        }
+
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "What is 6 * 7?");
+        props.base_url = mock_provider(r#"{"value": 42,}"#).await;
+        assert!(complete_schema::<Answer>(props.clone()).await.is_err());
+
+        props.base_url = mock_provider(r#"{"value": 42,}"#).await;
+        props.repair_json = true;
+        assert_eq!(complete_schema::<Answer>(props).await.unwrap(), Answer { value: 42 });
+    }
+
+    #[tokio::test]
+    async fn test_stream_schema_yields_tokens_and_parsed_struct() {
+        use crate::common::types::chat_response::{
+            LlmServiceChatCompletionChunk, LlmServiceChoiceStream, LlmServiceStreamDelta,
+        };
+        use crate::services::{providers::registry::CustomProvider, utils::stream::forward_stream};
+        use futures_util::{future::BoxFuture, StreamExt};
+
+        #[derive(Debug, PartialEq, serde::Deserialize, JsonSchema)]
+        struct City {
+            name: String,
+            population: u64,
+        }
+
+        // Streams the answer a few characters at a time
+        struct TokenProvider {
+            asked_for_schema: bool,
+        }
+
+        impl CustomProvider for TokenProvider {
+            fn execute_chat(&self) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>> {
+                Box::pin(async { Err(LlmError::NotImplemented("streaming only".to_string())) })
+            }
+
+            fn execute_chat_stream(
+                &self,
+                tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+            ) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>> {
+                Box::pin(async move {
+                    assert!(self.asked_for_schema);
+                    let answer: Vec<char> = r#"{"name":"Lisbon","population":545000}"#.chars().collect();
+                    let chunks = answer.chunks(6).map(|token| {
+                        Ok::<_, String>(LlmServiceChatCompletionChunk {
+                            id: "tokens-1".to_string(),
+                            choices: vec![LlmServiceChoiceStream {
+                                index: 0,
+                                delta: LlmServiceStreamDelta {
+                                    role: "assistant".to_string(),
+                                    content: token.iter().collect(),
+                                    thinking: None,
+                                    tool_calls: None,
+                                },
+                                finish_reason: None,
+                                native_finish_reason: None,
+                                safety_category: None,
+                            }],
+                            usage: None,
+                            model_version: None,
+                        })
+                    });
+
+                    let summary = forward_stream(futures_util::stream::iter(chunks), &tx, Instant::now(), &[]).await;
+                    Ok(LlmServiceChatCompletionResponse::new_streamed(
+                        summary.id,
+                        summary.content,
+                        "tokens".to_string(),
+                        0,
+                        None,
+                        None,
+                        None,
+                    ))
+                })
+            }
+        }
+
+        provider_registry().register("stream-schema-test", |props, _streaming| {
+            let asked_for_schema = props.request.response_format.as_ref().is_some_and(|f| f.json_schema.is_some());
+            Box::new(TokenProvider { asked_for_schema })
+        });
+
+        let props = LlmServiceRequest::from_user_prompt(
+            LlmApiProvider::Custom("stream-schema-test".to_string()),
+            "tokens",
+            "The capital of Portugal",
+        );
+        let (events, city) = stream_schema::<City>(props);
+
+        let tokens: Vec<String> = events
+            .filter_map(|event| async move {
+                match event {
+                    Ok(StreamEvent::Chunk(c)) if !c.is_done_sentinel() => Some(c.choices[0].delta.content.clone()),
+                    _ => None,
+                }
+            })
+            .collect()
+            .await;
+        assert!(tokens.len() > 1);
+        assert_eq!(tokens.concat(), r#"{"name":"Lisbon","population":545000}"#);
+
+        assert_eq!(city.await.unwrap(), City { name: "Lisbon".to_string(), population: 545000 });
+
+        provider_registry().unregister("stream-schema-test");
+    }
+
+    #[tokio::test]
+    async fn test_stream_with_take_while() {
+        use crate::services::providers::registry::CustomProvider;
+        use futures_util::{future::BoxFuture, StreamExt};
+
+        struct FixedProvider;
+
+        impl CustomProvider for FixedProvider {
+            fn execute_chat(&self) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>> {
+                Box::pin(async {
+                    Ok(LlmServiceChatCompletionResponse::new_streamed(
+                        "fixed-1".to_string(),
+                        "streamed answer".to_string(),
+                        "fixed".to_string(),
+                        0,
+                        None,
+                        None,
+                        None,
+                    ))
+                })
+            }
+        }
+
+        provider_registry().register("stream-with-test", |_props, _streaming| Box::new(FixedProvider));
+
+        let props = LlmServiceRequest::from_user_prompt(
+            LlmApiProvider::Custom("stream-with-test".to_string()),
+            "fixed",
+            "Hello",
+        );
+
+        // Stop at the sentinel like any other stream combinator would
+        let events: Vec<_> = stream_with(props)
+            .take_while(|event| {
+                let done = matches!(event, Ok(StreamEvent::Chunk(c)) if c.is_done_sentinel());
+                std::future::ready(!done)
+            })
+            .collect()
+            .await;
+
+        let content: String = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(StreamEvent::Chunk(c)) => Some(c.choices[0].delta.content.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(content, "streamed answer");
+        assert!(events.iter().any(|e| matches!(e, Ok(StreamEvent::Stats { .. }))));
+
+        provider_registry().unregister("stream-with-test");
+    }
+
+    #[tokio::test]
+    async fn test_stream_with_yields_setup_error() {
+        use futures_util::StreamExt;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Hello");
+        props.request.messages.clear();
+
+        let events: Vec<_> = stream_with(props).collect().await;
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Err(LlmStreamingError::StreamError(msg)) if msg.contains("messages")));
+    }
+
+    #[test]
+    fn test_ask_builds_minimal_request() {
+        let props = LlmServiceRequest::from_user_prompt(
+            LlmApiProvider::for_model("gemini-2.0-flash"),
+            "gemini-2.0-flash",
+            "Hello",
+        );
+
+        assert_eq!(props.provider, LlmApiProvider::Gemini);
+        assert_eq!(props.base_url, "https://generativelanguage.googleapis.com/v1beta");
+        assert_eq!(props.request.messages.len(), 1);
+        assert!(props.request.messages[0].is_user());
+        assert!(props.request.temperature.is_none());
+        assert!(props.request.max_tokens.is_none());
+        assert!(props.validate().is_ok());
+
+        assert_eq!(LlmApiProvider::for_model("openai/gpt-4o"), LlmApiProvider::Openrouter);
+    }
+
+    #[test]
+    fn test_provider_override_wins_over_inferred_provider() {
+        // Inferred as OpenRouter, but several vendors host this model
+        let model = "meta-llama/Llama-3.3-70B-Instruct-Turbo";
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::for_model(model), model, "Hello");
+        assert_eq!(props.provider, LlmApiProvider::Openrouter);
+
+        props.provider_override = Some(LlmApiProvider::Together);
+        let prepared = dry_run(&props).unwrap();
+        assert_eq!(prepared.url, "https://api.together.xyz/v1/chat/completions");
+        assert_eq!(prepared.body["model"], model);
+
+        // A base url set by hand is the caller's choice and stays
+        props.base_url = "http://localhost:8080/v1".to_string();
+        assert_eq!(dry_run(&props).unwrap().url, "http://localhost:8080/v1/chat/completions");
+    }
+
+    #[tokio::test]
+    async fn test_attempt_hook_sees_every_retry() {
+        let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = attempts.clone();
+        let hook: AttemptHook = Arc::new(move |attempt: &Attempt| seen.lock().unwrap().push(attempt.clone()));
+
+        let mut calls = 0;
+        let result = retry_attempts(std::iter::repeat(Duration::ZERO).take(3), Some(&hook), || {
+            calls += 1;
+            let failing = calls <= 2;
+            async move {
+                if failing {
+                    Err(LlmError::ProviderUnavailable("flaky".to_string()))
+                } else {
+                    Ok("answer")
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), "answer");
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.iter().map(|a| a.number).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(attempts[0].error.as_deref().is_some_and(|e| e.contains("flaky")));
+        assert!(attempts[1].error.is_some());
+        assert!(attempts[2].error.is_none());
+    }
+
+    #[test]
+    fn test_request_body_logged_on_error_only() {
+        let body = r#"{"model": "gpt-4"}"#;
+
+        assert_eq!(logged_request_body(body, true, true), Some(body));
+        assert_eq!(logged_request_body(body, false, true), None);
+    }
+
+    #[test]
+    fn test_request_body_always_logged_by_default() {
+        let body = r#"{"model": "gpt-4"}"#;
+
+        assert_eq!(logged_request_body(body, true, false), Some(body));
+        assert_eq!(logged_request_body(body, false, false), Some(body));
+    }
+
+    #[test]
+    fn test_log_formats_are_parseable() {
+        let props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2-latest", "Hello\nthere");
+        assert_eq!(LogFormat::default(), LogFormat::Jsonl);
+
+        let compact = LogFormat::Jsonl.serialize(&props).unwrap();
+        assert!(!compact.contains('\n'));
+        let pretty = LogFormat::Pretty.serialize(&props).unwrap();
+        assert!(pretty.lines().count() > 1);
+
+        let compact: serde_json::Value = serde_json::from_str(&compact).unwrap();
+        let pretty: serde_json::Value = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(compact, pretty);
+        assert_eq!(compact["request"]["messages"][0]["content"], "Hello\nthere");
+    }
 
     // This struct represents a test version of Llm that we can use for unit testing the validate_schema method
     struct TestLlm {}
@@ -542,4 +1861,61 @@ mod tests {
         let result = llm.validate_schema(response, schema);
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_complete_with_system_overrides_system_instruction() {
+        std::env::set_var("GOOGLE_API_KEY", "test-key");
+
+        let body = serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Arr, hello!" }] }, "finishReason": "STOP" }]
+        })
+        .to_string();
+        let (base_url, captured) = serve_capture(200, body).await;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Gemini, "gemini-2.0-flash", "Say hello");
+        props.request.messages.insert(0, ChatCompletionRequestMessage::System { content: "You are a butler.".to_string(), name: None });
+        props.base_url = base_url;
+
+        let answer = complete_with_system(&props, "You are a pirate.").await.unwrap();
+        assert_eq!(answer, "Arr, hello!");
+
+        let request = captured.await.unwrap();
+        let sent: serde_json::Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(sent["systemInstruction"]["parts"][0]["text"], "You are a pirate.");
+        assert_eq!(sent["contents"].as_array().unwrap().len(), 1);
+
+        // The original request keeps its system message
+        assert_eq!(props.request.messages.len(), 2);
+        assert_eq!(props.request.messages[0].content(), "You are a butler.");
+    }
+
+    #[tokio::test]
+    async fn test_complete_raw_returns_provider_json() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let body = serde_json::json!({
+            "id": "chatcmpl-raw",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "grok-2",
+            "system_fingerprint": "fp_123",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hi there" },
+                "finish_reason": "stop",
+                "logprobs": null
+            }],
+            "usage": { "prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5 }
+        });
+        let base_url = serve_once(200, body.to_string()).await;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2", "Hello");
+        props.base_url = base_url;
+
+        let (text, raw) = complete_raw(props).await.unwrap();
+        assert_eq!(text, "Hi there");
+        // Fields the response type doesn't model are kept too
+        assert_eq!(raw, body);
+        assert_eq!(raw["system_fingerprint"], "fp_123");
+    }
 }
@@ -1,3 +1,4 @@
+pub mod conversation;
 pub mod llm;
 pub mod providers;
 pub mod types;
@@ -1,25 +1,48 @@
-use std::vec;
-
-use crate::services::{
-    llm::{Error, LlmProvider}, 
-    types::{llm_props::LlmProps, message::Message, parse_response::LlmApiResponseProps, stream::LlmStreamingError}
+use crate::common::types::chat_request::{ChatCompletionRequestMessage, ChatCompletionRequestTool};
+use crate::common::types::models::LlmApiProvider;
+use crate::common::types::chat_response::{
+    LlmServiceChatCompletionChunk, LlmServiceChatCompletionResponse,
+    LlmServiceChatCompletionResponseChoice, LlmServiceChatCompletionResponseMessage,
+    LlmServiceChatCompletionResponseUsage, LlmServiceChoiceStream, LlmServiceContentPart,
+    LlmServiceStreamDelta, LlmServiceUsage, HarmCategory, HarmProbability, SafetyRating, Annotation,
+    LlmServiceChatCompletionResponseToolCall, LlmServiceChatCompletionResponseFunctionCall,
+    LlmServiceToolCallDelta, LlmServiceFunctionCallDelta,
+};
+use crate::services::types::{
+    llm_error::LlmError, llm_error::LlmStreamingError,
+    llm_service::{LlmServiceRequest, Modality, StreamTransport},
+    stream::StreamEvent,
+};
+use crate::services::utils::{
+    canonical_json::canonical_json,
+    cassette::send,
+    client::{accepts_gzip, error_for_status, json_body, read_body, shared_client, ByteBudget, RESPONSE_TOO_LARGE},
+    key_pool::{key_pool, PooledKey},
+    model_limits::{clamp_max_tokens, clamp_penalty},
+    schema::gemini_schema,
+    stream::{forward_stream_with, stream_error_message, StreamControl},
+    utf8::Utf8Decoder,
 };
 
 use anyhow::Result;
+use futures_util::{Stream, StreamExt};
 use reqwest::RequestBuilder;
-use reqwest_eventsource::Event;
+use reqwest_eventsource::{Event, EventSource};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::sync::mpsc::Sender;
-use futures_util::StreamExt;
+
+use std::collections::{BTreeSet, HashMap};
+use std::str::Utf8Error;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 pub struct GeminiProvider<'a> {
-    props: &'a LlmProps,
+    props: &'a LlmServiceRequest,
     streaming: bool,
 }
 
-impl<'a> GeminiProvider<'a > {
-    pub fn new(props: &'a LlmProps, streaming: bool) -> Self {
+impl<'a> GeminiProvider<'a> {
+    pub fn new(props: &'a LlmServiceRequest, streaming: bool) -> Self {
         GeminiProvider {
             props,
             streaming
@@ -30,32 +53,107 @@ impl<'a> GeminiProvider<'a > {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GeminiResponse {
+    // Gemini omits candidates entirely when the prompt itself was blocked
+    #[serde(default)]
     candidates: Vec<GeminiResponseCandidate>,
     #[serde(rename = "promptFeedback")]
     prompt_feedback: Option<GeminiPromptFeedback>,
     #[serde(rename = "usageMetadata")]
     usage_metadata: Option<GeminiUsageMetadata>,
+    #[serde(rename = "responseId")]
+    response_id: Option<String>,
+    #[serde(rename = "modelVersion")]
+    model_version: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GeminiResponseCandidate {
-    content: GeminiMessageContent,
+    content: Option<GeminiMessageContent>,
     #[serde(rename = "finishReason")]
     finish_reason: Option<String>,
     index: Option<i64>,
     #[serde(rename = "safetyRatings")]
     safety_ratings: Option<Vec<GeminiSafetyRating>>,
+    #[serde(rename = "groundingMetadata")]
+    grounding_metadata: Option<GeminiGroundingMetadata>,
+}
+
+/// Sources Gemini grounded the answer in (`groundingChunks`) and the spans of the answer
+/// each supports (`groundingSupports`, pointing at the chunks by index).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GeminiGroundingMetadata {
+    #[serde(rename = "groundingChunks", default)]
+    grounding_chunks: Vec<GeminiGroundingChunk>,
+    #[serde(rename = "groundingSupports", default)]
+    grounding_supports: Vec<GeminiGroundingSupport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GeminiGroundingChunk {
+    // Retrieved context from a data store has the same shape under `retrievedContext`
+    #[serde(alias = "retrievedContext")]
+    web: Option<GeminiGroundingSource>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GeminiGroundingSource {
+    uri: Option<String>,
+    title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GeminiGroundingSupport {
+    segment: GeminiSegment,
+    #[serde(rename = "groundingChunkIndices", default)]
+    grounding_chunk_indices: Vec<usize>,
+}
+
+/// Byte offsets into the candidate's text, a zero `startIndex` is omitted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GeminiSegment {
+    #[serde(rename = "startIndex", default)]
+    start_index: usize,
+    #[serde(rename = "endIndex", default)]
+    end_index: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GeminiMessageContent {
+    #[serde(default)]
     parts: Vec<GeminiContentPart>,
     role: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GeminiContentPart {
+    #[serde(default)]
     text: String,
+    // Set on parts holding the model's reasoning rather than the answer
+    #[serde(default)]
+    thought: bool,
+    // Generated images when IMAGE is one of the response modalities
+    #[serde(rename = "inlineData", skip_serializing_if = "Option::is_none")]
+    inline_data: Option<GeminiInlineData>,
+    // A call of one of the request's function declarations
+    #[serde(rename = "functionCall", skip_serializing_if = "Option::is_none")]
+    function_call: Option<GeminiFunctionCall>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GeminiFunctionCall {
+    // only sent by newer models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GeminiInlineData {
+    #[serde(rename = "mimeType")]
+    mime_type: String,
+    data: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -70,8 +168,9 @@ struct GeminiPromptFeedback {
 struct GeminiUsageMetadata {
     #[serde(rename = "promptTokenCount")]
     prompt_token_count: i64,
+    // Missing when nothing was generated, and only final on the last stream chunk
     #[serde(rename = "candidatesTokenCount")]
-    candidates_token_count: i64,
+    candidates_token_count: Option<i64>,
     #[serde(rename = "totalTokenCount")]
     total_token_count: i64,
 }
@@ -80,200 +179,718 @@ struct GeminiUsageMetadata {
 struct GeminiSafetyRating {
     category: String,
     probability: String,
+    #[serde(default)]
+    blocked: bool,
 }
 
-// STREAMING
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GeminiResponseStreamChunk {
-    candidates: Vec<GeminiResponseStreamCandidate>,
-    #[serde(rename = "usageMetadata")]
-    usage_metadata: Option<GeminiResponseStreamUsage>
+impl GeminiResponse {
+    /// Returns the block reason when Gemini refused the prompt as a whole.
+    fn block_reason(&self) -> Option<&str> {
+        self.prompt_feedback
+            .as_ref()
+            .and_then(|pf| pf.block_reason.as_deref())
+    }
+
+    /// The answer of the first candidate: all of its text parts but the thoughts, joined.
+    fn text(&self) -> String {
+        self.candidates.first().map(|c| c.split_text().1).unwrap_or_default()
+    }
+
+    /// The thought parts of the first candidate, `None` when there are none.
+    fn reasoning(&self) -> Option<String> {
+        let (thinking, _) = self.candidates.first()?.split_text();
+        Some(thinking).filter(|t| !t.is_empty())
+    }
+
+    /// All parts of the first candidate, but only when it held more than text.
+    fn parts(&self) -> Option<Vec<LlmServiceContentPart>> {
+        let parts = &self.candidates.first()?.content.as_ref()?.parts;
+        if parts.iter().all(|p| p.inline_data.is_none()) {
+            return None;
+        }
+
+        Some(parts.iter().filter_map(|p| match &p.inline_data {
+            Some(inline) => Some(LlmServiceContentPart::Image {
+                mime_type: inline.mime_type.clone(),
+                data: inline.data.clone(),
+            }),
+            None if !p.text.is_empty() && !p.thought => Some(LlmServiceContentPart::Text { text: p.text.clone() }),
+            None => None,
+        }).collect())
+    }
+
+    fn finish_reason(&self) -> Option<String> {
+        self.candidates.first().and_then(|c| c.finish_reason.clone())
+    }
+
+    fn safety_ratings(&self) -> Vec<SafetyRating> {
+        let ratings = self.candidates.first().and_then(|c| c.safety_ratings.as_deref()).unwrap_or_default();
+        ratings.iter().map(SafetyRating::from).collect()
+    }
+
+    /// One annotation per span and cited source of the first candidate, in the order
+    /// Gemini lists them. Sources without a uri are skipped.
+    fn annotations(&self) -> Vec<Annotation> {
+        let Some(grounding) = self.candidates.first().and_then(|c| c.grounding_metadata.as_ref()) else {
+            return Vec::new();
+        };
+
+        grounding
+            .grounding_supports
+            .iter()
+            .flat_map(|support| {
+                support.grounding_chunk_indices.iter().filter_map(|i| {
+                    let source = grounding.grounding_chunks.get(*i)?.web.as_ref()?;
+                    Some(Annotation {
+                        start: support.segment.start_index,
+                        end: support.segment.end_index,
+                        uri: source.uri.clone()?,
+                        title: source.title.clone(),
+                    })
+                })
+            })
+            .collect()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GeminiResponseStreamCandidate {
-    content: GeminiResponseStreamContent,
+impl From<&GeminiSafetyRating> for SafetyRating {
+    fn from(rating: &GeminiSafetyRating) -> Self {
+        SafetyRating {
+            category: match rating.category.as_str() {
+                "HARM_CATEGORY_HARASSMENT" => HarmCategory::Harassment,
+                "HARM_CATEGORY_HATE_SPEECH" => HarmCategory::HateSpeech,
+                "HARM_CATEGORY_SEXUALLY_EXPLICIT" => HarmCategory::SexuallyExplicit,
+                "HARM_CATEGORY_DANGEROUS_CONTENT" => HarmCategory::DangerousContent,
+                "HARM_CATEGORY_CIVIC_INTEGRITY" => HarmCategory::CivicIntegrity,
+                other => HarmCategory::Other(other.to_string()),
+            },
+            probability: match rating.probability.as_str() {
+                "NEGLIGIBLE" => HarmProbability::Negligible,
+                "LOW" => HarmProbability::Low,
+                "MEDIUM" => HarmProbability::Medium,
+                "HIGH" => HarmProbability::High,
+                _ => HarmProbability::Unknown,
+            },
+            blocked: rating.blocked,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GeminiResponseStreamContent {
-    parts: Vec<GeminiResponseStreamPart>,
+impl GeminiResponseCandidate {
+    /// Text of the parts, with the thought parts and the answer parts joined separately.
+    fn split_text(&self) -> (String, String) {
+        let parts = self.content.as_ref().map(|c| c.parts.as_slice()).unwrap_or_default();
+
+        let join = |thought: bool| {
+            parts
+                .iter()
+                .filter(|p| p.thought == thought)
+                .map(|p| p.text.as_str())
+                .collect::<String>()
+        };
+        (join(true), join(false))
+    }
+
+    /// The `functionCall` parts, in order. Gemini doesn't always give calls an id, those
+    /// are numbered by their position.
+    fn tool_calls(&self) -> Vec<LlmServiceChatCompletionResponseToolCall> {
+        let parts = self.content.as_ref().map(|c| c.parts.as_slice()).unwrap_or_default();
+
+        parts
+            .iter()
+            .filter_map(|p| p.function_call.as_ref())
+            .enumerate()
+            .map(|(position, call)| LlmServiceChatCompletionResponseToolCall {
+                id: call.id.clone().unwrap_or_else(|| format!("call_{}", position)),
+                kind: "function".to_string(),
+                function_call: LlmServiceChatCompletionResponseFunctionCall {
+                    name: call.name.clone(),
+                    arguments: match &call.args {
+                        serde_json::Value::Null => "{}".to_string(),
+                        args => args.to_string(),
+                    },
+                },
+            })
+            .collect()
+    }
+
+    /// The category responsible when the candidate was stopped for safety. Gemini marks
+    /// it as `blocked`, older responses only give it a HIGH probability.
+    fn safety_category(&self) -> Option<String> {
+        if self.finish_reason.as_deref() != Some("SAFETY") {
+            return None;
+        }
+
+        let ratings = self.safety_ratings.as_deref().unwrap_or_default();
+        ratings.iter()
+            .find(|r| r.blocked)
+            .or_else(|| ratings.iter().find(|r| r.probability == "HIGH"))
+            .map(|r| r.category.clone())
+            .or_else(|| Some("UNKNOWN".to_string()))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GeminiResponseStreamPart {
-    text: String,
+/// Maps Gemini's finish reasons onto the OpenAi values used by our response types.
+fn map_finish_reason(reason: &str) -> String {
+    match reason {
+        "STOP" => "stop".to_string(),
+        "MAX_TOKENS" => "length".to_string(),
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" => "content_filter".to_string(),
+        other => other.to_lowercase(),
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GeminiResponseStreamUsage {
-    #[serde(rename = "promptTokenCount")]
-    prompt_token_count: i32,
-    #[serde(rename = "candidatesTokenCount")]
-    candidates_token_count: Option<i32>,
-    #[serde(rename = "totalTokenCount")]
-    total_token_count: i32,
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
 }
 
-impl From<GeminiResponse> for LlmApiResponseProps {
+impl From<GeminiResponse> for LlmServiceChatCompletionResponse {
     fn from(response: GeminiResponse) -> Self {
-        let response_content = response
-            .candidates
-            .first()
-            .and_then(|c| c.content.parts.first())
-            .and_then(|p| Some(p.text.clone()))
-            .unwrap_or_default();
-
-        // Extract token counts
-        let (input_tokens, output_tokens) = response.clone().usage_metadata
-            .map(|um| (Some(um.prompt_token_count), Some(um.candidates_token_count)))
-            .unwrap_or((None, None));
-
-        // Extract error information
-        let (error_code, error_message) = response.clone().prompt_feedback
-            .and_then(|pf| pf.block_reason
-                .map(|br| (Some(br.clone()), Some(format!("Prompt blocked: {}", br)))))
-            .unwrap_or((None, None));
-
-        LlmApiResponseProps {
-            response_content,
-            raw_response: serde_json::to_string(&response).unwrap_or_default(),
-            input_tokens,
-            output_tokens,
-            reasoning_tokens: None
+        let finish_reason = response.finish_reason();
+
+        let usage = response.usage_metadata.as_ref().map(|um| LlmServiceChatCompletionResponseUsage {
+            prompt_tokens: um.prompt_token_count as u32,
+            completion_tokens: um.candidates_token_count.unwrap_or_default() as u32,
+            total_tokens: um.total_token_count as u32,
+        });
+
+        // With images in the mix the text can be spread over several parts
+        let parts = response.parts();
+        let content = match &parts {
+            Some(parts) => parts.iter().filter_map(|p| match p {
+                LlmServiceContentPart::Text { text } => Some(text.as_str()),
+                _ => None,
+            }).collect::<Vec<_>>().join(""),
+            None => response.text(),
+        };
+
+        LlmServiceChatCompletionResponse {
+            id: response
+                .response_id
+                .clone()
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            choices: vec![LlmServiceChatCompletionResponseChoice {
+                message: LlmServiceChatCompletionResponseMessage {
+                    role: "assistant".to_string(),
+                    content,
+                    name: None,
+                    tool_calls: response.candidates.first()
+                        .map(GeminiResponseCandidate::tool_calls)
+                        .filter(|calls| !calls.is_empty()),
+                    parts,
+                    reasoning: response.reasoning(),
+                },
+                finish_reason: finish_reason.as_deref().map(map_finish_reason),
+                native_finish_reason: finish_reason,
+                safety_ratings: response.safety_ratings(),
+                annotations: response.annotations(),
+            }],
+            created: now_unix(),
+            // filled in by the provider, Gemini only echoes the model on newer API versions
+            model: String::new(),
+            provider: None,
+            model_version: response.model_version.clone(),
+            rate_limit: None,
+            raw: None,
+            usage,
         }
     }
 }
 
 
-impl<'a> LlmProvider for GeminiProvider<'a> {
-    fn build_request(&self) -> Result<(RequestBuilder, String), Error> {
-        let client = reqwest::Client::new();
-        let api_key = std::env::var("GOOGLE_API_KEY").map_err(|_| Error::Auth)?;
+/// Decoder used for a streamed response, picked from the requested transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamDecoder {
+    EventSource,
+    JsonArray,
+}
+
+fn stream_decoder(transport: StreamTransport) -> StreamDecoder {
+    match transport {
+        StreamTransport::Sse => StreamDecoder::EventSource,
+        StreamTransport::JsonArray => StreamDecoder::JsonArray,
+    }
+}
 
-        let body = self.create_body();
-        let body_string = body.to_string();
+/// Splits an incrementally received JSON array (`[{...},{...}]`) into its top-level
+/// objects. Text is buffered until an object is complete, so elements may be split
+/// across network reads at any point, including inside a multi-byte character.
+#[derive(Debug, Default)]
+struct JsonArrayDecoder {
+    utf8: Utf8Decoder,
+    buffer: String,
+    // position of the next byte to scan
+    pos: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    // where the object currently being read starts in the buffer
+    start: Option<usize>,
+}
 
-        let url = format!(
-            "{}/models/{}:{}",
-            &self.props.base_url,
-            self.props.model_name,
-            if self.streaming { "streamGenerateContent" } else { "generateContent" }
-        );
+impl JsonArrayDecoder {
+    fn push(&mut self, bytes: &[u8]) -> Result<Vec<String>, Utf8Error> {
+        self.buffer.push_str(&self.utf8.decode(bytes)?);
+        let mut elements = Vec::new();
 
-        let mut request = client.post(&url)
-            .query(&[("key", api_key)]);
+        // Only ASCII bytes are structural, so every index kept below is a char boundary
+        while self.pos < self.buffer.len() {
+            let byte = self.buffer.as_bytes()[self.pos];
 
-        if self.streaming {
-            request = request.query(&[("alt", "sse")]);
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                }
+            } else {
+                match byte {
+                    b'"' if self.depth > 0 => self.in_string = true,
+                    b'{' => {
+                        if self.depth == 0 {
+                            self.start = Some(self.pos);
+                        }
+                        self.depth += 1;
+                    }
+                    b'}' if self.depth > 0 => {
+                        self.depth -= 1;
+                        if self.depth == 0 {
+                            if let Some(start) = self.start.take() {
+                                elements.push(self.buffer[start..=self.pos].to_string());
+                            }
+                        }
+                    }
+                    // array brackets, commas and whitespace between elements
+                    _ => {}
+                }
+            }
+
+            self.pos += 1;
         }
 
-        let request = request.json(&body);
+        // Drop everything before the element still being read
+        let keep_from = self.start.unwrap_or(self.pos);
+        self.buffer.drain(..keep_from);
+        self.pos -= keep_from;
+        if self.start.is_some() {
+            self.start = Some(0);
+        }
 
-        Ok((request, body_string))
+        Ok(elements)
     }
+}
 
-    fn parse_response(json_text: &str) -> Result<LlmApiResponseProps, Error> {
-        let response: GeminiResponse = serde_json::from_str(json_text)?;
-        Ok(response.into())
-
-    }
-
-    async fn stream_eventsource(
-        mut event_source: reqwest_eventsource::EventSource, 
-        tx: Sender<Result<String, LlmStreamingError>>
-    ) -> Result<LlmApiResponseProps, Error> {
-        let result = tokio::spawn(async move {
-            let mut stream_content = String::new();
-            let mut output_tokens = 0;
-            let mut input_tokens = 0;
-            let mut chunks = vec![];
-
-            while let Some(event_result) = event_source.next().await {
-                match event_result {
-                    Ok(event) => {
-                        if let Event::Message(message) = event {
-                            match serde_json::from_str::<GeminiResponseStreamChunk>(&message.data) {
-                                Ok(chunk) => {
-                                    if let Some(text) = chunk.candidates
-                                        .first()
-                                        .and_then(|c| c.content.parts.first())
-                                        .map(|p| p.text.clone())
-                                    {
-                                        stream_content += &text;
-                                        if let Err(_) = tx.send(Ok(text)).await {
-                                            break;
-                                        }
-                                    }
-
-                                    if let Some(usage) = &chunk.usage_metadata {
-                                        // we only get the candidates_token_count on the last
-                                        // message. Otherwise it just returns a running total,
-                                        // so it's only worth capturing it at the end
-                                        if let Some(c_toks) = usage.candidates_token_count {
-                                            input_tokens += usage.prompt_token_count;
-                                            output_tokens += c_toks;
-                                            chunks.push(chunk); // only capturing the last chunk
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(Err(LlmStreamingError::ParseError(e.to_string()))).await;
-                                    break;
-                                }
-                            }
-                        }
+
+/// Parses a single streamed element into a chunk. `id` is used when Gemini doesn't send a `responseId`.
+/// Every candidate becomes a choice with the candidate's index, so with `candidateCount`
+/// above one the interleaved candidates stay apart.
+fn parse_stream_chunk(json_text: &str, id: &str) -> Result<LlmServiceChatCompletionChunk, LlmError> {
+    if let Some(message) = stream_error_message(json_text) {
+        return Err(LlmError::Provider(message));
+    }
+    let chunk: GeminiResponse = serde_json::from_str(json_text)?;
+
+    if chunk.candidates.is_empty() {
+        if let Some(reason) = chunk.block_reason() {
+            return Err(LlmError::Provider(format!("prompt blocked: {}", reason)));
+        }
+    }
+
+    // we only get the candidates_token_count on the last message. Otherwise it
+    // just returns a running total, so it's only worth capturing it at the end
+    let usage = chunk.usage_metadata.as_ref().and_then(|um| {
+        um.candidates_token_count.map(|c_toks| LlmServiceUsage {
+            prompt_tokens: um.prompt_token_count as u32,
+            completion_tokens: c_toks as u32,
+            total_tokens: um.total_token_count as u32,
+        })
+    });
+
+    let mut choices: Vec<LlmServiceChoiceStream> = chunk
+        .candidates
+        .iter()
+        .enumerate()
+        .map(|(position, candidate)| {
+            let (thinking, content) = candidate.split_text();
+            // Gemini sends every call whole, in a single chunk
+            let tool_calls: Vec<LlmServiceToolCallDelta> = candidate
+                .tool_calls()
+                .into_iter()
+                .enumerate()
+                .map(|(index, call)| LlmServiceToolCallDelta {
+                    index: index as u32,
+                    id: Some(call.id),
+                    kind: Some(call.kind),
+                    function: Some(LlmServiceFunctionCallDelta {
+                        name: Some(call.function_call.name),
+                        arguments: Some(call.function_call.arguments),
+                    }),
+                })
+                .collect();
+            LlmServiceChoiceStream {
+                index: candidate.index.map_or(position as u32, |index| index as u32),
+                delta: LlmServiceStreamDelta {
+                    role: "assistant".to_string(),
+                    content,
+                    thinking: Some(thinking).filter(|t| !t.is_empty()),
+                    tool_calls: Some(tool_calls).filter(|calls| !calls.is_empty()),
+                },
+                finish_reason: candidate.finish_reason.as_deref().map(map_finish_reason),
+                native_finish_reason: candidate.finish_reason.clone(),
+                safety_category: candidate.safety_category(),
+            }
+        })
+        .collect();
+
+    // A usage only chunk still carries an (empty) choice
+    if choices.is_empty() {
+        choices.push(LlmServiceChoiceStream {
+            index: 0,
+            delta: LlmServiceStreamDelta {
+                role: "assistant".to_string(),
+                content: String::new(),
+                thinking: None,
+                tool_calls: None,
+            },
+            finish_reason: None,
+            native_finish_reason: None,
+            safety_category: None,
+        });
+    }
+
+    Ok(LlmServiceChatCompletionChunk {
+        id: chunk.response_id.clone().unwrap_or_else(|| id.to_string()),
+        choices,
+        usage,
+        model_version: chunk.model_version.clone(),
+    })
+}
+
+/// Chunks of an `alt=sse` response, one per event.
+fn sse_chunks(
+    request: RequestBuilder,
+    api_key: Option<PooledKey>,
+    fallback_id: String,
+    mut budget: ByteBudget,
+) -> Result<impl Stream<Item = Result<LlmServiceChatCompletionChunk, String>>, LlmError> {
+    let mut event_source = EventSource::new(request)?;
+
+    Ok(async_stream::stream! {
+        while let Some(event_result) = event_source.next().await {
+            match event_result {
+                Ok(Event::Open) => continue,
+                Ok(Event::Message(message)) => {
+                    if !budget.take(message.data.len()) {
+                        yield Err(RESPONSE_TOO_LARGE.to_string());
+                        break;
                     }
-                    Err(e) => {
-                        if e.to_string() == "Stream ended" {
+                    match parse_stream_chunk(&message.data, &fallback_id) {
+                        Ok(chunk) => yield Ok(chunk),
+                        Err(e) => {
+                            yield Err(e.to_string());
                             break;
                         }
+                    }
+                }
+                Err(reqwest_eventsource::Error::StreamEnded) => break,
+                Err(e) => {
+                    if let (reqwest_eventsource::Error::InvalidStatusCode(_, response), Some(api_key)) = (&e, &api_key) {
+                        api_key.bench_if_rate_limited(response);
+                    }
+                    yield Err(e.to_string());
+                    break;
+                }
+            }
+        }
+
+        event_source.close();
+    })
+}
 
-                        let _ = tx.send(Err(LlmStreamingError::StreamError(e.to_string()))).await;
+/// Chunks of a plain `streamGenerateContent` response, which is one JSON array
+/// whose elements are decoded as soon as each is complete.
+async fn json_array_chunks(
+    request: RequestBuilder,
+    api_key: Option<PooledKey>,
+    fallback_id: String,
+    mut budget: ByteBudget,
+) -> Result<impl Stream<Item = Result<LlmServiceChatCompletionChunk, String>>, LlmError> {
+    let response = send(request).await?;
+    if let Some(api_key) = &api_key {
+        api_key.bench_if_rate_limited(&response);
+    }
+    let response = error_for_status(response).await?;
+    let mut bytes = response.bytes_stream();
+
+    Ok(async_stream::stream! {
+        let mut decoder = JsonArrayDecoder::default();
+
+        'read: while let Some(bytes_result) = bytes.next().await {
+            match bytes_result {
+                Ok(bytes) => {
+                    if !budget.take(bytes.len()) {
+                        yield Err(RESPONSE_TOO_LARGE.to_string());
                         break;
                     }
+                    let elements = match decoder.push(&bytes) {
+                        Ok(elements) => elements,
+                        Err(e) => {
+                            yield Err(LlmError::from(e).to_string());
+                            break;
+                        }
+                    };
+                    for element in elements {
+                        match parse_stream_chunk(&element, &fallback_id) {
+                            Ok(chunk) => yield Ok(chunk),
+                            Err(e) => {
+                                yield Err(e.to_string());
+                                break 'read;
+                            }
+                        }
+                    }
                 }
+                Err(e) => {
+                    yield Err(e.to_string());
+                    break;
+                }
+            }
+        }
+    })
+}
+
+
+/// Gemini 2.x models take `frequencyPenalty`/`presencePenalty` in `generationConfig`,
+/// in the same -2..=2 range as OpenAi. Older models reject the fields, so for those the
+/// penalty is left out like for any provider without them.
+fn gemini_penalty(model: &str, name: &str, value: f64) -> Option<f64> {
+    let name_part = model.rsplit('/').next().unwrap_or(model);
+    if !name_part.starts_with("gemini-2") {
+        return clamp_penalty(&LlmApiProvider::Gemini, name, value);
+    }
+
+    let clamped = value.clamp(-2.0, 2.0);
+    if clamped != value {
+        tracing::warn!("{} {} is outside -2..=2 for {}, clamping to {}", name, value, model, clamped);
+    }
+    Some(clamped)
+}
+
+/// Resource path of `model`: tuned models are addressed by their full `tunedModels/{id}`
+/// name, base models live under `models/`.
+fn model_path(model: &str) -> String {
+    if model.starts_with("tunedModels/") {
+        model.to_string()
+    } else {
+        format!("models/{}", model)
+    }
+}
+
+impl<'a> GeminiProvider<'a> {
+    pub(crate) fn build_request(&self, api_key: &str) -> Result<RequestBuilder, LlmError> {
+        let url = format!("{}/{}:{}", &self.props.base_url, model_path(&self.props.request.model), self.method());
+        self.build_request_to(&url, |request| request.query(&[("key", api_key)]))
+    }
+
+    /// The method the request calls on the model.
+    pub(crate) fn method(&self) -> &'static str {
+        if self.streaming { "streamGenerateContent" } else { "generateContent" }
+    }
+
+    /// The request for these props sent to `url`, with `auth` adding the credentials.
+    /// Shared with endpoints that serve the same API under other urls, like Vertex AI.
+    pub(crate) fn build_request_to(
+        &self,
+        url: &str,
+        auth: impl FnOnce(RequestBuilder) -> RequestBuilder,
+    ) -> Result<RequestBuilder, LlmError> {
+        let body = self.create_body();
+        let mut request = auth(shared_client().post(url));
+
+        if self.streaming && stream_decoder(self.props.stream_transport) == StreamDecoder::EventSource {
+            request = request.query(&[("alt", "sse")]);
+        }
+
+        json_body(request, &body, self.props.compress_request && accepts_gzip(&self.props.provider))
+    }
+
+    /// Parses a `generateContent` response body, turning a blocked prompt into an error
+    /// since there is nothing else to return in that case.
+    pub fn parse_response(json_text: &str) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let response: GeminiResponse = serde_json::from_str(json_text)?;
+
+        if response.candidates.is_empty() {
+            if let Some(reason) = response.block_reason() {
+                return Err(LlmError::Provider(format!("prompt blocked: {}", reason)));
             }
+            return Err(LlmError::EmptyResponse);
+        }
+
+        let mut response: LlmServiceChatCompletionResponse = response.into();
+        response.raw = serde_json::from_str(json_text).ok();
+        Ok(response)
+    }
+
+    pub async fn execute_chat(&self) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let api_key = key_pool(&LlmApiProvider::Gemini)?.next_key();
+        let request = self.build_request(&api_key.key)?;
+        self.execute_chat_with(request, Some(api_key)).await
+    }
+
+    /// Sends `request` built by [`Self::build_request_to`] and parses the response. A
+    /// rate limited `api_key` is benched.
+    pub(crate) async fn execute_chat_with(
+        &self,
+        request: RequestBuilder,
+        api_key: Option<PooledKey>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let response = send(request).await?;
+        if let Some(api_key) = &api_key {
+            api_key.bench_if_rate_limited(&response);
+        }
+        let response = error_for_status(response).await?;
+        let json_text = read_body(response, self.props.max_response_bytes).await?;
+
+        let mut response = Self::parse_response(&json_text)?;
+        response.model = self.props.request.model.clone();
+        Ok(response)
+    }
 
-            let _ = tx.send(Ok("[DONE]".to_string())).await;
-            event_source.close();
+    pub async fn execute_chat_stream(
+        &self,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let api_key = key_pool(&LlmApiProvider::Gemini)?.next_key();
+        let request = self.build_request(&api_key.key)?;
+        self.execute_chat_stream_with(request, Some(api_key), tx).await
+    }
 
-            let raw_response = serde_json::to_string(&chunks)
-                .expect("Failed to serialize chunk to string");
+    /// Streaming counterpart of [`Self::execute_chat_with`].
+    pub(crate) async fn execute_chat_stream_with(
+        &self,
+        request: RequestBuilder,
+        api_key: Option<PooledKey>,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let started = Instant::now();
+        let fallback_id = uuid::Uuid::new_v4().to_string();
 
-            return LlmApiResponseProps {
-                response_content: stream_content,
-                raw_response,
-                input_tokens: Some(input_tokens.into()),
-                output_tokens: Some(output_tokens.into()),
-                reasoning_tokens: None
-            };
-        }).await?;
+        let mut summary = match stream_decoder(self.props.stream_transport) {
+            StreamDecoder::EventSource => {
+                let chunks = sse_chunks(request, api_key, fallback_id, ByteBudget::new(self.props.max_response_bytes))?;
+                forward_stream_with(chunks, &tx, started, &self.props.response_transforms, StreamControl::for_request(self.props)).await
+            }
+            StreamDecoder::JsonArray => {
+                let chunks = json_array_chunks(request, api_key, fallback_id, ByteBudget::new(self.props.max_response_bytes)).await?;
+                forward_stream_with(chunks, &tx, started, &self.props.response_transforms, StreamControl::for_request(self.props)).await
+            }
+        };
 
-        Ok(result)
+        let mut response = LlmServiceChatCompletionResponse::new_streamed(
+            summary.id,
+            summary.content,
+            self.props.request.model.clone(),
+            now_unix(),
+            Some(summary.prompt_tokens),
+            Some(summary.completion_tokens),
+            Some(summary.total_tokens)
+        );
+        response.model_version = summary.model_version;
+        if summary.finish_reason.is_some() {
+            response.choices[0].finish_reason = summary.finish_reason;
+        }
+        if !summary.tool_calls.is_empty() {
+            response.choices[0].message.tool_calls = Some(summary.tool_calls);
+        }
+        // Further candidates follow the first one in index order, a candidate may make
+        // only function calls
+        let mut indices: BTreeSet<u32> = summary.choice_contents.keys().copied().collect();
+        indices.extend(summary.choice_tool_calls.keys().copied());
+        indices.extend(summary.choice_finish_reasons.keys().copied());
+        for index in indices.into_iter().filter(|index| *index > 0) {
+            response.choices.push(LlmServiceChatCompletionResponseChoice {
+                message: LlmServiceChatCompletionResponseMessage {
+                    role: "assistant".to_string(),
+                    content: summary.choice_contents.remove(&index).unwrap_or_default(),
+                    name: None,
+                    tool_calls: summary.choice_tool_calls.remove(&index),
+                    parts: None,
+                    reasoning: None,
+                },
+                finish_reason: summary.choice_finish_reasons.remove(&index),
+                native_finish_reason: None,
+                safety_ratings: Vec::new(),
+                annotations: Vec::new(),
+            });
+        }
+
+        Ok(response)
     }
 
     fn create_body(&self) -> serde_json::Value {
-        let system_instruction = self.props.messages.iter()
-            .filter_map(|msg| match msg {
-                Message::System { content } => Some(content.as_str()),
-                _ => None
-            })
-            .collect::<Vec<&str>>()
-            .join("\n\n");
+        let messages = self.props.outgoing_messages();
 
-        // Convert conversation history to Gemini's format
-        let contents = self.props.messages.iter()
+        let system_instruction = self.props.system_prompt(&messages);
+
+        // Gemini matches a function response to its call by name, tool results don't
+        // always carry one
+        let call_names: HashMap<&str, &str> = messages.iter()
+            .filter_map(|msg| msg.tool_calls())
+            .flatten()
+            .map(|call| (call.id.as_str(), call.function_call.name.as_str()))
+            .collect();
+
+        // Convert conversation history to Gemini's format. A trailing assistant turn stays
+        // the last `model` turn, which Gemini continues from (prefill). Tool calls are
+        // `functionCall` parts of a `model` turn, their results `functionResponse` parts
+        // of a `user` turn.
+        let contents = messages.iter()
             .filter_map(|msg| match msg {
-                Message::System { .. } => None,
-                Message::User { content } => Some(json!({
+                ChatCompletionRequestMessage::System { .. } => None,
+                ChatCompletionRequestMessage::User { content, .. } => Some(json!({
                     "role": "user",
                     "parts": [{ "text": content }]
                 })),
-                Message::Assistant { content } => Some(json!({
+                ChatCompletionRequestMessage::Assistant { content, tool_calls: None, .. } => Some(json!({
                     "role": "model",
                     "parts": [{ "text": content }]
                 })),
+                ChatCompletionRequestMessage::Assistant { content, tool_calls: Some(tool_calls), .. } => {
+                    let text = Some(json!({ "text": content })).filter(|_| !content.is_empty());
+                    let calls = tool_calls.iter().map(|call| json!({
+                        "functionCall": {
+                            "name": call.function_call.name,
+                            // the arguments have to be an object, malformed ones are sent empty
+                            "args": serde_json::from_str::<serde_json::Value>(&call.function_call.arguments)
+                                .ok()
+                                .filter(serde_json::Value::is_object)
+                                .unwrap_or_else(|| json!({}))
+                        }
+                    }));
+                    Some(json!({
+                        "role": "model",
+                        "parts": text.into_iter().chain(calls).collect::<Vec<_>>()
+                    }))
+                }
+                ChatCompletionRequestMessage::Tool { content, tool_call_id, name } => Some(json!({
+                    "role": "user",
+                    "parts": [{
+                        "functionResponse": {
+                            "name": name.as_deref()
+                                .or_else(|| call_names.get(tool_call_id.as_str()).copied())
+                                .unwrap_or(tool_call_id),
+                            // the response has to be an object, anything else is wrapped in one
+                            "response": match content {
+                                serde_json::Value::Object(_) => content.clone(),
+                                _ => json!({ "content": content }),
+                            }
+                        }
+                    }]
+                })),
             })
             .collect::<Vec<_>>();
 
@@ -289,20 +906,63 @@ impl<'a> LlmProvider for GeminiProvider<'a> {
             });
         }
 
+        // Functions are declared in a single tool, their parameters in Gemini's schema subset
+        if let Some(tools) = self.props.request.tools.as_ref().filter(|tools| !tools.is_empty()) {
+            let declarations = tools.iter().map(|ChatCompletionRequestTool::Function { function }| {
+                let mut declaration = json!({ "name": function.name });
+                if let Some(description) = &function.description {
+                    declaration["description"] = json!(description);
+                }
+                // a function without parameters has to leave them out
+                if function.parameters.get("properties").is_some_and(|p| p.as_object().is_some_and(|p| !p.is_empty())) {
+                    declaration["parameters"] = gemini_schema(&function.parameters);
+                }
+                declaration
+            }).collect::<Vec<_>>();
+            body["tools"] = json!([{ "functionDeclarations": declarations }]);
+        }
+
         let mut generation_config = json!({
-            "temperature": self.props.temperature,
-            "maxOutputTokens": self.props.max_tokens
+            "temperature": self.props.request.temperature,
+            "maxOutputTokens": self.props.request.max_tokens
+                .map(|max_tokens| clamp_max_tokens(&self.props.request.model, max_tokens))
         });
 
-        if self.props.json_mode {
+        let penalties = [
+            ("frequencyPenalty", "frequency_penalty", self.props.request.frequency_penalty),
+            ("presencePenalty", "presence_penalty", self.props.request.presence_penalty),
+        ];
+        for (key, name, value) in penalties {
+            if let Some(value) = value.and_then(|v| gemini_penalty(&self.props.request.model, name, v)) {
+                generation_config[key] = json!(value);
+            }
+        }
+
+        if let Some(format) = &self.props.request.response_format {
             generation_config["responseMimeType"] = json!("application/json");
-        } else {
+            if let Some(json_schema) = &format.json_schema {
+                generation_config["responseSchema"] = gemini_schema(&json_schema.schema);
+            }
+        } else if !self.props.response_modalities.contains(&Modality::Image) {
+            // a text/plain mime type would rule out image parts
             generation_config["responseMimeType"] = json!("text/plain");
         }
 
+        if !self.props.response_modalities.is_empty() {
+            generation_config["responseModalities"] = json!(self.props.response_modalities);
+        }
+
+        if let Some(count) = self.props.candidate_count {
+            generation_config["candidateCount"] = json!(count);
+        }
+
+        if self.props.include_thoughts {
+            generation_config["thinkingConfig"] = json!({ "includeThoughts": true });
+        }
+
         body["generationConfig"] = generation_config;
 
-        body
+        canonical_json(body)
     }
 }
 
@@ -310,8 +970,430 @@ impl<'a> LlmProvider for GeminiProvider<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::types::{
+        chat::{ChatParams, ChatRequest, ChatResponse},
+        chat_request::{ChatCompletionRequestFunctionCall, ChatCompletionRequestFunctionDescription, ChatCompletionRequestToolCall},
+        models::LlmApiProvider,
+    };
+    use crate::services::utils::stream::forward_stream;
     use serde_json::json;
 
+    fn create_test_props(model: &str, max_tokens: i64) -> LlmServiceRequest {
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Gemini, model, "Hello");
+        props.request.messages.insert(0, ChatCompletionRequestMessage::System {
+            content: "You are helpful".to_string(),
+            name: None,
+        });
+        props.request.max_tokens = Some(max_tokens);
+        props.request.temperature = Some(0.7);
+        props
+    }
+
+    #[test]
+    fn test_google_stream_transport() {
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        let url = GeminiProvider::new(&props, true).build_request("test-key").unwrap().build().unwrap().url().clone();
+        assert!(url.path().ends_with(":streamGenerateContent"));
+        assert!(url.query_pairs().any(|(k, v)| k == "alt" && v == "sse"));
+        assert_eq!(stream_decoder(props.stream_transport), StreamDecoder::EventSource);
+
+        props.stream_transport = StreamTransport::JsonArray;
+        let url = GeminiProvider::new(&props, true).build_request("test-key").unwrap().build().unwrap().url().clone();
+        assert!(url.path().ends_with(":streamGenerateContent"));
+        assert!(!url.query_pairs().any(|(k, _)| k == "alt"));
+        assert_eq!(stream_decoder(props.stream_transport), StreamDecoder::JsonArray);
+    }
+
+    #[tokio::test]
+    async fn test_google_json_array_stream_error_status() {
+        use crate::services::utils::test_server::serve_once;
+
+        std::env::set_var("GOOGLE_API_KEY", "test-key");
+
+        // An error is a single JSON object, not an array, and must not reach the decoder
+        let body = json!({ "error": { "code": 400, "message": "Invalid JSON payload", "status": "INVALID_ARGUMENT" } });
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.stream_transport = StreamTransport::JsonArray;
+        props.base_url = serve_once(400, body.to_string()).await;
+
+        let (tx, _rx) = tokio::sync::mpsc::channel(8);
+        let err = GeminiProvider::new(&props, true).execute_chat_stream(tx).await.unwrap_err();
+        assert!(matches!(
+            err,
+            LlmError::ProviderError { status: 400, code: Some(code), message } if code == "INVALID_ARGUMENT" && message == "Invalid JSON payload"
+        ));
+    }
+
+    #[test]
+    fn test_google_tuned_model_url() {
+        let props = create_test_props("tunedModels/support-bot-v2-abc123", 100);
+        let url = GeminiProvider::new(&props, false).build_request("test-key").unwrap().build().unwrap().url().clone();
+        assert_eq!(url.path(), "/v1beta/tunedModels/support-bot-v2-abc123:generateContent");
+
+        let props = create_test_props("gemini-2.0-flash", 100);
+        let url = GeminiProvider::new(&props, false).build_request("test-key").unwrap().build().unwrap().url().clone();
+        assert_eq!(url.path(), "/v1beta/models/gemini-2.0-flash:generateContent");
+    }
+
+    #[test]
+    fn test_google_json_array_decoder() {
+        let body = r#"[{"candidates": [{"content": {"parts": [{"text": "He said \"hi}\""}]}}]}
+,
+{"candidates": [{"content": {"parts": [{"text": "there"}]}, "finishReason": "STOP"}]}
+]"#;
+
+        // Feed it in awkward pieces to make sure elements survive being split
+        let mut decoder = JsonArrayDecoder::default();
+        let mut elements = Vec::new();
+        for piece in body.as_bytes().chunks(7) {
+            elements.extend(decoder.push(piece).unwrap());
+        }
+
+        assert_eq!(elements.len(), 2);
+
+        let first = parse_stream_chunk(&elements[0], "fallback").unwrap();
+        assert_eq!(first.choices[0].delta.content, "He said \"hi}\"");
+
+        let second = parse_stream_chunk(&elements[1], "fallback").unwrap();
+        assert_eq!(second.choices[0].delta.content, "there");
+        assert_eq!(second.choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[test]
+    fn test_google_json_array_decoder_split_emoji() {
+        let body = r#"[{"candidates": [{"content": {"parts": [{"text": "hi 😀"}]}}]}]"#.as_bytes();
+        let emoji_start = body.windows(4).position(|w| w == "😀".as_bytes()).unwrap();
+
+        // Cut the 4 byte emoji in half across two reads
+        let mut decoder = JsonArrayDecoder::default();
+        let mut elements = decoder.push(&body[..emoji_start + 2]).unwrap();
+        assert!(elements.is_empty());
+        elements.extend(decoder.push(&body[emoji_start + 2..]).unwrap());
+
+        assert_eq!(elements.len(), 1);
+        let chunk = parse_stream_chunk(&elements[0], "fallback").unwrap();
+        assert_eq!(chunk.choices[0].delta.content, "hi 😀");
+    }
+
+    #[test]
+    fn test_google_response_modalities() {
+        let mut props = create_test_props("gemini-2.0-flash-exp", 1000);
+        props.response_modalities = vec![Modality::Text, Modality::Image];
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["generationConfig"]["responseModalities"], json!(["TEXT", "IMAGE"]));
+        assert!(body["generationConfig"].get("responseMimeType").is_none());
+
+        // Left out entirely when not asked for
+        let props = create_test_props("gemini-2.0-flash", 1000);
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert!(body["generationConfig"].get("responseModalities").is_none());
+    }
+
+    #[test]
+    fn test_google_response_schema() {
+        use crate::common::types::chat_request::{ChatCompletionRequestJsonSchema, ChatCompletionRequestResponseFormat};
+
+        let mut props = create_test_props("gemini-2.0-flash", 1000);
+        props.request.response_format = Some(ChatCompletionRequestResponseFormat {
+            format_type: "json_schema".to_string(),
+            json_schema: Some(ChatCompletionRequestJsonSchema {
+                name: "answer".to_string(),
+                strict: true,
+                schema: json!({
+                    "$schema": "http://json-schema.org/draft-07/schema#",
+                    "title": "Answer",
+                    "type": "object",
+                    "required": ["value"],
+                    "properties": { "value": { "type": ["string", "null"] } },
+                    "additionalProperties": false
+                }),
+            }),
+        });
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["generationConfig"]["responseMimeType"], "application/json");
+        assert_eq!(
+            body["generationConfig"]["responseSchema"],
+            json!({
+                "type": "object",
+                "required": ["value"],
+                "properties": { "value": { "type": "string", "nullable": true } }
+            })
+        );
+    }
+
+    #[test]
+    fn test_google_format_hint_in_system_instruction() {
+        use crate::services::types::llm_service::FormatHint;
+
+        let mut props = create_test_props("gemini-2.0-flash", 1000);
+        props.format_hint = Some(FormatHint::Markdown);
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(
+            body["systemInstruction"]["parts"][0]["text"],
+            "You are helpful\n\nFormat your answer as Markdown."
+        );
+        // The hint never turns into a turn of its own
+        assert!(!body["contents"].to_string().contains("Markdown"));
+
+        // Without a system prompt the hint becomes the whole instruction
+        props.request.messages.retain(|msg| !msg.is_system());
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "Format your answer as Markdown.");
+    }
+
+    #[test]
+    fn test_google_mixed_text_and_image_parts() {
+        let response = json!({
+            "candidates": [{
+                "content": {
+                    "parts": [
+                        { "text": "Here is a cat: " },
+                        { "inlineData": { "mimeType": "image/png", "data": "iVBORw0KGgo=" } },
+                        { "text": "Enjoy!" }
+                    ],
+                    "role": "model"
+                },
+                "finishReason": "STOP",
+                "index": 0
+            }]
+        })
+        .to_string();
+
+        let result = GeminiProvider::parse_response(&response).unwrap();
+        let message = &result.choices[0].message;
+        assert_eq!(message.content, "Here is a cat: Enjoy!");
+        assert_eq!(
+            message.parts.as_ref().unwrap(),
+            &vec![
+                LlmServiceContentPart::Text { text: "Here is a cat: ".to_string() },
+                LlmServiceContentPart::Image {
+                    mime_type: "image/png".to_string(),
+                    data: "iVBORw0KGgo=".to_string(),
+                },
+                LlmServiceContentPart::Text { text: "Enjoy!".to_string() },
+            ]
+        );
+
+        // Text-only responses don't carry parts
+        let response = json!({
+            "candidates": [{ "content": { "parts": [{ "text": "Just text" }] }, "finishReason": "STOP" }]
+        })
+        .to_string();
+        let result = GeminiProvider::parse_response(&response).unwrap();
+        assert!(result.choices[0].message.parts.is_none());
+    }
+
+    #[test]
+    fn test_google_assistant_prefill() {
+        let mut props = create_test_props("gemini-2.0-flash", 1000);
+        props.request.messages.push(ChatCompletionRequestMessage::Assistant {
+            content: "{\"colors\": [".to_string(),
+            tool_calls: None,
+            name: None,
+        });
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        let contents = body["contents"].as_array().unwrap();
+        let last = contents.last().unwrap();
+        assert_eq!(last["role"], "model");
+        assert_eq!(last["parts"][0]["text"], "{\"colors\": [");
+    }
+
+    #[test]
+    fn test_google_structured_tool_result() {
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.request.messages.push(ChatCompletionRequestMessage::Tool {
+            content: json!({ "temp_c": 18, "conditions": ["cloudy", "windy"] }),
+            tool_call_id: "call_1".to_string(),
+            name: Some("get_weather".to_string()),
+        });
+        props.request.messages.push(ChatCompletionRequestMessage::Tool {
+            content: "sunny".to_string().into(),
+            tool_call_id: "call_2".to_string(),
+            name: Some("get_forecast".to_string()),
+        });
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        let contents = body["contents"].as_array().unwrap();
+
+        // Objects are embedded as the response itself
+        let response = &contents[1]["parts"][0]["functionResponse"];
+        assert_eq!(response["name"], "get_weather");
+        assert_eq!(response["response"], json!({ "temp_c": 18, "conditions": ["cloudy", "windy"] }));
+
+        // Plain strings are still wrapped
+        let response = &contents[2]["parts"][0]["functionResponse"];
+        assert_eq!(response["response"], json!({ "content": "sunny" }));
+    }
+
+    #[test]
+    fn test_google_tool_round_trip_roles() {
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.request.messages.push(ChatCompletionRequestMessage::Assistant {
+            content: String::new(),
+            tool_calls: Some(vec![ChatCompletionRequestToolCall {
+                id: "call_1".to_string(),
+                kind: "function".to_string(),
+                function_call: ChatCompletionRequestFunctionCall {
+                    name: "get_weather".to_string(),
+                    arguments: r#"{"city":"Paris"}"#.to_string(),
+                },
+            }]),
+            name: None,
+        });
+        props.request.messages.push(ChatCompletionRequestMessage::Tool {
+            content: json!({ "temp_c": 18 }),
+            tool_call_id: "call_1".to_string(),
+            name: None,
+        });
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        let contents = body["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+
+        // The call is the only part of a model turn, with its arguments as an object
+        assert_eq!(contents[1]["role"], "model");
+        assert_eq!(
+            contents[1]["parts"],
+            json!([{ "functionCall": { "name": "get_weather", "args": { "city": "Paris" } } }])
+        );
+
+        // The result answers it by the call's name
+        assert_eq!(contents[2]["role"], "user");
+        assert_eq!(
+            contents[2]["parts"],
+            json!([{ "functionResponse": { "name": "get_weather", "response": { "temp_c": 18 } } }])
+        );
+    }
+
+    #[test]
+    fn test_google_function_declarations() {
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.request.tools = Some(vec![
+            ChatCompletionRequestTool::Function {
+                function: ChatCompletionRequestFunctionDescription {
+                    name: "get_weather".to_string(),
+                    description: Some("Current weather of a city".to_string()),
+                    parameters: json!({
+                        "type": "object",
+                        "properties": { "city": { "type": "string" } },
+                        "required": ["city"]
+                    }),
+                },
+            },
+            ChatCompletionRequestTool::Function {
+                function: ChatCompletionRequestFunctionDescription {
+                    name: "get_time".to_string(),
+                    description: None,
+                    parameters: json!({ "type": "object", "properties": {} }),
+                },
+            },
+        ]);
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        let declarations = &body["tools"][0]["functionDeclarations"];
+        assert_eq!(declarations[0]["name"], "get_weather");
+        assert_eq!(declarations[0]["description"], "Current weather of a city");
+        assert_eq!(declarations[0]["parameters"]["properties"]["city"]["type"], "string");
+        assert_eq!(declarations[1], json!({ "name": "get_time" }));
+
+        props.request.tools = None;
+        assert!(GeminiProvider::new(&props, false).create_body().get("tools").is_none());
+    }
+
+    #[test]
+    fn test_google_function_call_response() {
+        let response = json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [
+                    { "functionCall": { "name": "get_weather", "args": { "city": "Paris" } } },
+                    { "functionCall": { "id": "fc-2", "name": "get_time", "args": {} } }
+                ]},
+                "finishReason": "STOP"
+            }]
+        })
+        .to_string();
+
+        let response = GeminiProvider::parse_response(&response).unwrap();
+        let tool_calls = response.choices[0].message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "call_0");
+        assert_eq!(tool_calls[0].function_call.name, "get_weather");
+        assert_eq!(tool_calls[0].function_call.arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(tool_calls[1].id, "fc-2");
+        assert_eq!(tool_calls[1].function_call.arguments, "{}");
+        assert_eq!(response.choices[0].message.content, "");
+
+        let chunk = parse_stream_chunk(&json!({
+            "candidates": [{ "content": { "parts": [
+                { "functionCall": { "name": "get_weather", "args": { "city": "Paris" } } }
+            ]}}]
+        }).to_string(), "fallback").unwrap();
+        let deltas = chunk.choices[0].delta.tool_calls.as_ref().unwrap();
+        assert_eq!(deltas[0].index, 0);
+        assert_eq!(deltas[0].function.as_ref().unwrap().name.as_deref(), Some("get_weather"));
+        assert_eq!(deltas[0].function.as_ref().unwrap().arguments.as_deref(), Some(r#"{"city":"Paris"}"#));
+    }
+
+    #[test]
+    fn test_google_penalties_for_gemini_2() {
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.request.frequency_penalty = Some(0.5);
+        props.request.presence_penalty = Some(3.0);
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["generationConfig"]["frequencyPenalty"], 0.5);
+        assert_eq!(body["generationConfig"]["presencePenalty"], 2.0);
+        assert!(body.get("frequency_penalty").is_none());
+    }
+
+    #[test]
+    fn test_google_penalties_omitted() {
+        let mut props = create_test_props("gemini-1.5-flash", 100);
+        props.request.frequency_penalty = Some(0.5);
+        props.request.presence_penalty = Some(0.5);
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        let config = body["generationConfig"].as_object().unwrap();
+        assert!(!config.contains_key("frequencyPenalty"));
+        assert!(!config.contains_key("presencePenalty"));
+        assert!(body.get("frequency_penalty").is_none());
+    }
+
+    #[test]
+    fn test_google_compressed_request() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.compress_request = true;
+        let provider = GeminiProvider::new(&props, false);
+        let request = provider.build_request("test-key").unwrap().build().unwrap();
+
+        assert_eq!(request.headers()["content-encoding"], "gzip");
+        assert_eq!(request.headers()["content-type"], "application/json");
+
+        let mut json = String::new();
+        GzDecoder::new(request.body().unwrap().as_bytes().unwrap()).read_to_string(&mut json).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(body, provider.create_body());
+    }
+
+    #[test]
+    fn test_google_max_tokens_clamped_to_model_limit() {
+        let props = create_test_props("gemini-2.0-flash", 100_000);
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 8192);
+
+        // Unknown models are sent whatever was asked for
+        let props = create_test_props("gemini-exp-1206", 100_000);
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 100_000);
+    }
+
     #[test]
     fn test_google_response_parsing() {
         let response = json!({
@@ -338,9 +1420,498 @@ mod tests {
         .to_string();
 
         let result = GeminiProvider::parse_response(&response).unwrap();
-        let result: LlmApiResponseProps = result.into();
-        assert_eq!(result.response_content, "test response");
-        assert_eq!(result.input_tokens, Some(50));
-        assert_eq!(result.output_tokens, Some(20));
+        assert_eq!(result.choices[0].message.content, "test response");
+        assert_eq!(result.choices[0].finish_reason.as_deref(), Some("stop"));
+
+        let usage = result.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 50);
+        assert_eq!(usage.completion_tokens, 20);
+    }
+
+    #[test]
+    fn test_google_chat_request_conversion() {
+        let chat = ChatRequest {
+            messages: vec![
+                ChatCompletionRequestMessage::System { content: "Be brief".to_string(), name: None },
+                ChatCompletionRequestMessage::User { content: "What is 2 + 2?".to_string(), name: None },
+            ],
+            params: ChatParams {
+                max_tokens: Some(64),
+                temperature: Some(0.2),
+                ..Default::default()
+            },
+            tools: None,
+        };
+        let props = LlmServiceRequest::from_chat_request(LlmApiProvider::Gemini, "gemini-2.0-flash", chat);
+        assert_eq!(props.chat_request().params.max_tokens, Some(64));
+
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "Be brief");
+        assert_eq!(body["contents"], json!([{ "role": "user", "parts": [{ "text": "What is 2 + 2?" }] }]));
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 64);
+        assert_eq!(body["generationConfig"]["temperature"], 0.2);
+
+        let response = json!({
+            "candidates": [{ "content": { "parts": [{ "text": "4" }] }, "finishReason": "MAX_TOKENS", "index": 0 }],
+            "usageMetadata": { "promptTokenCount": 8, "candidatesTokenCount": 1, "totalTokenCount": 9 }
+        })
+        .to_string();
+
+        let response = ChatResponse::try_from(GeminiProvider::parse_response(&response).unwrap()).unwrap();
+        assert_eq!(response.text, "4");
+        assert!(response.tool_calls.is_empty());
+        assert_eq!(response.finish_reason.as_deref(), Some("length"));
+        assert_eq!(response.usage.unwrap().total_tokens, 9);
+    }
+
+    #[test]
+    fn test_google_blocked_prompt() {
+        // Blocked prompts come back without any candidates
+        let response = json!({
+            "promptFeedback": {
+                "blockReason": "SAFETY",
+                "safetyRatings": [{
+                    "category": "HARM_CATEGORY_DANGEROUS_CONTENT",
+                    "probability": "HIGH"
+                }]
+            },
+            "usageMetadata": {
+                "promptTokenCount": 12,
+                "totalTokenCount": 12
+            }
+        })
+        .to_string();
+
+        let result = GeminiProvider::parse_response(&response);
+        match result {
+            Err(LlmError::Provider(msg)) => assert_eq!(msg, "prompt blocked: SAFETY"),
+            other => panic!("Expected a prompt blocked error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_google_safety_ratings() {
+        let response = json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": "I can't help with that." }] },
+                "finishReason": "SAFETY",
+                "index": 0,
+                "safetyRatings": [
+                    { "category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE" },
+                    { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "HIGH", "blocked": true },
+                    { "category": "HARM_CATEGORY_JAILBREAK", "probability": "MEDIUM" }
+                ]
+            }],
+            "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 6, "totalTokenCount": 16 }
+        })
+        .to_string();
+
+        let result = GeminiProvider::parse_response(&response).unwrap();
+        let choice = &result.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("content_filter"));
+        assert_eq!(
+            choice.safety_ratings,
+            [
+                SafetyRating { category: HarmCategory::Harassment, probability: HarmProbability::Negligible, blocked: false },
+                SafetyRating { category: HarmCategory::DangerousContent, probability: HarmProbability::High, blocked: true },
+                SafetyRating {
+                    category: HarmCategory::Other("HARM_CATEGORY_JAILBREAK".to_string()),
+                    probability: HarmProbability::Medium,
+                    blocked: false,
+                },
+            ]
+        );
+
+        let serialized = serde_json::to_value(&choice.safety_ratings[1]).unwrap();
+        assert_eq!(serialized, json!({ "category": "dangerous_content", "probability": "high", "blocked": true }));
+
+        let chat = ChatResponse::try_from(result).unwrap();
+        assert_eq!(chat.safety_ratings.len(), 3);
+    }
+
+    #[test]
+    fn test_google_model_version() {
+        let with_version = json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi" }] }, "finishReason": "STOP" }],
+            "modelVersion": "gemini-2.0-flash-001",
+            "responseId": "resp-1"
+        });
+        let result = GeminiProvider::parse_response(&with_version.to_string()).unwrap();
+        assert_eq!(result.model_version.as_deref(), Some("gemini-2.0-flash-001"));
+        assert_eq!(ChatResponse::try_from(result).unwrap().model_version.as_deref(), Some("gemini-2.0-flash-001"));
+
+        let without = json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi" }] }, "finishReason": "STOP" }]
+        });
+        assert_eq!(GeminiProvider::parse_response(&without.to_string()).unwrap().model_version, None);
+    }
+
+    #[test]
+    fn test_google_grounding_citations() {
+        let text = "Ada Lovelace wrote the first program. She worked with Charles Babbage.";
+        let response = json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": text }] },
+                "finishReason": "STOP",
+                "index": 0,
+                "groundingMetadata": {
+                    "webSearchQueries": ["ada lovelace"],
+                    "groundingChunks": [
+                        { "web": { "uri": "https://example.com/ada", "title": "example.com" } },
+                        { "web": { "uri": "https://example.org/babbage", "title": "example.org" } }
+                    ],
+                    "groundingSupports": [
+                        {
+                            "segment": { "endIndex": 37, "text": "Ada Lovelace wrote the first program." },
+                            "groundingChunkIndices": [0],
+                            "confidenceScores": [0.97]
+                        },
+                        {
+                            "segment": { "startIndex": 38, "endIndex": 70, "text": "She worked with Charles Babbage." },
+                            "groundingChunkIndices": [1],
+                            "confidenceScores": [0.91]
+                        }
+                    ]
+                }
+            }]
+        })
+        .to_string();
+
+        let result = GeminiProvider::parse_response(&response).unwrap();
+        let choice = &result.choices[0];
+        assert_eq!(choice.message.content, text);
+        assert_eq!(
+            choice.annotations,
+            [
+                Annotation { start: 0, end: 37, uri: "https://example.com/ada".to_string(), title: Some("example.com".to_string()) },
+                Annotation {
+                    start: 38,
+                    end: 70,
+                    uri: "https://example.org/babbage".to_string(),
+                    title: Some("example.org".to_string()),
+                },
+            ]
+        );
+        assert_eq!(&text[choice.annotations[1].start..choice.annotations[1].end], "She worked with Charles Babbage.");
+
+        let chat = ChatResponse::try_from(result).unwrap();
+        assert_eq!(chat.annotations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_google_stream_safety_block() {
+        let chunks = vec![
+            json!({
+                "candidates": [{
+                    "content": { "parts": [{ "text": "Here is how" }], "role": "model" },
+                    "index": 0
+                }],
+                "responseId": "resp-1"
+            }),
+            json!({
+                "candidates": [{
+                    "content": { "parts": [], "role": "model" },
+                    "finishReason": "SAFETY",
+                    "index": 0,
+                    "safetyRatings": [
+                        { "category": "HARM_CATEGORY_HARASSMENT", "probability": "LOW" },
+                        { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "HIGH", "blocked": true }
+                    ]
+                }],
+                "responseId": "resp-1"
+            }),
+        ];
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(20);
+        let stream = futures_util::stream::iter(
+            chunks.into_iter().map(|c| parse_stream_chunk(&c.to_string(), "fallback")),
+        );
+
+        forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            match event.unwrap() {
+                StreamEvent::FinishReason(reason) => events.push(format!("finish:{}", reason)),
+                StreamEvent::SafetyBlock { category } => events.push(format!("safety:{}", category)),
+                StreamEvent::Chunk(c) if c.is_done_sentinel() => events.push("done".to_string()),
+                _ => {}
+            }
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                "finish:content_filter".to_string(),
+                "safety:HARM_CATEGORY_DANGEROUS_CONTENT".to_string(),
+                "done".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_google_stream_interleaved_candidates() {
+        let candidate = |index: u32, text: &str, finish_reason: Option<&str>| {
+            let mut candidate = json!({ "content": { "parts": [{ "text": text }], "role": "model" }, "index": index });
+            if let Some(reason) = finish_reason {
+                candidate["finishReason"] = json!(reason);
+            }
+            json!({ "candidates": [candidate], "responseId": "resp-3" })
+        };
+        let chunks = vec![
+            candidate(0, "Red", None),
+            candidate(1, "Blue", None),
+            candidate(1, " sky", Some("STOP")),
+            candidate(0, " apple", Some("STOP")),
+        ];
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(20);
+        let stream = futures_util::stream::iter(
+            chunks.into_iter().map(|c| parse_stream_chunk(&c.to_string(), "fallback")),
+        );
+
+        let summary = forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let mut tokens = Vec::new();
+        while let Some(event) = rx.recv().await {
+            if let StreamEvent::Chunk(c) = event.unwrap() {
+                if !c.is_done_sentinel() {
+                    tokens.extend(c.choices.iter().map(|choice| (choice.index, choice.delta.content.clone())));
+                }
+            }
+        }
+
+        assert_eq!(
+            tokens,
+            vec![
+                (0, "Red".to_string()),
+                (1, "Blue".to_string()),
+                (1, " sky".to_string()),
+                (0, " apple".to_string()),
+            ]
+        );
+        assert_eq!(summary.content, "Red apple");
+        assert_eq!(summary.choice_contents[&0], "Red apple");
+        assert_eq!(summary.choice_contents[&1], "Blue sky");
+    }
+
+    #[tokio::test]
+    async fn test_google_stream_candidate_finish_reasons_and_calls() {
+        let chunks = vec![
+            json!({ "candidates": [
+                { "content": { "parts": [{ "text": "Sunny" }], "role": "model" }, "index": 0 },
+                { "content": { "parts": [{ "functionCall": { "name": "get_weather", "args": { "city": "Paris" } } }], "role": "model" }, "index": 1 }
+            ], "responseId": "resp-4" }),
+            json!({ "candidates": [
+                { "content": { "parts": [{ "text": "It is" }], "role": "model" }, "finishReason": "MAX_TOKENS", "index": 1 }
+            ], "responseId": "resp-4" }),
+            json!({ "candidates": [
+                { "content": { "parts": [{ "text": " today" }], "role": "model" }, "finishReason": "STOP", "index": 0 }
+            ], "responseId": "resp-4" }),
+        ];
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(20);
+        let stream = futures_util::stream::iter(
+            chunks.into_iter().map(|c| parse_stream_chunk(&c.to_string(), "fallback")),
+        );
+
+        let summary = forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+        while rx.recv().await.is_some() {}
+
+        assert_eq!(summary.finish_reason.as_deref(), Some("stop"));
+        assert!(summary.tool_calls.is_empty());
+        assert_eq!(summary.choice_finish_reasons[&1], "length");
+        assert_eq!(summary.choice_contents[&1], "It is");
+        let calls = &summary.choice_tool_calls[&1];
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function_call.name, "get_weather");
+        assert_eq!(calls[0].function_call.arguments, r#"{"city":"Paris"}"#);
+    }
+
+    #[test]
+    fn test_google_candidate_count() {
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.candidate_count = Some(2);
+
+        let body = GeminiProvider::new(&props, true).create_body();
+        assert_eq!(body["generationConfig"]["candidateCount"], 2);
+    }
+
+    #[test]
+    fn test_google_multiple_text_parts() {
+        let response = json!({
+            "candidates": [{
+                "content": {
+                    "role": "model",
+                    "parts": [
+                        { "text": "The capital of France " },
+                        { "text": "Checking the second half.", "thought": true },
+                        { "text": "is Paris." }
+                    ]
+                },
+                "finishReason": "STOP"
+            }]
+        })
+        .to_string();
+
+        let result = GeminiProvider::parse_response(&response).unwrap();
+        assert_eq!(result.choices[0].message.content, "The capital of France is Paris.");
+        assert_eq!(result.choices[0].message.reasoning.as_deref(), Some("Checking the second half."));
+    }
+
+    #[test]
+    fn test_google_include_thoughts() {
+        let mut props = create_test_props("gemini-2.5-flash", 100);
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert!(body["generationConfig"].get("thinkingConfig").is_none());
+
+        props.include_thoughts = true;
+        let body = GeminiProvider::new(&props, false).create_body();
+        assert_eq!(body["generationConfig"]["thinkingConfig"], json!({ "includeThoughts": true }));
+
+        let response = json!({
+            "candidates": [{
+                "content": {
+                    "role": "model",
+                    "parts": [
+                        { "text": "The user wants a number between 1 and 10.", "thought": true },
+                        { "text": "7" }
+                    ]
+                },
+                "finishReason": "STOP"
+            }]
+        })
+        .to_string();
+
+        let result = GeminiProvider::parse_response(&response).unwrap();
+        let message = &result.choices[0].message;
+        assert_eq!(message.content, "7");
+        assert_eq!(message.reasoning.as_deref(), Some("The user wants a number between 1 and 10."));
+
+        let chat = ChatResponse::try_from(result).unwrap();
+        assert_eq!(chat.text, "7");
+        assert_eq!(chat.reasoning.as_deref(), Some("The user wants a number between 1 and 10."));
+    }
+
+    #[tokio::test]
+    async fn test_google_stream_thinking_blocks() {
+        let chunks = vec![
+            json!({
+                "candidates": [{
+                    "content": { "parts": [{ "text": "Weighing the options.", "thought": true }], "role": "model" },
+                    "index": 0
+                }],
+                "responseId": "resp-2"
+            }),
+            json!({
+                "candidates": [{
+                    "content": {
+                        "parts": [
+                            { "text": " Settled.", "thought": true },
+                            { "text": "Go with B." }
+                        ],
+                        "role": "model"
+                    },
+                    "finishReason": "STOP",
+                    "index": 0
+                }],
+                "responseId": "resp-2"
+            }),
+        ];
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(20);
+        let stream = futures_util::stream::iter(
+            chunks.into_iter().map(|c| parse_stream_chunk(&c.to_string(), "fallback")),
+        );
+
+        let summary = forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        // Thoughts never leak into the answer
+        assert_eq!(summary.content, "Go with B.");
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            match event.unwrap() {
+                StreamEvent::BlockStart { kind } => events.push(format!("start:{}", kind.as_str())),
+                StreamEvent::BlockStop => events.push("stop".to_string()),
+                StreamEvent::FinishReason(reason) => events.push(format!("finish:{}", reason)),
+                _ => {}
+            }
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                "start:thinking".to_string(),
+                "stop".to_string(),
+                "start:text".to_string(),
+                "finish:stop".to_string(),
+                "stop".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_google_completion_record_and_replay() {
+        use crate::services::utils::{
+            cassette::{Cassette, CassetteMode},
+            test_server::serve_once,
+        };
+
+        std::env::set_var("GOOGLE_API_KEY", "test-key");
+
+        let body = json!({
+            "candidates": [{
+                "content": { "parts": [{ "text": "Recorded answer" }], "role": "model" },
+                "finishReason": "STOP",
+                "index": 0
+            }],
+            "usageMetadata": { "promptTokenCount": 4, "candidatesTokenCount": 2, "totalTokenCount": 6 },
+            "responseId": "resp-rec"
+        });
+
+        let mut props = create_test_props("gemini-2.0-flash", 100);
+        props.base_url = serve_once(200, body.to_string()).await;
+
+        let path = std::env::temp_dir().join(format!("gemini-cassette-{}.json", uuid::Uuid::new_v4()));
+
+        // First run goes to the server and records the exchange
+        let cassette = Cassette::open(&path).unwrap();
+        assert_eq!(cassette.mode(), CassetteMode::Record);
+        let recorded = cassette
+            .run(GeminiProvider::new(&props, false).execute_chat())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(recorded.choices[0].message.content, "Recorded answer");
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("test-key"));
+
+        // The mock server only answers once, so this can only come from the cassette
+        let cassette = Cassette::open(&path).unwrap();
+        assert_eq!(cassette.mode(), CassetteMode::Replay);
+        let replayed = cassette
+            .run(GeminiProvider::new(&props, false).execute_chat())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(replayed.id, recorded.id);
+        assert_eq!(replayed.choices[0].message.content, "Recorded answer");
+        assert_eq!(replayed.usage.unwrap().total_tokens, 6);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_google_empty_response_without_block_reason() {
+        let response = json!({ "candidates": [] }).to_string();
+
+        let result = GeminiProvider::parse_response(&response);
+        assert!(matches!(result, Err(LlmError::EmptyResponse)));
     }
 }
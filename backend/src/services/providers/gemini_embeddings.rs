@@ -0,0 +1,186 @@
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::common::types::models::LlmApiProvider;
+use crate::services::types::llm_error::LlmError;
+use crate::services::utils::{
+    cassette::send,
+    client::{error_for_status, shared_client},
+    key_pool::key_pool,
+};
+
+/// Most texts Gemini accepts in one `batchEmbedContents` call.
+pub const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize, Debug)]
+struct BatchEmbedResponse {
+    #[serde(default)]
+    embeddings: Vec<Embedding>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Embedding {
+    values: Vec<f32>,
+}
+
+/// Text embeddings through Gemini's `batchEmbedContents`. Inputs over the batch size
+/// are split into several calls and the vectors come back in input order.
+#[derive(Debug, Clone)]
+pub struct GeminiEmbedder {
+    base_url: String,
+    model: String,
+    batch_size: usize,
+    concurrency: usize,
+}
+
+impl GeminiEmbedder {
+    pub fn new(model: &str) -> Self {
+        GeminiEmbedder {
+            base_url: LlmApiProvider::Gemini.default_base_url().to_string(),
+            model: model.to_string(),
+            batch_size: MAX_BATCH_SIZE,
+            concurrency: 1,
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    /// Texts per call, capped at [`MAX_BATCH_SIZE`].
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.clamp(1, MAX_BATCH_SIZE);
+        self
+    }
+
+    /// How many batches are in flight at once, one by default.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// One vector per text, in the order of `texts`. If any batch fails the whole call
+    /// fails, naming the batch and the range of inputs it covered.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let batches: Vec<&[String]> = texts.chunks(self.batch_size).collect();
+        let total = batches.len();
+
+        let results: Vec<Vec<Vec<f32>>> = stream::iter(batches.into_iter().enumerate())
+            .map(|(i, batch)| async move {
+                self.embed_batch(batch).await.map_err(|e| {
+                    let start = i * self.batch_size;
+                    LlmError::Provider(format!(
+                        "embedding batch {}/{} (inputs {}..{}) failed: {}",
+                        i + 1,
+                        total,
+                        start,
+                        start + batch.len(),
+                        e
+                    ))
+                })
+            })
+            // `buffered` keeps the batches in order while up to `concurrency` of them run
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        let model = format!("models/{}", self.model);
+        let requests: Vec<_> = texts
+            .iter()
+            .map(|text| json!({ "model": model, "content": { "parts": [{ "text": text }] } }))
+            .collect();
+
+        let api_key = key_pool(&LlmApiProvider::Gemini)?.next_key();
+        let request = shared_client()
+            .post(format!("{}/{}:batchEmbedContents", self.base_url, model))
+            .query(&[("key", &api_key.key)])
+            .json(&json!({ "requests": requests }));
+
+        let response = send(request).await?;
+        api_key.bench_if_rate_limited(&response);
+        let response: BatchEmbedResponse = error_for_status(response).await?.json().await?;
+
+        if response.embeddings.len() != texts.len() {
+            return Err(LlmError::Provider(format!(
+                "expected {} embeddings, got {}",
+                texts.len(),
+                response.embeddings.len()
+            )));
+        }
+        Ok(response.embeddings.into_iter().map(|e| e.values).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::utils::test_server::serve_with;
+
+    // Embeds every text, a number, as a one element vector holding that number
+    fn embedding_server_response(body: &str) -> (u16, String) {
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        let requests = body["requests"].as_array().unwrap();
+        if requests.len() > 100 {
+            return (400, r#"{"error":"at most 100 requests per batch"}"#.to_string());
+        }
+
+        let embeddings: Vec<_> = requests
+            .iter()
+            .map(|r| {
+                let n: f32 = r["content"]["parts"][0]["text"].as_str().unwrap().parse().unwrap();
+                json!({ "values": [n] })
+            })
+            .collect();
+        (200, json!({ "embeddings": embeddings }).to_string())
+    }
+
+    fn numbered_texts(n: usize) -> Vec<String> {
+        (0..n).map(|i| i.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_embed_splits_oversized_batches() {
+        std::env::set_var("GOOGLE_API_KEY", "test-key");
+        let base_url = serve_with(embedding_server_response).await;
+
+        let embedder = GeminiEmbedder::new("text-embedding-004")
+            .with_base_url(&base_url)
+            .with_batch_size(100)
+            .with_concurrency(3);
+
+        let embeddings = embedder.embed(&numbered_texts(250)).await.unwrap();
+        assert_eq!(embeddings.len(), 250);
+        for (i, embedding) in embeddings.iter().enumerate() {
+            assert_eq!(embedding, &vec![i as f32]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_embed_failed_batch_fails_the_call() {
+        std::env::set_var("GOOGLE_API_KEY", "test-key");
+        let base_url = serve_with(|body| {
+            if body.contains(r#""text":"100""#) {
+                return (500, r#"{"error":"internal"}"#.to_string());
+            }
+            embedding_server_response(body)
+        })
+        .await;
+
+        let embedder = GeminiEmbedder::new("text-embedding-004").with_base_url(&base_url);
+
+        let err = embedder.embed(&numbered_texts(250)).await.unwrap_err();
+        assert!(matches!(err, LlmError::Provider(msg) if msg.starts_with("embedding batch 2/3 (inputs 100..200)")));
+    }
+
+    #[tokio::test]
+    async fn test_embed_empty_input() {
+        let embedder = GeminiEmbedder::new("text-embedding-004").with_base_url("http://127.0.0.1:9");
+        assert!(embedder.embed(&[]).await.unwrap().is_empty());
+    }
+}
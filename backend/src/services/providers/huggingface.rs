@@ -0,0 +1,166 @@
+use crate::common::types::chat_response::LlmServiceChatCompletionResponse;
+use crate::common::types::models::LlmApiProvider;
+use crate::services::types::{
+    llm_error::LlmError, llm_error::LlmStreamingError, llm_service::LlmServiceRequest, stream::StreamEvent
+};
+
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+
+use std::time::Duration;
+
+use super::openai_compatible::OpenaiCompatibleProvider;
+
+/// How often a request is retried while the model is still being loaded.
+const MAX_LOADING_RETRIES: u32 = 3;
+/// Longest single wait for a loading model, whatever `estimated_time` says.
+const MAX_LOADING_WAIT: Duration = Duration::from_secs(60);
+
+/// Body of the 503 sent while a model is loaded onto an inference server.
+#[derive(Deserialize, Debug)]
+struct ModelLoading {
+    estimated_time: f64,
+}
+
+/// Hugging Face Inference, through the OpenAi compatible router at
+/// `https://router.huggingface.co/v1`. Model ids are Hub repo ids (`meta-llama/...`),
+/// optionally suffixed with the serving provider (`...:together`).
+///
+/// A model that isn't loaded yet is answered with a 503 and its `estimated_time`; regular
+/// requests wait that long and try again, streams fail right away.
+pub struct HuggingFaceProvider<'a> {
+    inner: OpenaiCompatibleProvider<'a>,
+}
+
+impl<'a> HuggingFaceProvider<'a> {
+    pub fn new(props: &'a LlmServiceRequest, streaming: bool) -> Self {
+        HuggingFaceProvider {
+            inner: OpenaiCompatibleProvider::new(props, streaming, LlmApiProvider::HuggingFace),
+        }
+    }
+
+    pub(crate) fn build_request(&self, api_key: &str) -> Result<RequestBuilder, LlmError> {
+        self.inner.build_request(api_key)
+    }
+
+    pub async fn execute_chat(&self) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let mut retries = 0;
+        loop {
+            match self.inner.execute_chat().await {
+                Err(LlmError::ServerError { status: 503, body }) if retries < MAX_LOADING_RETRIES => {
+                    let Some(wait) = loading_wait(&body) else {
+                        return Err(LlmError::ServerError { status: 503, body });
+                    };
+                    tracing::info!("Model is loading, retrying in {:?}", wait);
+                    tokio::time::sleep(wait).await;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn execute_chat_stream(
+        &self,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        self.inner.execute_chat_stream(tx).await
+    }
+}
+
+/// How long to wait before retrying, `None` when the 503 isn't about a loading model.
+fn loading_wait(body: &str) -> Option<Duration> {
+    let loading: ModelLoading = serde_json::from_str(body).ok()?;
+    if !loading.estimated_time.is_finite() || loading.estimated_time < 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(loading.estimated_time).min(MAX_LOADING_WAIT))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::models::LlmApiProvider;
+    use crate::services::utils::test_server::serve_with;
+    use serde_json::json;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Instant;
+
+    fn create_test_props(base_url: &str) -> LlmServiceRequest {
+        let mut props =
+            LlmServiceRequest::from_user_prompt(LlmApiProvider::HuggingFace, "meta-llama/Llama-3.1-8B-Instruct", "Hello");
+        props.base_url = base_url.to_string();
+        props
+    }
+
+    fn completion_body() -> String {
+        json!({
+            "id": "hf-1",
+            "object": "chat.completion",
+            "created": 1741000000,
+            "model": "meta-llama/Llama-3.1-8B-Instruct",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hi there" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 9, "completion_tokens": 2, "total_tokens": 11 }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_huggingface_loading_wait() {
+        let body = r#"{"error":"Model meta-llama/Llama-3.1-8B-Instruct is currently loading","estimated_time":20.5}"#;
+        assert_eq!(loading_wait(body), Some(Duration::from_millis(20_500)));
+        assert_eq!(loading_wait(r#"{"estimated_time":600.0}"#), Some(MAX_LOADING_WAIT));
+        assert_eq!(loading_wait(r#"{"error":"Service unavailable"}"#), None);
+    }
+
+    #[tokio::test]
+    async fn test_huggingface_retries_while_model_loads() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let counted = calls.clone();
+        let base_url = serve_with(move |_| {
+            if counted.fetch_add(1, Ordering::SeqCst) < 2 {
+                return (503, r#"{"error":"Model is currently loading","estimated_time":0.05}"#.to_string());
+            }
+            (200, completion_body())
+        })
+        .await;
+
+        let props = create_test_props(&base_url);
+        let started = Instant::now();
+        let response = HuggingFaceProvider::new(&props, false).execute_chat().await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "Hi there");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_huggingface_other_503_not_retried() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let counted = calls.clone();
+        let base_url = serve_with(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            (503, r#"{"error":"Service unavailable"}"#.to_string())
+        })
+        .await;
+
+        let props = create_test_props(&base_url);
+        let err = HuggingFaceProvider::new(&props, false).execute_chat().await.unwrap_err();
+
+        assert!(matches!(err, LlmError::ServerError { status: 503, .. }));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
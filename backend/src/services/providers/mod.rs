@@ -1 +1,8 @@
+pub mod gemini;
+pub mod gemini_embeddings;
+pub mod huggingface;
+pub mod openai_compatible;
 pub mod openrouter;
+pub mod registry;
+pub mod replicate;
+pub mod vertex_gemini;
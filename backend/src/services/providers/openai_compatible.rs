@@ -0,0 +1,684 @@
+use crate::common::types::chat_request::ChatCompletionRequestMessage;
+use crate::common::types::models::LlmApiProvider;
+use crate::common::types::chat_response::{
+    LlmServiceChatCompletionChunk, LlmServiceChatCompletionResponse,
+    LlmServiceChatCompletionResponseChoice, LlmServiceChatCompletionResponseMessage,
+    LlmServiceChatCompletionResponseToolCall, LlmServiceChatCompletionResponseUsage,
+    LlmServiceChoiceStream, LlmServiceStreamDelta, LlmServiceToolCallDelta, LlmServiceUsage,
+};
+use crate::services::types::{
+    llm_error::LlmError, llm_error::LlmStreamingError, llm_service::LlmServiceRequest, stream::StreamEvent
+};
+use crate::services::utils::{
+    canonical_json::canonical_json,
+    cassette::send,
+    client::{
+        accepts_gzip, error_for_status, json_body, rate_limit_from_headers, read_body, shared_client, ByteBudget,
+        RESPONSE_TOO_LARGE,
+    },
+    key_pool::key_pool,
+    model_limits::{clamp_max_tokens, clamp_penalty},
+    stream::{forward_stream_with, stream_error_message, StreamControl},
+};
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use reqwest::RequestBuilder;
+use reqwest_eventsource::{Event, EventSource};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc::Sender;
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Shared implementation for providers that expose an OpenAi compatible
+/// `/chat/completions` endpoint. Each provider only says which provider it is, so its
+/// keys come from the [credential provider](crate::services::utils::credentials) and are
+/// rotated through a [`KeyPool`](crate::services::utils::key_pool::KeyPool), plus any
+/// headers or body fields of its own.
+///
+/// Grok, Together, Fireworks and Groq are served by it directly, at their
+/// [`default_base_url`](LlmApiProvider::default_base_url). Model ids are sent exactly as
+/// configured, e.g. Together's `meta-llama/...` or Fireworks' `accounts/fireworks/models/...`.
+pub struct OpenaiCompatibleProvider<'a> {
+    props: &'a LlmServiceRequest,
+    streaming: bool,
+    provider: LlmApiProvider,
+    headers: Vec<(&'static str, String)>,
+    extra_body: Map<String, Value>,
+}
+
+impl<'a> OpenaiCompatibleProvider<'a> {
+    pub fn new(props: &'a LlmServiceRequest, streaming: bool, provider: LlmApiProvider) -> Self {
+        OpenaiCompatibleProvider {
+            props,
+            streaming,
+            provider,
+            headers: Vec::new(),
+            extra_body: Map::new(),
+        }
+    }
+
+    /// Sends `name: value` with every request.
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Adds a top level field to the request body, for extensions of the OpenAi format.
+    pub fn with_body_field(mut self, name: &str, value: Value) -> Self {
+        self.extra_body.insert(name.to_string(), value);
+        self
+    }
+}
+
+
+#[derive(Deserialize, Debug)]
+struct OpenaiCompatibleResponse {
+    id: String,
+    created: Option<i64>,
+    model: Option<String>,
+    // set by routers, naming the upstream that served the request
+    provider: Option<String>,
+    #[serde(default)]
+    choices: Vec<OpenaiCompatibleChoice>,
+    usage: Option<OpenaiCompatibleUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenaiCompatibleChoice {
+    message: OpenaiCompatibleMessage,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenaiCompatibleMessage {
+    role: Option<String>,
+    // null when the assistant only made tool calls
+    content: Option<String>,
+    tool_calls: Option<Vec<LlmServiceChatCompletionResponseToolCall>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenaiCompatibleUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    total_tokens: u32,
+}
+
+// STREAMING RESPONSE
+#[derive(Deserialize, Debug)]
+struct OpenaiCompatibleStreamChunk {
+    id: Option<String>,
+    #[serde(default)]
+    choices: Vec<OpenaiCompatibleStreamChoice>,
+    // only sent on the last chunk when `stream_options.include_usage` is set
+    usage: Option<OpenaiCompatibleUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenaiCompatibleStreamChoice {
+    #[serde(default)]
+    index: u32,
+    delta: OpenaiCompatibleStreamDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenaiCompatibleStreamDelta {
+    role: Option<String>,
+    content: Option<String>,
+    tool_calls: Option<Vec<LlmServiceToolCallDelta>>,
+}
+
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
+impl From<OpenaiCompatibleUsage> for LlmServiceUsage {
+    fn from(usage: OpenaiCompatibleUsage) -> Self {
+        LlmServiceUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+        }
+    }
+}
+
+impl From<OpenaiCompatibleResponse> for LlmServiceChatCompletionResponse {
+    fn from(response: OpenaiCompatibleResponse) -> Self {
+        LlmServiceChatCompletionResponse {
+            id: response.id,
+            choices: response.choices.into_iter().map(|choice| {
+                LlmServiceChatCompletionResponseChoice {
+                    message: LlmServiceChatCompletionResponseMessage {
+                        role: choice.message.role.unwrap_or_else(|| "assistant".to_string()),
+                        content: choice.message.content.unwrap_or_default(),
+                        name: None,
+                        tool_calls: choice.message.tool_calls,
+                        parts: None,
+                        reasoning: None,
+                    },
+                    native_finish_reason: choice.finish_reason.clone(),
+                    finish_reason: choice.finish_reason,
+                    safety_ratings: Vec::new(),
+                    annotations: Vec::new(),
+                }
+            }).collect(),
+            created: response.created.unwrap_or_else(now_unix),
+            model: response.model.unwrap_or_default(),
+            provider: response.provider,
+            model_version: None,
+            rate_limit: None,
+            raw: None,
+            usage: response.usage.map(|usage| LlmServiceChatCompletionResponseUsage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: usage.total_tokens,
+            }),
+        }
+    }
+}
+
+
+impl<'a> OpenaiCompatibleProvider<'a> {
+    pub(crate) fn build_request(&self, api_key: &str) -> Result<RequestBuilder, LlmError> {
+        let body = self.create_body();
+
+        let mut request = shared_client()
+            .post(format!("{}/chat/completions", &self.props.base_url))
+            .bearer_auth(api_key);
+        for (name, value) in &self.headers {
+            request = request.header(*name, value);
+        }
+
+        json_body(request, &body, self.props.compress_request && accepts_gzip(&self.props.provider))
+    }
+
+    /// Parses a `/chat/completions` response body.
+    pub fn parse_response(json_text: &str) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let response: OpenaiCompatibleResponse = serde_json::from_str(json_text)?;
+
+        if response.choices.is_empty() {
+            return Err(LlmError::EmptyResponse);
+        }
+
+        let mut response: LlmServiceChatCompletionResponse = response.into();
+        response.raw = serde_json::from_str(json_text).ok();
+        Ok(response)
+    }
+
+    /// Parses a single SSE event into a chunk, `None` for the `[DONE]` marker. An error
+    /// envelope is a `Provider` error with its message. `id` is used when the provider
+    /// doesn't send one.
+    pub fn parse_stream_chunk(json_text: &str, id: &str) -> Result<Option<LlmServiceChatCompletionChunk>, LlmError> {
+        if json_text.trim() == "[DONE]" {
+            return Ok(None);
+        }
+        if let Some(message) = stream_error_message(json_text) {
+            return Err(LlmError::Provider(message));
+        }
+
+        let chunk: OpenaiCompatibleStreamChunk = serde_json::from_str(json_text)?;
+
+        Ok(Some(LlmServiceChatCompletionChunk {
+            id: chunk.id.unwrap_or_else(|| id.to_string()),
+            choices: chunk.choices.into_iter().map(|choice| {
+                LlmServiceChoiceStream {
+                    index: choice.index,
+                    delta: LlmServiceStreamDelta {
+                        role: choice.delta.role.unwrap_or_else(|| "assistant".to_string()),
+                        content: choice.delta.content.unwrap_or_default(),
+                        thinking: None,
+                        tool_calls: choice.delta.tool_calls,
+                    },
+                    native_finish_reason: choice.finish_reason.clone(),
+                    finish_reason: choice.finish_reason,
+                    safety_category: None,
+                }
+            }).collect(),
+            usage: chunk.usage.map(LlmServiceUsage::from),
+            model_version: None,
+        }))
+    }
+
+    pub async fn execute_chat(&self) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let api_key = key_pool(&self.provider)?.next_key();
+        let response = send(self.build_request(&api_key.key)?).await?;
+        api_key.bench_if_rate_limited(&response);
+        let response = error_for_status(response).await?;
+        let rate_limit = rate_limit_from_headers(response.headers());
+        let json_text = read_body(response, self.props.max_response_bytes).await?;
+
+        let mut response = Self::parse_response(&json_text)?;
+        if response.model.is_empty() {
+            response.model = self.props.request.model.clone();
+        }
+        response.rate_limit = rate_limit;
+        Ok(response)
+    }
+
+    pub async fn execute_chat_stream(
+        &self,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let started = Instant::now();
+        let api_key = key_pool(&self.provider)?.next_key();
+        let mut event_source = EventSource::new(self.build_request(&api_key.key)?)?;
+        let fallback_id = uuid::Uuid::new_v4().to_string();
+        let mut budget = ByteBudget::new(self.props.max_response_bytes);
+
+        let chunks = async_stream::stream! {
+            while let Some(event_result) = event_source.next().await {
+                match event_result {
+                    Ok(Event::Open) => continue,
+                    Ok(Event::Message(message)) => {
+                        if !budget.take(message.data.len()) {
+                            yield Err(RESPONSE_TOO_LARGE.to_string());
+                            break;
+                        }
+                        match Self::parse_stream_chunk(&message.data, &fallback_id) {
+                            Ok(Some(chunk)) => yield Ok(chunk),
+                            Ok(None) => break,
+                            Err(e) => {
+                                yield Err(e.to_string());
+                                break;
+                            }
+                        }
+                    }
+                    Err(reqwest_eventsource::Error::StreamEnded) => break,
+                    Err(e) => {
+                        if let reqwest_eventsource::Error::InvalidStatusCode(_, response) = &e {
+                            api_key.bench_if_rate_limited(response);
+                        }
+                        yield Err(e.to_string());
+                        break;
+                    }
+                }
+            }
+
+            event_source.close();
+        };
+
+        let summary = forward_stream_with(chunks, &tx, started, &self.props.response_transforms, StreamControl::for_request(self.props)).await;
+
+        let mut response = LlmServiceChatCompletionResponse::new_streamed(
+            summary.id,
+            summary.content,
+            self.props.request.model.clone(),
+            now_unix(),
+            Some(summary.prompt_tokens),
+            Some(summary.completion_tokens),
+            Some(summary.total_tokens)
+        );
+        if summary.finish_reason.is_some() {
+            response.choices[0].finish_reason = summary.finish_reason;
+        }
+        if !summary.tool_calls.is_empty() {
+            response.choices[0].message.tool_calls = Some(summary.tool_calls);
+        }
+
+        Ok(response)
+    }
+
+    pub(super) fn create_body(&self) -> Value {
+        let request = &self.props.request;
+
+        // Most of these APIs start a new assistant turn after a trailing assistant message,
+        // for those a prefill is passed on as an instruction instead
+        let messages = self.props.outgoing_messages();
+        let prefill = ChatCompletionRequestMessage::prefill(&messages).filter(|_| !self.provider.continues_prefill());
+        let messages = match prefill {
+            Some(prefix) => {
+                let mut messages = messages[..messages.len() - 1].to_vec();
+                messages.push(ChatCompletionRequestMessage::User {
+                    content: format!("Begin your reply with exactly this text and continue from it: {}", prefix),
+                    name: None,
+                });
+                messages
+            }
+            None => messages.into_owned(),
+        };
+
+        // The model id goes out exactly as configured, providers namespace them (e.g. `meta-llama/...`)
+        let mut body = json!({
+            "model": request.model,
+            "messages": ChatCompletionRequestMessage::to_openai_json(&messages),
+        });
+
+        if let Some(max_tokens) = request.max_tokens {
+            body["max_tokens"] = json!(clamp_max_tokens(&request.model, max_tokens));
+        }
+
+        if let Some(temperature) = request.temperature {
+            body["temperature"] = json!(temperature);
+        }
+
+        let penalties = [
+            ("frequency_penalty", request.frequency_penalty),
+            ("presence_penalty", request.presence_penalty),
+        ];
+        for (name, value) in penalties {
+            if let Some(value) = value.and_then(|v| clamp_penalty(&self.props.provider, name, v)) {
+                body[name] = json!(value);
+            }
+        }
+
+        if let Some(response_format) = &request.response_format {
+            body["response_format"] = match &response_format.json_schema {
+                Some(schema) => json!({
+                    "type": "json_schema",
+                    "json_schema": schema
+                }),
+                None => json!({ "type": "json_object" }),
+            };
+        }
+
+        if let Some(tools) = &request.tools {
+            body["tools"] = json!(tools);
+        }
+
+        if self.streaming {
+            body["stream"] = json!(true);
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+
+        for (name, value) in &self.extra_body {
+            body[name] = value.clone();
+        }
+
+        canonical_json(body)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::chat_response::LlmServiceRateLimit;
+    use crate::services::utils::test_server::serve_capture_with_headers;
+    use serde_json::json;
+
+    fn together_props() -> LlmServiceRequest {
+        let mut props =
+            LlmServiceRequest::from_user_prompt(LlmApiProvider::Together, "meta-llama/Llama-3.3-70B-Instruct-Turbo", "Hello");
+        props.request.max_tokens = Some(100);
+        props.request.temperature = Some(0.7);
+        props
+    }
+
+    /// Requests go to the provider's public base url, with the key from its env var.
+    fn assert_defaults(provider: LlmApiProvider, model: &str, url: &str, key_var: &str) {
+        let props = LlmServiceRequest::from_user_prompt(provider.clone(), model, "Hello");
+        let request = OpenaiCompatibleProvider::new(&props, false, provider.clone())
+            .build_request("test-key")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().as_str(), url);
+        assert_eq!(provider.api_key_var(), key_var);
+    }
+
+    #[test]
+    fn test_grok_defaults() {
+        assert_defaults(LlmApiProvider::Grok, "grok-2-latest", "https://api.x.ai/v1/chat/completions", "XAI_API_KEY");
+    }
+
+    #[test]
+    fn test_together_defaults() {
+        assert_defaults(
+            LlmApiProvider::Together,
+            "meta-llama/Llama-3.3-70B-Instruct-Turbo",
+            "https://api.together.xyz/v1/chat/completions",
+            "TOGETHER_API_KEY",
+        );
+    }
+
+    #[test]
+    fn test_fireworks_defaults() {
+        assert_defaults(
+            LlmApiProvider::Fireworks,
+            "accounts/fireworks/models/llama-v3p1-70b-instruct",
+            "https://api.fireworks.ai/inference/v1/chat/completions",
+            "FIREWORKS_API_KEY",
+        );
+    }
+
+    #[test]
+    fn test_groq_defaults() {
+        assert_defaults(
+            LlmApiProvider::Groq,
+            "llama-3.3-70b-versatile",
+            "https://api.groq.com/openai/v1/chat/completions",
+            "GROQ_API_KEY",
+        );
+    }
+
+    #[test]
+    fn test_together_penalties_clamped() {
+        let mut props = together_props();
+        props.request.frequency_penalty = Some(3.0);
+        props.request.presence_penalty = Some(0.5);
+
+        let body = OpenaiCompatibleProvider::new(&props, false, LlmApiProvider::Together).create_body();
+        assert_eq!(body["frequency_penalty"], 2.0);
+        assert_eq!(body["presence_penalty"], 0.5);
+
+        // Unset penalties aren't sent
+        let props = together_props();
+        let body = OpenaiCompatibleProvider::new(&props, false, LlmApiProvider::Together).create_body();
+        assert!(body.get("frequency_penalty").is_none());
+    }
+
+    #[test]
+    fn test_together_compression_is_a_no_op() {
+        let mut props = together_props();
+        props.compress_request = true;
+
+        let request = OpenaiCompatibleProvider::new(&props, false, LlmApiProvider::Together)
+            .build_request("test-key")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(request.headers().get("content-encoding").is_none());
+        let body: serde_json::Value = serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["model"], "meta-llama/Llama-3.3-70B-Instruct-Turbo");
+    }
+
+    #[test]
+    fn test_together_prefill_folded_into_hint() {
+        let mut props = together_props();
+        props.request.messages.push(ChatCompletionRequestMessage::Assistant {
+            content: "Once upon a time".to_string(),
+            tool_calls: None,
+            name: None,
+        });
+
+        let body = OpenaiCompatibleProvider::new(&props, false, LlmApiProvider::Together).create_body();
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["role"], "user");
+        assert!(messages[1]["content"].as_str().unwrap().ends_with("Once upon a time"));
+    }
+
+    #[tokio::test]
+    async fn test_groq_captures_rate_limit_headers() {
+        std::env::set_var("GROQ_API_KEY", "test-key");
+        let headers = vec![
+            ("x-ratelimit-limit-requests", "14400".to_string()),
+            ("x-ratelimit-limit-tokens", "18000".to_string()),
+            ("x-ratelimit-remaining-requests", "14370".to_string()),
+            ("x-ratelimit-remaining-tokens", "17997".to_string()),
+            ("x-ratelimit-reset-requests", "2m59.56s".to_string()),
+            ("x-ratelimit-reset-tokens", "7.66s".to_string()),
+        ];
+        let body = json!({
+            "id": "chatcmpl-groq-1",
+            "object": "chat.completion",
+            "created": 1741000000,
+            "model": "llama-3.3-70b-versatile",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Fast answer" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 14, "completion_tokens": 3, "total_tokens": 17 },
+            "x_groq": { "id": "req_01abc" }
+        });
+        let (base_url, captured) = serve_capture_with_headers(200, headers, body.to_string()).await;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Groq, "llama-3.3-70b-versatile", "Hello");
+        props.base_url = base_url;
+        let response = OpenaiCompatibleProvider::new(&props, false, LlmApiProvider::Groq).execute_chat().await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "Fast answer");
+        assert_eq!(
+            response.rate_limit,
+            Some(LlmServiceRateLimit {
+                limit_requests: Some(14_400),
+                limit_tokens: Some(18_000),
+                remaining_requests: Some(14_370),
+                remaining_tokens: Some(17_997),
+                reset_requests: Some("2m59.56s".to_string()),
+                reset_tokens: Some("7.66s".to_string()),
+            })
+        );
+
+        let request = captured.await.unwrap();
+        assert!(request.starts_with("POST /chat/completions "));
+    }
+
+    #[test]
+    fn test_openai_compatible_body_bytes_are_stable() {
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Together, "meta-llama/Llama-3-8b-chat-hf", "Hello");
+        props.request.max_tokens = Some(64);
+        props.request.temperature = Some(0.5);
+
+        let body_bytes = || {
+            let provider = OpenaiCompatibleProvider::new(&props, true, LlmApiProvider::Together)
+                .with_body_field("top_k", json!(40))
+                .with_body_field("repetition_penalty", json!({ "value": 1.1, "decay": 0.5 }));
+            let request = provider.build_request("key").unwrap().build().unwrap();
+            request.body().unwrap().as_bytes().unwrap().to_vec()
+        };
+
+        let first = body_bytes();
+        assert_eq!(first, body_bytes());
+
+        let body = String::from_utf8(first).unwrap();
+        let keys = ["\"max_tokens\"", "\"messages\"", "\"model\"", "\"repetition_penalty\"", "\"stream\"", "\"stream_options\"", "\"temperature\"", "\"top_k\""];
+        let positions: Vec<usize> = keys.iter().map(|key| body.find(key).unwrap()).collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "keys out of order in {}", body);
+        assert!(body.contains(r#"{"decay":0.5,"value":1.1}"#));
+    }
+
+    #[test]
+    fn test_openai_compatible_response_parsing() {
+        let response = json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "test-model",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "test response" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 50, "completion_tokens": 20, "total_tokens": 70 }
+        })
+        .to_string();
+
+        let result = OpenaiCompatibleProvider::parse_response(&response).unwrap();
+        assert_eq!(result.id, "chatcmpl-123");
+        assert_eq!(result.model, "test-model");
+        assert_eq!(result.choices[0].message.content, "test response");
+        assert_eq!(result.choices[0].finish_reason.as_deref(), Some("stop"));
+
+        let usage = result.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 50);
+        assert_eq!(usage.completion_tokens, 20);
+    }
+
+    #[test]
+    fn test_openai_compatible_tool_call_response() {
+        let response = json!({
+            "id": "chatcmpl-456",
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "get_weather", "arguments": "{}" }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        })
+        .to_string();
+
+        let result = OpenaiCompatibleProvider::parse_response(&response).unwrap();
+        let message = &result.choices[0].message;
+        assert_eq!(message.content, "");
+        assert_eq!(message.tool_calls.as_ref().unwrap()[0].function_call.name, "get_weather");
+    }
+
+    #[test]
+    fn test_openai_compatible_stream_chunk() {
+        let chunk = json!({
+            "id": "chatcmpl-789",
+            "choices": [{ "index": 0, "delta": { "content": "Hel" }, "finish_reason": null }]
+        })
+        .to_string();
+
+        let result = OpenaiCompatibleProvider::parse_stream_chunk(&chunk, "fallback").unwrap().unwrap();
+        assert_eq!(result.id, "chatcmpl-789");
+        assert_eq!(result.choices[0].delta.content, "Hel");
+        assert_eq!(result.choices[0].delta.role, "assistant");
+        assert!(result.usage.is_none());
+
+        // The usage-only chunk at the end has no choices
+        let usage = json!({
+            "choices": [],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8 }
+        })
+        .to_string();
+
+        let result = OpenaiCompatibleProvider::parse_stream_chunk(&usage, "fallback").unwrap().unwrap();
+        assert_eq!(result.id, "fallback");
+        assert_eq!(result.usage.unwrap().completion_tokens, 3);
+
+        assert!(OpenaiCompatibleProvider::parse_stream_chunk("[DONE]", "fallback").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_openai_compatible_stream_starts_with_error() {
+        let events = [
+            json!({ "error": { "message": "Invalid value for 'temperature': must be at most 2", "type": "invalid_request_error" } }).to_string(),
+            json!({ "id": "chatcmpl-1", "choices": [{ "index": 0, "delta": { "content": "Hi" } }] }).to_string(),
+        ];
+
+        let err = OpenaiCompatibleProvider::parse_stream_chunk(&events[0], "fallback").unwrap_err();
+        assert!(matches!(&err, LlmError::Provider(msg) if msg == "Invalid value for 'temperature': must be at most 2"));
+
+        // Nothing after the error is forwarded
+        let chunks = futures_util::stream::iter(events.iter().map(|e| {
+            OpenaiCompatibleProvider::parse_stream_chunk(e, "fallback").map(Option::unwrap).map_err(|e| e.to_string())
+        }));
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(20);
+        forward_stream_with(chunks, &tx, Instant::now(), &[], StreamControl::default()).await;
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Err(LlmStreamingError::StreamError(msg)) if msg.contains("must be at most 2")));
+    }
+}
@@ -1,155 +1,152 @@
-use crate::common::types::chat_response::{LlmServiceChatCompletionChunk, LlmServiceChatCompletionResponse};
-use crate::common::types::chat_request::ChatCompletionRequestMessage;
+use crate::common::types::chat_response::LlmServiceChatCompletionResponse;
+use crate::common::types::models::LlmApiProvider;
 use crate::services::types::{
-    llm_error::LlmError, llm_error::LlmStreamingError, llm_service::LlmServiceRequest
+    llm_error::LlmError, llm_error::LlmStreamingError, llm_service::LlmServiceRequest, stream::StreamEvent
 };
-use anyhow::Result;
-use futures_util::StreamExt;
-use openrouter_api::models::tool::Tool;
-use openrouter_api::{OpenRouterClient, Ready};
-use openrouter_api::types::chat::ChatCompletionRequest;
+
+use reqwest::RequestBuilder;
+use serde_json::json;
 use tokio::sync::mpsc::Sender;
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use super::openai_compatible::OpenaiCompatibleProvider;
 
+/// Attribution headers and the env vars they're read from, sent when set.
+const ATTRIBUTION_HEADERS: [(&str, &str); 2] = [
+    ("HTTP-Referer", "OPENROUTER_HTTP_REFERER"),
+    ("X-Title", "OPENROUTER_X_TITLE"),
+];
 
+/// OpenRouter, through its OpenAi compatible `/chat/completions` endpoint.
+///
+/// The request's `models` are sent as OpenRouter's fallback list, tried in order when the
+/// primary model is unavailable. The response names the model and the upstream provider
+/// that actually served it.
 pub struct OpenrouterProvider<'a> {
-    props: &'a LlmServiceRequest,
-    streaming: bool,
-    client: OpenRouterClient<Ready>,
+    inner: OpenaiCompatibleProvider<'a>,
 }
 
 impl<'a> OpenrouterProvider<'a> {
-    /// Creates a new instance of `OpenrouterProvider` with the given properties and streaming flag.
-    pub fn new(props: &'a LlmServiceRequest, streaming: bool) -> Result<Self, LlmError> {
-        let api_key = std::env::var("OPENROUTER_API_KEY").expect("Missing OPENROUTER_API_KEY");
-        let client = OpenRouterClient::new()
-            .with_base_url("https://openrouter.ai/api/v1/")?
-            .with_api_key(api_key)?;
-
-        Ok(OpenrouterProvider {
-            props,
-            streaming,
-            client,
-        })
+    pub fn new(props: &'a LlmServiceRequest, streaming: bool) -> Self {
+        let mut inner = OpenaiCompatibleProvider::new(props, streaming, LlmApiProvider::Openrouter);
+
+        if let Some(models) = props.request.models.as_ref().filter(|models| !models.is_empty()) {
+            inner = inner.with_body_field("models", json!(models));
+        }
+
+        for (header, var) in ATTRIBUTION_HEADERS {
+            if let Some(value) = std::env::var(var).ok().filter(|value| !value.is_empty()) {
+                inner = inner.with_header(header, value);
+            }
+        }
+
+        OpenrouterProvider { inner }
+    }
+
+    pub(crate) fn build_request(&self, api_key: &str) -> Result<RequestBuilder, LlmError> {
+        self.inner.build_request(api_key)
     }
 
-    /// Builds an HTTP request using the OpenRouter API library's client configuration.
     pub async fn execute_chat(&self) -> Result<LlmServiceChatCompletionResponse, LlmError> {
-        let messages = self.props.request.messages.iter().map(|msg| {
-            openrouter_api::types::chat::Message {
-                role: msg.role().to_string(),
-                content: msg.content().to_string(),
-                name: msg.name().map(|n| n.to_string()),
-                tool_calls: match msg {
-                    ChatCompletionRequestMessage::Assistant { tool_calls, .. } => {
-                        match tool_calls {
-                            Some(tcs) => {
-                                Some(tcs.iter().map(|tc| tc.clone().into()).collect::<Vec<openrouter_api::models::tool::ToolCall>>())
-                            },
-                            None => None
-                        }
-                    },
-                    _ => None
-                },
-            }
-        }).collect();
-
-        let request = ChatCompletionRequest {
-            model: self.props.request.model.clone(),
-            messages,
-            stream: if self.streaming { Some(true) } else { None },
-            response_format: self.props.request.response_format.clone().map(|rf| rf.into()),
-            tools: self.props.request.tools.clone().map(|vt| vt.into_iter().map(|t| t.into()).collect::<Vec<Tool>>()),
-            provider: None,
-            models: None,
-            transforms: None,
-        };
-
-        let response = self.client.chat_completion(request).await?;
-        Ok(response.into())
+        let response = self.inner.execute_chat().await?;
+        tracing::debug!(
+            "OpenRouter served {} through {}",
+            response.model,
+            response.provider.as_deref().unwrap_or("an unnamed provider")
+        );
+        Ok(response)
     }
 
     pub async fn execute_chat_stream(
         &self,
-        tx: Sender<Result<LlmServiceChatCompletionChunk, LlmStreamingError>>,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
     ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
-        let messages: Vec<openrouter_api::types::chat::Message> = self.props.request.messages.iter().map(|msg| {
-            openrouter_api::types::chat::Message {
-                role: msg.role().to_string(),
-                content: msg.content().to_string(),
-                name: msg.name().map(|n| n.to_string()),
-                tool_calls: match msg {
-                    ChatCompletionRequestMessage::Assistant { tool_calls, .. } => {
-                        match tool_calls {
-                            Some(tcs) => {
-                                Some(tcs.iter().map(|tc| tc.clone().into()).collect::<Vec<openrouter_api::models::tool::ToolCall>>())
-                            },
-                            None => None
-                        }
-                    },
-                    _ => None
-                },
-            }
-        }).collect();
-
-        let request = ChatCompletionRequest {
-            model: self.props.request.model.clone(),
-            messages,
-            stream: if self.streaming { Some(true) } else { None },
-            response_format: self.props.request.response_format.clone().map(|rf| rf.into()),
-            tools: self.props.request.tools.clone().map(|vt| vt.into_iter().map(|t| t.into()).collect::<Vec<Tool>>()),
-            provider: None,
-            models: None,
-            transforms: None,
-        };
-
-        let mut stream = self.client.chat()?.chat_completion_stream(request);
-        let mut content = String::new();
-        let mut prompt_tokens = 0;
-        let mut completion_tokens = 0;
-        let mut total_tokens = 0;
-        let mut id = String::new();
-
-        while let Some(chunk) = stream.next().await {
-            tracing::info!("chunk: {:?}", chunk);
-            match chunk {
-                Ok(c) => {
-                    id = c.id.clone();
-
-                    if let Some(u) = &c.usage {
-                        completion_tokens = u.completion_tokens;         
-                        prompt_tokens = u.prompt_tokens;
-                        total_tokens = u.total_tokens;
-                    }
-
-                    if let Some(c) = &c.choices.first() {
-                        content += &c.delta.content;
-                    }
-
-                    if let Err(_) = tx.send(Ok(c.into())).await {
-                        break;
-                    }
-                }
-                Err(e) => eprintln!("Error during streaming: {}", e),
-            }
-        }
+        self.inner.execute_chat_stream(tx).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::{chat_request::ChatCompletionRequestMessage, models::LlmApiProvider};
+    use crate::services::utils::test_server::serve_capture;
+    use serde_json::Value;
+
+    fn create_test_props(base_url: &str) -> LlmServiceRequest {
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Openrouter, "openai/gpt-4o", "Hello");
+        props.base_url = base_url.to_string();
+        props.request.models = Some(vec!["anthropic/claude-3.5-sonnet".to_string(), "google/gemini-flash-1.5".to_string()]);
+        props
+    }
+
+    fn completion_body() -> String {
+        json!({
+            "id": "gen-123",
+            "object": "chat.completion",
+            "created": 1741000000,
+            "model": "anthropic/claude-3.5-sonnet",
+            "provider": "Anthropic",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hi there" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 9, "completion_tokens": 2, "total_tokens": 11 }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_openrouter_request_without_fallbacks() {
+        let mut props = create_test_props("http://localhost");
+        props.request.models = Some(Vec::new());
+
+        let request = OpenrouterProvider::new(&props, false).build_request("key").unwrap().build().unwrap();
+        let body: Value = serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["model"], "openai/gpt-4o");
+        assert!(body.get("models").is_none());
+    }
+
+    #[test]
+    fn test_openrouter_keeps_assistant_prefill() {
+        let mut props = create_test_props("http://localhost");
+        props.request.messages.push(ChatCompletionRequestMessage::Assistant {
+            content: "Once upon a time".to_string(),
+            tool_calls: None,
+            name: None,
+        });
+
+        let request = OpenrouterProvider::new(&props, false).build_request("key").unwrap().build().unwrap();
+        let body: Value = serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "Once upon a time");
+    }
 
-        let _ = tx.send(Ok(LlmServiceChatCompletionChunk::done_sentinel(id.clone()))).await;
-    
-        let created = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs() as i64;
-
-        Ok(LlmServiceChatCompletionResponse::new_streamed(
-            id, 
-            content, 
-            self.props.request.model.clone(),
-            created, 
-            Some(prompt_tokens), 
-            Some(completion_tokens), 
-            Some(total_tokens)
-        ))
+    #[tokio::test]
+    async fn test_openrouter_sends_fallbacks_and_parses_routing() {
+        std::env::set_var("OPENROUTER_API_KEY", "test-key");
+        std::env::set_var("OPENROUTER_HTTP_REFERER", "https://llmkit.example");
+        std::env::set_var("OPENROUTER_X_TITLE", "llmkit");
+        let (base_url, captured) = serve_capture(200, completion_body()).await;
+
+        let props = create_test_props(&base_url);
+        let response = OpenrouterProvider::new(&props, false).execute_chat().await.unwrap();
+
+        // The first fallback served the request
+        assert_eq!(response.model, "anthropic/claude-3.5-sonnet");
+        assert_eq!(response.provider.as_deref(), Some("Anthropic"));
+        assert_eq!(response.choices[0].message.content, "Hi there");
+
+        let request = captured.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let head = head.to_lowercase();
+        assert!(head.contains("http-referer: https://llmkit.example\r\n"));
+        assert!(head.contains("x-title: llmkit\r\n"));
+
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["model"], "openai/gpt-4o");
+        assert_eq!(body["models"], json!(["anthropic/claude-3.5-sonnet", "google/gemini-flash-1.5"]));
     }
 }
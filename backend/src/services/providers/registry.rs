@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+    time::Instant,
+};
+
+use futures_util::future::BoxFuture;
+use tokio::sync::mpsc::Sender;
+
+use crate::common::types::{chat_response::LlmServiceChatCompletionResponse, models::ProviderCapabilities};
+use crate::services::types::{
+    llm_error::LlmError, llm_error::LlmStreamingError, llm_service::LlmServiceRequest, stream::StreamEvent
+};
+use crate::services::utils::stream::forward_response;
+
+/// A provider implemented outside of llmkit, e.g. an internal gateway.
+pub trait CustomProvider: Send + Sync {
+    fn execute_chat(&self) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>>;
+
+    /// Streams the response to `tx` over whatever transport the provider speaks: decode
+    /// its chunks (SSE through `reqwest_eventsource`, newline delimited JSON through
+    /// [`ndjson_lines`](crate::services::utils::ndjson::ndjson_lines), ...) and hand them to
+    /// [`forward_stream`](crate::services::utils::stream::forward_stream).
+    /// By default the regular response is delivered as a single chunk.
+    fn execute_chat_stream(
+        &self,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let response = self.execute_chat().await?;
+            forward_response(&response, &tx, started, &[]).await;
+            Ok(response)
+        })
+    }
+
+    /// Prepares for the first request, e.g. by opening a connection, see
+    /// [`warmup`](crate::services::llm::warmup). Does nothing by default.
+    fn warmup(&self) -> BoxFuture<'_, Result<(), LlmError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Builds a provider for one request, like the built-in providers' `new`.
+pub type ProviderFactory = Arc<dyn Fn(&LlmServiceRequest, bool) -> Box<dyn CustomProvider> + Send + Sync>;
+
+static PROVIDER_REGISTRY: OnceLock<ProviderRegistry> = OnceLock::new();
+
+/// The registry consulted by the dispatch layer before the built-in providers.
+pub fn provider_registry() -> &'static ProviderRegistry {
+    PROVIDER_REGISTRY.get_or_init(ProviderRegistry::default)
+}
+
+/// Custom providers by name. The name is what the provider row in the db (or
+/// `LlmApiProvider::Custom`) refers to, registering a built-in name overrides it.
+#[derive(Default)]
+pub struct ProviderRegistry {
+    factories: RwLock<HashMap<String, ProviderFactory>>,
+    capabilities: RwLock<HashMap<String, ProviderCapabilities>>,
+}
+
+impl ProviderRegistry {
+    /// Registers a provider that supports every request feature, see [`register_with_capabilities`](Self::register_with_capabilities).
+    pub fn register<F>(&self, name: &str, factory: F)
+    where
+        F: Fn(&LlmServiceRequest, bool) -> Box<dyn CustomProvider> + Send + Sync + 'static,
+    {
+        self.register_with_capabilities(name, ProviderCapabilities::ALL, factory);
+    }
+
+    /// Registers a provider along with what it supports, requests with
+    /// `adapt_to_capabilities` set are adapted to it before the factory sees them.
+    pub fn register_with_capabilities<F>(&self, name: &str, capabilities: ProviderCapabilities, factory: F)
+    where
+        F: Fn(&LlmServiceRequest, bool) -> Box<dyn CustomProvider> + Send + Sync + 'static,
+    {
+        self.factories
+            .write()
+            .expect("provider registry lock poisoned")
+            .insert(name.to_string(), Arc::new(factory));
+        self.capabilities
+            .write()
+            .expect("provider registry lock poisoned")
+            .insert(name.to_string(), capabilities);
+    }
+
+    pub fn unregister(&self, name: &str) {
+        self.factories
+            .write()
+            .expect("provider registry lock poisoned")
+            .remove(name);
+        self.capabilities
+            .write()
+            .expect("provider registry lock poisoned")
+            .remove(name);
+    }
+
+    pub fn capabilities(&self, name: &str) -> Option<ProviderCapabilities> {
+        self.capabilities
+            .read()
+            .expect("provider registry lock poisoned")
+            .get(name)
+            .copied()
+    }
+
+    pub fn get(&self, name: &str) -> Option<ProviderFactory> {
+        self.factories
+            .read()
+            .expect("provider registry lock poisoned")
+            .get(name)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::models::LlmApiProvider;
+    use crate::common::types::chat_request::ChatCompletionRequestResponseFormat;
+    use crate::common::types::chat_response::{LlmServiceChatCompletionChunk, LlmServiceChoiceStream, LlmServiceStreamDelta};
+    use crate::services::llm::{ask_with, stream_with};
+    use crate::services::utils::{ndjson::ndjson_lines, stream::forward_stream, test_server::serve_once};
+    use futures_util::StreamExt;
+
+    // Answers with the last message it was sent
+    struct EchoProvider {
+        reply: String,
+    }
+
+    impl CustomProvider for EchoProvider {
+        fn execute_chat(&self) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>> {
+            Box::pin(async move {
+                Ok(LlmServiceChatCompletionResponse::new_streamed(
+                    "echo-1".to_string(),
+                    self.reply.clone(),
+                    "echo".to_string(),
+                    0,
+                    None,
+                    None,
+                    None,
+                ))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_to_registered_provider() {
+        provider_registry().register("internal-gateway", |props, _streaming| {
+            let reply = props.request.messages.last().map(|m| m.content().to_string()).unwrap_or_default();
+            Box::new(EchoProvider { reply })
+        });
+
+        let props = LlmServiceRequest::from_user_prompt(
+            LlmApiProvider::Custom("internal-gateway".to_string()),
+            "gateway-model",
+            "ping",
+        );
+        assert_eq!(ask_with(props).await.unwrap(), "ping");
+    }
+
+    #[tokio::test]
+    async fn test_json_mode_emulated_for_provider_without_it() {
+        let capabilities = ProviderCapabilities { json_mode: false, json_schema: false, tools: true };
+        provider_registry().register_with_capabilities("plain-gateway", capabilities, |props, _streaming| {
+            // Reports what it was sent: whether a response_format came along, and the system prompt
+            let system = props.request.messages.iter().find(|m| m.role() == "system").map(|m| m.content().to_string());
+            let reply = format!("{}|{}", props.request.response_format.is_some(), system.unwrap_or_default());
+            Box::new(EchoProvider { reply })
+        });
+
+        let mut props = LlmServiceRequest::from_user_prompt(
+            LlmApiProvider::Custom("plain-gateway".to_string()),
+            "gateway-model",
+            "List three colors",
+        );
+        props.request.response_format = Some(ChatCompletionRequestResponseFormat {
+            format_type: "json_object".to_string(),
+            json_schema: None,
+        });
+        assert_eq!(ask_with(props.clone()).await.unwrap(), "true|");
+
+        props.adapt_to_capabilities = true;
+        assert_eq!(
+            ask_with(props).await.unwrap(),
+            "false|Respond only with valid JSON, without Markdown code fences or any other text."
+        );
+    }
+
+    // Streams Ollama style newline delimited JSON from a local server
+    struct NdjsonProvider {
+        base_url: String,
+    }
+
+    impl CustomProvider for NdjsonProvider {
+        fn execute_chat(&self) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>> {
+            Box::pin(async { Err(LlmError::NotImplemented("streaming only".to_string())) })
+        }
+
+        fn execute_chat_stream(
+            &self,
+            tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+        ) -> BoxFuture<'_, Result<LlmServiceChatCompletionResponse, LlmError>> {
+            Box::pin(async move {
+                let started = Instant::now();
+                let response = reqwest::get(format!("{}/api/chat", self.base_url)).await?;
+
+                let chunks = ndjson_lines(response.bytes_stream()).map(|line| {
+                    let json: serde_json::Value = serde_json::from_str(&line?).map_err(|e| e.to_string())?;
+                    Ok::<_, String>(LlmServiceChatCompletionChunk {
+                        id: "ndjson-1".to_string(),
+                        choices: vec![LlmServiceChoiceStream {
+                            index: 0,
+                            delta: LlmServiceStreamDelta {
+                                role: "assistant".to_string(),
+                                content: json["message"]["content"].as_str().unwrap_or_default().to_string(),
+                                thinking: None,
+                                tool_calls: None,
+                            },
+                            finish_reason: json["done"].as_bool().filter(|done| *done).map(|_| "stop".to_string()),
+                            native_finish_reason: None,
+                            safety_category: None,
+                        }],
+                        usage: None,
+                        model_version: None,
+                    })
+                });
+
+                let summary = forward_stream(chunks, &tx, started, &[]).await;
+                Ok(LlmServiceChatCompletionResponse::new_streamed(
+                    summary.id,
+                    summary.content,
+                    "ndjson".to_string(),
+                    0,
+                    None,
+                    None,
+                    None,
+                ))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_provider_streams_ndjson() {
+        let body = [
+            r#"{"message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            r#"{"message":{"role":"assistant","content":"lo"},"done":false}"#,
+            r#"{"message":{"role":"assistant","content":""},"done":true}"#,
+        ]
+        .join("\n");
+        let base_url = serve_once(200, body).await;
+
+        provider_registry().register("ndjson-test", move |_props, _streaming| {
+            Box::new(NdjsonProvider { base_url: base_url.clone() })
+        });
+
+        let props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Custom("ndjson-test".to_string()), "local", "Hi");
+        let events: Vec<_> = stream_with(props).collect().await;
+
+        let content: String = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(StreamEvent::Chunk(c)) if !c.is_done_sentinel() => Some(c.choices[0].delta.content.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(content, "Hello");
+        assert!(events.iter().any(|e| matches!(e, Ok(StreamEvent::FinishReason(reason)) if reason == "stop")));
+
+        provider_registry().unregister("ndjson-test");
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_custom_provider() {
+        let props = LlmServiceRequest::from_user_prompt(
+            LlmApiProvider::Custom("not-registered".to_string()),
+            "model",
+            "ping",
+        );
+
+        let result = ask_with(props).await;
+        assert!(matches!(result, Err(LlmError::ProviderUnavailable(name)) if name == "not-registered"));
+    }
+}
@@ -0,0 +1,492 @@
+use crate::common::types::chat_request::{non_system_messages, ChatCompletionRequestMessage};
+use crate::common::types::models::LlmApiProvider;
+use crate::common::types::chat_response::{
+    LlmServiceChatCompletionChunk, LlmServiceChatCompletionResponse, LlmServiceChoiceStream, LlmServiceStreamDelta,
+};
+use crate::services::types::{
+    llm_error::LlmError, llm_error::LlmStreamingError, llm_service::LlmServiceRequest, stream::StreamEvent,
+};
+use crate::services::utils::{
+    canonical_json::canonical_json,
+    cassette::send,
+    client::{error_for_status, read_body, shared_client, ByteBudget, RESPONSE_TOO_LARGE},
+    key_pool::{key_pool, PooledKey},
+    model_limits::clamp_max_tokens,
+    stream::{forward_response, forward_stream_with, StreamControl},
+};
+
+use futures_util::{Stream, StreamExt};
+use reqwest::header::ACCEPT;
+use reqwest_eventsource::{Event, EventSource};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc::Sender;
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// First wait between polls of a running prediction, doubled after every poll.
+const INITIAL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest wait between two polls.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a prediction may run before polling gives up.
+const PREDICTION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Replicate, at `https://api.replicate.com/v1`. Requests create a prediction, which
+/// runs asynchronously: the answer is either polled for until the prediction finished
+/// or, when streaming, read from the prediction's SSE stream url. Model ids are
+/// `owner/name`, or `owner/name:version` to run a specific version, and the prompt is
+/// sent as the model's `prompt`/`system_prompt` inputs.
+pub struct ReplicateProvider<'a> {
+    props: &'a LlmServiceRequest,
+    streaming: bool,
+    timeout: Duration,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PredictionStatus {
+    Starting,
+    Processing,
+    Succeeded,
+    Failed,
+    Canceled,
+}
+
+#[derive(Deserialize, Debug)]
+struct Prediction {
+    id: String,
+    status: PredictionStatus,
+    /// Language models return their output as a list of tokens.
+    output: Option<serde_json::Value>,
+    error: Option<serde_json::Value>,
+    #[serde(default)]
+    urls: PredictionUrls,
+    metrics: Option<PredictionMetrics>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct PredictionUrls {
+    stream: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PredictionMetrics {
+    input_token_count: Option<u32>,
+    output_token_count: Option<u32>,
+}
+
+/// Where a prediction stands after a poll.
+#[derive(Debug, PartialEq)]
+enum PollState {
+    Running,
+    Succeeded(String),
+    Failed(String),
+}
+
+impl Prediction {
+    fn poll_state(&self) -> PollState {
+        match self.status {
+            PredictionStatus::Starting | PredictionStatus::Processing => PollState::Running,
+            PredictionStatus::Succeeded => PollState::Succeeded(self.output_text()),
+            PredictionStatus::Failed => PollState::Failed(
+                self.error
+                    .as_ref()
+                    .map(|e| e.as_str().map(str::to_string).unwrap_or_else(|| e.to_string()))
+                    .unwrap_or_else(|| "prediction failed".to_string()),
+            ),
+            PredictionStatus::Canceled => PollState::Failed("prediction was canceled".to_string()),
+        }
+    }
+
+    fn output_text(&self) -> String {
+        match &self.output {
+            Some(serde_json::Value::Array(tokens)) => tokens.iter().filter_map(|t| t.as_str()).collect(),
+            Some(serde_json::Value::String(text)) => text.clone(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl<'a> ReplicateProvider<'a> {
+    pub fn new(props: &'a LlmServiceRequest, streaming: bool) -> Self {
+        ReplicateProvider {
+            props,
+            streaming,
+            timeout: PREDICTION_TIMEOUT,
+        }
+    }
+
+    /// Gives up waiting for a prediction after `timeout` instead of the default ten minutes.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The version of a `owner/name:version` model id, `None` for the model's latest version.
+    fn version(&self) -> Option<&str> {
+        self.props.request.model.split_once(':').map(|(_, version)| version)
+    }
+
+    fn create_body(&self) -> serde_json::Value {
+        let messages = self.props.outgoing_messages();
+
+        let system_prompt = self.props.system_prompt(&messages);
+
+        // Models take a single prompt, so a conversation is sent as a transcript
+        let turns: Vec<&ChatCompletionRequestMessage> = non_system_messages(&messages).collect();
+        let prompt = match turns.as_slice() {
+            [only] if only.is_user() => only.content().to_string(),
+            _ => turns
+                .iter()
+                .map(|msg| format!("{}: {}", msg.role(), msg.content()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+
+        let mut input = json!({ "prompt": prompt });
+        if !system_prompt.is_empty() {
+            input["system_prompt"] = json!(system_prompt);
+        }
+        if let Some(max_tokens) = self.props.request.max_tokens {
+            input["max_tokens"] = json!(clamp_max_tokens(&self.props.request.model, max_tokens));
+        }
+        if let Some(temperature) = self.props.request.temperature {
+            input["temperature"] = json!(temperature);
+        }
+
+        let mut body = json!({ "input": input, "stream": self.streaming });
+        if let Some(version) = self.version() {
+            body["version"] = json!(version);
+        }
+        canonical_json(body)
+    }
+
+    /// Versioned models are run through `/predictions`, the latest version of one through
+    /// `/models/{owner}/{name}/predictions`.
+    fn predictions_url(&self) -> String {
+        match self.version() {
+            Some(_) => format!("{}/predictions", self.props.base_url),
+            None => format!("{}/models/{}/predictions", self.props.base_url, self.props.request.model),
+        }
+    }
+
+    async fn create_prediction(&self, api_key: &PooledKey) -> Result<Prediction, LlmError> {
+        let request = shared_client()
+            .post(self.predictions_url())
+            .bearer_auth(&api_key.key)
+            .json(&self.create_body());
+
+        self.read_prediction(request, api_key).await
+    }
+
+    /// Sends `request` and parses the prediction it answers with, within `max_response_bytes`.
+    async fn read_prediction(&self, request: reqwest::RequestBuilder, api_key: &PooledKey) -> Result<Prediction, LlmError> {
+        let response = send(request).await?;
+        api_key.bench_if_rate_limited(&response);
+        let response = error_for_status(response).await?;
+        Ok(serde_json::from_str(&read_body(response, self.props.max_response_bytes).await?)?)
+    }
+
+    /// Polls with exponential backoff until the prediction succeeded or failed. Fails with
+    /// [`LlmError::Timeout`] when it's still running after the provider's timeout.
+    async fn wait_for(&self, api_key: &PooledKey, prediction: Prediction) -> Result<Prediction, LlmError> {
+        tokio::time::timeout(self.timeout, self.poll(api_key, prediction))
+            .await
+            .map_err(|_| LlmError::Timeout(self.timeout.as_millis() as u64))?
+    }
+
+    async fn poll(&self, api_key: &PooledKey, mut prediction: Prediction) -> Result<Prediction, LlmError> {
+        let mut interval = INITIAL_POLL_INTERVAL;
+
+        loop {
+            match prediction.poll_state() {
+                PollState::Succeeded(_) => return Ok(prediction),
+                PollState::Failed(reason) => {
+                    return Err(LlmError::Provider(format!("Replicate prediction {} failed: {}", prediction.id, reason)))
+                }
+                PollState::Running => {}
+            }
+
+            tokio::time::sleep(interval).await;
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+
+            let request = shared_client()
+                .get(format!("{}/predictions/{}", self.props.base_url, prediction.id))
+                .bearer_auth(&api_key.key);
+            prediction = self.read_prediction(request, api_key).await?;
+        }
+    }
+
+    fn response_from(&self, prediction: Prediction) -> LlmServiceChatCompletionResponse {
+        let content = prediction.output_text();
+        let metrics = prediction.metrics.as_ref();
+        let prompt_tokens = metrics.and_then(|m| m.input_token_count);
+        let completion_tokens = metrics.and_then(|m| m.output_token_count);
+
+        LlmServiceChatCompletionResponse::new_streamed(
+            prediction.id,
+            content,
+            self.props.request.model.clone(),
+            now_unix(),
+            prompt_tokens,
+            completion_tokens,
+            prompt_tokens.zip(completion_tokens).map(|(p, c)| p + c),
+        )
+    }
+
+    pub async fn execute_chat(&self) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let api_key = key_pool(&LlmApiProvider::Replicate)?.next_key();
+        let prediction = self.create_prediction(&api_key).await?;
+        let prediction = self.wait_for(&api_key, prediction).await?;
+        Ok(self.response_from(prediction))
+    }
+
+    pub async fn execute_chat_stream(
+        &self,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let started = Instant::now();
+        let api_key = key_pool(&LlmApiProvider::Replicate)?.next_key();
+        let prediction = self.create_prediction(&api_key).await?;
+
+        // Models without streaming support have no stream url, poll and deliver the whole answer
+        let Some(stream_url) = prediction.urls.stream.clone() else {
+            let prediction = self.wait_for(&api_key, prediction).await?;
+            let response = self.response_from(prediction);
+            forward_response(&response, &tx, started, &self.props.response_transforms).await;
+            return Ok(response);
+        };
+
+        let request = shared_client()
+            .get(stream_url)
+            .bearer_auth(&api_key.key)
+            .header(ACCEPT, "text/event-stream");
+        let chunks = prediction_chunks(request, prediction.id.clone(), ByteBudget::new(self.props.max_response_bytes))?;
+        let summary = forward_stream_with(
+            chunks,
+            &tx,
+            started,
+            &self.props.response_transforms,
+            StreamControl::for_request(self.props),
+        )
+        .await;
+
+        Ok(LlmServiceChatCompletionResponse::new_streamed(
+            prediction.id,
+            summary.content,
+            self.props.request.model.clone(),
+            now_unix(),
+            None,
+            None,
+            None,
+        ))
+    }
+}
+
+fn text_chunk(id: &str, content: String, finish_reason: Option<&str>) -> LlmServiceChatCompletionChunk {
+    LlmServiceChatCompletionChunk {
+        id: id.to_string(),
+        choices: vec![LlmServiceChoiceStream {
+            index: 0,
+            delta: LlmServiceStreamDelta {
+                role: "assistant".to_string(),
+                content,
+                thinking: None,
+                tool_calls: None,
+            },
+            finish_reason: finish_reason.map(str::to_string),
+            native_finish_reason: None,
+            safety_category: None,
+        }],
+        usage: None,
+        model_version: None,
+    }
+}
+
+/// Replicate's prediction stream: `output` events carry text, `done` ends it and
+/// `error` reports a failed prediction. Ends with [`RESPONSE_TOO_LARGE`] once the events
+/// are over `budget`.
+fn prediction_chunks(
+    request: reqwest::RequestBuilder,
+    id: String,
+    mut budget: ByteBudget,
+) -> Result<impl Stream<Item = Result<LlmServiceChatCompletionChunk, String>>, LlmError> {
+    let mut event_source = EventSource::new(request)?;
+
+    Ok(async_stream::stream! {
+        while let Some(event_result) = event_source.next().await {
+            match event_result {
+                Ok(Event::Open) => continue,
+                Ok(Event::Message(message)) if !budget.take(message.data.len()) => {
+                    yield Err(RESPONSE_TOO_LARGE.to_string());
+                    break;
+                }
+                Ok(Event::Message(message)) => match message.event.as_str() {
+                    "output" => yield Ok(text_chunk(&id, message.data, None)),
+                    "done" => {
+                        yield Ok(text_chunk(&id, String::new(), Some("stop")));
+                        break;
+                    }
+                    "error" => {
+                        yield Err(format!("Replicate prediction {} failed: {}", id, message.data));
+                        break;
+                    }
+                    _ => continue,
+                },
+                Err(reqwest_eventsource::Error::StreamEnded) => break,
+                Err(e) => {
+                    yield Err(e.to_string());
+                    break;
+                }
+            }
+        }
+
+        event_source.close();
+    })
+}
+
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::models::LlmApiProvider;
+    use crate::services::utils::test_server::{serve_capture, serve_with};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn prediction(status: &str, output: serde_json::Value) -> String {
+        json!({
+            "id": "pred-1",
+            "status": status,
+            "output": output,
+            "error": null,
+            "urls": { "get": "https://api.replicate.com/v1/predictions/pred-1" },
+            "metrics": { "input_token_count": 7, "output_token_count": 2 }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_poll_states() {
+        let parse = |body: String| serde_json::from_str::<Prediction>(&body).unwrap().poll_state();
+
+        assert_eq!(parse(prediction("starting", json!(null))), PollState::Running);
+        assert_eq!(parse(prediction("processing", json!(["Hel"]))), PollState::Running);
+        assert_eq!(parse(prediction("succeeded", json!(["Hel", "lo"]))), PollState::Succeeded("Hello".to_string()));
+        assert_eq!(parse(prediction("canceled", json!(null))), PollState::Failed("prediction was canceled".to_string()));
+
+        let failed = r#"{"id": "pred-1", "status": "failed", "error": "CUDA out of memory"}"#.to_string();
+        assert_eq!(parse(failed), PollState::Failed("CUDA out of memory".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_polls_until_succeeded() {
+        std::env::set_var("REPLICATE_API_TOKEN", "test-token");
+
+        // Created, then one poll still running, then done
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let base_url = serve_with(move |_| {
+            let body = match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => prediction("starting", json!(null)),
+                1 => prediction("processing", json!(["Hel"])),
+                _ => prediction("succeeded", json!(["Hel", "lo"])),
+            };
+            (200, body)
+        })
+        .await;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Replicate, "meta/meta-llama-3-8b-instruct", "Hi");
+        props.base_url = base_url;
+
+        let response = ReplicateProvider::new(&props, false).execute_chat().await.unwrap();
+        assert_eq!(response.choices[0].message.content, "Hello");
+        assert_eq!(response.usage.unwrap().total_tokens, 9);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_prediction_is_an_error() {
+        std::env::set_var("REPLICATE_API_TOKEN", "test-token");
+
+        let base_url = serve_with(|_| {
+            (200, r#"{"id": "pred-2", "status": "failed", "error": "model crashed"}"#.to_string())
+        })
+        .await;
+
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Replicate, "meta/meta-llama-3-8b-instruct", "Hi");
+        props.base_url = base_url;
+
+        let err = ReplicateProvider::new(&props, false).execute_chat().await.unwrap_err();
+        assert!(matches!(err, LlmError::Provider(msg) if msg.contains("pred-2") && msg.contains("model crashed")));
+    }
+
+    #[tokio::test]
+    async fn test_polling_gives_up_after_timeout() {
+        std::env::set_var("REPLICATE_API_TOKEN", "test-token");
+
+        let base_url = serve_with(|_| (200, prediction("processing", json!(null)))).await;
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Replicate, "meta/meta-llama-3-8b-instruct", "Hi");
+        props.base_url = base_url;
+
+        let err = ReplicateProvider::new(&props, false)
+            .with_timeout(Duration::from_millis(100))
+            .execute_chat()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Timeout(100)));
+    }
+
+    #[tokio::test]
+    async fn test_prediction_over_max_response_bytes() {
+        std::env::set_var("REPLICATE_API_TOKEN", "test-token");
+
+        let base_url = serve_with(|_| (200, prediction("succeeded", json!(["Hel", "lo"])))).await;
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Replicate, "meta/meta-llama-3-8b-instruct", "Hi");
+        props.base_url = base_url;
+        props.max_response_bytes = Some(16);
+
+        let err = ReplicateProvider::new(&props, false).execute_chat().await.unwrap_err();
+        assert!(matches!(err, LlmError::Provider(msg) if msg == RESPONSE_TOO_LARGE));
+    }
+
+    #[tokio::test]
+    async fn test_versioned_model_runs_through_predictions() {
+        std::env::set_var("REPLICATE_API_TOKEN", "test-token");
+
+        let (base_url, received) = serve_capture(200, prediction("succeeded", json!(["Hi"]))).await;
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Replicate, "replicate/flan-t5-xl:7a216605", "Hi");
+        props.base_url = base_url;
+
+        let provider = ReplicateProvider::new(&props, false);
+        assert_eq!(provider.create_body()["version"], "7a216605");
+        provider.execute_chat().await.unwrap();
+        assert!(received.await.unwrap().starts_with("POST /predictions HTTP/1.1\r\n"));
+
+        props.request.model = "meta/meta-llama-3-8b-instruct".to_string();
+        assert!(ReplicateProvider::new(&props, false).create_body().get("version").is_none());
+    }
+
+    #[test]
+    fn test_conversation_sent_as_transcript() {
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Replicate, "meta/meta-llama-3-8b-instruct", "Hi");
+        props.request.messages.insert(0, ChatCompletionRequestMessage::System { content: "Be brief".to_string(), name: None });
+        props.request.max_tokens = Some(64);
+
+        let body = ReplicateProvider::new(&props, false).create_body();
+        assert_eq!(body["input"], json!({ "prompt": "Hi", "system_prompt": "Be brief", "max_tokens": 64 }));
+        assert_eq!(body["stream"], false);
+
+        props.request.messages.push(ChatCompletionRequestMessage::Assistant { content: "Hello!".to_string(), tool_calls: None, name: None });
+        props.request.messages.push(ChatCompletionRequestMessage::User { content: "Bye".to_string(), name: None });
+        let body = ReplicateProvider::new(&props, true).create_body();
+        assert_eq!(body["input"]["prompt"], "user: Hi\nassistant: Hello!\nuser: Bye");
+        assert_eq!(body["stream"], true);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::common::types::chat_response::LlmServiceChatCompletionResponse;
+use crate::services::types::{
+    llm_error::LlmError, llm_error::LlmStreamingError, llm_service::LlmServiceRequest, stream::StreamEvent
+};
+
+use reqwest::RequestBuilder;
+use std::sync::Arc;
+use tokio::sync::{mpsc::Sender, OnceCell};
+
+use super::gemini::GeminiProvider;
+
+/// Region used when neither the request nor `GOOGLE_CLOUD_LOCATION` sets one.
+const DEFAULT_LOCATION: &str = "us-central1";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+static ADC: OnceCell<Arc<dyn gcp_auth::TokenProvider>> = OnceCell::const_new();
+
+/// Gemini models on Vertex AI, in the project and region of the request, by default
+/// `GOOGLE_CLOUD_PROJECT` and `GOOGLE_CLOUD_LOCATION`. Requests and responses are those
+/// of [`GeminiProvider`], only the url differs and the credential is an OAuth access
+/// token sent as a bearer token.
+pub struct VertexGeminiProvider<'a> {
+    props: &'a LlmServiceRequest,
+    inner: GeminiProvider<'a>,
+}
+
+impl<'a> VertexGeminiProvider<'a> {
+    pub fn new(props: &'a LlmServiceRequest, streaming: bool) -> Self {
+        VertexGeminiProvider {
+            props,
+            inner: GeminiProvider::new(props, streaming),
+        }
+    }
+
+    pub fn parse_response(json_text: &str) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        GeminiProvider::parse_response(json_text)
+    }
+
+    /// The base url of the request, an empty one is the endpoint of the region.
+    pub(crate) fn base_url(&self) -> String {
+        if self.props.base_url.is_empty() {
+            format!("https://{}-aiplatform.googleapis.com/v1", self.location())
+        } else {
+            self.props.base_url.clone()
+        }
+    }
+
+    /// The request sent to `{base_url}/projects/{project}/locations/{location}/publishers/google/models/{model}`.
+    pub(crate) fn build_request(&self, access_token: &str) -> Result<RequestBuilder, LlmError> {
+        let props = self.props;
+        let project = match &props.vertex_project {
+            Some(project) => project.clone(),
+            None => std::env::var("GOOGLE_CLOUD_PROJECT")
+                .map_err(|_| LlmError::InvalidConfig("Missing GOOGLE_CLOUD_PROJECT".to_string()))?,
+        };
+        let location = self.location();
+
+        let url = format!(
+            "{}/projects/{}/locations/{}/publishers/google/models/{}:{}",
+            self.base_url(),
+            project,
+            location,
+            publisher_model(&props.request.model),
+            self.inner.method()
+        );
+
+        self.inner.build_request_to(&url, |request| request.bearer_auth(access_token))
+    }
+
+    pub async fn execute_chat(&self) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        self.execute_chat_as(&access_token().await?).await
+    }
+
+    /// [`execute_chat`](Self::execute_chat) with `access_token` instead of one from
+    /// Application Default Credentials.
+    pub(crate) async fn execute_chat_as(&self, access_token: &str) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let request = self.build_request(access_token)?;
+        self.inner.execute_chat_with(request, None).await
+    }
+
+    pub async fn execute_chat_stream(
+        &self,
+        tx: Sender<Result<StreamEvent, LlmStreamingError>>,
+    ) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+        let request = self.build_request(&access_token().await?)?;
+        self.inner.execute_chat_stream_with(request, None, tx).await
+    }
+
+    fn location(&self) -> String {
+        self.props
+            .vertex_location
+            .clone()
+            .or_else(|| std::env::var("GOOGLE_CLOUD_LOCATION").ok())
+            .unwrap_or_else(|| DEFAULT_LOCATION.to_string())
+    }
+}
+
+/// Gemini ids are also written as `models/{model}`, Vertex only takes the bare id.
+fn publisher_model(model: &str) -> &str {
+    model.strip_prefix("models/").unwrap_or(model)
+}
+
+/// An access token from Application Default Credentials, cached and refreshed before it
+/// expires.
+async fn access_token() -> Result<String, LlmError> {
+    let adc = ADC
+        .get_or_try_init(gcp_auth::provider)
+        .await
+        .map_err(|e| LlmError::Auth(format!("No Application Default Credentials: {}", e)))?;
+    let token = adc
+        .token(&[CLOUD_PLATFORM_SCOPE])
+        .await
+        .map_err(|e| LlmError::Auth(e.to_string()))?;
+    Ok(token.as_str().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::models::LlmApiProvider;
+    use crate::services::utils::test_server::serve_capture;
+    use serde_json::json;
+
+    /// A request in `my-project` and `europe-west4`, whatever the environment says.
+    fn vertex_props(model: &str) -> LlmServiceRequest {
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::VertexAi, model, "Hello");
+        props.vertex_project = Some("my-project".to_string());
+        props.vertex_location = Some("europe-west4".to_string());
+        props
+    }
+
+    #[test]
+    fn test_vertex_url_and_bearer_token() {
+        let props = vertex_props("gemini-2.0-flash");
+
+        let request = VertexGeminiProvider::new(&props, false).build_request("ya29.token").unwrap().build().unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://europe-west4-aiplatform.googleapis.com/v1/projects/my-project/locations/europe-west4/publishers/google/models/gemini-2.0-flash:generateContent"
+        );
+        assert_eq!(request.headers()["authorization"], "Bearer ya29.token");
+        assert!(request.url().query().is_none());
+
+        let request = VertexGeminiProvider::new(&props, true).build_request("ya29.token").unwrap().build().unwrap();
+        assert!(request.url().path().ends_with("/models/gemini-2.0-flash:streamGenerateContent"));
+        assert_eq!(request.url().query(), Some("alt=sse"));
+
+        let props = vertex_props("models/gemini-2.0-flash");
+        let request = VertexGeminiProvider::new(&props, false).build_request("ya29.token").unwrap().build().unwrap();
+        assert!(request.url().path().ends_with("/publishers/google/models/gemini-2.0-flash:generateContent"));
+    }
+
+    #[test]
+    fn test_vertex_default_base_url() {
+        let mut props = vertex_props("gemini-2.0-flash");
+        assert_eq!(VertexGeminiProvider::new(&props, false).base_url(), "https://europe-west4-aiplatform.googleapis.com/v1");
+
+        props.base_url = "http://localhost:8080".to_string();
+        assert_eq!(VertexGeminiProvider::new(&props, false).base_url(), "http://localhost:8080");
+    }
+
+    #[tokio::test]
+    async fn test_vertex_execute_chat() {
+        let body = json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi from Vertex" }] }, "finishReason": "STOP" }],
+            "modelVersion": "gemini-2.0-flash-001"
+        });
+        let (base_url, received) = serve_capture(200, body.to_string()).await;
+        let mut props = vertex_props("gemini-2.0-flash");
+        props.base_url = base_url;
+
+        let response = VertexGeminiProvider::new(&props, false).execute_chat_as("ya29.test-token").await.unwrap();
+        assert_eq!(response.choices[0].message.content, "Hi from Vertex");
+        assert_eq!(response.model_version.as_deref(), Some("gemini-2.0-flash-001"));
+
+        let request = received.await.unwrap();
+        assert!(request.starts_with(
+            "POST /projects/my-project/locations/europe-west4/publishers/google/models/gemini-2.0-flash:generateContent HTTP/1.1\r\n"
+        ));
+        assert!(request.to_lowercase().contains("authorization: bearer ya29.test-token\r\n"));
+    }
+}
@@ -7,7 +7,8 @@ pub enum LlmError {
     Network(#[from] reqwest::Error),
     #[error("Eventsource cannot clone request: {0}")]
     EventSourceError(#[from] reqwest_eventsource::CannotCloneRequestError),
-    #[error("Request timeout after {0} seconds")]
+    /// The time budget that ran out, in milliseconds.
+    #[error("Request timeout after {0} ms")]
     Timeout(u64),
     #[error("Rate limit exceeded: {0}")]
     RateLimit(String),
@@ -23,14 +24,20 @@ pub enum LlmError {
     // Serialization/Deserialization errors
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
-    #[error("JSON returned from model did match match the JSON schema specified in the prompt")]
-    InvalidJsonSchema,
+    #[error("JSON returned from model did not match the JSON schema specified in the prompt: {0}")]
+    InvalidJsonSchema(String),
     #[error("Invalid UTF8 in chunk: {0}")]
     Utf8Error(#[from] std::str::Utf8Error),
     
     // Provider-specific errors
     #[error("Provider error: {0}")]
     Provider(String),
+    /// A request the provider rejected, with the machine readable `code` from its error
+    /// body when it has one: Gemini's `status` (`INVALID_ARGUMENT`) or OpenAi's `code`.
+    #[error("Provider error: API error ({status}): {message}")]
+    ProviderError { status: u16, code: Option<String>, message: String },
+    #[error("Provider server error ({status}): {body}")]
+    ServerError { status: u16, body: String },
     #[error("Provider unavailable: {0}")]
     ProviderUnavailable(String),
     #[error("Provider quota exceeded")]
@@ -59,6 +66,8 @@ pub enum LlmError {
     PromptTooLong(usize, usize),
     #[error("Content policy violation: {0}")]
     ContentPolicy(String),
+    #[error("Invalid {field}: {message}")]
+    Validation { field: String, message: String },
     
     // Concurrency/Task errors
     #[error("MPSC Sender failed to send message in channel: {0}")]
@@ -67,6 +76,8 @@ pub enum LlmError {
     TokioTaskJoin(#[from] tokio::task::JoinError),
     #[error("Task canceled")]
     TaskCanceled,
+    #[error("Model still calling tools after {0} iterations")]
+    ToolLoopLimit(u32),
     
     // Logging/Metrics errors
     #[error("Missing Usage from chunk")]
@@ -86,43 +97,274 @@ pub enum LlmError {
     #[error("Invalid configuration: {0}")]
     InvalidConfig(String),
     
-    // New errors for OpenRouter API library integration
-    #[error("Invalid URL: {0}")]
-    InvalidUrl(String),
-    #[error("Authentication error from OpenRouter: {0}")]
-    AuthError(String),
-    #[error("Header construction error: {0}")]
-    HeaderError(String),
     #[error("Serialization error: {0}")]
     SerializationError(String),
     #[error("Deserialization error: {0}")]
     DeserializationError(String)
 }
 
-impl From<openrouter_api::Error> for LlmError {
-    fn from(err: openrouter_api::Error) -> Self {
+#[derive(Debug)]
+pub enum LlmStreamingError {
+    StreamError(String),
+    ParseError(String),
+    ReceiverDropped,
+}
+
+impl From<LlmStreamingError> for LlmError {
+    fn from(err: LlmStreamingError) -> Self {
         match err {
-            openrouter_api::Error::HttpError(e) => LlmError::Network(e),
-            openrouter_api::Error::ApiError { code, message, metadata: _ } => {
-                // Map based on status code
-                match code {
-                    401 | 403 => LlmError::Auth(message),
-                    404 => LlmError::NotFound(message),
-                    429 => LlmError::RateLimit(message),
-                    _ => LlmError::Provider(format!("API error ({}): {}", code, message))
+            LlmStreamingError::StreamError(msg) => LlmError::Provider(msg),
+            LlmStreamingError::ParseError(msg) => LlmError::DeserializationError(msg),
+            LlmStreamingError::ReceiverDropped => LlmError::TaskCanceled,
+        }
+    }
+}
+
+/// Provider independent category of an error, for reacting to failures the same way
+/// whichever provider phrased them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalizedError {
+    /// The prompt, or prompt plus `max_tokens`, doesn't fit the model's context window.
+    ContextOverflow,
+    /// The model doesn't exist or can't be used for this kind of request.
+    InvalidModel,
+    /// The prompt or the answer was blocked by a safety or moderation filter.
+    ContentFiltered,
+    RateLimited,
+    Auth,
+    Unknown,
+}
+
+impl NormalizedError {
+    /// Snake case name, e.g. for metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NormalizedError::ContextOverflow => "context_overflow",
+            NormalizedError::InvalidModel => "invalid_model",
+            NormalizedError::ContentFiltered => "content_filtered",
+            NormalizedError::RateLimited => "rate_limited",
+            NormalizedError::Auth => "auth",
+            NormalizedError::Unknown => "unknown",
+        }
+    }
+}
+
+/// Phrases providers use in error messages and codes, checked in order on the lowercased
+/// text. Context length errors come first since they often mention the model as well.
+const ERROR_PHRASES: &[(NormalizedError, &[&str])] = &[
+    (
+        NormalizedError::ContextOverflow,
+        &[
+            "context_length_exceeded",
+            "maximum context length",
+            "context length",
+            "context window",
+            "input token count",
+            "exceeds the maximum number of tokens",
+            "prompt is too long",
+            "too many tokens",
+        ],
+    ),
+    (
+        NormalizedError::InvalidModel,
+        &["model_not_found", "invalid model", "model not found", "is not found for api version", "does not exist", "unknown model"],
+    ),
+    (
+        NormalizedError::ContentFiltered,
+        &["content_filter", "content filter", "content management policy", "safety", "prompt blocked", "moderation"],
+    ),
+    (NormalizedError::RateLimited, &["rate_limit", "rate limit", "resource_exhausted", "quota", "too many requests"]),
+    (NormalizedError::Auth, &["api key not valid", "invalid_api_key", "invalid api key", "unauthenticated", "permission_denied"]),
+];
+
+impl LlmError {
+    /// The error for a rejected request from its status and error body, see
+    /// [`LlmError::ProviderError`]. A body that isn't a JSON error is kept as the message.
+    pub fn from_error_body(status: u16, body: &str) -> Self {
+        let (code, message) = parse_error_body(body);
+        LlmError::ProviderError { status, code, message }
+    }
+
+    /// The provider's error code, e.g. Gemini's `RESOURCE_EXHAUSTED`, also for the errors
+    /// a status is mapped to first ([`LlmError::Auth`], [`LlmError::RateLimit`], ...).
+    pub fn provider_code(&self) -> Option<String> {
+        match self {
+            LlmError::ProviderError { code, .. } => code.clone(),
+            _ => parse_error_body(self.error_body()?).0,
+        }
+    }
+
+    /// The message of the provider's error body, the whole body when it isn't a JSON error.
+    /// `None` for errors that didn't come from a provider response.
+    pub fn provider_message(&self) -> Option<String> {
+        match self {
+            LlmError::ProviderError { message, .. } => Some(message.clone()),
+            _ => Some(parse_error_body(self.error_body()?).1),
+        }
+    }
+
+    /// The response body carried by the variants [`error_for_status`](crate::services::utils::client::error_for_status) maps statuses to.
+    fn error_body(&self) -> Option<&str> {
+        match self {
+            LlmError::Auth(body) | LlmError::NotFound(body) | LlmError::RateLimit(body) | LlmError::ServerError { body, .. } => {
+                Some(body.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// Classifies the error, parsing the provider's error body where there is one.
+    pub fn normalized(&self) -> NormalizedError {
+        match self {
+            LlmError::PromptTooLong(..) => NormalizedError::ContextOverflow,
+            LlmError::ModelNotFound(_) => NormalizedError::InvalidModel,
+            LlmError::ContentPolicy(_) => NormalizedError::ContentFiltered,
+            LlmError::RateLimit(_) | LlmError::ProviderQuotaExceeded => NormalizedError::RateLimited,
+            LlmError::Auth(body) => {
+                // Gemini answers 403 for a key that can't use the model, keep that as a model problem
+                match classify_error_body(body) {
+                    NormalizedError::InvalidModel => NormalizedError::InvalidModel,
+                    _ => NormalizedError::Auth,
                 }
+            }
+            LlmError::InvalidApiKey | LlmError::InsufficientPermissions => NormalizedError::Auth,
+            LlmError::Http(status) if status.as_u16() == 429 => NormalizedError::RateLimited,
+            LlmError::Http(status) if matches!(status.as_u16(), 401 | 403) => NormalizedError::Auth,
+            LlmError::NotFound(body) => match classify_error_body(body) {
+                NormalizedError::Unknown => NormalizedError::InvalidModel,
+                other => other,
             },
-            openrouter_api::Error::ConfigError(msg) => LlmError::InvalidConfig(msg),
-            openrouter_api::Error::StructuredOutputNotSupported => LlmError::NotImplemented("Structured output not supported".to_string()),
-            openrouter_api::Error::SchemaValidationError(msg) => LlmError::DeserializationError(msg),
-            openrouter_api::Error::Unknown => LlmError::Internal("Unknown OpenRouter API error".to_string()),
+            LlmError::Provider(body) | LlmError::ServerError { body, .. } => classify_error_body(body),
+            LlmError::ProviderError { code, message, .. } => {
+                classify_error_body(&format!("{} {}", message, code.as_deref().unwrap_or_default()))
+            }
+            _ => NormalizedError::Unknown,
         }
     }
 }
 
-#[derive(Debug)]
-pub enum LlmStreamingError {
-    StreamError(String),
-    ParseError(String),
-    ReceiverDropped,
+/// The machine readable code and the message of an error body: Gemini's `status` or
+/// OpenAi's `code`, and the `message`. A body that isn't a JSON error is the message.
+fn parse_error_body(body: &str) -> (Option<String>, String) {
+    let json = serde_json::from_str::<serde_json::Value>(body).ok();
+    let error = json.as_ref().map(|json| json.get("error").unwrap_or(json));
+
+    match error {
+        Some(serde_json::Value::String(message)) => (None, message.clone()),
+        Some(error) => (
+            ["status", "code"].iter().find_map(|field| error.get(field)?.as_str().map(str::to_string)),
+            error.get("message").and_then(|m| m.as_str()).unwrap_or(body).to_string(),
+        ),
+        None => (None, body.to_string()),
+    }
+}
+
+/// Classifies a provider error body. Bodies are usually `{"error": {"message", "code",
+/// "status"/"type"}}` (OpenAi style and Gemini alike); anything else is matched as text.
+fn classify_error_body(body: &str) -> NormalizedError {
+    let text = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(json) => {
+            let error = json.get("error").unwrap_or(&json);
+            ["message", "code", "status", "type"]
+                .iter()
+                .filter_map(|field| error.get(field).map(|v| v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string())))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        Err(_) => body.to_string(),
+    }
+    .to_lowercase();
+
+    ERROR_PHRASES
+        .iter()
+        .find(|(_, phrases)| phrases.iter().any(|phrase| text.contains(phrase)))
+        .map_or(NormalizedError::Unknown, |(category, _)| *category)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gemini_context_length_error() {
+        let body = r#"{"error": {"code": 400, "message": "The input token count (1200000) exceeds the maximum number of tokens allowed (1048576).", "status": "INVALID_ARGUMENT"}}"#;
+        let err = LlmError::Provider(format!("API error (400): {}", body));
+
+        // The prefix added to the body doesn't stop it from being classified as text
+        assert_eq!(err.normalized(), NormalizedError::ContextOverflow);
+        assert_eq!(classify_error_body(body), NormalizedError::ContextOverflow);
+    }
+
+    #[test]
+    fn test_gemini_invalid_model_error() {
+        let body = r#"{"error": {"code": 404, "message": "models/gemini-9-ultra is not found for API version v1beta, or is not supported for generateContent.", "status": "NOT_FOUND"}}"#;
+        assert_eq!(LlmError::NotFound(body.to_string()).normalized(), NormalizedError::InvalidModel);
+    }
+
+    #[test]
+    fn test_openai_style_errors() {
+        let body = r#"{"error": {"message": "This model's maximum context length is 128000 tokens.", "type": "invalid_request_error", "code": "context_length_exceeded"}}"#;
+        assert_eq!(LlmError::Provider(body.to_string()).normalized(), NormalizedError::ContextOverflow);
+
+        let body = r#"{"error": {"message": "The response was filtered", "code": "content_filter"}}"#;
+        assert_eq!(LlmError::Provider(body.to_string()).normalized(), NormalizedError::ContentFiltered);
+
+        assert_eq!(LlmError::RateLimit("slow down".to_string()).normalized(), NormalizedError::RateLimited);
+        assert_eq!(LlmError::Auth(r#"{"error": "bad key"}"#.to_string()).normalized(), NormalizedError::Auth);
+        assert_eq!(
+            LlmError::ServerError { status: 502, body: "<html>Bad Gateway</html>".to_string() }.normalized(),
+            NormalizedError::Unknown
+        );
+    }
+
+    #[test]
+    fn test_gemini_error_codes() {
+        let body = r#"{"error": {"code": 400, "message": "API key not valid. Please pass a valid API key.", "status": "INVALID_ARGUMENT"}}"#;
+        let err = LlmError::from_error_body(400, body);
+        match &err {
+            LlmError::ProviderError { status, code, message } => {
+                assert_eq!(*status, 400);
+                assert_eq!(code.as_deref(), Some("INVALID_ARGUMENT"));
+                assert_eq!(message, "API key not valid. Please pass a valid API key.");
+            }
+            other => panic!("Expected a provider error, got {:?}", other),
+        }
+        assert_eq!(err.to_string(), "Provider error: API error (400): API key not valid. Please pass a valid API key.");
+        assert_eq!(err.normalized(), NormalizedError::Auth);
+
+        let body = r#"{"error": {"code": 400, "message": "User location is not supported for the API use.", "status": "FAILED_PRECONDITION"}}"#;
+        assert!(matches!(
+            LlmError::from_error_body(400, body),
+            LlmError::ProviderError { status: 400, code: Some(code), .. } if code == "FAILED_PRECONDITION"
+        ));
+
+        // Without a JSON error the body is the message
+        assert!(matches!(
+            LlmError::from_error_body(422, "unprocessable"),
+            LlmError::ProviderError { status: 422, code: None, message } if message == "unprocessable"
+        ));
+    }
+
+    #[test]
+    fn test_provider_code_of_mapped_statuses() {
+        let body = r#"{"error": {"code": 429, "message": "Resource has been exhausted (e.g. check quota).", "status": "RESOURCE_EXHAUSTED"}}"#;
+        let err = LlmError::RateLimit(body.to_string());
+        assert_eq!(err.provider_code().as_deref(), Some("RESOURCE_EXHAUSTED"));
+        assert_eq!(err.provider_message().as_deref(), Some("Resource has been exhausted (e.g. check quota)."));
+
+        let body = r#"{"error": {"code": 503, "message": "The model is overloaded.", "status": "UNAVAILABLE"}}"#;
+        let err = LlmError::ServerError { status: 503, body: body.to_string() };
+        assert_eq!(err.provider_code().as_deref(), Some("UNAVAILABLE"));
+
+        let err = LlmError::from_error_body(400, r#"{"error": {"message": "bad", "status": "INVALID_ARGUMENT"}}"#);
+        assert_eq!(err.provider_code().as_deref(), Some("INVALID_ARGUMENT"));
+        assert_eq!(err.provider_message().as_deref(), Some("bad"));
+
+        // An error page has no code but is still the message
+        let err = LlmError::ServerError { status: 502, body: "<html>Bad Gateway</html>".to_string() };
+        assert_eq!(err.provider_code(), None);
+        assert_eq!(err.provider_message().as_deref(), Some("<html>Bad Gateway</html>"));
+
+        assert_eq!(LlmError::EmptyResponse.provider_message(), None);
+    }
 }
@@ -1,16 +1,27 @@
-use serde::Serialize;
+use std::{borrow::Cow, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tera::{Context, Tera};
 
 use crate::{
     common::types::{
+        chat::ChatRequest,
         chat_request::{
             ChatCompletionRequest, ChatCompletionRequestJsonSchema, ChatCompletionRequestMessage,
             ChatCompletionRequestResponseFormat,
         },
-        models::LlmApiProvider,
+        models::{LlmApiProvider, ProviderCapabilities},
     },
     db::types::prompt::PromptRowWithModel,
+    services::{
+        types::llm_error::LlmError,
+        utils::{
+            stream::{Coalescing, StreamCancel},
+            token_budget::{check_context_fit, CharEstimate},
+            transform::ResponseTransform,
+        },
+    },
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -23,13 +34,265 @@ pub enum LlmServiceRequestError {
     ChatMessagesInputError,
 }
 
-#[derive(Serialize, Clone, Debug)]
+/// How a streamed response is framed on the wire, for providers that offer a choice.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamTransport {
+    /// Server-sent events, one chunk per `data:` line.
+    #[default]
+    Sse,
+    /// A single JSON array whose elements arrive incrementally. Useful behind
+    /// proxies that buffer or mangle SSE.
+    JsonArray,
+}
+
+/// How a request whose prompt and `max_tokens` together overflow the context window is
+/// handled. Providers don't agree on it, some reject the request and some silently cut
+/// the answer short.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextOverflow {
+    /// Log a warning and send the request anyway.
+    #[default]
+    Warn,
+    /// Fail with [`LlmError::PromptTooLong`] before anything is sent.
+    Error,
+}
+
+/// How several system messages are reduced for a provider that takes a single system
+/// prompt, see [`LlmApiProvider::single_system_message`]. Other providers get them as sent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMessagePolicy {
+    /// Join them in order, separated by a blank line.
+    #[default]
+    Join,
+    /// Keep the first one and drop the rest.
+    FirstOnly,
+    /// Fail validation with more than one.
+    Error,
+}
+
+/// Kinds of output a model can be asked to produce.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Modality {
+    Text,
+    Image,
+}
+
+/// Soft request for how the answer should be formatted. No provider has a native field
+/// for this, so it is sent as a fixed sentence appended to the system prompt.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FormatHint {
+    Markdown,
+    PlainText,
+}
+
+impl FormatHint {
+    pub fn instruction(&self) -> &'static str {
+        match self {
+            FormatHint::Markdown => "Format your answer as Markdown.",
+            FormatHint::PlainText => "Format your answer as plain text, without Markdown or other markup.",
+        }
+    }
+}
+
+/// A field that differs between two requests, see [`LlmServiceRequest::diff`].
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FieldDiff {
+    /// Path of the field in the serialized request, e.g. `request.temperature` or
+    /// `request.messages[1].content`. Lists that differ in length are reported as `.len`.
+    pub field: String,
+    /// `null` when the field is missing on that side.
+    pub left: Value,
+    pub right: Value,
+}
+
+/// Everything needed to send one request to a provider.
+///
+/// Serializes to a stable JSON object so requests can be saved and replayed later:
+/// `provider` is the provider name, `request` is the OpenAi style chat request and
+/// the remaining fields are plain values. Fields added over time default when missing,
+/// so older saved requests keep loading. No credentials are part of a request, API keys
+/// are always read from the environment when it is sent. `response_transforms` are code
+/// and are not serialized; a loaded request has none.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LlmServiceRequest {
     pub provider: LlmApiProvider,
     pub base_url: String,
     pub prompt_id: i64,
     pub model_id: i64,
     pub request: ChatCompletionRequest,
+    /// Whether the model can stream responses.
+    pub supports_streaming: bool,
+    /// When streaming is requested for a model that can't stream, make a regular request
+    /// and deliver the whole response as a single chunk instead of failing. On by default.
+    #[serde(default = "default_true")]
+    pub fallback_to_nonstreaming: bool,
+    #[serde(default)]
+    pub stream_transport: StreamTransport,
+    /// Output modalities to request, empty leaves it to the provider (text only).
+    #[serde(default)]
+    pub response_modalities: Vec<Modality>,
+    /// Alternative answers to generate, Gemini only. They come back as separate choices,
+    /// streamed chunks tell them apart by their choice index.
+    #[serde(default)]
+    pub candidate_count: Option<u32>,
+    /// Ask for the model's thought summaries, Gemini only (`thinkingConfig.includeThoughts`).
+    /// They come back as the message's `reasoning`, or as thinking blocks when streaming.
+    #[serde(default)]
+    pub include_thoughts: bool,
+    #[serde(default)]
+    pub format_hint: Option<FormatHint>,
+    /// Abort a stream when this long passes between two chunks, see
+    /// [`forward_stream_with`](crate::services::utils::stream::forward_stream_with).
+    /// Serialized as integer milliseconds.
+    #[serde(default, with = "crate::services::utils::duration_ms::option")]
+    pub max_token_gap: Option<Duration>,
+    /// Merge streamed text into fewer, larger chunks, see [`Coalescing`]. Off by default.
+    #[serde(default)]
+    pub coalesce: Option<Coalescing>,
+    /// End a stream as soon as the first tool call is complete, for agent loops that run
+    /// it right away. Whatever the model would have sent after it is never requested.
+    #[serde(default)]
+    pub stop_at_tool_call: bool,
+    /// Hard local cap on a streamed answer, regardless of `max_tokens`: the stream ends
+    /// once about this many tokens were forwarded. Counted from the text, so approximate.
+    #[serde(default)]
+    pub client_max_tokens: Option<u32>,
+    /// Fail once a response body is longer than this many bytes, against runaway answers
+    /// or a misbehaving endpoint. Streams count the bytes of their events and end with a
+    /// `StreamError` instead.
+    #[serde(default)]
+    pub max_response_bytes: Option<usize>,
+    /// Drop consecutive identical messages (same role and content) before sending.
+    #[serde(default)]
+    pub dedupe_adjacent: bool,
+    /// How many times a reply cut off by `max_tokens` is continued automatically, by
+    /// sending it back with a request to continue. Regular requests only, not streams.
+    #[serde(default)]
+    pub auto_continue: Option<u8>,
+    /// Gzip the request body, for very large prompts. Ignored by providers that don't
+    /// accept compressed requests (see [`accepts_gzip`](crate::services::utils::client::accepts_gzip)).
+    #[serde(default)]
+    pub compress_request: bool,
+    /// Check the answer against `response_format.json_schema`, since providers don't always
+    /// hold to it, and fail with [`LlmError::InvalidJsonSchema`] when it doesn't conform.
+    #[serde(default)]
+    pub validate_response: bool,
+    /// Run a JSON answer that doesn't parse through [`repair_json`](crate::services::utils::json_repair::repair_json)
+    /// before it is validated or deserialized. Only for requests with a `response_format`.
+    #[serde(default)]
+    pub repair_json: bool,
+    /// What to do when the estimated prompt plus `max_tokens` is more than the model's
+    /// context window, checked by [`validate`](Self::validate).
+    #[serde(default)]
+    pub context_overflow: ContextOverflow,
+    /// How several system messages are sent to a provider that takes only one.
+    #[serde(default)]
+    pub system_message_policy: SystemMessagePolicy,
+    /// Adapt the request to what the provider can do instead of sending options it would
+    /// reject or ignore, see [`adapted_to`](Self::adapted_to).
+    #[serde(default)]
+    pub adapt_to_capabilities: bool,
+    /// Send the request to this provider instead of `provider`, e.g. when `provider` was
+    /// inferred from an ambiguous model id that several vendors host. See [`routed`](Self::routed).
+    #[serde(default)]
+    pub provider_override: Option<LlmApiProvider>,
+    /// Google Cloud project of a Vertex AI request, `GOOGLE_CLOUD_PROJECT` when unset.
+    #[serde(default)]
+    pub vertex_project: Option<String>,
+    /// Google Cloud region of a Vertex AI request, `GOOGLE_CLOUD_LOCATION` when unset.
+    #[serde(default)]
+    pub vertex_location: Option<String>,
+    /// Applied in order to the final response text and to each streamed token.
+    #[serde(skip)]
+    pub response_transforms: Vec<Arc<dyn ResponseTransform>>,
+    /// Cancels a stream of this request from elsewhere, see [`StreamCancel`]. Not serialized.
+    #[serde(skip)]
+    pub stream_cancel: Option<StreamCancel>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn diff_values(path: &str, left: &Value, right: &Value, diffs: &mut Vec<FieldDiff>) {
+    if left == right {
+        return;
+    }
+
+    match (left, right) {
+        (Value::Object(l), Value::Object(r)) => {
+            let mut keys: Vec<&String> = l.keys().chain(r.keys().filter(|k| !l.contains_key(*k))).collect();
+            keys.sort();
+            for key in keys {
+                let field = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                diff_values(&field, l.get(key).unwrap_or(&Value::Null), r.get(key).unwrap_or(&Value::Null), diffs);
+            }
+        }
+        (Value::Array(l), Value::Array(r)) => {
+            if l.len() != r.len() {
+                diffs.push(FieldDiff {
+                    field: format!("{}.len", path),
+                    left: l.len().into(),
+                    right: r.len().into(),
+                });
+            }
+            for (i, (l, r)) in l.iter().zip(r).enumerate() {
+                diff_values(&format!("{}[{}]", path, i), l, r, diffs);
+            }
+        }
+        _ => diffs.push(FieldDiff {
+            field: path.to_string(),
+            left: left.clone(),
+            right: right.clone(),
+        }),
+    }
+}
+
+/// Appends `instruction` to the first system message, or adds one at the start when there is none.
+fn append_system_instruction(messages: &mut Vec<ChatCompletionRequestMessage>, instruction: &str) {
+    match messages.iter_mut().find_map(|msg| match msg {
+        ChatCompletionRequestMessage::System { content, .. } => Some(content),
+        _ => None,
+    }) {
+        Some(content) if content.is_empty() => content.push_str(instruction),
+        Some(content) => {
+            content.push_str("\n\n");
+            content.push_str(instruction);
+        }
+        None => messages.insert(
+            0,
+            ChatCompletionRequestMessage::System {
+                content: instruction.to_string(),
+                name: None,
+            },
+        ),
+    }
+}
+
+/// Stands in for a `response_format` the provider can't take natively.
+fn json_instruction(format: &ChatCompletionRequestResponseFormat) -> String {
+    let instruction = "Respond only with valid JSON, without Markdown code fences or any other text.";
+    match &format.json_schema {
+        Some(json_schema) => format!("{} It must conform to this JSON schema:\n{}", instruction, json_schema.schema),
+        None => instruction.to_string(),
+    }
+}
+
+/// Same role, name and content. Tool results and assistant turns calling tools are
+/// never duplicates, each answers or makes its own call even when the text matches.
+fn is_duplicate_turn(prev: &ChatCompletionRequestMessage, next: &ChatCompletionRequestMessage) -> bool {
+    let calls_tools = |msg: &ChatCompletionRequestMessage| {
+        matches!(msg, ChatCompletionRequestMessage::Assistant { tool_calls: Some(_), .. })
+    };
+    if prev.is_tool() || next.is_tool() || calls_tools(prev) || calls_tools(next) {
+        return false;
+    }
+
+    prev.role() == next.role() && prev.name() == next.name() && prev.content() == next.content()
 }
 
 impl LlmServiceRequest {
@@ -158,6 +421,31 @@ impl LlmServiceRequest {
             provider: prompt.provider_name.clone().into(),
             base_url: prompt.provider_base_url.clone(),
             request: new_request,
+            supports_streaming: prompt.supports_streaming,
+            fallback_to_nonstreaming: true,
+            stream_transport: StreamTransport::default(),
+            response_modalities: vec![],
+            candidate_count: None,
+            include_thoughts: false,
+            format_hint: None,
+            max_token_gap: None,
+            coalesce: None,
+            stop_at_tool_call: false,
+            client_max_tokens: None,
+            max_response_bytes: None,
+            dedupe_adjacent: false,
+            auto_continue: None,
+            compress_request: false,
+            validate_response: false,
+            repair_json: false,
+            context_overflow: ContextOverflow::Warn,
+            system_message_policy: SystemMessagePolicy::Join,
+            adapt_to_capabilities: false,
+            provider_override: None,
+            vertex_project: None,
+            vertex_location: None,
+            response_transforms: vec![],
+            stream_cancel: None,
         };
 
         // Override input with inputs from Prompt table
@@ -204,6 +492,221 @@ impl LlmServiceRequest {
 
         Ok(service_request)
     }
+
+    /// A bare request for ad-hoc use outside of the prompt tables: a single user message,
+    /// provider default sampling and the provider's public base url.
+    pub fn from_user_prompt(provider: LlmApiProvider, model: &str, prompt: &str) -> Self {
+        let chat = ChatRequest::new(vec![ChatCompletionRequestMessage::User {
+            content: prompt.to_string(),
+            name: None,
+        }]);
+        Self::from_chat_request(provider, model, chat)
+    }
+
+    /// Sends `chat` to `model` at the provider's public base url, with every provider
+    /// specific option left at its default.
+    pub fn from_chat_request(provider: LlmApiProvider, model: &str, chat: ChatRequest) -> Self {
+        LlmServiceRequest {
+            base_url: provider.default_base_url().to_string(),
+            provider,
+            prompt_id: 0,
+            model_id: 0,
+            request: chat.into_completion_request(model),
+            supports_streaming: true,
+            fallback_to_nonstreaming: true,
+            stream_transport: StreamTransport::default(),
+            response_modalities: vec![],
+            candidate_count: None,
+            include_thoughts: false,
+            format_hint: None,
+            max_token_gap: None,
+            coalesce: None,
+            stop_at_tool_call: false,
+            client_max_tokens: None,
+            max_response_bytes: None,
+            dedupe_adjacent: false,
+            auto_continue: None,
+            compress_request: false,
+            validate_response: false,
+            repair_json: false,
+            context_overflow: ContextOverflow::Warn,
+            system_message_policy: SystemMessagePolicy::Join,
+            adapt_to_capabilities: false,
+            provider_override: None,
+            vertex_project: None,
+            vertex_location: None,
+            response_transforms: vec![],
+            stream_cancel: None,
+        }
+    }
+
+    /// The provider agnostic part of the request.
+    pub fn chat_request(&self) -> ChatRequest {
+        ChatRequest::from(&self.request)
+    }
+
+    /// A copy of the request with `message` appended to the conversation, leaving this
+    /// one as it is.
+    pub fn with_appended(&self, message: ChatCompletionRequestMessage) -> Self {
+        let mut props = self.clone();
+        props.request.messages.push(message);
+        props
+    }
+
+    /// The fields that differ between this request and `other`, by path in their JSON
+    /// form, for auditing what changed between two configs. Fields that aren't
+    /// serialized, such as the response transforms, aren't compared.
+    pub fn diff(&self, other: &LlmServiceRequest) -> Vec<FieldDiff> {
+        let left = serde_json::to_value(self).expect("requests always serialize");
+        let right = serde_json::to_value(other).expect("requests always serialize");
+
+        let mut diffs = Vec::new();
+        diff_values("", &left, &right, &mut diffs);
+        diffs
+    }
+
+    /// A copy of the request with `system` as its only system message, at the start in
+    /// place of the ones it had. The rest of the conversation is kept as it is.
+    pub fn with_system(&self, system: &str) -> Self {
+        let mut props = self.clone();
+        props.request.messages.retain(|msg| !msg.is_system());
+        props.request.messages.insert(
+            0,
+            ChatCompletionRequestMessage::System {
+                content: system.to_string(),
+                name: None,
+            },
+        );
+        props
+    }
+
+    /// The messages as they are sent: with adjacent duplicates dropped when
+    /// `dedupe_adjacent` is set, and the [`FormatHint`] folded into the first system
+    /// message, or into a new one at the start when there is none.
+    pub fn outgoing_messages(&self) -> Cow<'_, [ChatCompletionRequestMessage]> {
+        if self.format_hint.is_none() && !self.dedupe_adjacent {
+            return Cow::Borrowed(&self.request.messages);
+        }
+
+        let mut messages = self.request.messages.clone();
+        if self.dedupe_adjacent {
+            messages.dedup_by(|next, prev| is_duplicate_turn(prev, next));
+        }
+
+        if let Some(hint) = self.format_hint {
+            append_system_instruction(&mut messages, hint.instruction());
+        }
+        Cow::Owned(messages)
+    }
+
+    /// The system prompt of `messages` for a provider that takes a single one, reduced
+    /// by `system_message_policy`. Empty when there are no system messages.
+    pub fn system_prompt(&self, messages: &[ChatCompletionRequestMessage]) -> String {
+        let mut prompts = messages.iter().filter(|msg| msg.is_system()).map(|msg| msg.content());
+        match self.system_message_policy {
+            SystemMessagePolicy::FirstOnly => prompts.next().unwrap_or_default().into_owned(),
+            // `Error` never gets here with more than one, `validate` rejects it
+            SystemMessagePolicy::Join | SystemMessagePolicy::Error => prompts.collect::<Vec<_>>().join("\n\n"),
+        }
+    }
+
+    /// The request as sent to `provider_override` when it is set. A `base_url` still at the
+    /// default of `provider` moves to the override's default, one set by hand is kept.
+    pub fn routed(&self) -> Cow<'_, Self> {
+        let Some(provider) = self.provider_override.as_ref().filter(|p| **p != self.provider) else {
+            return Cow::Borrowed(self);
+        };
+
+        let mut routed = self.clone();
+        if self.base_url == self.provider.default_base_url() {
+            routed.base_url = provider.default_base_url().to_string();
+        }
+        routed.provider = provider.clone();
+        routed.provider_override = None;
+        Cow::Owned(routed)
+    }
+
+    /// The request with what `capabilities` lacks emulated or dropped, when
+    /// `adapt_to_capabilities` is set:
+    /// - without JSON mode the `response_format` becomes a system instruction to answer
+    ///   only with JSON, naming the schema if there is one
+    /// - with JSON mode but no schema support the schema moves into that instruction and
+    ///   a plain JSON object is requested
+    /// - tools are dropped with a warning, a model can't be talked into calling them
+    pub fn adapted_to(&self, capabilities: ProviderCapabilities) -> Cow<'_, Self> {
+        let format = self.request.response_format.as_ref();
+        let emulate_json = format.is_some() && !capabilities.json_mode;
+        let emulate_schema = format.is_some_and(|f| f.json_schema.is_some()) && !capabilities.json_schema;
+        let drop_tools = self.request.tools.is_some() && !capabilities.tools;
+        if !self.adapt_to_capabilities || !(emulate_json || emulate_schema || drop_tools) {
+            return Cow::Borrowed(self);
+        }
+
+        let mut adapted = self.clone();
+        if emulate_json || emulate_schema {
+            let format = adapted.request.response_format.take().expect("checked above");
+            append_system_instruction(&mut adapted.request.messages, &json_instruction(&format));
+            if !emulate_json {
+                adapted.request.response_format = Some(ChatCompletionRequestResponseFormat {
+                    format_type: "json_object".to_string(),
+                    json_schema: None,
+                });
+            }
+        }
+        if drop_tools {
+            tracing::warn!("{} doesn't support tools, sending the request without them", String::from(self.provider.clone()));
+            adapted.request.tools = None;
+        }
+        Cow::Owned(adapted)
+    }
+
+    /// Checks the request before it is sent, naming the offending field on failure. Also
+    /// checks the prompt and `max_tokens` fit the context window, see `context_overflow`.
+    pub fn validate(&self) -> Result<(), LlmError> {
+        let invalid = |field: &str, message: &str| {
+            Err(LlmError::Validation {
+                field: field.to_string(),
+                message: message.to_string(),
+            })
+        };
+
+        if self.request.model.trim().is_empty() {
+            return invalid("model", "must not be empty");
+        }
+
+        if self.request.messages.is_empty() {
+            return invalid("messages", "must not be empty");
+        }
+
+        for (i, message) in self.request.messages.iter().enumerate() {
+            if let ChatCompletionRequestMessage::Tool { tool_call_id, .. } = message {
+                if tool_call_id.is_empty() {
+                    return invalid(&format!("messages[{}].tool_call_id", i), "must not be empty");
+                }
+            }
+        }
+
+        if let Some(temperature) = self.request.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return invalid("temperature", "must be 0.0..=2.0");
+            }
+        }
+
+        if let Some(max_tokens) = self.request.max_tokens {
+            if max_tokens <= 0 {
+                return invalid("max_tokens", "must be greater than 0");
+            }
+        }
+
+        if self.system_message_policy == SystemMessagePolicy::Error && self.provider.single_system_message() {
+            if self.request.messages.iter().filter(|msg| msg.is_system()).count() > 1 {
+                return invalid("messages", "only one system message is supported by this provider");
+            }
+        }
+
+        check_context_fit(self, &CharEstimate)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -238,6 +741,7 @@ mod tests {
             supports_json: true,
             supports_tools: true,
             supports_json_schema: true,
+            supports_streaming: true,
             provider_base_url: "https://api.openrouter.ai/api/v1".to_string(),
             version_number: 1,
             version_id: 1,
@@ -261,6 +765,8 @@ mod tests {
             transforms: None,
             max_tokens: None,
             temperature: None,
+            frequency_penalty: None,
+            presence_penalty: None,
         }
     }
     
@@ -294,6 +800,8 @@ mod tests {
             transforms: None,
             max_tokens: None,
             temperature: None,
+            frequency_penalty: None,
+            presence_penalty: None,
         }
     }
 
@@ -1231,4 +1739,275 @@ mod tests {
         assert_eq!(response_format.format_type, "json_object");
         assert!(response_format.json_schema.is_some());
     }
+
+    #[test]
+    fn test_streaming_capability_carried_over() {
+        // A model that can't stream still gets a response, through the fallback
+        let mut prompt = create_test_prompt("System prompt.", Some("User prompt"), "static");
+        prompt.supports_streaming = false;
+
+        let messages = vec![
+            ChatCompletionRequestMessage::User {
+                content: "Hello".to_string(),
+                name: None,
+            },
+        ];
+
+        let request = create_chat_request(messages);
+        let service_request = LlmServiceRequest::new(prompt, request).unwrap();
+
+        assert!(!service_request.supports_streaming);
+        assert!(service_request.fallback_to_nonstreaming);
+    }
+
+    // Builds a request that passes validation
+    fn create_valid_service_request() -> LlmServiceRequest {
+        let prompt = create_test_prompt("System prompt.", Some("User prompt"), "static");
+        let messages = vec![
+            ChatCompletionRequestMessage::User {
+                content: "Hello".to_string(),
+                name: None,
+            },
+        ];
+
+        LlmServiceRequest::new(prompt, create_chat_request(messages)).unwrap()
+    }
+
+    fn validation_field(request: &LlmServiceRequest) -> Option<String> {
+        match request.validate() {
+            Err(LlmError::Validation { field, .. }) => Some(field),
+            Err(other) => panic!("Expected a validation error, got {:?}", other),
+            Ok(()) => None,
+        }
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_request() {
+        let request = create_valid_service_request();
+        assert!(request.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_names_offending_field() {
+        let mut request = create_valid_service_request();
+        request.request.temperature = Some(3.5);
+        assert_eq!(validation_field(&request).as_deref(), Some("temperature"));
+
+        let mut request = create_valid_service_request();
+        request.request.max_tokens = Some(0);
+        assert_eq!(validation_field(&request).as_deref(), Some("max_tokens"));
+
+        let mut request = create_valid_service_request();
+        request.request.messages.clear();
+        assert_eq!(validation_field(&request).as_deref(), Some("messages"));
+
+        let mut request = create_valid_service_request();
+        request.request.model = " ".to_string();
+        assert_eq!(validation_field(&request).as_deref(), Some("model"));
+
+        let mut request = create_valid_service_request();
+        request.request.messages.push(ChatCompletionRequestMessage::Tool {
+            content: serde_json::json!({}),
+            tool_call_id: String::new(),
+            name: None,
+        });
+        assert_eq!(validation_field(&request).as_deref(), Some("messages[2].tool_call_id"));
+    }
+
+    #[test]
+    fn test_validation_error_message() {
+        let mut request = create_valid_service_request();
+        request.request.temperature = Some(-1.0);
+
+        let err = request.validate().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid temperature: must be 0.0..=2.0");
+    }
+
+    #[test]
+    fn test_request_serde_round_trip() {
+        let mut props = LlmServiceRequest::from_user_prompt(
+            LlmApiProvider::Custom("local".to_string()),
+            "local-model",
+            "What's the weather in Paris?",
+        );
+        props.base_url = "http://localhost:8080/v1".to_string();
+        props.request = create_chat_request_with_tools(vec![
+            ChatCompletionRequestMessage::System {
+                content: "You are a weather bot".to_string(),
+                name: None,
+            },
+            ChatCompletionRequestMessage::User {
+                content: "What's the weather in Paris?".to_string(),
+                name: Some("alice".to_string()),
+            },
+            ChatCompletionRequestMessage::Assistant {
+                content: String::new(),
+                tool_calls: Some(vec![ChatCompletionRequestToolCall {
+                    id: "call_1".to_string(),
+                    kind: "function".to_string(),
+                    function_call: ChatCompletionRequestFunctionCall {
+                        name: "get_weather".to_string(),
+                        arguments: r#"{"location":"Paris"}"#.to_string(),
+                    },
+                }]),
+                name: None,
+            },
+            ChatCompletionRequestMessage::Tool {
+                content: serde_json::json!({ "temp_c": 21 }),
+                tool_call_id: "call_1".to_string(),
+                name: Some("get_weather".to_string()),
+            },
+        ]);
+        props.request.response_format = Some(ChatCompletionRequestResponseFormat {
+            format_type: "json_schema".to_string(),
+            json_schema: Some(ChatCompletionRequestJsonSchema {
+                name: "weather".to_string(),
+                strict: true,
+                schema: serde_json::json!({ "type": "object" }),
+            }),
+        });
+        props.request.max_tokens = Some(256);
+        props.request.temperature = Some(0.2);
+        props.stream_transport = StreamTransport::JsonArray;
+        props.response_modalities = vec![Modality::Text, Modality::Image];
+        props.fallback_to_nonstreaming = false;
+        props.max_token_gap = Some(Duration::from_millis(1_500));
+        props.coalesce = Some(Coalescing { window: Duration::from_millis(50), max_chars: 200 });
+
+        let json = serde_json::to_value(&props).unwrap();
+        assert_eq!(json["provider"], "local");
+        // Durations are plain milliseconds, not serde's {secs, nanos}
+        assert_eq!(json["max_token_gap"], 1_500);
+        assert_eq!(json["coalesce"], serde_json::json!({ "window": 50, "max_chars": 200 }));
+        assert_eq!(json["stream_transport"], "JsonArray");
+        assert_eq!(json["response_modalities"], serde_json::json!(["TEXT", "IMAGE"]));
+        assert!(json.get("response_transforms").is_none());
+
+        let restored: LlmServiceRequest = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(restored.provider, props.provider);
+        assert_eq!(restored.request.messages.len(), 4);
+        assert_eq!(restored.max_token_gap, Some(Duration::from_millis(1_500)));
+        assert_eq!(restored.coalesce, props.coalesce);
+        assert_eq!(serde_json::to_value(&restored).unwrap(), json);
+    }
+
+    #[test]
+    fn test_request_deserialize_defaults_newer_fields() {
+        let json = serde_json::json!({
+            "provider": "gemini",
+            "base_url": "https://generativelanguage.googleapis.com/v1beta",
+            "prompt_id": 1,
+            "model_id": 2,
+            "request": { "model": "gemini-2.0-flash", "messages": [{ "role": "user", "content": "Hi" }] },
+            "supports_streaming": true
+        });
+
+        let props: LlmServiceRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(props.provider, LlmApiProvider::Gemini);
+        assert!(props.fallback_to_nonstreaming);
+        assert_eq!(props.stream_transport, StreamTransport::Sse);
+        assert!(props.response_modalities.is_empty());
+        assert!(!props.dedupe_adjacent);
+    }
+
+    #[test]
+    fn test_dedupe_adjacent_messages() {
+        let user = |content: &str| ChatCompletionRequestMessage::User {
+            content: content.to_string(),
+            name: None,
+        };
+        let tool_result = || ChatCompletionRequestMessage::Tool {
+            content: serde_json::json!("ok"),
+            tool_call_id: "call_1".to_string(),
+            name: None,
+        };
+
+        let mut props = create_valid_service_request();
+        props.request.messages = vec![user("Hi"), user("Hi"), user("Hi"), user("Bye"), user("Hi"), tool_result(), tool_result()];
+
+        // Left alone unless asked for
+        assert_eq!(props.outgoing_messages().len(), 7);
+
+        props.dedupe_adjacent = true;
+        let contents: Vec<String> = props.outgoing_messages().iter().map(|m| m.content().to_string()).collect();
+        assert_eq!(contents, vec!["Hi", "Bye", "Hi", "ok", "ok"]);
+
+        // The same text from a different role is kept
+        props.request.messages = vec![
+            user("Hi"),
+            ChatCompletionRequestMessage::Assistant {
+                content: "Hi".to_string(),
+                tool_calls: None,
+                name: None,
+            },
+        ];
+        assert_eq!(props.outgoing_messages().len(), 2);
+    }
+
+    fn two_system_prompts(provider: LlmApiProvider, policy: SystemMessagePolicy) -> LlmServiceRequest {
+        let mut props = LlmServiceRequest::from_user_prompt(provider, "some-model", "Hi");
+        props.request.messages.insert(0, ChatCompletionRequestMessage::System { content: "Be brief.".to_string(), name: None });
+        props.request.messages.insert(1, ChatCompletionRequestMessage::System { content: "Answer in French.".to_string(), name: None });
+        props.system_message_policy = policy;
+        props
+    }
+
+    #[test]
+    fn test_system_message_policy_join() {
+        let props = two_system_prompts(LlmApiProvider::Gemini, SystemMessagePolicy::Join);
+        assert!(props.validate().is_ok());
+        assert_eq!(props.system_prompt(&props.request.messages), "Be brief.\n\nAnswer in French.");
+    }
+
+    #[test]
+    fn test_system_message_policy_first_only() {
+        let props = two_system_prompts(LlmApiProvider::Replicate, SystemMessagePolicy::FirstOnly);
+        assert!(props.validate().is_ok());
+        assert_eq!(props.system_prompt(&props.request.messages), "Be brief.");
+
+        let props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Gemini, "some-model", "Hi");
+        assert_eq!(props.system_prompt(&props.request.messages), "");
+    }
+
+    #[test]
+    fn test_system_message_policy_error() {
+        let props = two_system_prompts(LlmApiProvider::Gemini, SystemMessagePolicy::Error);
+        assert_eq!(validation_field(&props).as_deref(), Some("messages"));
+
+        // Providers taking system messages get them all
+        let props = two_system_prompts(LlmApiProvider::Openrouter, SystemMessagePolicy::Error);
+        assert!(props.validate().is_ok());
+
+        let mut props = two_system_prompts(LlmApiProvider::Gemini, SystemMessagePolicy::Error);
+        props.request.messages.remove(1);
+        assert!(props.validate().is_ok());
+    }
+
+    #[test]
+    fn test_with_appended_leaves_original_unchanged() {
+        let props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Gemini, "some-model", "Hi");
+        let reply = ChatCompletionRequestMessage::Assistant { content: "Hello!".to_string(), tool_calls: None, name: None };
+
+        let next = props.with_appended(reply);
+        assert_eq!(props.request.messages.len(), 1);
+        assert_eq!(next.request.messages.len(), 2);
+        assert_eq!(next.request.messages[0].content(), "Hi");
+        assert!(matches!(&next.request.messages[1], ChatCompletionRequestMessage::Assistant { content, .. } if content == "Hello!"));
+    }
+
+    #[test]
+    fn test_diff_reports_changed_fields() {
+        let left = LlmServiceRequest::from_user_prompt(LlmApiProvider::Gemini, "gemini-2.0-flash", "Hi");
+        let mut right = left.with_appended(ChatCompletionRequestMessage::Assistant { content: "Hello!".to_string(), tool_calls: None, name: None });
+        right.request.temperature = Some(0.2);
+
+        assert_eq!(
+            left.diff(&right),
+            vec![
+                FieldDiff { field: "request.messages.len".to_string(), left: 1.into(), right: 2.into() },
+                FieldDiff { field: "request.temperature".to_string(), left: Value::Null, right: 0.2.into() },
+            ]
+        );
+        assert!(left.diff(&left.clone()).is_empty());
+    }
 }
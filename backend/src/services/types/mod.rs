@@ -1,2 +1,3 @@
 pub mod llm_service;
 pub mod llm_error;
+pub mod stream;
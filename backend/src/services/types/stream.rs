@@ -0,0 +1,75 @@
+use crate::common::types::chat_response::{
+    LlmServiceChatCompletionChunk, LlmServiceChatCompletionResponseToolCall, LlmServiceUsage,
+};
+
+/// The kind of logical block a run of streamed chunks belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    Thinking,
+    Text,
+    ToolCall,
+}
+
+impl BlockKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockKind::Thinking => "thinking",
+            BlockKind::Text => "text",
+            BlockKind::ToolCall => "tool_call",
+        }
+    }
+}
+
+/// Events sent from a provider stream to the consumer.
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// A chunk from the provider, forwarded as-is.
+    Chunk(LlmServiceChatCompletionChunk),
+    /// The chunks that follow belong to a new block of the given kind.
+    BlockStart {
+        kind: BlockKind,
+    },
+    /// The current block is complete.
+    BlockStop,
+    /// A tool call reassembled from its streamed fragments, sent once the stream is finished.
+    ToolCall(LlmServiceChatCompletionResponseToolCall),
+    /// Usage reported by a chunk mid-stream, cumulative for the stream so far. Lets a consumer
+    /// meter cost as tokens arrive instead of waiting for the final `Stats`.
+    UsageDelta(LlmServiceUsage),
+    /// A chunk reported why generation stopped, e.g. `length` when the response was truncated.
+    FinishReason(String),
+    /// Generation was stopped by the provider's safety filter.
+    SafetyBlock {
+        category: String,
+    },
+    /// Timing information for the stream, measured from when the request was sent.
+    Stats {
+        /// Milliseconds until the first content token arrived, if any did.
+        ttft_ms: Option<u64>,
+        /// Number of tokens received so far.
+        tokens: u32,
+        /// Milliseconds elapsed since the request was sent.
+        elapsed_ms: u64,
+    },
+    /// The end of stream metadata in one place, sent exactly once right before the `[DONE]`
+    /// sentinel of a stream that wasn't aborted.
+    Completed {
+        /// The last finish reason reported for the first choice.
+        finish_reason: Option<String>,
+        /// Final usage, reported by the provider or estimated when the stream was cut short.
+        usage: Option<LlmServiceUsage>,
+        model_version: Option<String>,
+    },
+}
+
+impl StreamEvent {
+    /// Tokens per second for a `Stats` event, `None` for other events or before any time has passed.
+    pub fn tokens_per_sec(&self) -> Option<f64> {
+        match self {
+            StreamEvent::Stats { tokens, elapsed_ms, .. } if *elapsed_ms > 0 => {
+                Some(*tokens as f64 / (*elapsed_ms as f64 / 1000.0))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use serde_json::{Map, Value};
+
+/// `value` with the keys of every object in sorted order, however deep. serde_json keeps
+/// maps sorted already, unless some dependency turns on its `preserve_order` feature, in
+/// which case they follow insertion order. Bodies built from this serialize the same
+/// either way, byte for byte, which snapshot tests and request caching depend on.
+pub fn canonical_json(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(entries.into_iter().map(|(key, value)| (key, canonical_json(value))).collect::<Map<_, _>>())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(canonical_json).collect()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_nested_keys_are_sorted() {
+        let value = json!({ "b": 1, "a": { "z": [{ "y": 1, "x": 2 }], "c": null } });
+        assert_eq!(
+            serde_json::to_string(&canonical_json(value)).unwrap(),
+            r#"{"a":{"c":null,"z":[{"x":2,"y":1}]},"b":1}"#
+        );
+    }
+}
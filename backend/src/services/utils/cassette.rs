@@ -0,0 +1,190 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use reqwest::{header::CONTENT_TYPE, RequestBuilder, Response, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::services::{types::llm_error::LlmError, utils::client::{request_body_bytes, shared_client}};
+
+tokio::task_local! {
+    static CASSETTE: Arc<Cassette>;
+}
+
+/// Whether a cassette talks to the network or answers from its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Requests go out as usual and every exchange is written to the cassette.
+    Record,
+    /// Requests are answered from the cassette, nothing goes over the network.
+    Replay,
+}
+
+/// One recorded request and the response it got.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    method: String,
+    /// With the `key` query parameter removed, so cassettes never contain API keys.
+    url: String,
+    request_body: Option<Value>,
+    status: u16,
+    response_body: String,
+}
+
+/// VCR style recorder for provider HTTP calls, meant for tests that exercise the real
+/// request building and parsing without live calls.
+///
+/// A cassette records on the first run, when its file doesn't exist yet, and replays
+/// afterwards. It only applies to requests made while running under [`Cassette::run`];
+/// SSE streams are opened elsewhere and always go to the network.
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Mutex<Vec<Interaction>>,
+    replayed: Mutex<Vec<bool>>,
+}
+
+impl Cassette {
+    /// Replays `path` if it exists, otherwise starts recording into it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LlmError> {
+        let path = path.as_ref().to_path_buf();
+
+        let (mode, interactions) = match std::fs::read_to_string(&path) {
+            Ok(json) => (CassetteMode::Replay, serde_json::from_str::<Vec<Interaction>>(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (CassetteMode::Record, Vec::new()),
+            Err(e) => {
+                return Err(LlmError::InvalidConfig(format!(
+                    "Failed to read cassette {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+
+        Ok(Cassette {
+            path,
+            mode,
+            replayed: Mutex::new(vec![false; interactions.len()]),
+            interactions: Mutex::new(interactions),
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Runs `future` with this cassette handling its provider requests. When recording,
+    /// the cassette is written once the future completes.
+    pub async fn run<F: Future>(self, future: F) -> Result<F::Output, LlmError> {
+        let cassette = Arc::new(self);
+        let output = CASSETTE.scope(cassette.clone(), future).await;
+
+        if cassette.mode == CassetteMode::Record {
+            cassette.save()?;
+        }
+        Ok(output)
+    }
+
+    fn save(&self) -> Result<(), LlmError> {
+        let interactions = self.interactions.lock().expect("cassette lock poisoned");
+        let json = serde_json::to_string_pretty(&*interactions)?;
+
+        std::fs::write(&self.path, json).map_err(|e| {
+            LlmError::InvalidConfig(format!("Failed to write cassette {}: {}", self.path.display(), e))
+        })
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response, LlmError> {
+        let request = request.build()?;
+        let method = request.method().to_string();
+        let url = redact_key(request.url());
+        let request_body = request_body_bytes(&request)
+            .map(|bytes| serde_json::from_slice(&bytes).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned())));
+
+        match self.mode {
+            CassetteMode::Record => {
+                let response = shared_client().execute(request).await?;
+                let status = response.status().as_u16();
+                let response_body = response.text().await?;
+
+                self.interactions.lock().expect("cassette lock poisoned").push(Interaction {
+                    method,
+                    url,
+                    request_body,
+                    status,
+                    response_body: response_body.clone(),
+                });
+
+                recorded_response(status, response_body)
+            }
+            CassetteMode::Replay => {
+                let interactions = self.interactions.lock().expect("cassette lock poisoned");
+                let mut replayed = self.replayed.lock().expect("cassette lock poisoned");
+
+                // Identical requests are answered in the order they were recorded
+                let index = interactions
+                    .iter()
+                    .enumerate()
+                    .position(|(i, recorded)| {
+                        !replayed[i]
+                            && recorded.method == method
+                            && recorded.url == url
+                            && recorded.request_body == request_body
+                    })
+                    .ok_or_else(|| {
+                        LlmError::Provider(format!("No recorded interaction for {} {} in {}", method, url, self.path.display()))
+                    })?;
+
+                replayed[index] = true;
+                let recorded = &interactions[index];
+                recorded_response(recorded.status, recorded.response_body.clone())
+            }
+        }
+    }
+}
+
+/// Sends `request`, through the active cassette if there is one, else through the
+/// middleware client when the `middleware` feature set one up. Streams read through an
+/// `EventSource` open their own connection and bypass both.
+pub async fn send(request: RequestBuilder) -> Result<Response, LlmError> {
+    match CASSETTE.try_with(|cassette| cassette.clone()) {
+        Ok(cassette) => cassette.send(request).await,
+        Err(_) => {
+            #[cfg(feature = "middleware")]
+            if let Some(client) = super::middleware::middleware_client() {
+                return super::middleware::send_through(client, request).await;
+            }
+            Ok(request.send().await?)
+        }
+    }
+}
+
+fn recorded_response(status: u16, body: String) -> Result<Response, LlmError> {
+    let response = http::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .map_err(|e| LlmError::Provider(format!("Invalid recorded response: {}", e)))?;
+
+    Ok(Response::from(response))
+}
+
+fn redact_key(url: &Url) -> String {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| k != "key")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
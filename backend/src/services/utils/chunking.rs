@@ -0,0 +1,185 @@
+use crate::common::types::chat_request::ChatCompletionRequestMessage;
+
+use super::token_budget::TokenCounter;
+
+/// How finely a piece of text that doesn't fit in a chunk is split further.
+#[derive(Debug, Clone, Copy)]
+enum Level {
+    Sentence,
+    Word,
+    Char,
+}
+
+/// Splits a long user input into user messages of at most `max_tokens` each, by
+/// `counter`. Chunks end on sentence boundaries where possible; a sentence too long for
+/// a chunk of its own is split between words, and only a single word longer than
+/// `max_tokens` is split inside. Chunks are trimmed and never empty.
+pub fn chunk_user_input(text: &str, max_tokens: u32, counter: &impl TokenCounter) -> Vec<ChatCompletionRequestMessage> {
+    let mut chunker = Chunker {
+        max_tokens,
+        counter,
+        chunks: Vec::new(),
+        current: String::new(),
+    };
+
+    for sentence in sentences(text) {
+        chunker.push(sentence, Level::Sentence);
+    }
+    chunker.flush();
+
+    chunker
+        .chunks
+        .into_iter()
+        .map(|content| ChatCompletionRequestMessage::User { content, name: None })
+        .collect()
+}
+
+struct Chunker<'a, C> {
+    max_tokens: u32,
+    counter: &'a C,
+    chunks: Vec<String>,
+    current: String,
+}
+
+impl<C: TokenCounter> Chunker<'_, C> {
+    fn fits(&self, text: &str) -> bool {
+        self.counter.count_tokens(text.trim()) <= self.max_tokens
+    }
+
+    fn push(&mut self, piece: &str, level: Level) {
+        let candidate = format!("{}{}", self.current, piece);
+        if self.fits(&candidate) {
+            self.current = candidate;
+            return;
+        }
+
+        self.flush();
+        if self.fits(piece) {
+            self.current = piece.to_string();
+            return;
+        }
+
+        match level {
+            Level::Sentence => {
+                for word in piece.split_inclusive(char::is_whitespace) {
+                    self.push(word, Level::Word);
+                }
+            }
+            Level::Word => {
+                let mut buf = [0u8; 4];
+                for c in piece.chars() {
+                    self.push(c.encode_utf8(&mut buf), Level::Char);
+                }
+            }
+            // A single character over the limit still has to go somewhere
+            Level::Char => self.current = piece.to_string(),
+        }
+    }
+
+    fn flush(&mut self) {
+        let chunk = self.current.trim();
+        if !chunk.is_empty() {
+            self.chunks.push(chunk.to_string());
+        }
+        self.current.clear();
+    }
+}
+
+/// Sentences with the whitespace that follows them. A sentence ends at `.`, `!` or `?`
+/// followed by whitespace, and at every line break.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let boundary = match c {
+            '.' | '!' | '?' => chars.peek().is_some_and(|(_, next)| next.is_whitespace()),
+            '\n' => true,
+            _ => false,
+        };
+        if !boundary {
+            continue;
+        }
+
+        let mut end = i + c.len_utf8();
+        while let Some(&(j, next)) = chars.peek() {
+            if !next.is_whitespace() {
+                break;
+            }
+            end = j + next.len_utf8();
+            chars.next();
+        }
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One token per whitespace separated word, as in the token budget tests
+    struct WordCounter;
+
+    impl TokenCounter for WordCounter {
+        fn count_tokens(&self, text: &str) -> u32 {
+            text.split_whitespace().count() as u32
+        }
+    }
+
+    fn contents(messages: &[ChatCompletionRequestMessage]) -> Vec<String> {
+        messages.iter().map(|msg| msg.content().to_string()).collect()
+    }
+
+    #[test]
+    fn test_document_splits_into_three_chunks() {
+        let document = "The river rose overnight. Farmers moved their cattle uphill.\n\
+            By noon the bridge was closed! Nobody could reach the village.\n\
+            Help arrived by boat. Was it too late?";
+
+        let messages = chunk_user_input(document, 11, &WordCounter);
+        assert!(messages.iter().all(|msg| msg.is_user()));
+        assert_eq!(
+            contents(&messages),
+            [
+                "The river rose overnight. Farmers moved their cattle uphill.",
+                "By noon the bridge was closed! Nobody could reach the village.",
+                "Help arrived by boat. Was it too late?",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_long_sentence_splits_between_words() {
+        let messages = chunk_user_input("one two three four five six seven", 3, &WordCounter);
+        assert_eq!(contents(&messages), ["one two three", "four five six", "seven"]);
+    }
+
+    #[test]
+    fn test_no_empty_chunks() {
+        assert!(chunk_user_input("", 5, &WordCounter).is_empty());
+        assert!(chunk_user_input(" \n\n  ", 5, &WordCounter).is_empty());
+
+        let messages = chunk_user_input("First.\n\n\nSecond.", 1, &WordCounter);
+        assert_eq!(contents(&messages), ["First.", "Second."]);
+    }
+
+    #[test]
+    fn test_oversized_word_is_split() {
+        struct CharCounter;
+        impl TokenCounter for CharCounter {
+            fn count_tokens(&self, text: &str) -> u32 {
+                text.chars().count() as u32
+            }
+        }
+
+        let messages = chunk_user_input("ok Zürichsee", 4, &CharCounter);
+        assert_eq!(contents(&messages), ["ok", "Züri", "chse", "e"]);
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE},
+    Certificate, Identity, IntoUrl, Request, RequestBuilder, Response,
+};
+use serde::Serialize;
+
+use crate::common::types::{chat_response::LlmServiceRateLimit, models::LlmApiProvider};
+use crate::services::types::llm_error::LlmError;
+
+static SHARED_CLIENT: OnceLock<HttpClient> = OnceLock::new();
+
+/// Connection pool and keep-alive settings for the HTTP client shared by providers.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Maximum idle connections kept open per host.
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle connection stays in the pool. `None` keeps it forever.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Interval for HTTP/2 keep-alive pings. `None` disables them.
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Set `TCP_NODELAY` on connections.
+    pub tcp_nodelay: bool,
+    /// Headers sent with every request, e.g. an org id or a gateway token. A header a
+    /// provider sets on the request itself wins over the default.
+    pub default_headers: Vec<(String, String)>,
+    /// Query parameters appended to every request url.
+    pub default_query: Vec<(String, String)>,
+    /// PEM encoded certificates trusted in addition to the system roots, e.g. a corporate CA.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Certificate presented to servers asking for one (mTLS), e.g. by a gateway.
+    pub client_identity: Option<ClientIdentity>,
+}
+
+/// A client certificate with its private key.
+#[derive(Clone)]
+pub enum ClientIdentity {
+    /// PEM certificate chain and PKCS #8 PEM private key.
+    Pem { cert: Vec<u8>, key: Vec<u8> },
+    /// DER encoded PKCS #12 archive and its password.
+    Pkcs12 { der: Vec<u8>, password: String },
+}
+
+// Key material stays out of logs
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientIdentity::Pem { .. } => f.write_str("ClientIdentity::Pem"),
+            ClientIdentity::Pkcs12 { .. } => f.write_str("ClientIdentity::Pkcs12"),
+        }
+    }
+}
+
+impl ClientIdentity {
+    fn to_identity(&self) -> Result<Identity, reqwest::Error> {
+        match self {
+            ClientIdentity::Pem { cert, key } => Identity::from_pkcs8_pem(cert, key),
+            ClientIdentity::Pkcs12 { der, password } => Identity::from_pkcs12_der(der, password),
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            http2_keep_alive_interval: Some(Duration::from_secs(30)),
+            tcp_nodelay: true,
+            default_headers: Vec::new(),
+            default_query: Vec::new(),
+            root_certificates: Vec::new(),
+            client_identity: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Builds an [`HttpClient`] with these settings applied. Certificates that don't parse
+    /// fail here rather than on the first request.
+    pub fn build(&self) -> Result<HttpClient, LlmError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.default_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| LlmError::InvalidConfig(format!("Invalid default header name: {}", name)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| LlmError::InvalidConfig(format!("Invalid value for default header {}", name)))?;
+            headers.insert(name, value);
+        }
+
+        // reqwest only fills in default headers the request doesn't already have
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .http2_keep_alive_interval(self.http2_keep_alive_interval)
+            .tcp_nodelay(self.tcp_nodelay)
+            .default_headers(headers);
+
+        for (i, pem) in self.root_certificates.iter().enumerate() {
+            let certificate = Certificate::from_pem(pem)
+                .map_err(|e| LlmError::InvalidConfig(format!("Invalid root certificate {}: {}", i, e)))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(identity) = &self.client_identity {
+            let identity = identity
+                .to_identity()
+                .map_err(|e| LlmError::InvalidConfig(format!("Invalid client identity: {}", e)))?;
+            builder = builder.identity(identity);
+        }
+
+        let client = builder.build()?;
+
+        Ok(HttpClient {
+            client,
+            default_query: self.default_query.clone(),
+        })
+    }
+}
+
+/// A `reqwest::Client` that also appends the configured default query parameters.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    default_query: Vec<(String, String)>,
+}
+
+impl HttpClient {
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.with_defaults(self.client.get(url))
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.with_defaults(self.client.post(url))
+    }
+
+    /// The underlying client, e.g. to wrap in middleware. Clones share its connection pool.
+    #[cfg(feature = "middleware")]
+    pub fn reqwest_client(&self) -> reqwest::Client {
+        self.client.clone()
+    }
+
+    /// Sends an already built request, which had its defaults applied when it was built.
+    pub async fn execute(&self, request: Request) -> Result<Response, reqwest::Error> {
+        self.client.execute(request).await
+    }
+
+    fn with_defaults(&self, request: RequestBuilder) -> RequestBuilder {
+        if self.default_query.is_empty() {
+            return request;
+        }
+        request.query(&self.default_query)
+    }
+}
+
+/// Replaces the default settings of the shared client. This has to happen before
+/// the first call to `shared_client`, afterwards the client is already in use.
+pub fn init_shared_client(config: &ClientConfig) -> Result<(), LlmError> {
+    let client = config.build()?;
+    SHARED_CLIENT
+        .set(client)
+        .map_err(|_| LlmError::InvalidConfig("Shared HTTP client is already initialized".to_string()))
+}
+
+/// Returns the process-wide HTTP client, building it from the default config on first use.
+pub fn shared_client() -> &'static HttpClient {
+    SHARED_CLIENT.get_or_init(|| {
+        ClientConfig::default()
+            .build()
+            .expect("Failed to build default HTTP client")
+    })
+}
+
+/// Providers known to accept gzip compressed request bodies.
+pub fn accepts_gzip(provider: &LlmApiProvider) -> bool {
+    matches!(provider, LlmApiProvider::Gemini)
+}
+
+/// Sets `body` as the JSON body of `request`. With `compress` the body is gzipped and
+/// sent with `Content-Encoding: gzip`.
+pub fn json_body<T: Serialize + ?Sized>(request: RequestBuilder, body: &T, compress: bool) -> Result<RequestBuilder, LlmError> {
+    if !compress {
+        return Ok(request.json(body));
+    }
+
+    let json = serde_json::to_vec(body)?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let gzipped = encoder
+        .write_all(&json)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| LlmError::SerializationError(format!("Failed to gzip the request body: {}", e)))?;
+
+    Ok(request
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_ENCODING, "gzip")
+        .body(gzipped))
+}
+
+/// The body of a built request as it was before [`json_body`] compressed it.
+pub fn request_body_bytes(request: &Request) -> Option<Vec<u8>> {
+    let bytes = request.body()?.as_bytes()?;
+    let gzipped = request
+        .headers()
+        .get(CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes() == b"gzip");
+    if !gzipped {
+        return Some(bytes.to_vec());
+    }
+
+    let mut decoded = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut decoded).ok()?;
+    Some(decoded)
+}
+
+/// Message of the [`LlmError::Provider`] for a response over `max_response_bytes`.
+pub const RESPONSE_TOO_LARGE: &str = "response too large";
+
+/// Reads the body of `response` as text. With `max_bytes` set, fails with
+/// [`RESPONSE_TOO_LARGE`] as soon as the body is longer, without reading the rest.
+pub async fn read_body(mut response: Response, max_bytes: Option<usize>) -> Result<String, LlmError> {
+    let Some(max_bytes) = max_bytes else {
+        return Ok(response.text().await?);
+    };
+
+    if response.content_length().is_some_and(|len| len as usize > max_bytes) {
+        return Err(LlmError::Provider(RESPONSE_TOO_LARGE.to_string()));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > max_bytes {
+            return Err(LlmError::Provider(RESPONSE_TOO_LARGE.to_string()));
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Counts the bytes of a streamed response against `max_response_bytes`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteBudget {
+    remaining: Option<usize>,
+}
+
+impl ByteBudget {
+    /// `None` never runs out.
+    pub fn new(max_bytes: Option<usize>) -> Self {
+        ByteBudget { remaining: max_bytes }
+    }
+
+    /// Takes `len` bytes from the budget, `false` once it is exceeded.
+    pub fn take(&mut self, len: usize) -> bool {
+        match &mut self.remaining {
+            Some(remaining) => match remaining.checked_sub(len) {
+                Some(left) => {
+                    *remaining = left;
+                    true
+                }
+                None => false,
+            },
+            None => true,
+        }
+    }
+}
+
+/// Turns a non-2xx response into the matching error, carrying the response body, so
+/// callers never try to parse an error page as a completion.
+pub async fn error_for_status(response: Response) -> Result<Response, LlmError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    Err(match status.as_u16() {
+        401 | 403 => LlmError::Auth(body),
+        404 => LlmError::NotFound(body),
+        429 => LlmError::RateLimit(body),
+        code @ 500..=599 => LlmError::ServerError { status: code, body },
+        code => LlmError::from_error_body(code, &body),
+    })
+}
+
+/// Rate limit state from the `x-ratelimit-*` headers, `None` when none were sent.
+pub fn rate_limit_from_headers(headers: &HeaderMap) -> Option<LlmServiceRateLimit> {
+    let text = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.trim().to_string());
+    let number = |name: &str| text(name).and_then(|v| v.parse::<u64>().ok());
+
+    let rate_limit = LlmServiceRateLimit {
+        limit_requests: number("x-ratelimit-limit-requests"),
+        limit_tokens: number("x-ratelimit-limit-tokens"),
+        remaining_requests: number("x-ratelimit-remaining-requests"),
+        remaining_tokens: number("x-ratelimit-remaining-tokens"),
+        reset_requests: text("x-ratelimit-reset-requests"),
+        reset_tokens: text("x-ratelimit-reset-tokens"),
+    };
+    (rate_limit != LlmServiceRateLimit::default()).then_some(rate_limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::utils::test_server::{serve_capture, serve_once};
+
+    #[test]
+    fn test_default_config_builds() {
+        let result = ClientConfig::default().build();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_custom_config_builds() {
+        let config = ClientConfig {
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout: None,
+            http2_keep_alive_interval: Some(Duration::from_secs(5)),
+            tcp_nodelay: false,
+            ..Default::default()
+        };
+
+        let result = config.build();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_invalid_default_header_is_rejected() {
+        let config = ClientConfig {
+            default_headers: vec![("bad header".to_string(), "value".to_string())],
+            ..Default::default()
+        };
+
+        assert!(matches!(config.build(), Err(LlmError::InvalidConfig(_))));
+    }
+
+    // Self-signed CA, only ever parsed
+    const TEST_CA: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBiTCCAS+gAwIBAgIUNUYRM4tidF5IR9aSL0ushxV1BVswCgYIKoZIzj0EAwIw\n\
+GTEXMBUGA1UEAwwObGxta2l0IHRlc3QgQ0EwIBcNMjYxMDE0MTIwNTQ1WhgPMjEy\n\
+NjA5MjAxMjA1NDVaMBkxFzAVBgNVBAMMDmxsbWtpdCB0ZXN0IENBMFkwEwYHKoZI\n\
+zj0CAQYIKoZIzj0DAQcDQgAEoa9QeY65i3Ga1BIpY4G0eIVxYNezkr0LVGwTAr6o\n\
+XVI6HxIxA34vYxCkRl+7AiGo/jfGLc21IlMFCcGs9H8f16NTMFEwHQYDVR0OBBYE\n\
+FNkkefSOlsrkspupWqhkLcuASgGjMB8GA1UdIwQYMBaAFNkkefSOlsrkspupWqhk\n\
+LcuASgGjMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIhAK6fQOVE\n\
+ZwuCl2jhue0SqkisPBFVJ4a1kXYKYYHHwsrNAiBfw9ElQ+jI+nBpalrH+on0CqpD\n\
+d3QOoLTEjcTVRULOUQ==\n\
+-----END CERTIFICATE-----";
+
+    #[test]
+    fn test_extra_root_certificate() {
+        let config = ClientConfig {
+            root_certificates: vec![TEST_CA.as_bytes().to_vec()],
+            ..Default::default()
+        };
+        assert!(config.build().is_ok());
+
+        let config = ClientConfig {
+            root_certificates: vec![b"-----BEGIN CERTIFICATE-----\nnot a certificate\n-----END CERTIFICATE-----\n".to_vec()],
+            ..Default::default()
+        };
+        assert!(matches!(config.build(), Err(LlmError::InvalidConfig(msg)) if msg.starts_with("Invalid root certificate 0")));
+
+        let config = ClientConfig {
+            client_identity: Some(ClientIdentity::Pkcs12 { der: b"garbage".to_vec(), password: "secret".to_string() }),
+            ..Default::default()
+        };
+        assert!(matches!(config.build(), Err(LlmError::InvalidConfig(msg)) if msg.starts_with("Invalid client identity")));
+        assert!(!format!("{:?}", config).contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_default_headers_and_query() {
+        let client = ClientConfig {
+            default_headers: vec![
+                ("X-Org-Id".to_string(), "org-1".to_string()),
+                ("Authorization".to_string(), "Bearer gateway".to_string()),
+            ],
+            default_query: vec![("api-version".to_string(), "2024-10-01".to_string())],
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+
+        let (url, received) = serve_capture(200, "{}".to_string()).await;
+        let response = client
+            .post(format!("{}/chat/completions?key=abc", url))
+            .bearer_auth("per-request")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let request = received.await.unwrap().to_lowercase();
+        assert!(request.starts_with("post /chat/completions?key=abc&api-version=2024-10-01 "));
+        assert!(request.contains("x-org-id: org-1\r\n"));
+        // The header set on the request wins over the default
+        assert!(request.contains("authorization: bearer per-request\r\n"));
+        assert!(!request.contains("gateway"));
+    }
+
+    async fn status_error(status: u16, body: &str) -> LlmError {
+        let url = serve_once(status, body.to_string()).await;
+        let response = shared_client().get(url).send().await.unwrap();
+        error_for_status(response).await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn test_error_for_status_maps_status_classes() {
+        let err = status_error(401, r#"{"error":"bad key"}"#).await;
+        assert!(matches!(err, LlmError::Auth(body) if body.contains("bad key")));
+
+        let err = status_error(403, "{}").await;
+        assert!(matches!(err, LlmError::Auth(_)));
+
+        let err = status_error(429, r#"{"error":"slow down"}"#).await;
+        assert!(matches!(err, LlmError::RateLimit(body) if body.contains("slow down")));
+
+        // An HTML error page is carried along instead of failing to parse
+        let err = status_error(502, "<html>Bad Gateway</html>").await;
+        assert!(matches!(err, LlmError::ServerError { status: 502, body } if body.contains("Bad Gateway")));
+
+        let err = status_error(400, r#"{"error":"bad request"}"#).await;
+        assert!(matches!(err, LlmError::ProviderError { status: 400, code: None, message } if message == "bad request"));
+
+        // The provider's code survives the mapping
+        let err = status_error(429, r#"{"error":{"code":429,"message":"quota","status":"RESOURCE_EXHAUSTED"}}"#).await;
+        assert!(matches!(err, LlmError::RateLimit(_)));
+        assert_eq!(err.provider_code().as_deref(), Some("RESOURCE_EXHAUSTED"));
+    }
+
+    #[tokio::test]
+    async fn test_error_for_status_passes_success_through() {
+        let url = serve_once(200, r#"{"ok":true}"#.to_string()).await;
+        let response = shared_client().get(url).send().await.unwrap();
+
+        let response = error_for_status(response).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), r#"{"ok":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_is_rejected() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+        let answer = "word ".repeat(1000);
+        let body = serde_json::json!({
+            "id": "chatcmpl-1",
+            "created": 0,
+            "model": "grok-2",
+            "choices": [{ "message": { "role": "assistant", "content": answer }, "finish_reason": "stop" }]
+        })
+        .to_string();
+
+        let mut props = crate::services::types::llm_service::LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2", "Hello");
+        props.base_url = serve_once(200, body.clone()).await;
+        props.max_response_bytes = Some(1024);
+        let err = crate::services::llm::ask_with(props.clone()).await.unwrap_err();
+        assert!(matches!(err, LlmError::Provider(msg) if msg == RESPONSE_TOO_LARGE));
+
+        // Within the limit the body is read as usual
+        props.base_url = serve_once(200, body.clone()).await;
+        props.max_response_bytes = Some(body.len());
+        assert_eq!(crate::services::llm::ask_with(props).await.unwrap(), answer);
+
+        let mut budget = ByteBudget::new(Some(10));
+        assert!(budget.take(6));
+        assert!(budget.take(4));
+        assert!(!budget.take(1));
+        assert!(ByteBudget::new(None).take(usize::MAX));
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::common::types::models::LlmApiProvider;
+use crate::services::types::llm_error::LlmError;
+
+/// Where provider API keys come from, e.g. a secrets manager instead of the environment.
+pub trait CredentialProvider: Send + Sync {
+    /// The key for `provider`, or several separated by commas to rotate through.
+    /// It's asked for on every request so a rotated secret is picked up right away;
+    /// implementations backed by a remote store should cache.
+    fn get_key(&self, provider: &LlmApiProvider) -> Result<String, LlmError>;
+}
+
+/// Reads each provider's keys from its [`api_key_var`](LlmApiProvider::api_key_var).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnvCredentials;
+
+impl CredentialProvider for EnvCredentials {
+    fn get_key(&self, provider: &LlmApiProvider) -> Result<String, LlmError> {
+        let var = provider.api_key_var();
+        std::env::var(&var).map_err(|_| LlmError::Auth(format!("Missing {}", var)))
+    }
+}
+
+static CREDENTIAL_PROVIDER: OnceLock<RwLock<Arc<dyn CredentialProvider>>> = OnceLock::new();
+
+fn slot() -> &'static RwLock<Arc<dyn CredentialProvider>> {
+    CREDENTIAL_PROVIDER.get_or_init(|| RwLock::new(Arc::new(EnvCredentials)))
+}
+
+/// The credential provider every request gets its keys from, [`EnvCredentials`] unless
+/// replaced with [`set_credential_provider`].
+pub fn credential_provider() -> Arc<dyn CredentialProvider> {
+    slot().read().expect("credential provider lock poisoned").clone()
+}
+
+pub fn set_credential_provider(provider: Arc<dyn CredentialProvider>) {
+    *slot().write().expect("credential provider lock poisoned") = provider;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{providers::openai_compatible::OpenaiCompatibleProvider, types::llm_service::LlmServiceRequest};
+    use crate::services::utils::test_server::serve_capture;
+
+    // Supplies the Together key itself and leaves every other provider to the environment,
+    // so tests running alongside still find their keys
+    struct VaultCredentials;
+
+    impl CredentialProvider for VaultCredentials {
+        fn get_key(&self, provider: &LlmApiProvider) -> Result<String, LlmError> {
+            match provider {
+                LlmApiProvider::Together => Ok("vault-key".to_string()),
+                other => EnvCredentials.get_key(other),
+            }
+        }
+    }
+
+    /// Puts the credential provider it was made with back when dropped, also when the
+    /// test fails.
+    struct Restore(Arc<dyn CredentialProvider>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_credential_provider(self.0.clone());
+        }
+    }
+
+    #[test]
+    fn test_env_credentials_missing_var() {
+        let provider = LlmApiProvider::Custom("credentials-test-unset".to_string());
+        assert_eq!(provider.api_key_var(), "CREDENTIALS_TEST_UNSET_API_KEY");
+        assert!(matches!(EnvCredentials.get_key(&provider), Err(LlmError::Auth(msg)) if msg.contains("CREDENTIALS_TEST_UNSET_API_KEY")));
+    }
+
+    #[tokio::test]
+    async fn test_custom_credential_provider_supplies_key() {
+        let _restore = Restore(credential_provider());
+        set_credential_provider(Arc::new(VaultCredentials));
+
+        let body = r#"{"id":"t-1","choices":[{"message":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}]}"#;
+        let (base_url, received) = serve_capture(200, body.to_string()).await;
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Together, "meta-llama/Llama-3-8b-chat-hf", "Hello");
+        props.base_url = base_url;
+
+        let response = OpenaiCompatibleProvider::new(&props, false, LlmApiProvider::Together).execute_chat().await.unwrap();
+        assert_eq!(response.choices[0].message.content, "Hi");
+
+        let request = received.await.unwrap().to_lowercase();
+        assert!(request.contains("authorization: bearer vault-key\r\n"));
+    }
+}
@@ -0,0 +1,199 @@
+use serde_json::Value;
+
+/// Fixes the small mistakes models make in JSON mode: a surrounding Markdown code fence,
+/// trailing commas and unquoted object keys. Returns `None` when `text` already parses,
+/// or when it still doesn't after repairing, so a `Some` is always valid JSON.
+pub fn repair_json(text: &str) -> Option<String> {
+    if parses(text) {
+        return None;
+    }
+
+    let repaired = repair(strip_code_fence(text));
+    parses(&repaired).then_some(repaired)
+}
+
+/// The first balanced JSON object or array in `text`, ignoring the prose around it, for
+/// models that reason before answering with a JSON action. Brackets inside strings don't
+/// count, and a balanced block that isn't valid JSON is skipped for the next one.
+pub fn extract_first_json(text: &str) -> Option<Value> {
+    text.char_indices()
+        .filter(|(_, c)| matches!(c, '{' | '['))
+        .filter_map(|(start, _)| balanced_end(&text[start..]).map(|len| &text[start..start + len]))
+        .find_map(|candidate| serde_json::from_str(candidate).ok())
+}
+
+/// Length of the block `text` opens, up to and including its closing bracket.
+fn balanced_end(text: &str) -> Option<usize> {
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' => {
+                if closers.pop() != Some(c) {
+                    return None;
+                }
+                if closers.is_empty() {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn parses(text: &str) -> bool {
+    serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok()
+}
+
+/// The contents of a ```` ```json ```` block, or `text` when it isn't fenced.
+fn strip_code_fence(text: &str) -> &str {
+    let trimmed = text.trim();
+    let Some(rest) = trimmed.strip_prefix("```") else {
+        return text;
+    };
+
+    // Skip the language tag on the opening line
+    let body = rest.split_once('\n').map_or("", |(_, body)| body);
+    body.trim_end().strip_suffix("```").unwrap_or(body)
+}
+
+fn repair(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 16);
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            '}' | ']' => {
+                drop_trailing_comma(&mut out);
+                out.push(c);
+            }
+            c if is_ident_start(c) && expects_key(&out) => {
+                let mut ident = c.to_string();
+                while let Some(&next) = chars.peek().filter(|next| is_ident_char(**next)) {
+                    ident.push(next);
+                    chars.next();
+                }
+                while chars.peek().is_some_and(|next| next.is_whitespace()) {
+                    chars.next();
+                }
+
+                if chars.peek() == Some(&':') {
+                    out.push('"');
+                    out.push_str(&ident);
+                    out.push('"');
+                } else {
+                    // A bare literal such as `true` in an array, not a key
+                    out.push_str(&ident);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Whether the last thing written opens an object or separates its members, so an
+/// identifier here is a key.
+fn expects_key(out: &str) -> bool {
+    matches!(out.trim_end().chars().last(), Some('{') | Some(','))
+}
+
+fn drop_trailing_comma(out: &mut String) {
+    let content_len = out.trim_end().len();
+    if out[..content_len].ends_with(',') {
+        out.remove(content_len - 1);
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '$'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn repaired(text: &str) -> Value {
+        serde_json::from_str(&repair_json(text).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_trailing_commas_removed() {
+        let text = r#"{"name": "Ada", "tags": ["math", "engines",], "note": "a, b,",}"#;
+        assert_eq!(repaired(text), json!({ "name": "Ada", "tags": ["math", "engines"], "note": "a, b," }));
+    }
+
+    #[test]
+    fn test_unquoted_keys_and_code_fence() {
+        let text = "```json\n{name: \"Ada\", born_in: 1815, flags: [true, null]}\n```";
+        assert_eq!(repaired(text), json!({ "name": "Ada", "born_in": 1815, "flags": [true, null] }));
+    }
+
+    #[test]
+    fn test_valid_json_is_left_alone() {
+        assert_eq!(repair_json(r#"{"name": "Ada", "tags": []}"#), None);
+        assert_eq!(repair_json("[1, 2, 3]"), None);
+    }
+
+    #[test]
+    fn test_unrepairable_json() {
+        assert_eq!(repair_json(r#"{"name": "Ada""#), None);
+        assert_eq!(repair_json("not json at all"), None);
+    }
+
+    #[test]
+    fn test_first_json_in_prose() {
+        let text = r#"Thought: I need the weather {maybe}. Action: {"tool": "weather", "args": {"city": "Paris}"}} then [1, 2]"#;
+        assert_eq!(extract_first_json(text), Some(json!({ "tool": "weather", "args": { "city": "Paris}" } })));
+
+        let text = "The ids are [3, 5] and {\"a\": 1}";
+        assert_eq!(extract_first_json(text), Some(json!([3, 5])));
+    }
+
+    #[test]
+    fn test_no_json_in_prose() {
+        assert_eq!(extract_first_json("No action needed, {just thinking} out loud."), None);
+        assert_eq!(extract_first_json(r#"Unclosed {"tool": "weather""#), None);
+        assert_eq!(extract_first_json(""), None);
+    }
+}
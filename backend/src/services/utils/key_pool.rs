@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::RETRY_AFTER, Response};
+
+use crate::common::types::models::LlmApiProvider;
+use crate::services::types::llm_error::LlmError;
+
+use super::credentials::credential_provider;
+
+/// How long a key sits out after a 429 that didn't say when to retry.
+const DEFAULT_BENCH: Duration = Duration::from_secs(60);
+
+static KEY_POOLS: OnceLock<Mutex<HashMap<String, (String, Arc<KeyPool>)>>> = OnceLock::new();
+
+/// The pool for `provider`'s keys, shared by every request to that provider. The keys are
+/// asked of the [credential provider](super::credentials::CredentialProvider) each time and
+/// the pool is only rebuilt when they change, so benched keys stay benched.
+pub fn key_pool(provider: &LlmApiProvider) -> Result<Arc<KeyPool>, LlmError> {
+    let credential = credential_provider().get_key(provider)?;
+    let name = String::from(provider.clone());
+
+    let mut pools = KEY_POOLS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .expect("key pool lock poisoned");
+
+    if let Some((cached, pool)) = pools.get(&name) {
+        if *cached == credential {
+            return Ok(pool.clone());
+        }
+    }
+
+    let pool = Arc::new(KeyPool::from_list(&credential).map_err(|_| LlmError::Auth(format!("No API key for {}", name)))?);
+    pools.insert(name, (credential, pool.clone()));
+    Ok(pool)
+}
+
+/// API keys for one provider, handed out round-robin. A key that was rate limited is
+/// benched and skipped until its bench expires; if every key is benched the one that
+/// comes back first is used.
+#[derive(Debug)]
+pub struct KeyPool {
+    keys: Vec<String>,
+    state: Mutex<KeyPoolState>,
+}
+
+#[derive(Debug)]
+struct KeyPoolState {
+    next: usize,
+    benched_until: Vec<Option<Instant>>,
+}
+
+/// A key handed out by a [`KeyPool`], remembering where it came from so it can be benched.
+#[derive(Debug, Clone)]
+pub struct PooledKey {
+    pool: Arc<KeyPool>,
+    index: usize,
+    pub key: String,
+}
+
+impl PooledKey {
+    /// Takes this key out of rotation for `duration`.
+    pub fn bench(&self, duration: Duration) {
+        self.pool.bench(self.index, duration);
+    }
+
+    /// Benches the key if `response` is a 429, for as long as its `Retry-After` asks.
+    /// Returns whether it was rate limited.
+    pub fn bench_if_rate_limited(&self, response: &Response) -> bool {
+        if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return false;
+        }
+
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+
+        self.bench(retry_after.unwrap_or(DEFAULT_BENCH));
+        true
+    }
+}
+
+impl KeyPool {
+    pub fn new(keys: Vec<String>) -> Result<Self, LlmError> {
+        if keys.is_empty() {
+            return Err(LlmError::InvalidConfig("Key pool needs at least one key".to_string()));
+        }
+
+        Ok(KeyPool {
+            state: Mutex::new(KeyPoolState {
+                next: 0,
+                benched_until: vec![None; keys.len()],
+            }),
+            keys,
+        })
+    }
+
+    /// Reads comma separated keys from `var`.
+    pub fn from_env(var: &str) -> Result<Self, LlmError> {
+        let value = std::env::var(var).map_err(|_| LlmError::Auth(format!("Missing {}", var)))?;
+        KeyPool::from_list(&value).map_err(|_| LlmError::Auth(format!("Missing {}", var)))
+    }
+
+    /// Builds a pool from comma separated keys, ignoring blanks.
+    pub fn from_list(value: &str) -> Result<Self, LlmError> {
+        let keys: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        KeyPool::new(keys)
+    }
+
+    /// The next key in rotation that isn't benched.
+    pub fn next_key(self: &Arc<Self>) -> PooledKey {
+        let mut state = self.state.lock().expect("key pool lock poisoned");
+        let now = Instant::now();
+        let len = self.keys.len();
+
+        let available = (0..len)
+            .map(|offset| (state.next + offset) % len)
+            .find(|&i| state.benched_until[i].is_none_or(|until| until <= now));
+
+        // Everything is benched, take the key that is closest to coming back
+        let index = available.unwrap_or_else(|| {
+            (0..len)
+                .min_by_key(|&i| state.benched_until[i])
+                .unwrap_or(0)
+        });
+
+        state.next = (index + 1) % len;
+
+        PooledKey {
+            pool: self.clone(),
+            index,
+            key: self.keys[index].clone(),
+        }
+    }
+
+    fn bench(&self, index: usize, duration: Duration) {
+        let mut state = self.state.lock().expect("key pool lock poisoned");
+        state.benched_until[index] = Some(Instant::now() + duration);
+        tracing::warn!("API key {} of {} rate limited, benched for {:?}", index + 1, self.keys.len(), duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn three_keys() -> Arc<KeyPool> {
+        Arc::new(KeyPool::new(vec!["a".to_string(), "b".to_string(), "c".to_string()]).unwrap())
+    }
+
+    #[test]
+    fn test_key_pool_round_robin() {
+        let pool = three_keys();
+
+        let keys: Vec<String> = (0..6).map(|_| pool.next_key().key).collect();
+        assert_eq!(keys, vec!["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_key_pool_skips_benched_key() {
+        let pool = three_keys();
+
+        let a = pool.next_key();
+        a.bench(Duration::from_secs(60));
+
+        let keys: Vec<String> = (0..4).map(|_| pool.next_key().key).collect();
+        assert_eq!(keys, vec!["b", "c", "b", "c"]);
+    }
+
+    #[test]
+    fn test_key_pool_all_benched_uses_first_back() {
+        let pool = three_keys();
+
+        pool.next_key().bench(Duration::from_secs(30));
+        pool.next_key().bench(Duration::from_secs(10));
+        pool.next_key().bench(Duration::from_secs(20));
+
+        assert_eq!(pool.next_key().key, "b");
+    }
+
+    #[test]
+    fn test_key_pool_from_env() {
+        std::env::set_var("KEY_POOL_TEST_KEYS", "k1, k2,,k3");
+        let pool = KeyPool::from_env("KEY_POOL_TEST_KEYS").unwrap();
+        assert_eq!(pool.keys, vec!["k1", "k2", "k3"]);
+
+        assert!(matches!(KeyPool::from_env("KEY_POOL_TEST_UNSET"), Err(LlmError::Auth(_))));
+    }
+}
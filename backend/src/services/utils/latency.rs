@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use rand::Rng;
+
+use crate::common::types::models::LlmApiProvider;
+
+/// Samples kept per provider. Older samples are replaced at random once full.
+const DEFAULT_RESERVOIR_SIZE: usize = 1024;
+
+static LATENCY_TRACKER: OnceLock<LatencyTracker> = OnceLock::new();
+
+/// The process wide tracker every completion is recorded into.
+pub fn latency_tracker() -> &'static LatencyTracker {
+    LATENCY_TRACKER.get_or_init(|| LatencyTracker::new(DEFAULT_RESERVOIR_SIZE))
+}
+
+/// Latency percentiles for one provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// Completions recorded in total, including those no longer sampled.
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// Uniform sample of completion durations (Algorithm R), so memory stays bounded
+/// no matter how many completions are recorded.
+#[derive(Debug, Default)]
+struct Reservoir {
+    seen: u64,
+    samples: Vec<Duration>,
+}
+
+impl Reservoir {
+    fn record(&mut self, duration: Duration, capacity: usize) {
+        self.seen += 1;
+
+        if self.samples.len() < capacity {
+            self.samples.push(duration);
+            return;
+        }
+
+        let slot = rand::rng().random_range(0..self.seen);
+        if (slot as usize) < capacity {
+            self.samples[slot as usize] = duration;
+        }
+    }
+
+    fn snapshot(&self) -> Option<LatencySnapshot> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+
+        let percentile = |p: f64| {
+            let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+            sorted[index]
+        };
+
+        Some(LatencySnapshot {
+            count: self.seen,
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+        })
+    }
+}
+
+/// Tracks completion latency per provider.
+#[derive(Debug)]
+pub struct LatencyTracker {
+    capacity: usize,
+    reservoirs: Mutex<HashMap<LlmApiProvider, Reservoir>>,
+}
+
+impl LatencyTracker {
+    pub fn new(capacity: usize) -> Self {
+        LatencyTracker {
+            capacity: capacity.max(1),
+            reservoirs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, provider: &LlmApiProvider, duration: Duration) {
+        let mut reservoirs = self.reservoirs.lock().expect("latency tracker lock poisoned");
+        reservoirs
+            .entry(provider.clone())
+            .or_default()
+            .record(duration, self.capacity);
+    }
+
+    /// Percentiles for `provider`, `None` until a completion has been recorded for it.
+    pub fn snapshot(&self, provider: &LlmApiProvider) -> Option<LatencySnapshot> {
+        let reservoirs = self.reservoirs.lock().expect("latency tracker lock poisoned");
+        reservoirs.get(provider).and_then(Reservoir::snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let tracker = LatencyTracker::new(1000);
+        for ms in 1..=100 {
+            tracker.record(&LlmApiProvider::Openrouter, Duration::from_millis(ms));
+        }
+
+        let snapshot = tracker.snapshot(&LlmApiProvider::Openrouter).unwrap();
+        assert_eq!(snapshot.count, 100);
+        assert!(snapshot.p50.as_millis().abs_diff(50) <= 1);
+        assert!(snapshot.p95.as_millis().abs_diff(95) <= 1);
+        assert!(snapshot.p99.as_millis().abs_diff(99) <= 1);
+
+        // Providers are tracked separately
+        assert!(tracker.snapshot(&LlmApiProvider::Gemini).is_none());
+    }
+
+    #[test]
+    fn test_latency_reservoir_is_bounded() {
+        let tracker = LatencyTracker::new(50);
+        for _ in 0..10_000 {
+            tracker.record(&LlmApiProvider::Gemini, Duration::from_millis(200));
+        }
+
+        let snapshot = tracker.snapshot(&LlmApiProvider::Gemini).unwrap();
+        assert_eq!(snapshot.count, 10_000);
+        assert_eq!(snapshot.p99, Duration::from_millis(200));
+
+        let reservoirs = tracker.reservoirs.lock().unwrap();
+        assert_eq!(reservoirs[&LlmApiProvider::Gemini].samples.len(), 50);
+    }
+}
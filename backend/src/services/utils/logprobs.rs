@@ -0,0 +1,60 @@
+use crate::common::types::chat_response::TokenLogprob;
+
+/// Mean probability of the generated tokens, `exp(logprob)` averaged over all tokens,
+/// between 0 and 1. Higher is more confident; an answer with no tokens scores 0.
+///
+/// Unlike perplexity a single very unlikely token only lowers the score by its share,
+/// so low scores mean the model was unsure across much of the answer.
+pub fn response_confidence(logprobs: &[TokenLogprob]) -> f32 {
+    if logprobs.is_empty() {
+        return 0.0;
+    }
+
+    let total: f64 = logprobs.iter().map(|t| t.logprob.exp()).sum();
+    (total / logprobs.len() as f64) as f32
+}
+
+/// Perplexity of the generated tokens, `exp(-mean(logprob))`. 1 means every token was
+/// certain, larger values mean less confidence; `None` for an answer with no tokens.
+pub fn perplexity(logprobs: &[TokenLogprob]) -> Option<f32> {
+    if logprobs.is_empty() {
+        return None;
+    }
+
+    let mean: f64 = logprobs.iter().map(|t| t.logprob).sum::<f64>() / logprobs.len() as f64;
+    Some((-mean).exp() as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(probabilities: &[f64]) -> Vec<TokenLogprob> {
+        probabilities
+            .iter()
+            .enumerate()
+            .map(|(i, p)| TokenLogprob {
+                token: format!("t{}", i),
+                logprob: p.ln(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_response_confidence_is_mean_probability() {
+        let logprobs = tokens(&[1.0, 0.5, 0.25, 0.25]);
+        assert!((response_confidence(&logprobs) - 0.5).abs() < 1e-6);
+
+        assert_eq!(response_confidence(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_perplexity() {
+        // Every token at probability 0.5 is as uncertain as a coin flip
+        let logprobs = tokens(&[0.5, 0.5, 0.5]);
+        assert!((perplexity(&logprobs).unwrap() - 2.0).abs() < 1e-6);
+
+        assert!((perplexity(&tokens(&[1.0])).unwrap() - 1.0).abs() < 1e-6);
+        assert_eq!(perplexity(&[]), None);
+    }
+}
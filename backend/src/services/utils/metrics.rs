@@ -0,0 +1,152 @@
+use ::metrics::{counter, Label};
+
+use crate::common::types::{chat_response::LlmServiceChatCompletionResponse, models::LlmApiProvider};
+use crate::services::types::llm_error::LlmError;
+
+use super::model_limits::context_window;
+
+pub const REQUESTS_TOTAL: &str = "llmkit_requests_total";
+/// Labelled with the [`NormalizedError`](crate::services::types::llm_error::NormalizedError) category as well.
+pub const ERRORS_TOTAL: &str = "llmkit_errors_total";
+pub const PROMPT_TOKENS_TOTAL: &str = "llmkit_prompt_tokens_total";
+pub const COMPLETION_TOKENS_TOTAL: &str = "llmkit_completion_tokens_total";
+
+/// Label used for every model that isn't in the model tables.
+const OTHER_MODEL: &str = "other";
+
+/// Model label with bounded cardinality: the namespace and the date or version suffix
+/// are dropped (`openai/gpt-4o-2024-08-06` is `gpt-4o`), and models the tables in
+/// [`model_limits`](super::model_limits) don't know are all counted as `other`.
+pub fn model_label(model: &str) -> String {
+    let mut name = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+    if let Some(dated) = name.len().checked_sub(11) {
+        if name.get(dated..).is_some_and(is_iso_date_suffix) {
+            name.truncate(dated);
+        }
+    }
+
+    let mut parts: Vec<&str> = name.split('-').collect();
+    while parts.len() > 1 && parts.last().is_some_and(|part| is_version_suffix(part)) {
+        parts.pop();
+    }
+    let base = parts.join("-");
+
+    if context_window(&base).is_some() {
+        base
+    } else {
+        OTHER_MODEL.to_string()
+    }
+}
+
+/// `-2024-08-06`
+fn is_iso_date_suffix(suffix: &str) -> bool {
+    suffix.bytes().enumerate().all(|(i, b)| match i {
+        0 | 5 | 8 => b == b'-',
+        _ => b.is_ascii_digit(),
+    })
+}
+
+/// `latest`, or a version or date like `002`, `0806` and `20241022`. Shorter numbers
+/// are part of the name, as in `claude-3-5-sonnet`.
+fn is_version_suffix(part: &str) -> bool {
+    part == "latest" || (part.len() >= 3 && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Counts one provider call, and its tokens or its error category.
+pub fn record_completion(
+    provider: &LlmApiProvider,
+    model: &str,
+    result: &Result<LlmServiceChatCompletionResponse, LlmError>,
+) {
+    let mut labels = vec![
+        Label::new("provider", String::from(provider.clone())),
+        Label::new("model", model_label(model)),
+    ];
+    counter!(REQUESTS_TOTAL, labels.clone()).increment(1);
+
+    match result {
+        Ok(response) => {
+            if let Some(usage) = &response.usage {
+                counter!(PROMPT_TOKENS_TOTAL, labels.clone()).increment(usage.prompt_tokens as u64);
+                counter!(COMPLETION_TOKENS_TOTAL, labels).increment(usage.completion_tokens as u64);
+            }
+        }
+        Err(e) => {
+            labels.push(Label::new("category", e.normalized().as_str()));
+            counter!(ERRORS_TOTAL, labels).increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{llm::ask_with, types::llm_service::LlmServiceRequest, utils::test_server::serve_once};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+    fn counter_value(snapshotter: &Snapshotter, name: &str, labels: &[(&str, &str)]) -> Option<u64> {
+        snapshotter.snapshot().into_vec().into_iter().find_map(|(key, _, _, value)| {
+            let key = key.key();
+            let matches = key.name() == name
+                && labels.len() == key.labels().count()
+                && labels.iter().all(|(k, v)| key.labels().any(|l| l.key() == *k && l.value() == *v));
+            match value {
+                DebugValue::Counter(n) if matches => Some(n),
+                _ => None,
+            }
+        })
+    }
+
+    // The recorder is thread local, so everything runs on one current thread runtime
+    fn with_recorder<F: std::future::Future>(future: F) -> (F::Output, Snapshotter) {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        let output = ::metrics::with_local_recorder(&recorder, || runtime.block_on(future));
+        (output, snapshotter)
+    }
+
+    #[test]
+    fn test_model_label() {
+        assert_eq!(model_label("openai/gpt-4o-2024-08-06"), "gpt-4o");
+        assert_eq!(model_label("gemini-1.5-flash-002"), "gemini-1.5-flash");
+        assert_eq!(model_label("Claude-3-5-Sonnet-20241022"), "claude-3-5-sonnet");
+        assert_eq!(model_label("my-finetune-7f3a"), "other");
+    }
+
+    #[test]
+    fn test_counters_increment_after_completion() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let (result, snapshotter) = with_recorder(async {
+            let body = r#"{"id":"mock-1","created":0,"model":"gpt-4o","choices":[{"message":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}],"usage":{"prompt_tokens":12,"completion_tokens":3,"total_tokens":15}}"#;
+            let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "gpt-4o-2024-08-06", "Hello");
+            props.base_url = serve_once(200, body.to_string()).await;
+            ask_with(props).await
+        });
+        assert_eq!(result.unwrap(), "Hi");
+
+        let labels = [("provider", "grok"), ("model", "gpt-4o")];
+        assert_eq!(counter_value(&snapshotter, REQUESTS_TOTAL, &labels), Some(1));
+        assert_eq!(counter_value(&snapshotter, PROMPT_TOKENS_TOTAL, &labels), Some(12));
+        assert_eq!(counter_value(&snapshotter, COMPLETION_TOKENS_TOTAL, &labels), Some(3));
+    }
+
+    #[test]
+    fn test_error_counted_by_category() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        let (result, snapshotter) = with_recorder(async {
+            let body = r#"{"error":{"message":"This model's maximum context length is 8192 tokens","code":"context_length_exceeded"}}"#;
+            let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "unlisted-model", "Hello");
+            props.base_url = serve_once(400, body.to_string()).await;
+            ask_with(props).await
+        });
+        assert!(result.is_err());
+
+        let labels = [("provider", "grok"), ("model", "other"), ("category", "context_overflow")];
+        assert_eq!(counter_value(&snapshotter, ERRORS_TOTAL, &labels), Some(1));
+        assert_eq!(counter_value(&snapshotter, REQUESTS_TOTAL, &labels[..2]), Some(1));
+    }
+}
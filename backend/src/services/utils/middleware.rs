@@ -0,0 +1,114 @@
+use std::sync::OnceLock;
+
+use reqwest::{RequestBuilder, Response};
+use reqwest_middleware::ClientWithMiddleware;
+
+use crate::services::types::llm_error::LlmError;
+
+static MIDDLEWARE_CLIENT: OnceLock<ClientWithMiddleware> = OnceLock::new();
+
+/// Sends provider requests through `client` from now on, so its middleware (tracing,
+/// retries, caching...) runs on every one of them. Requests are still built by the
+/// [`shared_client`](super::client::shared_client), build `client` from its
+/// [`reqwest_client`](super::client::HttpClient::reqwest_client) to keep the pool and TLS
+/// settings. Streams read through an event source don't go through it.
+pub fn init_middleware_client(client: ClientWithMiddleware) -> Result<(), LlmError> {
+    MIDDLEWARE_CLIENT
+        .set(client)
+        .map_err(|_| LlmError::InvalidConfig("Middleware client is already initialized".to_string()))
+}
+
+/// The client set up by [`init_middleware_client`], if any.
+pub fn middleware_client() -> Option<&'static ClientWithMiddleware> {
+    MIDDLEWARE_CLIENT.get()
+}
+
+/// Sends `request` through `client` and its middleware.
+pub async fn send_through(client: &ClientWithMiddleware, request: RequestBuilder) -> Result<Response, LlmError> {
+    Ok(client.execute(request.build()?).await?)
+}
+
+impl From<reqwest_middleware::Error> for LlmError {
+    fn from(err: reqwest_middleware::Error) -> Self {
+        match err {
+            reqwest_middleware::Error::Reqwest(e) => LlmError::Network(e),
+            reqwest_middleware::Error::Middleware(e) => LlmError::Provider(format!("Middleware error: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::common::types::models::LlmApiProvider;
+    use crate::services::{
+        llm::ask_with,
+        providers::openai_compatible::OpenaiCompatibleProvider,
+        types::llm_service::LlmServiceRequest,
+        utils::{client::shared_client, test_server::serve_once},
+    };
+    use reqwest_middleware::{Middleware, Next};
+    use task_local_extensions::Extensions;
+
+    /// Records the url of every request it sees.
+    struct Counting(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait::async_trait]
+    impl Middleware for Counting {
+        async fn handle(
+            &self,
+            req: reqwest::Request,
+            extensions: &mut Extensions,
+            next: Next<'_>,
+        ) -> reqwest_middleware::Result<Response> {
+            self.0.lock().unwrap().push(req.url().to_string());
+            next.run(req, extensions).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_runs_on_completion() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        // A client of its own, the global one would route every other test through it
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(Counting(seen.clone()))
+            .build();
+
+        let body = r#"{"id":"mock-1","created":0,"model":"grok-2","choices":[{"message":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}]}"#;
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2", "Hello");
+        props.base_url = serve_once(200, body.to_string()).await;
+
+        let request = OpenaiCompatibleProvider::new(&props, false, LlmApiProvider::Grok).build_request("test-key").unwrap();
+        let response = send_through(&client, request).await.unwrap();
+        let completion = OpenaiCompatibleProvider::parse_response(&response.text().await.unwrap()).unwrap();
+        assert_eq!(completion.choices[0].message.content, "Hi");
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert!(seen[0].starts_with(&props.base_url));
+    }
+
+    #[tokio::test]
+    async fn test_registered_middleware_runs_on_ask_with() {
+        std::env::set_var("XAI_API_KEY", "test-key");
+
+        // The only test that registers the global client. It passes every request on, so
+        // the other tests sending meanwhile are only recorded
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = reqwest_middleware::ClientBuilder::new(shared_client().reqwest_client())
+            .with(Counting(seen.clone()))
+            .build();
+        init_middleware_client(client).unwrap();
+
+        let body = r#"{"id":"mock-2","created":0,"model":"grok-2","choices":[{"message":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}]}"#;
+        let mut props = LlmServiceRequest::from_user_prompt(LlmApiProvider::Grok, "grok-2", "Hello");
+        props.base_url = serve_once(200, body.to_string()).await;
+
+        assert_eq!(ask_with(props.clone()).await.unwrap(), "Hi");
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.iter().filter(|url| url.starts_with(&props.base_url)).count(), 1);
+    }
+}
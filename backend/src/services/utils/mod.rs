@@ -0,0 +1,24 @@
+pub mod cassette;
+pub mod chunking;
+pub mod canonical_json;
+pub mod client;
+pub mod credentials;
+pub mod duration_ms;
+pub mod json_repair;
+pub mod key_pool;
+#[cfg(feature = "latency-metrics")]
+pub mod latency;
+pub mod logprobs;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "middleware")]
+pub mod middleware;
+pub mod model_limits;
+pub mod ndjson;
+pub mod schema;
+pub mod stream;
+#[cfg(test)]
+pub mod test_server;
+pub mod token_budget;
+pub mod transform;
+pub mod utf8;
@@ -0,0 +1,134 @@
+use std::ops::RangeInclusive;
+
+use crate::common::types::models::LlmApiProvider;
+
+/// Known output token caps. Providers reject requests whose `max_tokens` goes over
+/// these, so the value is clamped before it is sent.
+const MAX_OUTPUT_TOKENS: &[(&str, i64)] = &[
+    ("gpt-4o", 16_384),
+    ("gpt-4o-mini", 16_384),
+    ("gpt-4-turbo", 4_096),
+    ("claude-3-5-sonnet", 8_192),
+    ("claude-3-5-haiku", 8_192),
+    ("claude-3-opus", 4_096),
+    ("gemini-2.0-flash", 8_192),
+    ("gemini-1.5-pro", 8_192),
+    ("gemini-1.5-flash", 8_192),
+];
+
+/// Output token cap for `model`, `None` when it isn't in the table. Namespaced ids
+/// like `openai/gpt-4o` are looked up by the part after the last `/`.
+pub fn max_output_tokens(model: &str) -> Option<i64> {
+    let name = model.rsplit('/').next().unwrap_or(model);
+
+    MAX_OUTPUT_TOKENS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, limit)| *limit)
+}
+
+/// Known context windows, prompt and output tokens combined.
+const CONTEXT_WINDOWS: &[(&str, u32)] = &[
+    ("gpt-4o", 128_000),
+    ("gpt-4o-mini", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("claude-3-5-sonnet", 200_000),
+    ("claude-3-5-haiku", 200_000),
+    ("claude-3-opus", 200_000),
+    ("gemini-2.0-flash", 1_048_576),
+    ("gemini-1.5-pro", 2_097_152),
+    ("gemini-1.5-flash", 1_048_576),
+];
+
+/// Context window of `model`, looked up like [`max_output_tokens`].
+pub fn context_window(model: &str) -> Option<u32> {
+    let name = model.rsplit('/').next().unwrap_or(model);
+
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, window)| *window)
+}
+
+/// Clamps `max_tokens` to the model's output cap, warning when it had to.
+/// Unknown models get the requested value as-is.
+pub fn clamp_max_tokens(model: &str, max_tokens: i64) -> i64 {
+    match max_output_tokens(model) {
+        Some(limit) if max_tokens > limit => {
+            tracing::warn!(
+                "max_tokens {} exceeds the output limit of {} for {}, clamping",
+                max_tokens,
+                limit,
+                model
+            );
+            limit
+        }
+        _ => max_tokens,
+    }
+}
+
+/// Range accepted for `frequency_penalty` and `presence_penalty`, `None` for providers
+/// that don't take them at all. Gemini 2.x does take them, which depends on the model
+/// and is handled by the Gemini provider.
+pub fn penalty_range(provider: &LlmApiProvider) -> Option<RangeInclusive<f64>> {
+    match provider {
+        LlmApiProvider::Openrouter
+        | LlmApiProvider::Grok
+        | LlmApiProvider::Together
+        | LlmApiProvider::Fireworks
+        | LlmApiProvider::HuggingFace
+        | LlmApiProvider::Groq => Some(-2.0..=2.0),
+        LlmApiProvider::Gemini | LlmApiProvider::VertexAi | LlmApiProvider::Replicate | LlmApiProvider::Custom(_) => None,
+    }
+}
+
+/// The value to send for the penalty `name`, clamped to the provider's range.
+/// `None` means the field is left out of the request, with a warning since the
+/// caller asked for it.
+pub fn clamp_penalty(provider: &LlmApiProvider, name: &str, value: f64) -> Option<f64> {
+    let Some(range) = penalty_range(provider) else {
+        tracing::warn!("{} is not supported by {}, leaving it out", name, String::from(provider.clone()));
+        return None;
+    };
+
+    let clamped = value.clamp(*range.start(), *range.end());
+    if clamped != value {
+        tracing::warn!("{} {} is outside {:?}, clamping to {}", name, value, range, clamped);
+    }
+    Some(clamped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp_max_tokens_for_known_model() {
+        assert_eq!(clamp_max_tokens("gemini-2.0-flash", 100_000), 8_192);
+        assert_eq!(clamp_max_tokens("openai/gpt-4o", 20_000), 16_384);
+
+        // Values under the limit are left alone
+        assert_eq!(clamp_max_tokens("gemini-2.0-flash", 1_000), 1_000);
+    }
+
+    #[test]
+    fn test_max_tokens_unknown_model_sent_as_is() {
+        assert_eq!(max_output_tokens("some-new-model"), None);
+        assert_eq!(clamp_max_tokens("some-new-model", 100_000), 100_000);
+    }
+
+    #[test]
+    fn test_clamp_penalty_to_provider_range() {
+        assert_eq!(clamp_penalty(&LlmApiProvider::Together, "frequency_penalty", 3.5), Some(2.0));
+        assert_eq!(clamp_penalty(&LlmApiProvider::Grok, "presence_penalty", -5.0), Some(-2.0));
+        assert_eq!(clamp_penalty(&LlmApiProvider::Fireworks, "frequency_penalty", 0.5), Some(0.5));
+        assert_eq!(clamp_penalty(&LlmApiProvider::Openrouter, "presence_penalty", 2.5), Some(2.0));
+    }
+
+    #[test]
+    fn test_penalty_omitted_for_unsupported_providers() {
+        assert_eq!(clamp_penalty(&LlmApiProvider::Gemini, "frequency_penalty", 0.5), None);
+        assert_eq!(clamp_penalty(&LlmApiProvider::Replicate, "presence_penalty", 0.5), None);
+        assert_eq!(clamp_penalty(&LlmApiProvider::Custom("local".to_string()), "presence_penalty", 0.5), None);
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt::Display;
+
+use futures_util::{Stream, StreamExt};
+
+use super::utf8::Utf8Decoder;
+
+/// Splits a newline delimited JSON body (Ollama and similar) into its lines as they
+/// arrive, for providers that stream without SSE. Lines may be split across reads at
+/// any byte; empty lines are skipped and a last line without a newline is kept.
+pub fn ndjson_lines<S, B, E>(bytes: S) -> impl Stream<Item = Result<String, String>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: Display,
+{
+    async_stream::stream! {
+        let mut bytes = std::pin::pin!(bytes);
+        let mut utf8 = Utf8Decoder::default();
+        let mut buffer = String::new();
+
+        while let Some(read) = bytes.next().await {
+            let text = match read {
+                Ok(read) => utf8.decode(read.as_ref()),
+                Err(e) => {
+                    yield Err(e.to_string());
+                    return;
+                }
+            };
+            match text {
+                Ok(text) => buffer.push_str(&text),
+                Err(e) => {
+                    yield Err(e.to_string());
+                    return;
+                }
+            }
+
+            while let Some(newline) = buffer.find('\n') {
+                let line: String = buffer.drain(..=newline).collect();
+                if !line.trim().is_empty() {
+                    yield Ok(line.trim().to_string());
+                }
+            }
+        }
+
+        if !buffer.trim().is_empty() {
+            yield Ok(buffer.trim().to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lines_split_across_reads() {
+        let body = "{\"a\":1}\n\n{\"b\":\"é\"}\n{\"c\":3}";
+        let reads: Vec<Result<Vec<u8>, String>> = body.as_bytes().chunks(5).map(|c| Ok(c.to_vec())).collect();
+
+        let lines: Vec<_> = ndjson_lines(futures_util::stream::iter(reads)).collect().await;
+        assert_eq!(
+            lines,
+            vec![Ok(r#"{"a":1}"#.to_string()), Ok(r#"{"b":"é"}"#.to_string()), Ok(r#"{"c":3}"#.to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_error_ends_lines() {
+        let reads: Vec<Result<&[u8], &str>> = vec![Ok(b"{\"a\":1}\n"), Err("connection reset")];
+
+        let lines: Vec<_> = ndjson_lines(futures_util::stream::iter(reads)).collect().await;
+        assert_eq!(lines, vec![Ok(r#"{"a":1}"#.to_string()), Err("connection reset".to_string())]);
+    }
+}
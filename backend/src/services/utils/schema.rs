@@ -0,0 +1,272 @@
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+use crate::services::types::llm_error::LlmError;
+
+/// Keywords Gemini's `responseSchema` understands. Everything else is dropped.
+const GEMINI_KEYWORDS: &[&str] = &[
+    "type",
+    "format",
+    "description",
+    "nullable",
+    "enum",
+    "properties",
+    "required",
+    "items",
+    "minItems",
+    "maxItems",
+];
+
+/// Formats Gemini accepts, other formats are dropped.
+const GEMINI_FORMATS: &[&str] = &["int32", "int64", "float", "double", "enum", "date-time"];
+
+/// Refs nested deeper than this are assumed to be recursive and become a plain object.
+const MAX_REF_DEPTH: usize = 8;
+
+/// The JSON schema for `T`, as derived by `schemars`.
+pub fn schema_for<T: JsonSchema>() -> Value {
+    serde_json::to_value(schemars::schema_for!(T)).expect("derived schemas always serialize")
+}
+
+/// Checks that `text` is JSON conforming to `schema`. The error lists every violation
+/// with the path it was found at, e.g. `/items/0/price: "12" is not of type "number"`.
+pub fn validate_against_schema(text: &str, schema: &Value) -> Result<(), LlmError> {
+    let instance: Value = serde_json::from_str(text)
+        .map_err(|e| LlmError::InvalidJsonSchema(format!("response is not JSON: {}", e)))?;
+    let validator = jsonschema::validator_for(schema)
+        .map_err(|e| LlmError::InvalidConfig(format!("Invalid JSON schema: {}", e)))?;
+
+    let violations: Vec<String> = validator
+        .iter_errors(&instance)
+        .map(|error| {
+            let path = error.instance_path.to_string();
+            if path.is_empty() {
+                error.to_string()
+            } else {
+                format!("{}: {}", path, error)
+            }
+        })
+        .collect();
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(LlmError::InvalidJsonSchema(violations.join("; ")))
+    }
+}
+
+/// Rewrites a JSON schema into the subset Gemini accepts as `responseSchema`:
+///
+/// - `$ref`s into `definitions`/`$defs` are inlined, recursive types are cut off as `{"type": "object"}`
+/// - an `allOf` with a single schema (how `schemars` attaches a description to a ref) is merged into its parent
+/// - a nullable type, `"type": [T, "null"]` or `anyOf: [T, {"type": "null"}]`, becomes `T` with `"nullable": true`
+/// - any other `anyOf`/`oneOf` is narrowed to its first schema
+/// - `const` becomes a single value `enum`
+/// - unsupported keywords and formats are dropped
+pub fn gemini_schema(schema: &Value) -> Value {
+    let definitions = schema
+        .get("definitions")
+        .or_else(|| schema.get("$defs"))
+        .cloned()
+        .unwrap_or(Value::Null);
+
+    convert(schema, &definitions, 0)
+}
+
+fn convert(schema: &Value, definitions: &Value, depth: usize) -> Value {
+    let Some(object) = schema.as_object() else {
+        return schema.clone();
+    };
+
+    if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+        let name = reference.rsplit('/').next().unwrap_or(reference);
+        return match definitions.get(name) {
+            Some(target) if depth < MAX_REF_DEPTH => {
+                let mut resolved = convert(target, definitions, depth + 1);
+                merge_description(&mut resolved, object);
+                resolved
+            }
+            _ => json!({ "type": "object" }),
+        };
+    }
+
+    if let Some(Value::Array(all_of)) = object.get("allOf") {
+        if all_of.len() == 1 {
+            let mut merged = convert(&all_of[0], definitions, depth);
+            merge_description(&mut merged, object);
+            return merged;
+        }
+    }
+
+    for key in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(variants)) = object.get(key) {
+            let non_null: Vec<&Value> = variants.iter().filter(|v| !is_null_schema(v)).collect();
+            let Some(first) = non_null.first() else {
+                continue;
+            };
+            if non_null.len() > 1 {
+                tracing::warn!("responseSchema doesn't support {}, keeping only its first schema", key);
+            }
+
+            let mut narrowed = convert(first, definitions, depth);
+            if non_null.len() < variants.len() {
+                narrowed["nullable"] = json!(true);
+            }
+            merge_description(&mut narrowed, object);
+            return narrowed;
+        }
+    }
+
+    let mut converted = Map::new();
+    for (key, value) in object {
+        match key.as_str() {
+            "type" => match value {
+                Value::Array(types) => {
+                    let non_null: Vec<&Value> = types.iter().filter(|t| *t != "null").collect();
+                    if let Some(first) = non_null.first() {
+                        converted.insert("type".to_string(), (*first).clone());
+                    }
+                    if non_null.len() < types.len() {
+                        converted.insert("nullable".to_string(), json!(true));
+                    }
+                }
+                _ => {
+                    converted.insert("type".to_string(), value.clone());
+                }
+            },
+            "format" => {
+                if value.as_str().is_some_and(|f| GEMINI_FORMATS.contains(&f)) {
+                    converted.insert("format".to_string(), value.clone());
+                }
+            }
+            "const" => {
+                converted.insert("enum".to_string(), json!([value]));
+            }
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .map(|properties| {
+                        properties
+                            .iter()
+                            .map(|(name, property)| (name.clone(), convert(property, definitions, depth)))
+                            .collect::<Map<_, _>>()
+                    })
+                    .unwrap_or_default();
+                converted.insert("properties".to_string(), Value::Object(properties));
+            }
+            "items" => {
+                converted.insert("items".to_string(), convert(value, definitions, depth));
+            }
+            key if GEMINI_KEYWORDS.contains(&key) => {
+                converted.insert(key.to_string(), value.clone());
+            }
+            _ => {}
+        }
+    }
+
+    Value::Object(converted)
+}
+
+fn is_null_schema(schema: &Value) -> bool {
+    schema.get("type").is_some_and(|t| t == "null")
+}
+
+/// Keeps the description given where a schema was referenced over the referenced one's.
+fn merge_description(target: &mut Value, source: &Map<String, Value>) {
+    if let (Some(description), Value::Object(target)) = (source.get("description"), target) {
+        target.insert("description".to_string(), description.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Address {
+        city: String,
+        postcode: Option<String>,
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Person {
+        /// Full name
+        name: String,
+        age: u32,
+        home: Address,
+        work: Option<Address>,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_gemini_schema_inlines_refs_and_nullables() {
+        let schema = gemini_schema(&schema_for::<Person>());
+
+        assert!(schema.get("$schema").is_none());
+        assert!(schema.get("definitions").is_none());
+        assert!(schema.get("title").is_none());
+        assert_eq!(schema["type"], "object");
+        assert_eq!(schema["properties"]["name"]["description"], "Full name");
+
+        // Unsupported formats and bounds are dropped
+        assert_eq!(schema["properties"]["age"], json!({ "type": "integer" }));
+
+        // Nested structs are inlined
+        let home = &schema["properties"]["home"];
+        assert_eq!(home["type"], "object");
+        assert_eq!(home["properties"]["city"]["type"], "string");
+        assert_eq!(home["properties"]["postcode"], json!({ "type": "string", "nullable": true }));
+
+        let work = &schema["properties"]["work"];
+        assert_eq!(work["type"], "object");
+        assert_eq!(work["nullable"], true);
+
+        assert_eq!(schema["properties"]["tags"]["items"]["type"], "string");
+        assert!(!schema.to_string().contains("$ref"));
+    }
+
+    #[test]
+    fn test_gemini_schema_cuts_off_recursive_types() {
+        let schema = json!({
+            "$ref": "#/definitions/Node",
+            "definitions": {
+                "Node": {
+                    "type": "object",
+                    "properties": { "child": { "$ref": "#/definitions/Node" } }
+                }
+            }
+        });
+
+        let converted = gemini_schema(&schema);
+        let mut node = &converted;
+        for _ in 0..MAX_REF_DEPTH - 1 {
+            node = &node["properties"]["child"];
+        }
+        assert_eq!(node["properties"]["child"], json!({ "type": "object" }));
+    }
+
+    #[test]
+    fn test_validate_against_schema_conforming() {
+        let schema = schema_for::<Person>();
+        let text = r#"{"name":"Ada","age":36,"home":{"city":"London","postcode":null},"work":null,"tags":["math"]}"#;
+
+        assert!(validate_against_schema(text, &schema).is_ok());
+    }
+
+    #[test]
+    fn test_validate_against_schema_lists_violations() {
+        let schema = schema_for::<Person>();
+        let text = r#"{"name":"Ada","age":"36","home":{"postcode":"N1"},"tags":[]}"#;
+
+        let err = validate_against_schema(text, &schema).unwrap_err();
+        let LlmError::InvalidJsonSchema(message) = err else {
+            panic!("expected a schema mismatch, got {:?}", err);
+        };
+        assert!(message.contains("/age"), "{}", message);
+        assert!(message.contains("/home") && message.contains("city"), "{}", message);
+
+        assert!(matches!(validate_against_schema("not json", &schema), Err(LlmError::InvalidJsonSchema(_))));
+    }
+}
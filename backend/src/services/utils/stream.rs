@@ -0,0 +1,1741 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{
+        mpsc::{error::SendError, Receiver, Sender},
+        watch,
+    },
+};
+
+use crate::{
+    common::types::{
+        chat_request::{ChatCompletionRequestMessage, ChatCompletionRequestToolCall},
+        chat_response::{
+            LlmServiceChatCompletionChunk, LlmServiceChatCompletionResponse,
+            LlmServiceChatCompletionResponseFunctionCall, LlmServiceChatCompletionResponseToolCall,
+            LlmServiceChoiceStream, LlmServiceToolCallDelta, LlmServiceUsage,
+        },
+    },
+    services::{
+        types::{
+            llm_error::{LlmError, LlmStreamingError},
+            llm_service::LlmServiceRequest,
+            stream::{BlockKind, StreamEvent},
+        },
+        utils::{
+            token_budget::{CharEstimate, TokenCounter},
+            transform::{apply_token_transforms, ResponseTransform},
+        },
+    },
+};
+
+/// Everything accumulated while forwarding a provider stream, used to build the final response.
+#[derive(Debug, Default)]
+pub struct StreamSummary {
+    pub id: String,
+    /// Text of the first choice.
+    pub content: String,
+    /// Text of every choice by index, for requests with several candidates. Choice 0
+    /// is the same as `content`.
+    pub choice_contents: BTreeMap<u32, String>,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    pub tool_calls: Vec<LlmServiceChatCompletionResponseToolCall>,
+    /// The last finish reason reported for the first choice.
+    pub finish_reason: Option<String>,
+    /// Tool calls of the other choices by index, tracked like `tool_calls` for choice 0.
+    pub choice_tool_calls: BTreeMap<u32, Vec<LlmServiceChatCompletionResponseToolCall>>,
+    /// The last finish reason reported for every choice by index.
+    pub choice_finish_reasons: BTreeMap<u32, String>,
+    /// Whether the provider reported usage, or it was estimated for a stream cut short.
+    has_usage: bool,
+    pub model_version: Option<String>,
+}
+
+impl StreamSummary {
+    /// The terminal `Completed` event for this stream.
+    fn completed(&self) -> StreamEvent {
+        StreamEvent::Completed {
+            finish_reason: self.finish_reason.clone(),
+            usage: self.has_usage.then(|| LlmServiceUsage {
+                prompt_tokens: self.prompt_tokens,
+                completion_tokens: self.completion_tokens,
+                total_tokens: self.total_tokens,
+            }),
+            model_version: self.model_version.clone(),
+        }
+    }
+}
+
+/// Reassembles streamed tool calls. Fragments are routed by their `index`, so
+/// parallel tool calls whose deltas are interleaved end up in separate calls.
+#[derive(Debug, Default)]
+pub struct ToolCallAccumulator {
+    calls: BTreeMap<u32, LlmServiceChatCompletionResponseToolCall>,
+}
+
+impl ToolCallAccumulator {
+    pub fn push(&mut self, delta: &LlmServiceToolCallDelta) {
+        let call = self
+            .calls
+            .entry(delta.index)
+            .or_insert_with(|| LlmServiceChatCompletionResponseToolCall {
+                id: String::new(),
+                kind: "function".to_string(),
+                function_call: LlmServiceChatCompletionResponseFunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+
+        if let Some(id) = &delta.id {
+            call.id = id.clone();
+        }
+        if let Some(kind) = &delta.kind {
+            call.kind = kind.clone();
+        }
+        if let Some(function) = &delta.function {
+            if let Some(name) = &function.name {
+                call.function_call.name += name;
+            }
+            if let Some(arguments) = &function.arguments {
+                call.function_call.arguments += arguments;
+            }
+        }
+    }
+
+    /// Completed tool calls, ordered by index.
+    pub fn finish(self) -> Vec<LlmServiceChatCompletionResponseToolCall> {
+        self.calls.into_values().collect()
+    }
+
+    /// Whether any call has received its name and all of its arguments.
+    pub fn has_complete_call(&self) -> bool {
+        self.calls.values().any(is_complete_call)
+    }
+}
+
+/// Collects the events a stream consumer receives into the assistant turn they make up,
+/// to keep in the conversation history: the text of the first choice and the tool calls
+/// reassembled during the stream.
+#[derive(Debug, Default)]
+pub struct StreamAccumulator {
+    content: String,
+    tool_calls: Vec<LlmServiceChatCompletionResponseToolCall>,
+}
+
+impl StreamAccumulator {
+    pub fn push(&mut self, event: &StreamEvent) {
+        match event {
+            StreamEvent::Chunk(chunk) if !chunk.is_done_sentinel() => {
+                for choice in chunk.choices.iter().filter(|c| c.index == 0) {
+                    self.content += &choice.delta.content;
+                }
+            }
+            StreamEvent::ToolCall(call) => self.tool_calls.push(call.clone()),
+            _ => {}
+        }
+    }
+
+    /// Text received so far.
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// The assistant message, with `tool_calls` only when the model made some.
+    pub fn into_message(self) -> ChatCompletionRequestMessage {
+        let tool_calls = (!self.tool_calls.is_empty())
+            .then(|| self.tool_calls.into_iter().map(ChatCompletionRequestToolCall::from).collect());
+        ChatCompletionRequestMessage::Assistant {
+            content: self.content,
+            tool_calls,
+            name: None,
+        }
+    }
+}
+
+/// Arguments arrive in fragments, they are complete once they parse as JSON.
+fn is_complete_call(call: &LlmServiceChatCompletionResponseToolCall) -> bool {
+    !call.function_call.name.is_empty()
+        && serde_json::from_str::<serde::de::IgnoredAny>(&call.function_call.arguments).is_ok()
+}
+
+/// Finish and safety events reported by the choices of a chunk, in choice order.
+fn finish_events(chunk: &LlmServiceChatCompletionChunk) -> Vec<StreamEvent> {
+    let mut events = Vec::new();
+
+    for choice in &chunk.choices {
+        if let Some(reason) = &choice.finish_reason {
+            events.push(StreamEvent::FinishReason(reason.clone()));
+        }
+        if let Some(category) = &choice.safety_category {
+            events.push(StreamEvent::SafetyBlock { category: category.clone() });
+        }
+    }
+
+    events
+}
+
+/// Tracks time-to-first-token and token count for a single stream.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamTimer {
+    started: Instant,
+    first_token: Option<Instant>,
+    tokens: u32,
+}
+
+impl StreamTimer {
+    /// `started` should be captured right before the request is sent.
+    pub fn new(started: Instant) -> Self {
+        StreamTimer {
+            started,
+            first_token: None,
+            tokens: 0,
+        }
+    }
+
+    pub fn record_token(&mut self) {
+        if self.first_token.is_none() {
+            self.first_token = Some(Instant::now());
+        }
+        self.tokens += 1;
+    }
+
+    /// Builds a `StreamEvent::Stats` for the current point in time. When the provider
+    /// reported a completion token count it is preferred over the number of chunks seen.
+    pub fn stats(&self, reported_tokens: Option<u32>) -> StreamEvent {
+        StreamEvent::Stats {
+            ttft_ms: self
+                .first_token
+                .map(|t| t.duration_since(self.started).as_millis() as u64),
+            tokens: reported_tokens.filter(|t| *t > 0).unwrap_or(self.tokens),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+/// Tracks which block the stream is currently in.
+#[derive(Debug, Default)]
+struct BlockTracker {
+    current: Option<BlockKind>,
+}
+
+impl BlockTracker {
+    /// Returns the boundary events to send before a chunk belonging to `kind`.
+    fn enter(&mut self, kind: BlockKind) -> Vec<StreamEvent> {
+        if self.current == Some(kind) {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        if self.current.is_some() {
+            events.push(StreamEvent::BlockStop);
+        }
+        events.push(StreamEvent::BlockStart { kind });
+        self.current = Some(kind);
+        events
+    }
+
+    /// Closes the open block, if any.
+    fn close(&mut self) -> Option<StreamEvent> {
+        self.current.take().map(|_| StreamEvent::BlockStop)
+    }
+}
+
+/// The blocks a choice carries content for, in the order they are streamed.
+fn block_kinds(choice: &LlmServiceChoiceStream) -> Vec<BlockKind> {
+    let mut kinds = Vec::new();
+    if choice.delta.thinking.as_deref().is_some_and(|t| !t.is_empty()) {
+        kinds.push(BlockKind::Thinking);
+    }
+    if !choice.delta.content.is_empty() {
+        kinds.push(BlockKind::Text);
+    }
+    if choice.delta.tool_calls.as_ref().is_some_and(|t| !t.is_empty()) {
+        kinds.push(BlockKind::ToolCall);
+    }
+    kinds
+}
+
+/// Splits a chunk that spans several blocks (e.g. the end of the thinking and the start
+/// of the answer) into one chunk per block, so a boundary never falls inside a chunk.
+/// The finish reason and usage stay on the last piece. Chunks without any content,
+/// such as a trailing usage chunk, are returned as-is without a block.
+fn split_blocks(
+    chunk: LlmServiceChatCompletionChunk,
+) -> Vec<(Option<BlockKind>, LlmServiceChatCompletionChunk)> {
+    let kinds = chunk.choices.first().map(block_kinds).unwrap_or_default();
+    if kinds.len() <= 1 {
+        return vec![(kinds.first().copied(), chunk)];
+    }
+
+    let last = kinds.len() - 1;
+    kinds
+        .iter()
+        .enumerate()
+        .map(|(i, kind)| {
+            let mut piece = chunk.clone();
+            if i != last {
+                piece.usage = None;
+                piece.choices.truncate(1);
+            }
+            if let Some(choice) = piece.choices.first_mut() {
+                if *kind != BlockKind::Thinking {
+                    choice.delta.thinking = None;
+                }
+                if *kind != BlockKind::Text {
+                    choice.delta.content.clear();
+                }
+                if *kind != BlockKind::ToolCall {
+                    choice.delta.tool_calls = None;
+                }
+                if i != last {
+                    choice.finish_reason = None;
+                    choice.native_finish_reason = None;
+                    choice.safety_category = None;
+                }
+            }
+            (Some(*kind), piece)
+        })
+        .collect()
+}
+
+/// The provider's message when a streamed event is an error envelope rather than a chunk,
+/// as in `{"error": {"message": "..."}}`, or a bare `{"error": "..."}`.
+/// Providers check this first, an error envelope would often parse as an empty chunk.
+pub fn stream_error_message(json_text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(json_text).ok()?;
+    let error = value.get("error").filter(|error| !error.is_null())?;
+
+    let message = match error.get("message").unwrap_or(error) {
+        serde_json::Value::String(message) => message.clone(),
+        other => other.to_string(),
+    };
+    Some(message)
+}
+
+/// Forwards provider chunks to `tx` until the stream ends or the receiver is dropped,
+/// running each token through `transforms` first. Each run of thinking, text or tool call
+/// chunks is wrapped in `BlockStart`/`BlockStop` events, and usage reported by a chunk is
+/// forwarded as a `UsageDelta` right after it.
+/// Once the stream is finished a `Stats` event, the `Completed` event and the `[DONE]` sentinel are sent. An
+/// error from the provider stream is sent as a `StreamError` instead, ending it there.
+/// If the receiver is dropped the provider stream is dropped straight away, closing its connection.
+pub async fn forward_stream<S, E>(
+    stream: S,
+    tx: &Sender<Result<StreamEvent, LlmStreamingError>>,
+    started: Instant,
+    transforms: &[Arc<dyn ResponseTransform>],
+) -> StreamSummary
+where
+    S: Stream<Item = Result<LlmServiceChatCompletionChunk, E>>,
+    E: Display,
+{
+    forward_stream_with(stream, tx, started, transforms, StreamControl::default()).await
+}
+
+/// Per-request limits on a forwarded stream, see [`forward_stream_with`].
+#[derive(Debug, Clone, Default)]
+pub struct StreamControl {
+    pub max_token_gap: Option<Duration>,
+    pub stop_at_tool_call: bool,
+    pub cancel: Option<StreamCancel>,
+    /// Estimated prompt size, reported on cancellation when the provider sent no usage.
+    pub prompt_tokens: u32,
+    pub coalesce: Option<Coalescing>,
+    /// Local cap on the streamed answer, in tokens estimated from the forwarded text.
+    pub max_tokens: Option<u32>,
+}
+
+impl StreamControl {
+    pub fn for_request(props: &LlmServiceRequest) -> Self {
+        StreamControl {
+            max_token_gap: props.max_token_gap,
+            stop_at_tool_call: props.stop_at_tool_call,
+            cancel: props.stream_cancel.clone(),
+            prompt_tokens: CharEstimate.count_prompt(props),
+            coalesce: props.coalesce,
+            max_tokens: props.client_max_tokens,
+        }
+    }
+}
+
+/// Ends a stream early on the caller's behalf. Unlike dropping the receiver, the stream
+/// still closes properly and reports the usage so far, see [`forward_stream_with`].
+/// Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct StreamCancel {
+    state: Arc<watch::Sender<bool>>,
+}
+
+impl StreamCancel {
+    pub fn new() -> Self {
+        let (state, _) = watch::channel(false);
+        StreamCancel { state: Arc::new(state) }
+    }
+
+    pub fn cancel(&self) {
+        self.state.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    async fn cancelled(&self) {
+        // Can't fail, `self` keeps the sender alive
+        let _ = self.state.subscribe().wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+impl Default for StreamCancel {
+    fn default() -> Self {
+        StreamCancel::new()
+    }
+}
+
+/// Merges runs of small text chunks before they're forwarded, so a UI isn't re-rendered
+/// for every token. A merged chunk is sent once `window` has passed since its first token
+/// or it holds `max_chars` characters, whichever comes first, and before any other event.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Coalescing {
+    /// Serialized as integer milliseconds.
+    #[serde(with = "crate::services::utils::duration_ms")]
+    pub window: Duration,
+    pub max_chars: usize,
+}
+
+/// The sending side of [`forward_stream_with`], holding back text chunks to merge when
+/// coalescing is on.
+struct EventSender<'a> {
+    tx: &'a Sender<Result<StreamEvent, LlmStreamingError>>,
+    coalesce: Option<Coalescing>,
+    pending: Option<(LlmServiceChatCompletionChunk, Instant)>,
+}
+
+type EventSendError = SendError<Result<StreamEvent, LlmStreamingError>>;
+
+impl EventSender<'_> {
+    async fn send(&mut self, item: Result<StreamEvent, LlmStreamingError>) -> Result<(), EventSendError> {
+        let Some(coalesce) = self.coalesce else {
+            return self.tx.send(item).await;
+        };
+
+        let chunk = match item {
+            Ok(StreamEvent::Chunk(chunk)) if is_plain_text(&chunk) => chunk,
+            item => {
+                self.flush().await?;
+                return self.tx.send(item).await;
+            }
+        };
+
+        match &mut self.pending {
+            Some((pending, _)) if pending.id == chunk.id && pending.choices[0].index == chunk.choices[0].index => {
+                pending.choices[0].delta.content += &chunk.choices[0].delta.content;
+            }
+            _ => {
+                self.flush().await?;
+                self.pending = Some((chunk, Instant::now()));
+            }
+        }
+
+        let full = self
+            .pending
+            .as_ref()
+            .is_some_and(|(pending, _)| pending.choices[0].delta.content.chars().count() >= coalesce.max_chars);
+        if full {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), EventSendError> {
+        match self.pending.take() {
+            Some((chunk, _)) => self.tx.send(Ok(StreamEvent::Chunk(chunk))).await,
+            None => Ok(()),
+        }
+    }
+
+    /// When the held back chunk is due.
+    fn flush_at(&self) -> Option<Instant> {
+        let window = self.coalesce?.window;
+        self.pending.as_ref().map(|(_, since)| *since + window)
+    }
+}
+
+/// A chunk of nothing but text for a single choice, which can be merged with its neighbours.
+fn is_plain_text(chunk: &LlmServiceChatCompletionChunk) -> bool {
+    let [choice] = chunk.choices.as_slice() else {
+        return false;
+    };
+    chunk.usage.is_none()
+        && !choice.delta.content.is_empty()
+        && choice.delta.thinking.is_none()
+        && choice.delta.tool_calls.as_ref().is_none_or(|calls| calls.is_empty())
+        && choice.finish_reason.is_none()
+        && choice.safety_category.is_none()
+}
+
+/// [`forward_stream`] with the limits in `control`:
+///
+/// - a stall watchdog: once the first chunk arrived, going `max_token_gap` without another
+///   one sends a `StreamError("token stall")` and aborts the stream, without the `Stats`
+///   event and `[DONE]` sentinel.
+/// - `stop_at_tool_call`: as soon as a tool call has its name and complete JSON arguments
+///   it is sent as a `ToolCall` event and the stream ends normally, dropping the request.
+///   Fragments of other tool calls received by then are discarded.
+/// - `cancel`: once cancelled the request is dropped and the stream ends normally, with a
+///   final `UsageDelta` for the tokens so far first. Counts the provider didn't report are
+///   estimated from the text received and `prompt_tokens`.
+/// - `coalesce`: text chunks are merged as described for [`Coalescing`].
+/// - `max_tokens`: once the text forwarded adds up to this many estimated tokens the
+///   request is dropped and the stream ends normally, reporting usage like `cancel`.
+///   The chunk crossing the limit is still sent whole, so it may be overshot slightly.
+pub async fn forward_stream_with<S, E>(
+    stream: S,
+    tx: &Sender<Result<StreamEvent, LlmStreamingError>>,
+    started: Instant,
+    transforms: &[Arc<dyn ResponseTransform>],
+    control: StreamControl,
+) -> StreamSummary
+where
+    S: Stream<Item = Result<LlmServiceChatCompletionChunk, E>>,
+    E: Display,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut summary = StreamSummary::default();
+    let mut timer = StreamTimer::new(started);
+    let mut tool_calls = ToolCallAccumulator::default();
+    let mut choice_tool_calls: BTreeMap<u32, ToolCallAccumulator> = BTreeMap::new();
+    let mut blocks = BlockTracker::default();
+    let mut last_chunk: Option<Instant> = None;
+    let mut stopped_at_tool_call = false;
+    let mut cancelled = false;
+    let mut forwarded_tokens = 0;
+    let mut capped = false;
+    let mut out = EventSender {
+        tx,
+        coalesce: control.coalesce,
+        pending: None,
+    };
+
+    loop {
+        let stall = async {
+            match (control.max_token_gap, last_chunk) {
+                (Some(gap), Some(last)) => tokio::time::sleep_until((last + gap).into()).await,
+                _ => std::future::pending().await,
+            }
+        };
+        let flush_at = out.flush_at();
+        let flush_due = async {
+            match flush_at {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let cancel = async {
+            match &control.cancel {
+                Some(cancel) => cancel.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        // Stop as soon as the consumer goes away rather than on the next send, which may be
+        // a long time coming. Returning drops `stream` and with it the in-flight HTTP request.
+        let chunk = tokio::select! {
+            biased;
+            _ = tx.closed() => {
+                tracing::debug!("Stream receiver dropped, aborting request");
+                break;
+            }
+            _ = cancel => {
+                tracing::debug!("Stream cancelled, aborting request");
+                cancelled = true;
+                break;
+            }
+            // Before the next chunk, so a steady stream can't hold back a merged one
+            _ = flush_due => {
+                let _ = out.flush().await;
+                continue;
+            }
+            chunk = stream.next() => match chunk {
+                Some(chunk) => chunk,
+                None => break,
+            },
+            _ = stall => {
+                tracing::warn!("No chunk for {:?}, aborting stalled stream", control.max_token_gap.unwrap_or_default());
+                let _ = out.send(Err(LlmStreamingError::StreamError("token stall".to_string()))).await;
+                return summary;
+            }
+        };
+        last_chunk = Some(Instant::now());
+
+        match chunk {
+            Ok(mut c) => {
+                summary.id = c.id.clone();
+
+                for choice in c.choices.iter_mut() {
+                    choice.delta.content = apply_token_transforms(transforms, &choice.delta.content);
+                }
+
+                if let Some(u) = &c.usage {
+                    summary.completion_tokens = u.completion_tokens;
+                    summary.prompt_tokens = u.prompt_tokens;
+                    summary.total_tokens = u.total_tokens;
+                    summary.has_usage = true;
+                }
+                for choice in &c.choices {
+                    if let Some(reason) = &choice.finish_reason {
+                        summary.choice_finish_reasons.insert(choice.index, reason.clone());
+                    }
+                }
+                if let Some(reason) = c.choices.iter().filter(|c| c.index == 0).find_map(|c| c.finish_reason.clone()) {
+                    summary.finish_reason = Some(reason);
+                }
+                if c.model_version.is_some() {
+                    summary.model_version = c.model_version.clone();
+                }
+
+                let mut closed = false;
+                for (kind, piece) in split_blocks(c) {
+                    if let Some(kind) = kind {
+                        for event in blocks.enter(kind) {
+                            let _ = out.send(Ok(event)).await;
+                        }
+                    }
+
+                    // Choices are routed by index, chunks of several candidates may interleave
+                    for choice in &piece.choices {
+                        if choice.delta.content.is_empty() {
+                            continue;
+                        }
+                        timer.record_token();
+                        forwarded_tokens += CharEstimate.count_tokens(&choice.delta.content);
+                        if choice.index == 0 {
+                            summary.content += &choice.delta.content;
+                        }
+                        *summary.choice_contents.entry(choice.index).or_default() += &choice.delta.content;
+                    }
+
+                    // Deltas are indexed within their choice, each choice has its own accumulator
+                    for choice in &piece.choices {
+                        let accumulator = match choice.index {
+                            0 => &mut tool_calls,
+                            index => choice_tool_calls.entry(index).or_default(),
+                        };
+                        for delta in choice.delta.tool_calls.iter().flatten() {
+                            accumulator.push(delta);
+                        }
+                    }
+
+                    let usage = piece.usage.clone();
+                    let events = finish_events(&piece);
+
+                    if out.send(Ok(StreamEvent::Chunk(piece))).await.is_err() {
+                        closed = true;
+                        break;
+                    }
+
+                    if let Some(usage) = usage {
+                        let _ = out.send(Ok(StreamEvent::UsageDelta(usage))).await;
+                    }
+                    for event in events {
+                        let _ = out.send(Ok(event)).await;
+                    }
+
+                    if control.stop_at_tool_call && tool_calls.has_complete_call() {
+                        tracing::debug!("Tool call complete, ending stream early");
+                        stopped_at_tool_call = true;
+                        break;
+                    }
+                    if control.max_tokens.is_some_and(|max| forwarded_tokens >= max) {
+                        tracing::debug!("Client token limit of {} reached, ending stream early", forwarded_tokens);
+                        capped = true;
+                        break;
+                    }
+                }
+
+                if closed || stopped_at_tool_call || capped {
+                    break;
+                }
+            }
+            Err(e) => {
+                tracing::error!("Error during streaming: {}", e);
+                let _ = out.send(Err(LlmStreamingError::StreamError(e.to_string()))).await;
+                return summary;
+            }
+        }
+    }
+
+    if let Some(event) = blocks.close() {
+        let _ = out.send(Ok(event)).await;
+    }
+
+    if cancelled || capped {
+        let usage = partial_usage(&mut summary, control.prompt_tokens);
+        let _ = out.send(Ok(StreamEvent::UsageDelta(usage))).await;
+    }
+
+    summary.tool_calls = tool_calls.finish();
+    summary.choice_tool_calls = choice_tool_calls
+        .into_iter()
+        .map(|(index, calls)| (index, calls.finish()))
+        .filter(|(_, calls)| !calls.is_empty())
+        .collect();
+    if stopped_at_tool_call || cancelled || capped {
+        summary.tool_calls.retain(is_complete_call);
+        for calls in summary.choice_tool_calls.values_mut() {
+            calls.retain(is_complete_call);
+        }
+    }
+    for tool_call in &summary.tool_calls {
+        let _ = out.send(Ok(StreamEvent::ToolCall(tool_call.clone()))).await;
+    }
+
+    let _ = out.send(Ok(timer.stats(Some(summary.completion_tokens)))).await;
+    let _ = out.send(Ok(summary.completed())).await;
+    let _ = out
+        .send(Ok(StreamEvent::Chunk(LlmServiceChatCompletionChunk::done_sentinel(
+            summary.id.clone(),
+        ))))
+        .await;
+
+    summary
+}
+
+/// Usage of a stream cut short, filling in what the provider hadn't reported yet: the
+/// completion from the text of every choice and the prompt from `prompt_tokens`.
+/// `summary` is updated to match.
+fn partial_usage(summary: &mut StreamSummary, prompt_tokens: u32) -> LlmServiceUsage {
+    if summary.completion_tokens == 0 {
+        summary.completion_tokens = summary.choice_contents.values().map(|text| CharEstimate.count_tokens(text)).sum();
+    }
+    if summary.prompt_tokens == 0 {
+        summary.prompt_tokens = prompt_tokens;
+    }
+    summary.total_tokens = summary.prompt_tokens + summary.completion_tokens;
+    summary.has_usage = true;
+
+    LlmServiceUsage {
+        prompt_tokens: summary.prompt_tokens,
+        completion_tokens: summary.completion_tokens,
+        total_tokens: summary.total_tokens,
+    }
+}
+
+/// Delivers a complete non-streamed response to a stream consumer as a single chunk
+/// (with token transforms applied), followed by the same `Stats`, `Completed` and `[DONE]` events a real stream ends with.
+pub async fn forward_response(
+    response: &LlmServiceChatCompletionResponse,
+    tx: &Sender<Result<StreamEvent, LlmStreamingError>>,
+    started: Instant,
+    transforms: &[Arc<dyn ResponseTransform>],
+) {
+    let mut timer = StreamTimer::new(started);
+    let mut chunk = LlmServiceChatCompletionChunk::from_response(response);
+    for choice in chunk.choices.iter_mut() {
+        choice.delta.content = apply_token_transforms(transforms, &choice.delta.content);
+    }
+    if chunk.choices.iter().any(|c| !c.delta.content.is_empty()) {
+        timer.record_token();
+    }
+
+    let mut blocks = BlockTracker::default();
+    for (kind, piece) in split_blocks(chunk) {
+        if let Some(kind) = kind {
+            for event in blocks.enter(kind) {
+                let _ = tx.send(Ok(event)).await;
+            }
+        }
+
+        let events = finish_events(&piece);
+
+        if tx.send(Ok(StreamEvent::Chunk(piece))).await.is_err() {
+            return;
+        }
+
+        for event in events {
+            let _ = tx.send(Ok(event)).await;
+        }
+    }
+    if let Some(event) = blocks.close() {
+        let _ = tx.send(Ok(event)).await;
+    }
+
+    let reported_tokens = response.usage.as_ref().map(|u| u.completion_tokens);
+    let _ = tx.send(Ok(timer.stats(reported_tokens))).await;
+    let _ = tx
+        .send(Ok(StreamEvent::Completed {
+            finish_reason: response.choices.first().and_then(|c| c.finish_reason.clone()),
+            usage: response.usage.as_ref().map(|u| LlmServiceUsage {
+                prompt_tokens: u.prompt_tokens,
+                completion_tokens: u.completion_tokens,
+                total_tokens: u.total_tokens,
+            }),
+            model_version: response.model_version.clone(),
+        }))
+        .await;
+    let _ = tx
+        .send(Ok(StreamEvent::Chunk(LlmServiceChatCompletionChunk::done_sentinel(
+            response.id.clone(),
+        ))))
+        .await;
+}
+
+/// Re-chunks the text of a stream to whole words, for a smoother typewriter effect in a UI:
+/// sub-word tokens are held back until their word is complete, and bursts are split into
+/// one chunk per word, each with the whitespace that follows it. Any other event first
+/// releases the partial word held back, then passes through unchanged.
+pub async fn rechunk_words(
+    mut rx: Receiver<Result<StreamEvent, LlmStreamingError>>,
+    tx: &Sender<Result<StreamEvent, LlmStreamingError>>,
+) {
+    let mut held: Option<LlmServiceChatCompletionChunk> = None;
+
+    while let Some(item) = rx.recv().await {
+        let chunk = match item {
+            Ok(StreamEvent::Chunk(chunk)) if is_plain_text(&chunk) => chunk,
+            item => {
+                if let Some(rest) = held.take() {
+                    if tx.send(Ok(StreamEvent::Chunk(rest))).await.is_err() {
+                        return;
+                    }
+                }
+                if tx.send(item).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // Text of another choice releases the word held back for the previous one
+        let mut buffer = match held.take() {
+            Some(mut previous) if previous.id == chunk.id && previous.choices[0].index == chunk.choices[0].index => {
+                previous.choices[0].delta.content += &chunk.choices[0].delta.content;
+                previous
+            }
+            previous => {
+                if let Some(previous) = previous {
+                    if tx.send(Ok(StreamEvent::Chunk(previous))).await.is_err() {
+                        return;
+                    }
+                }
+                chunk
+            }
+        };
+
+        for word in take_words(&mut buffer.choices[0].delta.content) {
+            let mut piece = buffer.clone();
+            piece.choices[0].delta.content = word;
+            if tx.send(Ok(StreamEvent::Chunk(piece))).await.is_err() {
+                return;
+            }
+        }
+        if !buffer.choices[0].delta.content.is_empty() {
+            held = Some(buffer);
+        }
+    }
+
+    if let Some(rest) = held {
+        let _ = tx.send(Ok(StreamEvent::Chunk(rest))).await;
+    }
+}
+
+/// Removes the complete words from the front of `text`, each with the whitespace after it.
+/// What is left is a word that may still continue in the next chunk.
+fn take_words(text: &mut String) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut after_space = false;
+
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            after_space = true;
+        } else if after_space {
+            words.push(text[start..i].to_string());
+            start = i;
+            after_space = false;
+        }
+    }
+    if after_space {
+        words.push(text[start..].to_string());
+        start = text.len();
+    }
+
+    text.drain(..start);
+    words
+}
+
+/// Drains a stream into the same response a non-streamed request returns: the
+/// accumulated text, the usage from the terminal chunk, the last reported finish
+/// reason and any tool calls. Text and finish reason are those of the first choice,
+/// further candidates of an `n > 1` stream are left out. The model isn't part of the
+/// stream and is left empty.
+pub async fn stream_to_completion(
+    mut rx: Receiver<Result<StreamEvent, LlmStreamingError>>,
+) -> Result<LlmServiceChatCompletionResponse, LlmError> {
+    let mut id = None;
+    let mut content = String::new();
+    let mut usage = None;
+    let mut finish_reason = None;
+    let mut tool_calls = Vec::new();
+
+    while let Some(event) = rx.recv().await {
+        match event? {
+            StreamEvent::Chunk(chunk) => {
+                if chunk.is_done_sentinel() {
+                    break;
+                }
+
+                if let Some(u) = &chunk.usage {
+                    usage = Some((u.prompt_tokens, u.completion_tokens, u.total_tokens));
+                }
+                if let Some(choice) = chunk.choices.iter().find(|c| c.index == 0) {
+                    content += &choice.delta.content;
+                    if choice.finish_reason.is_some() {
+                        finish_reason = choice.finish_reason.clone();
+                    }
+                }
+                id = Some(chunk.id);
+            }
+            StreamEvent::ToolCall(tool_call) => tool_calls.push(tool_call),
+            // Not tied to a choice, the chunk it came from is
+            StreamEvent::FinishReason(_)
+            | StreamEvent::BlockStart { .. }
+            | StreamEvent::BlockStop
+            | StreamEvent::UsageDelta(_)
+            | StreamEvent::SafetyBlock { .. }
+            | StreamEvent::Stats { .. }
+            | StreamEvent::Completed { .. } => {}
+        }
+    }
+
+    let id = id.ok_or(LlmError::EmptyResponse)?;
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64;
+
+    let mut response = LlmServiceChatCompletionResponse::new_streamed(
+        id,
+        content,
+        String::new(),
+        created,
+        usage.map(|u| u.0),
+        usage.map(|u| u.1),
+        usage.map(|u| u.2),
+    );
+
+    let choice = &mut response.choices[0];
+    if finish_reason.is_some() {
+        choice.finish_reason = finish_reason;
+    }
+    if !tool_calls.is_empty() {
+        choice.message.tool_calls = Some(tool_calls);
+    }
+
+    Ok(response)
+}
+
+/// How often [`stream_to_writer`] flushes its writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushCadence {
+    /// After every chunk of text, for interactive output.
+    EveryChunk,
+    /// After this many chunks.
+    Chunks(usize),
+    /// Once this much time passed since the last flush.
+    Interval(Duration),
+}
+
+/// Writes the text of a stream to `writer` as it arrives, e.g. to stdout or a file,
+/// flushing at `cadence` and once more at the end. Returns the number of bytes written.
+/// A stream error or a failed write ends the call with that error.
+pub async fn stream_to_writer<W: AsyncWrite + Unpin>(
+    mut rx: Receiver<Result<StreamEvent, LlmStreamingError>>,
+    mut writer: W,
+    cadence: FlushCadence,
+) -> Result<usize, LlmError> {
+    let write_error = |e: std::io::Error| LlmError::Internal(format!("Failed to write stream output: {}", e));
+
+    let mut written = 0;
+    let mut unflushed = 0;
+    let mut last_flush = Instant::now();
+
+    while let Some(event) = rx.recv().await {
+        let StreamEvent::Chunk(chunk) = event? else {
+            continue;
+        };
+        if chunk.is_done_sentinel() {
+            break;
+        }
+
+        // Only the first choice is written, further candidates would interleave with it
+        let Some(text) = chunk.choices.iter().find(|c| c.index == 0).map(|c| &c.delta.content).filter(|t| !t.is_empty()) else {
+            continue;
+        };
+        writer.write_all(text.as_bytes()).await.map_err(write_error)?;
+        written += text.len();
+        unflushed += 1;
+
+        let flush = match cadence {
+            FlushCadence::EveryChunk => true,
+            FlushCadence::Chunks(n) => unflushed >= n,
+            FlushCadence::Interval(interval) => last_flush.elapsed() >= interval,
+        };
+        if flush {
+            writer.flush().await.map_err(write_error)?;
+            unflushed = 0;
+            last_flush = Instant::now();
+        }
+    }
+
+    writer.flush().await.map_err(write_error)?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::chat_response::{
+        LlmServiceChatCompletionResponseChoice, LlmServiceChatCompletionResponseMessage,
+        LlmServiceChatCompletionResponseUsage, LlmServiceFunctionCallDelta,
+        LlmServiceStreamDelta, LlmServiceUsage,
+    };
+    use tokio::sync::mpsc;
+
+    // Builds a single-choice content chunk
+    fn content_chunk(content: &str) -> LlmServiceChatCompletionChunk {
+        LlmServiceChatCompletionChunk {
+            id: "gen-123".to_string(),
+            choices: vec![LlmServiceChoiceStream {
+                index: 0,
+                delta: LlmServiceStreamDelta {
+                    role: "assistant".to_string(),
+                    content: content.to_string(),
+                    thinking: None,
+                    tool_calls: None,
+                },
+                finish_reason: None,
+                native_finish_reason: None,
+                safety_category: None,
+            }],
+            usage: None,
+            model_version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_stream_records_ttft() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let started = Instant::now();
+
+        // First token only arrives after a delay
+        let stream = async_stream::stream! {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            yield Ok::<_, String>(content_chunk("Hello"));
+            yield Ok::<_, String>(content_chunk(" world"));
+        };
+
+        let summary = forward_stream(stream, &tx, started, &[]).await;
+        drop(tx);
+
+        assert_eq!(summary.content, "Hello world");
+
+        let mut stats = None;
+        let mut done = false;
+        while let Some(event) = rx.recv().await {
+            match event.unwrap() {
+                StreamEvent::Stats { ttft_ms, tokens, elapsed_ms } => {
+                    stats = Some((ttft_ms, tokens, elapsed_ms));
+                }
+                StreamEvent::Chunk(c) => done = c.is_done_sentinel(),
+                StreamEvent::ToolCall(_) => panic!("No tool calls were streamed"),
+                StreamEvent::BlockStart { .. } | StreamEvent::BlockStop => {}
+                StreamEvent::UsageDelta(_) => panic!("No chunk reported usage"),
+                StreamEvent::FinishReason(_) | StreamEvent::SafetyBlock { .. } => {
+                    panic!("No chunk reported a finish reason")
+                }
+                StreamEvent::Completed { .. } => {}
+            }
+        }
+
+        let (ttft_ms, tokens, elapsed_ms) = stats.expect("Stats event was not sent");
+        let ttft_ms = ttft_ms.expect("TTFT was not recorded");
+        assert!(ttft_ms >= 50);
+        assert!(elapsed_ms >= ttft_ms);
+        assert_eq!(tokens, 2);
+
+        // The sentinel is still the last event
+        assert!(done);
+    }
+
+    // Builds a chunk carrying a single tool call fragment
+    fn tool_call_chunk(index: u32, id: Option<&str>, name: Option<&str>, arguments: &str) -> LlmServiceChatCompletionChunk {
+        let mut chunk = content_chunk("");
+        chunk.choices[0].delta.tool_calls = Some(vec![LlmServiceToolCallDelta {
+            index,
+            id: id.map(str::to_string),
+            kind: id.map(|_| "function".to_string()),
+            function: Some(LlmServiceFunctionCallDelta {
+                name: name.map(str::to_string),
+                arguments: Some(arguments.to_string()),
+            }),
+        }]);
+        chunk
+    }
+
+    #[tokio::test]
+    async fn test_forward_stream_interleaved_tool_calls() {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(tool_call_chunk(0, Some("call_a"), Some("get_weather"), ""));
+            yield Ok::<_, String>(tool_call_chunk(1, Some("call_b"), Some("get_time"), ""));
+            yield Ok::<_, String>(tool_call_chunk(0, None, None, r#"{"city":"#));
+            yield Ok::<_, String>(tool_call_chunk(1, None, None, r#"{"tz":"UTC"}"#));
+            yield Ok::<_, String>(tool_call_chunk(0, None, None, r#""Paris"}"#));
+        };
+
+        let summary = forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        assert_eq!(summary.tool_calls.len(), 2);
+        assert_eq!(summary.tool_calls[0].id, "call_a");
+        assert_eq!(summary.tool_calls[0].function_call.name, "get_weather");
+        assert_eq!(summary.tool_calls[0].function_call.arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(summary.tool_calls[1].id, "call_b");
+        assert_eq!(summary.tool_calls[1].function_call.name, "get_time");
+        assert_eq!(summary.tool_calls[1].function_call.arguments, r#"{"tz":"UTC"}"#);
+
+        let mut emitted = Vec::new();
+        while let Some(event) = rx.recv().await {
+            if let StreamEvent::ToolCall(call) = event.unwrap() {
+                emitted.push(call.id);
+            }
+        }
+        assert_eq!(emitted, vec!["call_a", "call_b"]);
+    }
+
+    #[tokio::test]
+    async fn test_forward_stream_block_boundaries() {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        let mut thinking = content_chunk("");
+        thinking.choices[0].delta.thinking = Some("Let me think".to_string());
+
+        // The last thought and the start of the answer arrive in the same chunk
+        let mut both = content_chunk("The answer");
+        both.choices[0].delta.thinking = Some(" it through.".to_string());
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(thinking);
+            yield Ok::<_, String>(both);
+            yield Ok::<_, String>(content_chunk(" is 42."));
+        };
+
+        let summary = forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        assert_eq!(summary.content, "The answer is 42.");
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(match event.unwrap() {
+                StreamEvent::BlockStart { kind } => format!("start:{}", kind.as_str()),
+                StreamEvent::BlockStop => "stop".to_string(),
+                StreamEvent::Chunk(c) if c.is_done_sentinel() => "done".to_string(),
+                StreamEvent::Chunk(c) => {
+                    let delta = &c.choices[0].delta;
+                    match &delta.thinking {
+                        Some(thinking) => format!("thinking:{}", thinking),
+                        None => format!("text:{}", delta.content),
+                    }
+                }
+                StreamEvent::Stats { .. } => "stats".to_string(),
+                StreamEvent::Completed { .. } => "completed".to_string(),
+                other => panic!("Unexpected event {:?}", other),
+            });
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                "start:thinking",
+                "thinking:Let me think",
+                "thinking: it through.",
+                "stop",
+                "start:text",
+                "text:The answer",
+                "text: is 42.",
+                "stop",
+                "stats",
+                "completed",
+                "done",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_stream_aborts_when_receiver_dropped() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (dropped_tx, dropped_rx) = tokio::sync::oneshot::channel::<()>();
+
+        // A provider that sends one chunk and then stalls, holding on to its connection
+        let stream = async_stream::stream! {
+            let _connection = dropped_tx;
+            yield Ok::<_, String>(content_chunk("Hello"));
+            std::future::pending::<()>().await;
+        };
+
+        let task = tokio::spawn(async move {
+            forward_stream(stream, &tx, Instant::now(), &[]).await
+        });
+
+        // Read up to the first chunk, then hang up
+        while let Some(event) = rx.recv().await {
+            if let StreamEvent::Chunk(_) = event.unwrap() {
+                break;
+            }
+        }
+        drop(rx);
+
+        let summary = tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("forward_stream kept running after the receiver was dropped")
+            .unwrap();
+        assert_eq!(summary.content, "Hello");
+
+        // The provider stream, and with it the request, was dropped
+        assert!(dropped_rx.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_forward_stream_usage_deltas() {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        // Cumulative usage on every chunk, as some providers send it
+        let with_usage = |content: &str, completion_tokens: u32| {
+            let mut chunk = content_chunk(content);
+            chunk.usage = Some(LlmServiceUsage {
+                prompt_tokens: 10,
+                completion_tokens,
+                total_tokens: 10 + completion_tokens,
+            });
+            chunk
+        };
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(with_usage("One", 1));
+            yield Ok::<_, String>(content_chunk(" two"));
+            yield Ok::<_, String>(with_usage(" three", 3));
+            yield Ok::<_, String>(with_usage(" four", 4));
+        };
+
+        let summary = forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let mut deltas = Vec::new();
+        let mut final_tokens = None;
+        while let Some(event) = rx.recv().await {
+            match event.unwrap() {
+                StreamEvent::UsageDelta(usage) => deltas.push((usage.completion_tokens, usage.total_tokens)),
+                StreamEvent::Stats { tokens, .. } => final_tokens = Some(tokens),
+                _ => {}
+            }
+        }
+
+        assert_eq!(deltas, vec![(1, 11), (3, 13), (4, 14)]);
+
+        // The final total is still reported
+        assert_eq!(final_tokens, Some(4));
+        assert_eq!(summary.total_tokens, 14);
+    }
+
+    #[tokio::test]
+    async fn test_stream_to_completion() {
+        let (tx, rx) = mpsc::channel(20);
+
+        let mut last = content_chunk("!");
+        last.choices[0].finish_reason = Some("length".to_string());
+        last.usage = Some(LlmServiceUsage {
+            prompt_tokens: 12,
+            completion_tokens: 3,
+            total_tokens: 15,
+        });
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(content_chunk("Hello"));
+            yield Ok::<_, String>(content_chunk(" world"));
+            yield Ok::<_, String>(last);
+        };
+
+        forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let completion = stream_to_completion(rx).await.unwrap();
+        assert_eq!(completion.id, "gen-123");
+        assert_eq!(completion.choices[0].message.content, "Hello world!");
+        assert_eq!(completion.choices[0].finish_reason.as_deref(), Some("length"));
+
+        let usage = completion.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.total_tokens, 15);
+    }
+
+    #[tokio::test]
+    async fn test_stream_to_completion_error() {
+        let (tx, rx) = mpsc::channel(10);
+        tx.send(Err(LlmStreamingError::StreamError("connection reset".to_string()))).await.unwrap();
+        drop(tx);
+
+        let result = stream_to_completion(rx).await;
+        assert!(matches!(result, Err(LlmError::Provider(msg)) if msg == "connection reset"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_to_completion_keeps_first_choice() {
+        let (tx, rx) = mpsc::channel(20);
+
+        let candidate = |index: u32, content: &str, finish_reason: Option<&str>| {
+            let mut chunk = content_chunk(content);
+            chunk.choices[0].index = index;
+            chunk.choices[0].finish_reason = finish_reason.map(str::to_string);
+            chunk
+        };
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(candidate(0, "Red", None));
+            yield Ok::<_, String>(candidate(1, "Blue", None));
+            yield Ok::<_, String>(candidate(0, " apple", Some("stop")));
+            yield Ok::<_, String>(candidate(1, " sky", Some("length")));
+        };
+
+        forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let completion = stream_to_completion(rx).await.unwrap();
+        assert_eq!(completion.choices[0].message.content, "Red apple");
+        assert_eq!(completion.choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_aborts_on_token_stall() {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(content_chunk("Hello"));
+            std::future::pending::<()>().await;
+        };
+
+        let control = StreamControl { max_token_gap: Some(Duration::from_millis(50)), ..Default::default() };
+        let forwarding = forward_stream_with(stream, &tx, Instant::now(), &[], control);
+        let summary = tokio::time::timeout(Duration::from_secs(1), forwarding)
+            .await
+            .expect("stalled stream was not aborted");
+        assert_eq!(summary.content, "Hello");
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert!(matches!(events.last(), Some(Err(LlmStreamingError::StreamError(msg))) if msg == "token stall"));
+        assert!(!events.iter().any(|e| matches!(e, Ok(StreamEvent::Chunk(c)) if c.is_done_sentinel())));
+    }
+
+    #[tokio::test]
+    async fn test_stop_at_tool_call_ends_stream_early() {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(tool_call_chunk(0, Some("call_a"), Some("get_weather"), ""));
+            yield Ok::<_, String>(tool_call_chunk(0, None, None, r#"{"city":"#));
+            yield Ok::<_, String>(tool_call_chunk(0, None, None, r#""Paris"}"#));
+            yield Ok::<_, String>(content_chunk("Let me check that for you."));
+            std::future::pending::<()>().await;
+        };
+
+        let control = StreamControl { stop_at_tool_call: true, ..Default::default() };
+        let forwarding = forward_stream_with(stream, &tx, Instant::now(), &[], control);
+        let summary = tokio::time::timeout(Duration::from_secs(1), forwarding)
+            .await
+            .expect("stream did not stop at the tool call");
+        drop(tx);
+
+        assert_eq!(summary.content, "");
+        assert_eq!(summary.tool_calls.len(), 1);
+        assert_eq!(summary.tool_calls[0].function_call.arguments, r#"{"city":"Paris"}"#);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event.unwrap());
+        }
+        let tool_call = events.iter().position(|e| matches!(e, StreamEvent::ToolCall(c) if c.id == "call_a"));
+        assert!(tool_call.is_some());
+        assert!(matches!(events.last(), Some(StreamEvent::Chunk(c)) if c.is_done_sentinel()));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, StreamEvent::Chunk(c) if !c.is_done_sentinel() && c.choices[0].delta.content.contains("check"))));
+    }
+
+    #[tokio::test]
+    async fn test_accumulated_stream_becomes_assistant_message() {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(content_chunk("Let me "));
+            yield Ok::<_, String>(content_chunk("check."));
+            yield Ok::<_, String>(tool_call_chunk(0, Some("call_a"), Some("get_weather"), r#"{"city":"#));
+            yield Ok::<_, String>(tool_call_chunk(0, None, None, r#""Paris"}"#));
+        };
+        forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let mut accumulator = StreamAccumulator::default();
+        while let Some(event) = rx.recv().await {
+            accumulator.push(&event.unwrap());
+        }
+        assert_eq!(accumulator.content(), "Let me check.");
+
+        match accumulator.into_message() {
+            ChatCompletionRequestMessage::Assistant { content, tool_calls: Some(tool_calls), name: None } => {
+                assert_eq!(content, "Let me check.");
+                assert_eq!(tool_calls.len(), 1);
+                assert_eq!(tool_calls[0].id, "call_a");
+                assert_eq!(tool_calls[0].kind, "function");
+                assert_eq!(tool_calls[0].function_call.name, "get_weather");
+                assert_eq!(tool_calls[0].function_call.arguments, r#"{"city":"Paris"}"#);
+            }
+            other => panic!("expected an assistant turn with a tool call, got {:?}", other),
+        }
+
+        // Text only, no empty tool_calls list
+        let mut accumulator = StreamAccumulator::default();
+        accumulator.push(&StreamEvent::Chunk(content_chunk("Hi")));
+        assert!(matches!(
+            accumulator.into_message(),
+            ChatCompletionRequestMessage::Assistant { tool_calls: None, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_stream_reports_partial_usage() {
+        let (tx, mut rx) = mpsc::channel(20);
+        let cancel = StreamCancel::new();
+
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(content_chunk("Hello there, "));
+            yield Ok::<_, String>(content_chunk("general"));
+            std::future::pending::<()>().await;
+        };
+
+        let control = StreamControl { cancel: Some(cancel.clone()), prompt_tokens: 20, ..Default::default() };
+        let forwarding = tokio::spawn(async move { forward_stream_with(stream, &tx, Instant::now(), &[], control).await });
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            let event = event.unwrap();
+            let second_token = matches!(&event, StreamEvent::Chunk(c) if c.choices[0].delta.content == "general");
+            events.push(event);
+            if second_token {
+                cancel.cancel();
+            }
+        }
+        let summary = tokio::time::timeout(Duration::from_secs(1), forwarding)
+            .await
+            .expect("cancelled stream did not end")
+            .unwrap();
+
+        // "Hello there, general" is 20 characters, so 5 estimated tokens
+        assert!(cancel.is_cancelled());
+        assert_eq!((summary.prompt_tokens, summary.completion_tokens, summary.total_tokens), (20, 5, 25));
+        let usage = events.iter().find_map(|e| match e {
+            StreamEvent::UsageDelta(usage) => Some(usage),
+            _ => None,
+        });
+        assert!(matches!(usage, Some(u) if u.prompt_tokens == 20 && u.completion_tokens == 5 && u.total_tokens == 25));
+        assert!(matches!(events.last(), Some(StreamEvent::Chunk(c)) if c.is_done_sentinel()));
+    }
+
+    #[tokio::test]
+    async fn test_client_max_tokens_stops_stream() {
+        let (tx, mut rx) = mpsc::channel(50);
+        let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let counter = sent.clone();
+        let stream = async_stream::stream! {
+            for _ in 0..50 {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                yield Ok::<_, String>(content_chunk("word"));
+            }
+        };
+
+        let control = StreamControl { max_tokens: Some(10), ..Default::default() };
+        let summary = forward_stream_with(stream, &tx, Instant::now(), &[], control).await;
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event.unwrap());
+        }
+        let texts = events
+            .iter()
+            .filter(|e| matches!(e, StreamEvent::Chunk(c) if !c.is_done_sentinel()))
+            .count();
+
+        // Each "word" is one estimated token
+        assert_eq!(texts, 10);
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), 10);
+        assert_eq!(summary.content, "word".repeat(10));
+        assert_eq!(summary.completion_tokens, 10);
+        assert!(events.iter().any(|e| matches!(e, StreamEvent::Stats { .. })));
+        assert!(matches!(events.last(), Some(StreamEvent::Chunk(c)) if c.is_done_sentinel()));
+    }
+
+    #[tokio::test]
+    async fn test_rapid_tokens_coalesce() {
+        async fn forward_coalesced(coalesce: Coalescing) -> Vec<String> {
+            let (tx, mut rx) = mpsc::channel(50);
+            let stream = async_stream::stream! {
+                for token in ["Th", "e ", "qu", "ick"] {
+                    yield Ok::<_, String>(content_chunk(token));
+                }
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                yield Ok::<_, String>(content_chunk(" fox"));
+            };
+
+            let control = StreamControl { coalesce: Some(coalesce), ..Default::default() };
+            forward_stream_with(stream, &tx, Instant::now(), &[], control).await;
+            drop(tx);
+
+            let mut texts = Vec::new();
+            while let Some(event) = rx.recv().await {
+                if let Ok(StreamEvent::Chunk(c)) = event {
+                    if !c.is_done_sentinel() {
+                        texts.push(c.choices[0].delta.content.clone());
+                    }
+                }
+            }
+            texts
+        }
+
+        // The pause outlasts the window, so the last token goes out on its own
+        let texts = forward_coalesced(Coalescing { window: Duration::from_millis(50), max_chars: 100 }).await;
+        assert_eq!(texts, ["The quick", " fox"]);
+
+        let texts = forward_coalesced(Coalescing { window: Duration::from_secs(5), max_chars: 4 }).await;
+        assert_eq!(texts, ["The ", "quick", " fox"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_to_writer() {
+        let (tx, rx) = mpsc::channel(20);
+
+        // A second candidate is left out
+        let mut other = content_chunk("Goodbye");
+        other.choices[0].index = 1;
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(content_chunk("Hello"));
+            yield Ok::<_, String>(other);
+            yield Ok::<_, String>(content_chunk(" world"));
+            yield Ok::<_, String>(content_chunk("!"));
+        };
+
+        forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let mut buffer = Vec::new();
+        let written = stream_to_writer(rx, &mut buffer, FlushCadence::Chunks(2)).await.unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "Hello world!");
+        assert_eq!(written, 12);
+    }
+
+    #[tokio::test]
+    async fn test_stream_to_writer_error() {
+        let (tx, rx) = mpsc::channel(10);
+        tx.send(Ok(StreamEvent::Chunk(content_chunk("partial")))).await.unwrap();
+        tx.send(Err(LlmStreamingError::StreamError("connection reset".to_string()))).await.unwrap();
+        drop(tx);
+
+        let mut buffer = Vec::new();
+        let result = stream_to_writer(rx, &mut buffer, FlushCadence::EveryChunk).await;
+        assert!(matches!(result, Err(LlmError::Provider(msg)) if msg == "connection reset"));
+        assert_eq!(buffer, b"partial");
+    }
+
+    #[test]
+    fn test_tokens_per_sec() {
+        let stats = StreamEvent::Stats {
+            ttft_ms: Some(100),
+            tokens: 50,
+            elapsed_ms: 2000,
+        };
+        assert_eq!(stats.tokens_per_sec(), Some(25.0));
+
+        let empty = StreamEvent::Stats {
+            ttft_ms: None,
+            tokens: 0,
+            elapsed_ms: 0,
+        };
+        assert_eq!(empty.tokens_per_sec(), None);
+    }
+
+    #[tokio::test]
+    async fn test_forward_response_as_single_chunk() {
+        let (tx, mut rx) = mpsc::channel(10);
+
+        // What a non-streaming-only provider hands back
+        let response = LlmServiceChatCompletionResponse {
+            id: "gen-456".to_string(),
+            choices: vec![LlmServiceChatCompletionResponseChoice {
+                message: LlmServiceChatCompletionResponseMessage {
+                    role: "assistant".to_string(),
+                    content: "The whole answer".to_string(),
+                    name: None,
+                    tool_calls: None,
+                    parts: None,
+                    reasoning: None,
+                },
+                finish_reason: Some("stop".to_string()),
+                native_finish_reason: None,
+                safety_ratings: Vec::new(),
+                annotations: Vec::new(),
+            }],
+            created: 0,
+            model: "test-model".to_string(),
+            provider: None,
+            model_version: None,
+            rate_limit: None,
+            raw: None,
+            usage: Some(LlmServiceChatCompletionResponseUsage {
+                prompt_tokens: 10,
+                completion_tokens: 3,
+                total_tokens: 13,
+            }),
+        };
+
+        forward_response(&response, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let mut events = vec![];
+        while let Some(event) = rx.recv().await {
+            events.push(event.unwrap());
+        }
+
+        assert_eq!(events.len(), 7);
+        assert!(matches!(events[0], StreamEvent::BlockStart { kind: BlockKind::Text }));
+
+        // One chunk with the full content
+        match &events[1] {
+            StreamEvent::Chunk(c) => {
+                assert!(!c.is_done_sentinel());
+                assert_eq!(c.choices[0].delta.content, "The whole answer");
+                assert_eq!(c.usage.as_ref().unwrap().completion_tokens, 3);
+            }
+            other => panic!("Expected content chunk, got {:?}", other),
+        }
+
+        // The finish reason follows the chunk that reported it
+        match &events[2] {
+            StreamEvent::FinishReason(reason) => assert_eq!(reason, "stop"),
+            other => panic!("Expected finish reason, got {:?}", other),
+        }
+
+        assert!(matches!(events[3], StreamEvent::BlockStop));
+
+        // Then stats and completion
+        match &events[4] {
+            StreamEvent::Stats { tokens, ttft_ms, .. } => {
+                assert_eq!(*tokens, 3);
+                assert!(ttft_ms.is_some());
+            }
+            other => panic!("Expected stats, got {:?}", other),
+        }
+
+        match &events[5] {
+            StreamEvent::Completed { finish_reason, usage, .. } => {
+                assert_eq!(finish_reason.as_deref(), Some("stop"));
+                assert_eq!(usage.as_ref().unwrap().total_tokens, 13);
+            }
+            other => panic!("Expected completed, got {:?}", other),
+        }
+
+        match &events[6] {
+            StreamEvent::Chunk(c) => assert!(c.is_done_sentinel()),
+            other => panic!("Expected done sentinel, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_completed_event_ends_stream() {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        let mut last = content_chunk(" there");
+        last.choices[0].finish_reason = Some("length".to_string());
+        last.usage = Some(LlmServiceUsage { prompt_tokens: 8, completion_tokens: 2, total_tokens: 10 });
+        last.model_version = Some("model-2025-01-01".to_string());
+        let stream = async_stream::stream! {
+            yield Ok::<_, String>(content_chunk("Hi"));
+            yield Ok::<_, String>(last);
+        };
+        forward_stream(stream, &tx, Instant::now(), &[]).await;
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event.unwrap());
+        }
+
+        let completed: Vec<_> = events.iter().filter(|e| matches!(e, StreamEvent::Completed { .. })).collect();
+        assert_eq!(completed.len(), 1);
+        match completed[0] {
+            StreamEvent::Completed { finish_reason, usage, model_version } => {
+                assert_eq!(finish_reason.as_deref(), Some("length"));
+                assert_eq!(usage.as_ref().map(|u| (u.prompt_tokens, u.completion_tokens, u.total_tokens)), Some((8, 2, 10)));
+                assert_eq!(model_version.as_deref(), Some("model-2025-01-01"));
+            }
+            _ => unreachable!(),
+        }
+
+        // Right before the sentinel
+        assert!(matches!(&events[events.len() - 2], StreamEvent::Completed { .. }));
+        assert!(matches!(events.last(), Some(StreamEvent::Chunk(c)) if c.is_done_sentinel()));
+    }
+
+    #[tokio::test]
+    async fn test_rechunk_words_from_sub_word_tokens() {
+        let (provider_tx, provider_rx) = mpsc::channel(20);
+        let (tx, mut rx) = mpsc::channel(20);
+
+        for token in ["Hel", "lo", " wor", "ld, how are", " you", "?"] {
+            provider_tx.send(Ok(StreamEvent::Chunk(content_chunk(token)))).await.unwrap();
+        }
+        provider_tx.send(Ok(StreamEvent::FinishReason("stop".to_string()))).await.unwrap();
+        drop(provider_tx);
+
+        rechunk_words(provider_rx, &tx).await;
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(match event.unwrap() {
+                StreamEvent::Chunk(c) => c.choices[0].delta.content.clone(),
+                StreamEvent::FinishReason(reason) => format!("finish:{}", reason),
+                other => panic!("Unexpected event {:?}", other),
+            });
+        }
+
+        // The last word is released by the finish reason
+        assert_eq!(events, vec!["Hello ", "world, ", "how ", "are ", "you?", "finish:stop"]);
+    }
+}
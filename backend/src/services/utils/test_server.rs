@@ -0,0 +1,149 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// Answers a single HTTP request on a local port with `status` and a JSON `body`,
+/// returns the base url to point a provider at.
+pub async fn serve_once(status: u16, body: String) -> String {
+    serve_capture(status, body).await.0
+}
+
+/// Like [`serve_once`], also handing back the raw request that was received.
+pub async fn serve_capture(status: u16, body: String) -> (String, oneshot::Receiver<String>) {
+    serve_capture_with_headers(status, Vec::new(), body).await
+}
+
+/// Like [`serve_capture`], sending `headers` along with the response.
+pub async fn serve_capture_with_headers(
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+) -> (String, oneshot::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let (head, _) = read_request(&mut socket).await;
+        respond(&mut socket, status, &headers, &body, false).await;
+        let _ = tx.send(head);
+    });
+
+    (format!("http://{}", addr), rx)
+}
+
+/// Like [`serve_once`], waiting `delay` after the request arrived before answering.
+pub async fn serve_after(delay: Duration, status: u16, body: String) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        read_request(&mut socket).await;
+        tokio::time::sleep(delay).await;
+        respond(&mut socket, status, &[], &body, false).await;
+    });
+
+    format!("http://{}", addr)
+}
+
+/// Answers every request with what `handler` returns for the request body, until the
+/// test ends. Requests are handled concurrently.
+pub async fn serve_with<F>(handler: F) -> String
+where
+    F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let (_, body) = read_request(&mut socket).await;
+                let (status, response) = handler(&body);
+                respond(&mut socket, status, &[], &response, false).await;
+            });
+        }
+    });
+
+    format!("http://{}", addr)
+}
+
+/// Answers every request with `status` and `body` and keeps connections open between
+/// requests, counting the connections accepted so tests can check they are reused.
+pub async fn serve_keep_alive(status: u16, body: String) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let body = body.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (head, _) = read_request(&mut socket).await;
+                    if head.is_empty() {
+                        break;
+                    }
+                    respond(&mut socket, status, &[], &body, true).await;
+                }
+            });
+        }
+    });
+
+    (format!("http://{}", addr), connections)
+}
+
+/// Reads the whole request, returns it in full and its body.
+async fn read_request(socket: &mut TcpStream) -> (String, String) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = socket.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+
+        let text = String::from_utf8_lossy(&request);
+        if let Some(header_end) = text.find("\r\n\r\n") {
+            let content_length = text[..header_end]
+                .lines()
+                .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                .unwrap_or(0);
+            if request.len() >= header_end + 4 + content_length {
+                break;
+            }
+        }
+    }
+
+    let text = String::from_utf8_lossy(&request).into_owned();
+    let body = text.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+    (text, body)
+}
+
+async fn respond(socket: &mut TcpStream, status: u16, headers: &[(&'static str, String)], body: &str, keep_alive: bool) {
+    let extra: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        extra,
+        if keep_alive { "keep-alive" } else { "close" },
+        body
+    );
+    socket.write_all(response.as_bytes()).await.unwrap();
+}
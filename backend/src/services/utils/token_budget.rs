@@ -0,0 +1,162 @@
+use crate::services::types::{
+    llm_error::LlmError,
+    llm_service::{ContextOverflow, LlmServiceRequest},
+};
+
+use super::model_limits::{context_window, max_output_tokens};
+
+/// Tokens kept free on top of the prompt, since counters only approximate the
+/// provider's tokenizer.
+const SAFETY_MARGIN: u32 = 256;
+
+/// Role markers and separators every message costs on top of its content.
+const TOKENS_PER_MESSAGE: u32 = 4;
+
+/// Counts tokens the way a model's tokenizer would, or close enough to budget with.
+pub trait TokenCounter {
+    fn count_tokens(&self, text: &str) -> u32;
+
+    /// Tokens the messages of `props` take up in the context window.
+    fn count_prompt(&self, props: &LlmServiceRequest) -> u32 {
+        props
+            .request
+            .messages
+            .iter()
+            .map(|msg| self.count_tokens(&msg.content()) + TOKENS_PER_MESSAGE)
+            .sum()
+    }
+}
+
+/// Rough estimate of four characters per token, for when no tokenizer is at hand.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CharEstimate;
+
+impl TokenCounter for CharEstimate {
+    fn count_tokens(&self, text: &str) -> u32 {
+        (text.chars().count() as u32).div_ceil(4)
+    }
+}
+
+/// Largest `max_tokens` that still fits the model's context window: the window minus
+/// the prompt minus [`SAFETY_MARGIN`], capped at the model's output limit.
+///
+/// Fails with [`LlmError::InvalidConfig`] for models without a known context window
+/// and [`LlmError::PromptTooLong`] when the prompt leaves no room for output.
+pub fn max_output_budget(props: &LlmServiceRequest, counter: &impl TokenCounter) -> Result<u32, LlmError> {
+    let model = &props.request.model;
+    let window = context_window(model)
+        .ok_or_else(|| LlmError::InvalidConfig(format!("Unknown context window for {}", model)))?;
+
+    let prompt = counter.count_prompt(props);
+    let budget = window.saturating_sub(prompt).saturating_sub(SAFETY_MARGIN);
+    if budget == 0 {
+        return Err(LlmError::PromptTooLong(
+            (prompt + SAFETY_MARGIN) as usize,
+            window as usize,
+        ));
+    }
+
+    let output_cap = max_output_tokens(model).map_or(u32::MAX, |limit| limit as u32);
+    Ok(budget.min(output_cap))
+}
+
+/// Whether the prompt plus the requested `max_tokens` fits the model's context window.
+/// When it doesn't, the request is failed with [`LlmError::PromptTooLong`] or only
+/// warned about, as `props.context_overflow` says. Requests without `max_tokens` and
+/// models without a known window always pass.
+pub fn check_context_fit(props: &LlmServiceRequest, counter: &impl TokenCounter) -> Result<bool, LlmError> {
+    let model = &props.request.model;
+    let (Some(max_tokens), Some(window)) = (props.request.max_tokens, context_window(model)) else {
+        return Ok(true);
+    };
+
+    let prompt = counter.count_prompt(props);
+    let needed = prompt as u64 + max_tokens.max(0) as u64;
+    if needed <= window as u64 {
+        return Ok(true);
+    }
+
+    match props.context_overflow {
+        ContextOverflow::Warn => {
+            tracing::warn!(
+                "Prompt of ~{} tokens plus max_tokens {} exceeds the {} token context window of {}, the answer may be cut short",
+                prompt,
+                max_tokens,
+                window,
+                model
+            );
+            Ok(false)
+        }
+        ContextOverflow::Error => Err(LlmError::PromptTooLong(needed as usize, window as usize)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::models::LlmApiProvider;
+
+    // One token per whitespace separated word keeps the arithmetic readable
+    struct WordCounter;
+
+    impl TokenCounter for WordCounter {
+        fn count_tokens(&self, text: &str) -> u32 {
+            text.split_whitespace().count() as u32
+        }
+    }
+
+    fn props_with_prompt(model: &str, words: usize) -> LlmServiceRequest {
+        let prompt = vec!["word"; words].join(" ");
+        LlmServiceRequest::from_user_prompt(LlmApiProvider::Openrouter, model, &prompt)
+    }
+
+    #[test]
+    fn test_budget_is_window_minus_prompt_and_margin() {
+        // 128k window, 120k word prompt plus one message overhead
+        let props = props_with_prompt("openai/gpt-4o", 120_000);
+
+        let budget = max_output_budget(&props, &WordCounter).unwrap();
+        assert_eq!(budget, 128_000 - 120_000 - TOKENS_PER_MESSAGE - SAFETY_MARGIN);
+    }
+
+    #[test]
+    fn test_budget_capped_at_output_limit() {
+        let props = props_with_prompt("openai/gpt-4o", 1_000);
+        assert_eq!(max_output_budget(&props, &WordCounter).unwrap(), 16_384);
+    }
+
+    #[test]
+    fn test_budget_errors() {
+        let props = props_with_prompt("openai/gpt-4o", 128_000);
+        assert!(matches!(
+            max_output_budget(&props, &WordCounter),
+            Err(LlmError::PromptTooLong(_, 128_000))
+        ));
+
+        let props = props_with_prompt("some-new-model", 10);
+        assert!(matches!(max_output_budget(&props, &WordCounter), Err(LlmError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_context_overflow_warns_or_errors() {
+        let mut props = props_with_prompt("openai/gpt-4o", 120_000);
+        props.request.max_tokens = Some(16_000);
+        assert!(!check_context_fit(&props, &WordCounter).unwrap());
+
+        props.context_overflow = ContextOverflow::Error;
+        assert!(matches!(
+            check_context_fit(&props, &WordCounter),
+            Err(LlmError::PromptTooLong(136_004, 128_000))
+        ));
+
+        props.request.max_tokens = Some(4_000);
+        assert!(check_context_fit(&props, &WordCounter).unwrap());
+    }
+
+    #[test]
+    fn test_char_estimate() {
+        assert_eq!(CharEstimate.count_tokens(""), 0);
+        assert_eq!(CharEstimate.count_tokens("abcd"), 1);
+        assert_eq!(CharEstimate.count_tokens("abcde"), 2);
+    }
+}
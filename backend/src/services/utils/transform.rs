@@ -0,0 +1,105 @@
+use std::{fmt::Debug, sync::Arc};
+
+/// Post-processing step applied to model output before it is returned.
+pub trait ResponseTransform: Debug + Send + Sync {
+    /// Transforms the complete response text.
+    fn apply(&self, text: &str) -> String;
+
+    /// Transforms a single streamed token. Most transforms need the full text to do
+    /// anything useful, so tokens are passed through untouched by default.
+    fn apply_token(&self, token: &str) -> String {
+        token.to_string()
+    }
+}
+
+/// Trims leading and trailing whitespace from the response.
+#[derive(Debug, Clone, Copy)]
+pub struct Trim;
+
+impl ResponseTransform for Trim {
+    fn apply(&self, text: &str) -> String {
+        text.trim().to_string()
+    }
+}
+
+/// Removes a markdown code fence (and its language tag) wrapping the whole response.
+#[derive(Debug, Clone, Copy)]
+pub struct StripCodeFence;
+
+impl ResponseTransform for StripCodeFence {
+    fn apply(&self, text: &str) -> String {
+        let fenced = text
+            .trim()
+            .strip_prefix("```")
+            .and_then(|rest| rest.strip_suffix("```"));
+
+        match fenced {
+            Some(inner) => {
+                // everything up to the first newline is the language tag
+                let body = match inner.find('\n') {
+                    Some(pos) => &inner[pos + 1..],
+                    None => inner,
+                };
+                body.trim_end_matches(|c| c == '\n' || c == '\r').to_string()
+            }
+            None => text.to_string(),
+        }
+    }
+}
+
+/// Runs `text` through each transform in order.
+pub fn apply_transforms(transforms: &[Arc<dyn ResponseTransform>], text: &str) -> String {
+    transforms
+        .iter()
+        .fold(text.to_string(), |acc, transform| transform.apply(&acc))
+}
+
+/// Runs a streamed token through each transform in order.
+pub fn apply_token_transforms(transforms: &[Arc<dyn ResponseTransform>], token: &str) -> String {
+    transforms
+        .iter()
+        .fold(token.to_string(), |acc, transform| transform.apply_token(&acc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Uppercase;
+
+    impl ResponseTransform for Uppercase {
+        fn apply(&self, text: &str) -> String {
+            text.to_uppercase()
+        }
+
+        fn apply_token(&self, token: &str) -> String {
+            token.to_uppercase()
+        }
+    }
+
+    #[test]
+    fn test_strip_code_fence() {
+        let text = "```json\n{\"name\": \"test\"}\n```";
+        assert_eq!(StripCodeFence.apply(text), "{\"name\": \"test\"}");
+
+        // Unfenced text is left alone
+        assert_eq!(StripCodeFence.apply("plain text"), "plain text");
+    }
+
+    #[test]
+    fn test_chained_transforms() {
+        let transforms: Vec<Arc<dyn ResponseTransform>> = vec![Arc::new(StripCodeFence), Arc::new(Trim)];
+
+        let text = "  ```json\n  {\"name\": \"test\"}  \n```  \n";
+        assert_eq!(apply_transforms(&transforms, text), "{\"name\": \"test\"}");
+    }
+
+    #[test]
+    fn test_token_transforms() {
+        let transforms: Vec<Arc<dyn ResponseTransform>> = vec![Arc::new(Trim), Arc::new(Uppercase)];
+
+        // Trim leaves tokens alone so spacing between words survives
+        assert_eq!(apply_token_transforms(&transforms, " hello"), " HELLO");
+    }
+}
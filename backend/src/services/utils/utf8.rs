@@ -0,0 +1,58 @@
+use std::str::Utf8Error;
+
+/// Turns a byte stream into text when chunk boundaries may fall inside a multi-byte
+/// character. An incomplete sequence at the end of a chunk is held back until the
+/// bytes completing it arrive, so only whole characters are ever emitted.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Decodes `bytes` following whatever was held back from the previous call.
+    /// Bytes that can never form valid UTF-8 are an error.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<String, Utf8Error> {
+        self.pending.extend_from_slice(bytes);
+
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.len(),
+            // `error_len` is `None` when the input merely ends mid character
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(e),
+        };
+
+        let text = std::str::from_utf8(&self.pending[..valid])?.to_string();
+        self.pending.drain(..valid);
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emoji_split_across_chunks() {
+        let bytes = "a😀b".as_bytes();
+        let mut decoder = Utf8Decoder::default();
+
+        // The 4 byte emoji starts at index 1, split it after its second byte
+        assert_eq!(decoder.decode(&bytes[..3]).unwrap(), "a");
+        assert_eq!(decoder.decode(&bytes[3..]).unwrap(), "😀b");
+    }
+
+    #[test]
+    fn test_byte_at_a_time() {
+        let text = "日本語 ok";
+        let mut decoder = Utf8Decoder::default();
+
+        let decoded: String = text.as_bytes().iter().map(|b| decoder.decode(&[*b]).unwrap()).collect();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn test_invalid_bytes_are_an_error() {
+        let mut decoder = Utf8Decoder::default();
+        assert!(decoder.decode(&[b'a', 0xFF, b'b']).is_err());
+    }
+}